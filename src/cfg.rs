@@ -1,3 +1,4 @@
+use crate::node::retention::RetentionPolicy;
 use crate::{connectors, consensus, network};
 use clap::Parser;
 use figment::{
@@ -17,8 +18,13 @@ pub struct Config {
     pub consensus: consensus::consensus::Config,
     pub gossip: network::gossip::Config,
     pub rpc_address: String,
+    /// Address to serve Prometheus metrics on, metrics aren't served when unset
+    pub metrics_address: Option<String>,
     pub rocksdb_dir: String,
     pub clear_db: bool,
+    pub retention_policy: RetentionPolicy,
+    /// Most shard chunks returned by one GetShardChunks call, clients page through larger ranges
+    pub max_chunks_per_request: usize,
 }
 
 impl Default for Config {
@@ -31,8 +37,11 @@ impl Default for Config {
             consensus: consensus::consensus::Config::default(),
             gossip: network::gossip::Config::default(),
             rpc_address: "0.0.0.0:3383".to_string(),
+            metrics_address: None,
             rocksdb_dir: ".rocks".to_string(),
             clear_db: false,
+            retention_policy: RetentionPolicy::KeepAll,
+            max_chunks_per_request: network::server::DEFAULT_MAX_CHUNKS_PER_REQUEST,
         }
     }
 }
@@ -1,7 +1,9 @@
 use async_trait::async_trait;
-use libp2p::identity::ed25519::{Keypair, SecretKey};
+use libp2p::identity::ed25519::{Keypair, PublicKey, SecretKey};
 use malachite_common::ValidatorSet;
 use ractor::{Actor, ActorProcessingErr, ActorRef};
+use std::collections::BTreeMap;
+use std::ops::Range;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
@@ -13,14 +15,20 @@ use malachite_config::TimeoutConfig;
 use malachite_consensus::{Effect, ProposedValue, Resume, SignedConsensusMsg};
 use malachite_metrics::Metrics;
 
+use crate::consensus::proposer::{
+    ChunkWaitPolicy, DEFAULT_HEADER_HASH_LEN, DEFAULT_MAX_CONCURRENT_SYNCS, DEFAULT_PROPOSAL_TTL,
+};
 use crate::consensus::timers::{TimeoutElapsed, TimerScheduler};
 use crate::consensus::validator::ShardValidator;
+use crate::core::buffer_pool::DEFAULT_MAX_POOLED_BUFFERS;
 use crate::core::types::{
-    Height, ShardId, SnapchainContext, SnapchainShard, SnapchainValidator,
-    SnapchainValidatorContext,
+    Address, Height, ShardHash, ShardId, SnapchainContext, SnapchainShard, SnapchainValidator,
+    SnapchainValidatorContext, SnapchainValidatorSet, DEFAULT_ADDRESS_PREFIX_LEN,
 };
 use crate::network::gossip::GossipEvent;
 use crate::proto::snapchain::FullProposal;
+use crate::storage::db::StoreTuning;
+use crate::storage::store::engine::DEFAULT_MEMPOOL_CAPACITY;
 pub use malachite_consensus::Params as ConsensusParams;
 pub use malachite_consensus::State as ConsensusState;
 use ractor::time::send_after;
@@ -28,7 +36,17 @@ use serde::{Deserialize, Serialize};
 use tokio::time::Instant;
 
 pub type ConsensusRef<Ctx> = ActorRef<ConsensusMsg<Ctx>>;
-pub type Decision = FullProposal;
+
+/// Emitted by a proposer every time consensus decides on a value
+#[derive(Clone, Debug)]
+pub struct Decision {
+    pub height: Height,
+    pub round: Round,
+    pub value: ShardHash,
+    pub validator_set: SnapchainValidatorSet,
+    pub proposal: FullProposal,
+}
+
 pub type TxDecision = mpsc::Sender<Decision>;
 pub type RxDecision = mpsc::Receiver<Decision>;
 
@@ -47,10 +65,202 @@ impl<Ctx: Context + SnapchainContext> From<TimeoutElapsed<Timeout>> for Consensu
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub private_key: String,
-    pub shard_ids: String,
+    /// Comma separated ids of the shards this node runs, e.g. "1,2"
+    pub shard_ids: ShardIds,
 
     #[serde(with = "humantime_serde")]
     pub propose_value_delay: Duration,
+
+    /// Maximum number of blocks the block proposer will replay from a peer when syncing.
+    /// Nodes further behind than this should be restored from a checkpoint instead.
+    pub max_sync_lookback: u64,
+
+    /// Comma separated `fid:shard_id` pairs pinning specific fids to a shard, e.g. "1:2,42:1"
+    pub shard_overrides: ShardOverrides,
+
+    /// Shard ids fids were assigned over before `rebalance_height`, e.g. "1,2" when a third shard was
+    /// added. Fids don't move their existing data: chunks committed before the rebalance stay in the
+    /// shard the fid was assigned to then and remain readable there, only new transactions follow
+    /// the new assignment. Overrides apply on both sides of the rebalance.
+    #[serde(default)]
+    pub previous_shard_ids: ShardIds,
+
+    /// Block number from which fids are assigned over `shard_ids` instead of `previous_shard_ids`
+    #[serde(default)]
+    pub rebalance_height: Option<u64>,
+
+    /// Comma separated hex encoded public keys of the other validators. The local validator is always
+    /// part of the validator set, so this can be left empty for a single node network.
+    pub validator_public_keys: String,
+
+    /// Length in bytes of block and shard chunk header hashes, between 16 and 32. Shorter hashes save
+    /// space at the cost of collision resistance. All validators must use the same value.
+    pub header_hash_len: u32,
+
+    /// How long a shard can go without deciding a height before a liveness stall is reported
+    #[serde(with = "humantime_serde")]
+    pub liveness_stall_timeout: Duration,
+
+    /// Stop proposing for a height after this many rounds without a decision. Unlimited when unset.
+    pub max_rounds_per_height: Option<u32>,
+
+    /// Random delay, e.g. `{ start = "10ms", end = "100ms" }`, added before proposing so validators
+    /// don't all hit the network at the same time. No delay when unset.
+    #[serde(default, with = "humantime_range")]
+    pub propose_jitter: Option<Range<Duration>>,
+
+    /// Keep running with the shards whose db opened when others fail, instead of refusing to start
+    pub allow_partial_shards: bool,
+
+    /// Build a block without waiting for the slowest shards, e.g.
+    /// `{ min_fraction = 0.66, grace_period = "50ms" }`. Waits for all shards when unset.
+    #[serde(default)]
+    pub chunk_wait_policy: Option<ChunkWaitPolicy>,
+
+    /// Number of recently received consensus messages remembered to drop duplicates delivered by
+    /// gossip. 0 disables deduplication.
+    pub dedup_cache_size: usize,
+
+    /// Rocksdb tuning for the shard dbs, e.g. `{ write_buffer_size = 67108864, compaction_style =
+    /// "universal", prefix_bloom_len = 9 }`. Rocksdb's defaults when unset.
+    #[serde(default)]
+    pub store_tuning: StoreTuning,
+
+    /// Number of blocks decided on top of a block before it's sent to confirmed block subscribers
+    pub confirmation_depth: u64,
+
+    /// Record how long each transaction takes to apply, by message type. Off by default as it times
+    /// every transaction.
+    pub profiling: bool,
+
+    /// Messages a shard holds waiting to be applied. Submissions are rejected as busy while it's
+    /// full, so producers slow down to the rate the engine keeps up with.
+    pub mempool_capacity: usize,
+
+    /// Hex characters of the validator address used to identify nodes in logs. Widen it when
+    /// running many nodes whose short prefixes collide.
+    pub address_prefix_len: usize,
+
+    /// Serve the readable chunks around a shard chunk record that can't be decoded instead of failing
+    /// every range read that covers it. Skipped records are logged.
+    pub skip_corrupt_chunks: bool,
+
+    /// Block syncs from peers running at once, further syncs wait for one to finish so validators
+    /// registering together at boot don't all replay the chain in parallel
+    pub max_concurrent_syncs: usize,
+
+    /// How long a proposal is held waiting to be decided. Proposals of rounds that never decide are
+    /// dropped once they're this old.
+    #[serde(with = "humantime_serde")]
+    pub proposal_ttl: Duration,
+
+    /// Encode buffers each thread keeps for reuse instead of allocating one per chunk write or header
+    /// hash. 0 turns reuse off.
+    pub encode_buffer_pool_size: usize,
+}
+
+/// Shard ids parsed from a comma separated list, e.g. "1,2". Empty for a block-only node.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ShardIds(Vec<u32>);
+
+impl TryFrom<String> for ShardIds {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                s.parse()
+                    .map_err(|e| format!("Invalid shard id {}: {}", s, e))
+            })
+            .collect::<Result<_, _>>()
+            .map(ShardIds)
+    }
+}
+
+impl From<ShardIds> for String {
+    fn from(shard_ids: ShardIds) -> Self {
+        shard_ids
+            .0
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+/// Fid to shard id pairs parsed from a comma separated list of `fid:shard_id`, e.g. "1:2,42:1"
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ShardOverrides(BTreeMap<u64, u32>);
+
+impl TryFrom<String> for ShardOverrides {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value
+            .split(',')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let invalid = || format!("Invalid shard override {}, expected fid:shard_id", s);
+                let (fid, shard_id) = s.split_once(':').ok_or_else(invalid)?;
+                Ok((
+                    fid.parse().map_err(|_| invalid())?,
+                    shard_id.parse().map_err(|_| invalid())?,
+                ))
+            })
+            .collect::<Result<_, String>>()
+            .map(ShardOverrides)
+    }
+}
+
+impl From<ShardOverrides> for String {
+    fn from(shard_overrides: ShardOverrides) -> Self {
+        shard_overrides
+            .0
+            .iter()
+            .map(|(fid, shard_id)| format!("{}:{}", fid, shard_id))
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+mod humantime_range {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::ops::Range;
+    use std::time::Duration;
+
+    #[derive(Serialize, Deserialize)]
+    struct HumantimeRange {
+        #[serde(with = "humantime_serde")]
+        start: Duration,
+        #[serde(with = "humantime_serde")]
+        end: Duration,
+    }
+
+    pub fn serialize<S: Serializer>(
+        range: &Option<Range<Duration>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        range
+            .as_ref()
+            .map(|range| HumantimeRange {
+                start: range.start,
+                end: range.end,
+            })
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Range<Duration>>, D::Error> {
+        Ok(
+            Option::<HumantimeRange>::deserialize(deserializer)?
+                .map(|range| range.start..range.end),
+        )
+    }
 }
 
 impl Config {
@@ -60,26 +270,109 @@ impl Config {
         Keypair::from(secret_key.unwrap())
     }
 
+    // Empty for a block-only node, which runs no shards and only produces (empty) blocks
     pub fn shard_ids(&self) -> Vec<u32> {
-        self.shard_ids
+        self.shard_ids.0.clone()
+    }
+
+    pub fn num_shards(&self) -> u32 {
+        self.shard_ids.0.len() as u32
+    }
+
+    pub fn shard_overrides(&self) -> &BTreeMap<u64, u32> {
+        &self.shard_overrides.0
+    }
+
+    pub fn previous_shard_ids(&self) -> Vec<u32> {
+        self.previous_shard_ids.0.clone()
+    }
+
+    // The shard a fid is assigned to from now on
+    pub fn shard_for_fid(&self, fid: u64) -> u32 {
+        self.shard_for_fid_at_height(fid, u64::MAX)
+    }
+
+    // The shard a fid was assigned to when the block at block_number was built, i.e. where its
+    // transactions from that height are stored
+    pub fn shard_for_fid_at_height(&self, fid: u64, block_number: u64) -> u32 {
+        if let Some(shard_id) = self.shard_overrides.0.get(&fid) {
+            return *shard_id;
+        }
+        let shard_ids = match self.rebalance_height {
+            Some(rebalance_height) if block_number < rebalance_height => &self.previous_shard_ids.0,
+            _ => &self.shard_ids.0,
+        };
+        if shard_ids.is_empty() {
+            // Block-only node, the block shard doesn't take messages so they're turned away
+            return 0;
+        }
+        shard_ids[(fid % shard_ids.len() as u64) as usize]
+    }
+
+    pub fn validator_public_keys(&self) -> Vec<PublicKey> {
+        self.validator_public_keys
             .split(',')
-            .map(|s| s.parse().unwrap())
+            .filter(|s| !s.is_empty())
+            .map(|s| PublicKey::try_from_bytes(&hex::decode(s).unwrap()).unwrap())
             .collect()
     }
 
-    pub fn num_shards(&self) -> u32 {
-        self.shard_ids().len() as u32
+    pub fn validate(&self) -> Result<(), String> {
+        for key in self
+            .validator_public_keys
+            .split(',')
+            .filter(|s| !s.is_empty())
+        {
+            let bytes = hex::decode(key)
+                .map_err(|e| format!("Invalid validator public key {}: {}", key, e))?;
+            PublicKey::try_from_bytes(&bytes)
+                .map_err(|e| format!("Invalid validator public key {}: {}", key, e))?;
+        }
+
+        if !(16..=32).contains(&self.header_hash_len) {
+            return Err(format!(
+                "Header hash length must be between 16 and 32 bytes, got {}",
+                self.header_hash_len
+            ));
+        }
+
+        if self.mempool_capacity == 0 {
+            return Err("Mempool capacity must be at least 1".to_string());
+        }
+
+        if self.max_concurrent_syncs == 0 {
+            return Err("Max concurrent syncs must be at least 1".to_string());
+        }
+
+        if !(1..=64).contains(&self.address_prefix_len) {
+            return Err(format!(
+                "Address prefix length must be between 1 and 64 hex characters, got {}",
+                self.address_prefix_len
+            ));
+        }
+
+        if self.rebalance_height.is_some() && self.previous_shard_ids.0.is_empty() {
+            return Err(
+                "A rebalance height needs the previous shard ids the fids are moving from"
+                    .to_string(),
+            );
+        }
+
+        for (fid, shard_id) in self.shard_overrides() {
+            if !self.shard_ids.0.contains(shard_id) {
+                return Err(format!(
+                    "Shard override for fid {} targets unhosted shard {}",
+                    fid, shard_id
+                ));
+            }
+        }
+        Ok(())
     }
 
     pub fn with_shard_ids(&self, shard_ids: Vec<u32>) -> Self {
         Self {
-            private_key: self.private_key.clone(),
-            shard_ids: shard_ids
-                .iter()
-                .map(|i| i.to_string())
-                .collect::<Vec<String>>()
-                .join(","),
-            propose_value_delay: self.propose_value_delay,
+            shard_ids: ShardIds(shard_ids),
+            ..self.clone()
         }
     }
 }
@@ -88,8 +381,29 @@ impl Default for Config {
     fn default() -> Self {
         Self {
             private_key: hex::encode(SecretKey::generate()),
-            shard_ids: "1".to_string(),
+            shard_ids: ShardIds(vec![1]),
             propose_value_delay: Duration::from_millis(250),
+            max_sync_lookback: 100_000,
+            shard_overrides: ShardOverrides::default(),
+            previous_shard_ids: ShardIds::default(),
+            rebalance_height: None,
+            validator_public_keys: "".to_string(),
+            header_hash_len: DEFAULT_HEADER_HASH_LEN,
+            liveness_stall_timeout: Duration::from_secs(30),
+            max_rounds_per_height: None,
+            propose_jitter: None,
+            allow_partial_shards: false,
+            chunk_wait_policy: None,
+            dedup_cache_size: 10_000,
+            store_tuning: StoreTuning::default(),
+            confirmation_depth: 0,
+            profiling: false,
+            mempool_capacity: DEFAULT_MEMPOOL_CAPACITY,
+            address_prefix_len: DEFAULT_ADDRESS_PREFIX_LEN,
+            skip_corrupt_chunks: false,
+            max_concurrent_syncs: DEFAULT_MAX_CONCURRENT_SYNCS,
+            proposal_ttl: DEFAULT_PROPOSAL_TTL,
+            encode_buffer_pool_size: DEFAULT_MAX_POOLED_BUFFERS,
         }
     }
 }
@@ -111,8 +425,19 @@ pub enum ConsensusMsg<Ctx: SnapchainContext> {
 
     ReceivedFullProposal(FullProposal),
     RegisterValidator(SnapchainValidator),
+    /// Register several validators for the shard at once, syncing only from the furthest ahead
+    RegisterValidators(Vec<SnapchainValidator>),
 
     TimeoutElapsed(TimeoutElapsed<Timeout>),
+
+    /// Stop proposing values and starting new heights. Votes and proposals from peers are still processed.
+    PauseProposing,
+    /// Resume proposing, starting any height that was deferred while paused
+    ResumeProposing,
+    /// Refuse to propose or accept values for heights after the given block number
+    FreezeAtHeight(u64),
+    /// Lift a freeze set with FreezeAtHeight
+    Unfreeze,
 }
 
 impl ConsensusMsg<SnapchainValidatorContext> {
@@ -164,12 +489,28 @@ impl Timeouts {
     }
 }
 
+// Peers used to start consensus when no validator set is configured
+pub(crate) const DEFAULT_PEERS_TO_START: usize = 3;
+
+// Consensus starts once every other configured validator has registered. The local validator is
+// always in the set, a set of only ourselves means no validators were configured.
+pub(crate) fn peers_needed_to_start(initial_validator_set: &SnapchainValidatorSet) -> usize {
+    match initial_validator_set.count() {
+        0 | 1 => DEFAULT_PEERS_TO_START,
+        count => count - 1,
+    }
+}
+
+// Time between enough peers registering and starting the first height
+const START_DELAY: Duration = Duration::from_secs(10);
+
 pub struct Consensus {
     ctx: SnapchainValidatorContext,
     params: ConsensusParams<SnapchainValidatorContext>,
     timeout_config: TimeoutConfig,
     metrics: Metrics,
     shard_id: SnapchainShard,
+    start_delay: Duration,
 }
 
 // pub type ConsensusMsg<Ctx> = ConsensusMsg<Ctx>;
@@ -190,6 +531,9 @@ pub struct State<Ctx: SnapchainContext> {
     shard_validator: ShardValidator,
     gossip_tx: mpsc::Sender<GossipEvent<SnapchainValidatorContext>>,
     name: String,
+
+    /// Height to start once proposing is resumed
+    deferred_height: Option<Height>,
 }
 
 impl Consensus {
@@ -206,6 +550,14 @@ impl Consensus {
             params,
             timeout_config,
             metrics,
+            start_delay: START_DELAY,
+        }
+    }
+
+    pub fn with_start_delay(self, start_delay: Duration) -> Self {
+        Self {
+            start_delay,
+            ..self
         }
     }
 
@@ -249,10 +601,42 @@ impl Consensus {
     ) -> Result<(), ActorProcessingErr> {
         match msg {
             ConsensusMsg::StartHeight(height) => {
+                if state.shard_validator.is_paused() {
+                    info!("Proposing is paused, deferring start of height: {height}");
+                    state.deferred_height = Some(height);
+                    return Ok(());
+                }
                 self.start_height(&myself, state, height).await?;
                 Ok(())
             }
 
+            ConsensusMsg::PauseProposing => {
+                info!("Pausing proposing");
+                state.shard_validator.pause();
+                Ok(())
+            }
+
+            ConsensusMsg::FreezeAtHeight(block_number) => {
+                info!("Freezing at height {block_number}");
+                state.shard_validator.freeze_at(block_number);
+                Ok(())
+            }
+
+            ConsensusMsg::Unfreeze => {
+                info!("Unfreezing");
+                state.shard_validator.unfreeze();
+                Ok(())
+            }
+
+            ConsensusMsg::ResumeProposing => {
+                info!("Resuming proposing");
+                state.shard_validator.resume();
+                if let Some(height) = state.deferred_height.take() {
+                    self.start_height(&myself, state, height).await?;
+                }
+                Ok(())
+            }
+
             ConsensusMsg::ProposeValue(height, round, value, _) => {
                 let result = self
                     .process_input(
@@ -311,36 +695,22 @@ impl Consensus {
             }
 
             ConsensusMsg::RegisterValidator(validator) => {
-                let address = validator.address.to_hex();
-                if !state.shard_validator.add_validator(validator.clone()) {
-                    // We already saw that peer, ignoring...
-                    return Ok(());
+                if self.add_validator(&myself, state, validator.clone()) {
+                    state
+                        .shard_validator
+                        .sync_with_new_validator(&validator)
+                        .await;
                 }
+                Ok(())
+            }
 
-                let connected_peers = state.shard_validator.validator_count();
-                info!(
-                    "Connected to peer {address}. Total peers: {:?}",
-                    connected_peers
-                );
-                // let total_peers = state.consensus.driver.validator_set().count() - 1;
-
-                // println!("Connected to {connected_peers}/{total_peers} peers");
-                state
-                    .shard_validator
-                    .sync_with_new_validator(&validator)
-                    .await;
-
-                self.metrics.connected_peers.inc();
-
-                if connected_peers == 3 {
-                    info!("Enough peers ({connected_peers}) connected to start consensus");
-
-                    let height = state.consensus.driver.height();
-                    send_after(Duration::from_secs(10), myself.get_cell(), move || {
-                        info!("Starting consensus");
-                        ConsensusMsg::<SnapchainValidatorContext>::StartHeight(height)
-                    });
-                }
+            ConsensusMsg::RegisterValidators(validators) => {
+                let added: Vec<SnapchainValidator> = validators
+                    .into_iter()
+                    .filter(|validator| self.add_validator(&myself, state, validator.clone()))
+                    .collect();
+                // One sync from the furthest ahead peer covers what the others have
+                state.shard_validator.sync_with_new_validators(&added).await;
                 Ok(())
             }
 
@@ -412,6 +782,39 @@ impl Consensus {
         }
     }
 
+    // Returns false if the validator was already known
+    fn add_validator(
+        &self,
+        myself: &ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
+        state: &mut State<SnapchainValidatorContext>,
+        validator: SnapchainValidator,
+    ) -> bool {
+        let address = validator.address.to_hex();
+        if !state.shard_validator.add_validator(validator) {
+            // We already saw that peer, ignoring...
+            return false;
+        }
+
+        let connected_peers = state.shard_validator.registered_peer_count();
+        let total_peers = peers_needed_to_start(&self.params.initial_validator_set);
+        info!("Connected to peer {address}. Connected to {connected_peers}/{total_peers} peers");
+
+        self.metrics.connected_peers.inc();
+
+        // Peers registering after the threshold was reached schedule the same height again, which is
+        // ignored once it's started
+        if connected_peers >= total_peers && !state.shard_validator.started {
+            info!("Enough peers ({connected_peers}) connected to start consensus");
+
+            let height = state.consensus.driver.height();
+            send_after(self.start_delay, myself.get_cell(), move || {
+                info!("Starting consensus");
+                ConsensusMsg::<SnapchainValidatorContext>::StartHeight(height)
+            });
+        }
+        true
+    }
+
     async fn start_height(
         &self,
         myself: &ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
@@ -527,8 +930,19 @@ impl Consensus {
             }
 
             Effect::GetValue(height, round, timeout) => {
+                if shard_validator.is_paused() {
+                    info!("Proposing is paused, not proposing a value for height: {height}, round: {round}");
+                    return Ok(Resume::Continue);
+                }
                 let timeout = timeouts.duration_for(timeout.step);
-                let full_proposal = shard_validator.propose_value(height, round, timeout).await;
+                let full_proposal =
+                    match shard_validator.propose_value(height, round, timeout).await {
+                        Ok(full_proposal) => self.ctx.sign_full_proposal(full_proposal),
+                        Err(e) => {
+                            error!("Not proposing a value: {e}");
+                            return Ok(Resume::Continue);
+                        }
+                    };
 
                 let value = full_proposal.shard_hash();
 
@@ -561,7 +975,13 @@ impl Consensus {
                     self.params.address,
                     commits.len()
                 );
-                shard_validator.decide(height, round, value.clone()).await;
+                let commits: Vec<(Address, Vec<u8>)> = commits
+                    .iter()
+                    .map(|commit| (commit.message.voter.clone(), commit.signature.0.clone()))
+                    .collect();
+                shard_validator
+                    .decide(height, round, value.clone(), &commits)
+                    .await;
                 let result = myself.cast(ConsensusMsg::StartHeight(height.increment()));
                 if let Err(e) = result {
                     error!("Error when starting next height after decision on {height}: {e:?}");
@@ -600,6 +1020,7 @@ impl Actor for Consensus {
             shard_validator: args.1,
             gossip_tx: args.0,
             name,
+            deferred_height: None,
         })
     }
 
@@ -609,6 +1030,10 @@ impl Actor for Consensus {
         state: &mut State<SnapchainValidatorContext>,
     ) -> Result<(), ActorProcessingErr> {
         state.timers.cancel_all();
+        // Start from the configured validator set, their rpc addresses are filled in once they register
+        for validator in self.params.initial_validator_set.validators.iter() {
+            state.shard_validator.add_validator(validator.clone());
+        }
         // Add ourselves to the validator set
         state.shard_validator.add_validator(SnapchainValidator::new(
             self.shard_id.clone(),
@@ -658,3 +1083,201 @@ impl Actor for Consensus {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::metrics::ConsensusMetrics;
+    use crate::consensus::proposer::ShardProposer;
+    use crate::node::snapchain_node::{consensus_params_for_shard, validator_set_for_shard};
+    use crate::storage::db::RocksDB;
+    use crate::storage::store::engine::ShardEngine;
+    use crate::storage::store::shard::ShardStore;
+    use malachite_metrics::SharedRegistry;
+    use std::sync::Arc;
+
+    fn config_with_overrides(shard_overrides: &str) -> Config {
+        Config {
+            shard_overrides: shard_overrides.to_string().try_into().unwrap(),
+            ..Config::default().with_shard_ids(vec![1, 2, 3])
+        }
+    }
+
+    #[test]
+    fn test_shard_for_fid_override() {
+        let config = config_with_overrides("10:3,11:3");
+        assert!(config.validate().is_ok());
+        assert_eq!(config.shard_for_fid(10), 3);
+        assert_eq!(config.shard_for_fid(11), 3);
+    }
+
+    #[test]
+    fn test_shard_for_fid_default_rule() {
+        let config = config_with_overrides("10:3");
+        assert_eq!(config.shard_for_fid(12), 1);
+        assert_eq!(config.shard_for_fid(13), 2);
+        assert_eq!(config.shard_for_fid(14), 3);
+    }
+
+    #[test]
+    fn test_block_only_config() {
+        let config = Config::default().with_shard_ids(vec![]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.shard_ids(), Vec::<u32>::new());
+        assert_eq!(config.num_shards(), 0);
+        assert_eq!(config.shard_for_fid(12), 0);
+    }
+
+    #[test]
+    fn test_shard_for_fid_across_rebalance() {
+        let config = Config {
+            previous_shard_ids: "1,2".to_string().try_into().unwrap(),
+            rebalance_height: Some(100),
+            ..config_with_overrides("7:3")
+        };
+        assert!(config.validate().is_ok());
+
+        // fid 4 moves from shard 1 to shard 2 when the third shard is added
+        assert_eq!(config.shard_for_fid_at_height(4, 0), 1);
+        assert_eq!(config.shard_for_fid_at_height(4, 99), 1);
+        assert_eq!(config.shard_for_fid_at_height(4, 100), 2);
+        assert_eq!(config.shard_for_fid(4), 2);
+        // fid 6 was on shard 1 and stays there
+        assert_eq!(config.shard_for_fid_at_height(6, 99), 1);
+        assert_eq!(config.shard_for_fid_at_height(6, 100), 1);
+        // Overrides hold on both sides
+        assert_eq!(config.shard_for_fid_at_height(7, 99), 3);
+        assert_eq!(config.shard_for_fid_at_height(7, 100), 3);
+
+        let missing_previous = Config {
+            rebalance_height: Some(100),
+            ..config_with_overrides("")
+        };
+        assert!(missing_previous.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid_validator_public_key() {
+        let config = Config {
+            validator_public_keys: "not-a-key".to_string(),
+            ..Config::default()
+        };
+        assert!(config.validate().is_err());
+
+        let public_key = Keypair::generate().public();
+        let config = Config {
+            validator_public_keys: hex::encode(public_key.to_bytes()),
+            ..Config::default()
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.validator_public_keys(), vec![public_key]);
+    }
+
+    #[test]
+    fn test_header_hash_len_bounds() {
+        assert_eq!(Config::default().header_hash_len, 32);
+        for (header_hash_len, valid) in [(15, false), (16, true), (20, true), (33, false)] {
+            let config = Config {
+                header_hash_len,
+                ..Config::default()
+            };
+            assert_eq!(config.validate().is_ok(), valid);
+        }
+    }
+
+    #[test]
+    fn test_shard_override_to_unhosted_shard() {
+        let config = config_with_overrides("10:4");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_invalid_shard_lists_fail_to_load() {
+        for value in ["10", "10:x", "x:1", "10:1:2"] {
+            assert!(ShardOverrides::try_from(value.to_string()).is_err());
+        }
+        assert!(ShardIds::try_from("1,x".to_string()).is_err());
+
+        let mut value = serde_json::to_value(Config::default()).unwrap();
+        assert_eq!(value["shard_ids"], "1");
+        value["shard_overrides"] = "10:two".into();
+        assert!(serde_json::from_value::<Config>(value).is_err());
+    }
+
+    #[test]
+    fn test_shard_lists_round_trip() {
+        let config = config_with_overrides("42:1,1:2");
+        assert_eq!(String::from(config.shard_ids.clone()), "1,2,3");
+        assert_eq!(String::from(config.shard_overrides.clone()), "1:2,42:1");
+        assert_eq!(config.shard_overrides().get(&42), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_start_with_only_the_local_validator_configured() {
+        let keypair = Keypair::generate();
+        let address = Address(keypair.public().to_bytes());
+        let shard = SnapchainShard::new(1);
+        let db_dir = tempfile::tempdir().unwrap();
+        let db = RocksDB::new(db_dir.path().to_str().unwrap());
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let consensus_metrics = ConsensusMetrics::new();
+        let shard_proposer = ShardProposer::new(
+            address.clone(),
+            shard.clone(),
+            ShardEngine::new(1, shard_store.clone()),
+            None,
+            Duration::from_millis(0),
+            consensus_metrics.clone(),
+            Arc::new(|_, _| 1),
+        );
+        let shard_validator = ShardValidator::new(
+            address.clone(),
+            shard.clone(),
+            None,
+            Some(shard_proposer),
+            consensus_metrics,
+        );
+        // No validator keys configured, like the default config
+        let validator_set = validator_set_for_shard(&shard, keypair.public(), None, 0, &[]);
+        assert_eq!(
+            peers_needed_to_start(&validator_set),
+            DEFAULT_PEERS_TO_START
+        );
+        let consensus = Consensus::new(
+            SnapchainValidatorContext::new(keypair.clone()),
+            shard.clone(),
+            consensus_params_for_shard(&shard, validator_set, &address),
+            TimeoutConfig {
+                timeout_propose: Duration::from_millis(50),
+                ..TimeoutConfig::default()
+            },
+            Metrics::register(SharedRegistry::global()),
+        )
+        .with_start_delay(Duration::ZERO);
+        let (gossip_tx, mut gossip_rx) = mpsc::channel(100);
+        let (actor, _) = Actor::spawn(None, consensus, (gossip_tx, shard_validator))
+            .await
+            .unwrap();
+
+        for i in 0..DEFAULT_PEERS_TO_START {
+            actor
+                .cast(ConsensusMsg::RegisterValidator(SnapchainValidator::new(
+                    shard.clone(),
+                    Keypair::generate().public(),
+                    Some(format!("127.0.0.1:{}", 3000 + i)),
+                    0,
+                )))
+                .unwrap();
+        }
+
+        // Nothing is gossiped before a height starts, the first proposal or vote shows it did
+        tokio::time::timeout(Duration::from_secs(5), gossip_rx.recv())
+            .await
+            .expect("consensus didn't start")
+            .unwrap();
+
+        actor.stop(None);
+        shard_store.db.destroy().unwrap();
+    }
+}
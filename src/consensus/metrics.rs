@@ -0,0 +1,304 @@
+use crate::core::types::Height;
+use malachite_common::Round;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Upper bounds of the transaction apply time histogram buckets, slower applies only count towards +Inf
+const APPLY_TIME_BUCKETS: [Duration; 6] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+];
+
+/// Distribution of the time taken to apply transactions of one message type
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ApplyTimeHistogram {
+    pub count: u64,
+    pub sum: Duration,
+    // Number of samples at or below each of APPLY_TIME_BUCKETS, cumulative like prometheus buckets
+    pub buckets: [u64; APPLY_TIME_BUCKETS.len()],
+}
+
+impl ApplyTimeHistogram {
+    fn observe(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.sum += elapsed;
+        for (bucket, bound) in self.buckets.iter_mut().zip(APPLY_TIME_BUCKETS) {
+            if elapsed <= bound {
+                *bucket += 1;
+            }
+        }
+    }
+}
+
+/// Why a proposal received from a peer was rejected
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProposalRejection {
+    HashLength,
+    ForeignFid,
+    DuplicateMessage,
+    RootMismatch,
+    TimestampBeforeTip,
+    EmptyChunkChangesRoot,
+    InvalidStateChange,
+    UnexpectedValue,
+    InvalidSignature,
+}
+
+impl ProposalRejection {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ProposalRejection::HashLength => "hash_length",
+            ProposalRejection::ForeignFid => "foreign_fid",
+            ProposalRejection::DuplicateMessage => "duplicate_message",
+            ProposalRejection::RootMismatch => "root_mismatch",
+            ProposalRejection::TimestampBeforeTip => "timestamp_before_tip",
+            ProposalRejection::EmptyChunkChangesRoot => "empty_chunk_changes_root",
+            ProposalRejection::InvalidStateChange => "invalid_state_change",
+            ProposalRejection::UnexpectedValue => "unexpected_value",
+            ProposalRejection::InvalidSignature => "invalid_signature",
+        }
+    }
+}
+
+/// Snapchain specific consensus metrics, shared by all the shards on a node
+#[derive(Clone, Debug, Default)]
+pub struct ConsensusMetrics {
+    // shard id -> round -> number of heights decided in that round
+    decided_rounds: Arc<Mutex<BTreeMap<u32, BTreeMap<i64, u64>>>>,
+    // shard id -> (last decided block number, when it was decided)
+    last_decided: Arc<Mutex<BTreeMap<u32, (u64, Instant)>>>,
+    // shard id -> number of liveness stalls detected
+    liveness_stalls: Arc<Mutex<BTreeMap<u32, u64>>>,
+    // shard id -> number of times proposing was skipped because the round limit was reached
+    max_rounds_exceeded: Arc<Mutex<BTreeMap<u32, u64>>>,
+    // shard id -> rejection reason -> number of proposals rejected for it
+    proposal_rejections: Arc<Mutex<BTreeMap<u32, BTreeMap<&'static str, u64>>>>,
+    // message type -> time taken to apply transactions of that type, only recorded when profiling
+    transaction_apply_times: Arc<Mutex<BTreeMap<&'static str, ApplyTimeHistogram>>>,
+}
+
+impl ConsensusMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe_decided_round(&self, shard_id: u32, round: Round) {
+        let mut decided_rounds = self.decided_rounds.lock().unwrap();
+        *decided_rounds
+            .entry(shard_id)
+            .or_default()
+            .entry(round.as_i64())
+            .or_insert(0) += 1;
+    }
+
+    pub fn decided_rounds(&self, shard_id: u32) -> BTreeMap<i64, u64> {
+        self.decided_rounds
+            .lock()
+            .unwrap()
+            .get(&shard_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn observe_decided_height(&self, height: Height) {
+        self.last_decided
+            .lock()
+            .unwrap()
+            .insert(height.shard_index, (height.block_number, Instant::now()));
+    }
+
+    pub fn last_decided(&self, shard_id: u32) -> Option<(u64, Instant)> {
+        self.last_decided.lock().unwrap().get(&shard_id).cloned()
+    }
+
+    pub fn observe_liveness_stall(&self, shard_id: u32) {
+        *self
+            .liveness_stalls
+            .lock()
+            .unwrap()
+            .entry(shard_id)
+            .or_insert(0) += 1;
+    }
+
+    pub fn liveness_stalls(&self, shard_id: u32) -> u64 {
+        self.liveness_stalls
+            .lock()
+            .unwrap()
+            .get(&shard_id)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    pub fn observe_max_rounds_exceeded(&self, shard_id: u32) {
+        *self
+            .max_rounds_exceeded
+            .lock()
+            .unwrap()
+            .entry(shard_id)
+            .or_insert(0) += 1;
+    }
+
+    pub fn max_rounds_exceeded(&self, shard_id: u32) -> u64 {
+        self.max_rounds_exceeded
+            .lock()
+            .unwrap()
+            .get(&shard_id)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    pub fn observe_proposal_rejected(&self, shard_id: u32, rejection: ProposalRejection) {
+        *self
+            .proposal_rejections
+            .lock()
+            .unwrap()
+            .entry(shard_id)
+            .or_default()
+            .entry(rejection.reason())
+            .or_insert(0) += 1;
+    }
+
+    pub fn proposal_rejections(&self, shard_id: u32, rejection: ProposalRejection) -> u64 {
+        self.proposal_rejections
+            .lock()
+            .unwrap()
+            .get(&shard_id)
+            .and_then(|reasons| reasons.get(rejection.reason()).cloned())
+            .unwrap_or(0)
+    }
+
+    pub fn observe_transaction_applied(&self, message_type: &'static str, elapsed: Duration) {
+        self.transaction_apply_times
+            .lock()
+            .unwrap()
+            .entry(message_type)
+            .or_default()
+            .observe(elapsed);
+    }
+
+    pub fn transaction_apply_times(&self, message_type: &str) -> Option<ApplyTimeHistogram> {
+        self.transaction_apply_times
+            .lock()
+            .unwrap()
+            .get(message_type)
+            .cloned()
+    }
+
+    // Prometheus text exposition of all the metrics. Every series is labelled with its shard, except
+    // transaction apply times which are labelled with the message type.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP snapchain_decided_heights_total Heights decided, by the round they were decided in\n");
+        out.push_str("# TYPE snapchain_decided_heights_total counter\n");
+        for (shard_id, rounds) in self.decided_rounds.lock().unwrap().iter() {
+            for (round, count) in rounds {
+                out.push_str(&format!(
+                    "snapchain_decided_heights_total{{shard=\"{}\",round=\"{}\"}} {}\n",
+                    shard_id, round, count
+                ));
+            }
+        }
+
+        out.push_str("# HELP snapchain_last_decided_height Last block number decided\n");
+        out.push_str("# TYPE snapchain_last_decided_height gauge\n");
+        for (shard_id, (block_number, _)) in self.last_decided.lock().unwrap().iter() {
+            out.push_str(&format!(
+                "snapchain_last_decided_height{{shard=\"{}\"}} {}\n",
+                shard_id, block_number
+            ));
+        }
+
+        out.push_str(
+            "# HELP snapchain_proposal_rejections_total Proposals from peers rejected, by reason\n",
+        );
+        out.push_str("# TYPE snapchain_proposal_rejections_total counter\n");
+        for (shard_id, reasons) in self.proposal_rejections.lock().unwrap().iter() {
+            for (reason, count) in reasons {
+                out.push_str(&format!(
+                    "snapchain_proposal_rejections_total{{shard=\"{}\",reason=\"{}\"}} {}\n",
+                    shard_id, reason, count
+                ));
+            }
+        }
+
+        out.push_str("# HELP snapchain_transaction_apply_seconds Time taken to apply a transaction, by message type\n");
+        out.push_str("# TYPE snapchain_transaction_apply_seconds histogram\n");
+        for (message_type, histogram) in self.transaction_apply_times.lock().unwrap().iter() {
+            for (bound, count) in APPLY_TIME_BUCKETS.iter().zip(histogram.buckets) {
+                out.push_str(&format!(
+                    "snapchain_transaction_apply_seconds_bucket{{message_type=\"{}\",le=\"{}\"}} {}\n",
+                    message_type,
+                    bound.as_secs_f64(),
+                    count
+                ));
+            }
+            out.push_str(&format!(
+                "snapchain_transaction_apply_seconds_bucket{{message_type=\"{}\",le=\"+Inf\"}} {}\n",
+                message_type, histogram.count
+            ));
+            out.push_str(&format!(
+                "snapchain_transaction_apply_seconds_sum{{message_type=\"{}\"}} {}\n",
+                message_type,
+                histogram.sum.as_secs_f64()
+            ));
+            out.push_str(&format!(
+                "snapchain_transaction_apply_seconds_count{{message_type=\"{}\"}} {}\n",
+                message_type, histogram.count
+            ));
+        }
+
+        for (name, help, values) in [
+            (
+                "snapchain_liveness_stalls_total",
+                "Liveness stalls detected",
+                &self.liveness_stalls,
+            ),
+            (
+                "snapchain_max_rounds_exceeded_total",
+                "Times proposing was skipped because the round limit was reached",
+                &self.max_rounds_exceeded,
+            ),
+        ] {
+            out.push_str(&format!("# HELP {} {}\n", name, help));
+            out.push_str(&format!("# TYPE {} counter\n", name));
+            for (shard_id, count) in values.lock().unwrap().iter() {
+                out.push_str(&format!("{}{{shard=\"{}\"}} {}\n", name, shard_id, count));
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus_labels_shards() {
+        let metrics = ConsensusMetrics::new();
+        // Both shards record into the same registry
+        let shard1 = metrics.clone();
+        let shard2 = metrics.clone();
+        shard1.observe_decided_round(1, Round::new(0));
+        shard1.observe_decided_height(Height::new(1, 5));
+        shard2.observe_decided_round(2, Round::new(1));
+        shard2.observe_decided_round(2, Round::new(1));
+        shard2.observe_liveness_stall(2);
+
+        let rendered = metrics.render_prometheus();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert!(lines.contains(&"snapchain_decided_heights_total{shard=\"1\",round=\"0\"} 1"));
+        assert!(lines.contains(&"snapchain_decided_heights_total{shard=\"2\",round=\"1\"} 2"));
+        assert!(lines.contains(&"snapchain_last_decided_height{shard=\"1\"} 5"));
+        assert!(lines.contains(&"snapchain_liveness_stalls_total{shard=\"2\"} 1"));
+        assert!(!rendered.contains("snapchain_liveness_stalls_total{shard=\"1\"}"));
+    }
+}
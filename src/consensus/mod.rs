@@ -1,4 +1,6 @@
 pub mod consensus;
+pub mod metrics;
+pub mod peer_scores;
 pub mod proposer;
 mod timers;
 pub mod validator;
@@ -0,0 +1,116 @@
+use crate::consensus::proposer::BlockProposerError;
+use crate::core::types::Address;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const SUCCESSFUL_SYNC_SCORE: i64 = 10;
+const TRANSPORT_ERROR_PENALTY: i64 = 20;
+// A fork means the peer served a history that doesn't extend ours, much worse than being unreachable
+const FORK_PENALTY: i64 = 100;
+
+/// How a peer behaved when we synced blocks from it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    pub successful_syncs: u64,
+    pub fork_detections: u64,
+    pub transport_errors: u64,
+    // Summed over successful syncs only
+    pub total_sync_latency: Duration,
+}
+
+impl PeerStats {
+    pub fn score(&self) -> i64 {
+        self.successful_syncs as i64 * SUCCESSFUL_SYNC_SCORE
+            - self.transport_errors as i64 * TRANSPORT_ERROR_PENALTY
+            - self.fork_detections as i64 * FORK_PENALTY
+    }
+
+    pub fn average_sync_latency(&self) -> Option<Duration> {
+        if self.successful_syncs == 0 {
+            return None;
+        }
+        Some(self.total_sync_latency / self.successful_syncs as u32)
+    }
+}
+
+/// Per peer sync behaviour, shared between the block proposer that records it and the node
+#[derive(Clone, Debug, Default)]
+pub struct PeerScores {
+    peers: Arc<Mutex<BTreeMap<Address, PeerStats>>>,
+}
+
+impl PeerScores {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_sync(
+        &self,
+        peer: &Address,
+        result: &Result<(), BlockProposerError>,
+        latency: Duration,
+    ) {
+        let mut peers = self.peers.lock().unwrap();
+        let stats = peers.entry(peer.clone()).or_default();
+        match result {
+            Ok(()) => {
+                stats.successful_syncs += 1;
+                stats.total_sync_latency += latency;
+            }
+            Err(BlockProposerError::ForkDetected { .. }) => stats.fork_detections += 1,
+            Err(BlockProposerError::RpcTransportError(_))
+            | Err(BlockProposerError::RpcResponseError(_)) => stats.transport_errors += 1,
+            // Not the peer's fault, e.g. a cancelled sync or a local storage error
+            Err(_) => {}
+        }
+    }
+
+    pub fn stats(&self, peer: &Address) -> Option<PeerStats> {
+        self.peers.lock().unwrap().get(peer).cloned()
+    }
+
+    /// Highest scoring peer, ties go to the one that synced faster on average
+    pub fn best_peer(&self) -> Option<Address> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .max_by_key(|(_, stats)| {
+                (
+                    stats.score(),
+                    Reverse(stats.average_sync_latency().unwrap_or(Duration::MAX)),
+                )
+            })
+            .map(|(peer, _)| peer.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_best_peer_prefers_faster_peer_on_equal_score() {
+        let scores = PeerScores::new();
+        let slow = Address([1; 32]);
+        let fast = Address([2; 32]);
+        scores.record_sync(&slow, &Ok(()), Duration::from_millis(500));
+        scores.record_sync(&fast, &Ok(()), Duration::from_millis(50));
+
+        assert!(scores.best_peer() == Some(fast));
+        assert_eq!(
+            scores.stats(&slow).unwrap().average_sync_latency(),
+            Some(Duration::from_millis(500))
+        );
+
+        // Cancelled syncs don't count against a peer
+        scores.record_sync(
+            &slow,
+            &Err(BlockProposerError::SyncCancelled { synced_to: 1 }),
+            Duration::ZERO,
+        );
+        assert_eq!(scores.stats(&slow).unwrap().score(), SUCCESSFUL_SYNC_SCORE);
+    }
+}
@@ -1,24 +1,57 @@
-use crate::consensus::consensus::{RxDecision, TxDecision};
+use crate::consensus::consensus::{Decision, RxDecision, TxDecision};
+use crate::consensus::metrics::{ConsensusMetrics, ProposalRejection};
+use crate::consensus::peer_scores::PeerScores;
+use crate::consensus::validator::ProposeError;
+use crate::core::buffer_pool;
+use crate::core::certificate::{self, CertificateError};
+use crate::core::merkle::transactions_root;
 use crate::core::types::{
-    proto, Address, Height, ShardHash, ShardId, SnapchainShard, SnapchainValidator,
+    proto, Address, FullProposalBuilder, Height, ShardHash, ShardId, SnapchainShard,
+    SnapchainValidator, SnapchainValidatorSet,
 };
 use crate::proto::rpc::snapchain_service_client::SnapchainServiceClient;
 use crate::proto::rpc::BlocksRequest;
-use crate::proto::snapchain::{Block, BlockHeader, FullProposal, ShardChunk, ShardHeader};
+use crate::proto::snapchain::{
+    Block, BlockHeader, FullProposal, ShardChunk, ShardHeader, Transaction,
+};
+use crate::storage::store::commit_log::CommitLog;
 use crate::storage::store::engine::{BlockEngine, ShardEngine, ShardStateChange};
 use crate::storage::store::BlockStorageError;
 use malachite_common::{Round, Validity};
 use prost::Message;
-use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use thiserror::Error;
-use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::{broadcast, mpsc, Semaphore};
 use tokio::time::Instant;
 use tokio::{select, time};
+use tonic::transport::Channel;
 use tonic::Request;
-use tracing::{error, warn};
+use tracing::{error, info, warn};
+
+pub(crate) const FARCASTER_EPOCH: u64 = 1609459200; // January 1, 2021 UTC
+
+// Length of block and shard chunk header hashes, recorded in the header version
+pub const DEFAULT_HEADER_HASH_LEN: u32 = 32;
+
+// blake3 hash of the encoded header, truncated to hash_len bytes
+pub fn header_hash(header_bytes: &[u8], hash_len: u32) -> Vec<u8> {
+    blake3::hash(header_bytes).as_bytes()[..hash_len as usize].to_vec()
+}
 
-const FARCASTER_EPOCH: u64 = 1609459200; // January 1, 2021 UTC
+// Hash length recorded by a header version, headers from before versioning are unversioned (0) and
+// were hashed with the default length
+pub fn header_hash_len_for_version(version: u32) -> u32 {
+    if version == 0 {
+        DEFAULT_HEADER_HASH_LEN
+    } else {
+        version
+    }
+}
 
 pub fn current_time() -> u64 {
     std::time::SystemTime::now()
@@ -35,24 +68,143 @@ pub trait Proposer {
         height: Height,
         round: Round,
         timeout: Duration,
-    ) -> FullProposal;
+    ) -> Result<FullProposal, ProposeError>;
     // Receive a block/shard chunk proposed by another validator and return whether it is valid
     fn add_proposed_value(&mut self, full_proposal: &FullProposal) -> Validity;
 
     // Consensus has confirmed the block/shard_chunk, apply it to the local state
-    async fn decide(&mut self, height: Height, round: Round, value: ShardHash);
+    async fn decide(
+        &mut self,
+        height: Height,
+        round: Round,
+        value: ShardHash,
+        validator_set: &SnapchainValidatorSet,
+    );
 
     fn get_confirmed_height(&self) -> Height;
+
+    // Height and round of the latest proposal made or received, for debugging and metrics
+    fn current_round(&self) -> Option<(Height, Round)>;
+
+    // The height of the next block/shard chunk this proposer will produce, None once the block
+    // number can't go any higher
+    fn next_height(&self) -> Option<Height> {
+        self.get_confirmed_height().checked_increment()
+    }
+}
+
+pub type CommitHookError = Box<dyn std::error::Error + Send + Sync>;
+
+// Called synchronously from `decide` once a block or shard chunk has been written to the store, e.g.
+// to feed an indexer. Errors are logged and don't affect the committed state.
+pub trait CommitHook: Send + Sync {
+    fn block_committed(&self, _block: &Block) -> Result<(), CommitHookError> {
+        Ok(())
+    }
+
+    fn shard_chunk_committed(&self, _shard_chunk: &ShardChunk) -> Result<(), CommitHookError> {
+        Ok(())
+    }
+}
+
+// How long a proposal that's never decided, e.g. from an abandoned round, is held before it's dropped
+pub const DEFAULT_PROPOSAL_TTL: Duration = Duration::from_secs(300);
+
+struct HeldProposal {
+    proposal: FullProposal,
+    held_since: Instant,
+}
+
+// Proposals we hold for each value, by the round they were proposed in. A value can be re-proposed in
+// a later round, and only the proposal of the decided round carries the matching round and votes.
+type ProposalsByRound = BTreeMap<ShardHash, BTreeMap<i64, HeldProposal>>;
+
+fn hold_proposal(proposals: &mut ProposalsByRound, value: ShardHash, proposal: FullProposal) {
+    proposals.entry(value).or_default().insert(
+        proposal.round,
+        HeldProposal {
+            proposal,
+            held_since: Instant::now(),
+        },
+    );
+}
+
+// Drop the proposals held for longer than the ttl as of `now`, returns how many were dropped
+fn evict_expired_proposals(proposals: &mut ProposalsByRound, ttl: Duration, now: Instant) -> usize {
+    let mut evicted = 0;
+    proposals.retain(|value, by_round| {
+        by_round.retain(|round, held| {
+            let expired = now.saturating_duration_since(held.held_since) > ttl;
+            if expired {
+                info!(value = %value, round, "Evicting proposal that was never decided");
+                evicted += 1;
+            }
+            !expired
+        });
+        !by_round.is_empty()
+    });
+    evicted
+}
+
+fn decided_proposal(
+    proposals: &ProposalsByRound,
+    value: &ShardHash,
+    round: Round,
+) -> Option<FullProposal> {
+    let by_round = proposals.get(value)?;
+    match by_round.get(&round.as_i64()) {
+        Some(held) => Some(held.proposal.clone()),
+        None => {
+            error!(
+                value = %value,
+                round = round.as_i64(),
+                held_rounds = ?by_round.keys().collect::<Vec<_>>(),
+                "Decided value at a round we hold no proposal for, not committing"
+            );
+            None
+        }
+    }
 }
 
+// Maps a fid to the id of the shard that owns it at a shard height, assignments change when shards
+// are rebalanced
+pub type ShardAssignment = Arc<dyn Fn(u64, u64) -> u32 + Send + Sync>;
+
 pub struct ShardProposer {
     shard_id: SnapchainShard,
     address: Address,
-    chunks: Vec<ShardChunk>,
-    proposed_chunks: BTreeMap<ShardHash, FullProposal>,
+    // Parent of the next chunk. Only the latest committed chunk is kept, the store has the rest.
+    last_chunk: Option<ShardChunk>,
+    proposed_chunks: ProposalsByRound,
+    proposal_ttl: Duration,
     tx_decision: Option<TxDecision>,
     engine: ShardEngine,
     propose_value_delay: Duration,
+    metrics: ConsensusMetrics,
+    shard_assignment: ShardAssignment,
+    header_hash_len: u32,
+    // Pinned header timestamp for tests and replays, current_time() is used when None
+    timestamp_override: Option<u64>,
+    commit_hook: Option<Arc<dyn CommitHook>>,
+    commit_log: Option<CommitLog>,
+    current_round: Option<(Height, Round)>,
+    // Confirmed height to start from when the store is behind it, see from_checkpoint
+    checkpoint_height: Option<Height>,
+}
+
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("Checkpoint height {height} isn't for shard {shard_id}")]
+    WrongShard { height: Height, shard_id: u32 },
+
+    #[error("Store is at height {tip}, past the checkpoint at {checkpoint}")]
+    StoreAhead { checkpoint: Height, tip: Height },
+
+    #[error("Stored chunk at {height} doesn't match the checkpoint parent hash")]
+    ParentMismatch { height: Height },
+
+    #[error("Engine state root {actual} doesn't match the checkpoint state root {expected}")]
+    StateRootMismatch { expected: String, actual: String },
 }
 
 impl ShardProposer {
@@ -62,74 +214,318 @@ impl ShardProposer {
         engine: ShardEngine,
         tx_decision: Option<TxDecision>,
         propose_value_delay: Duration,
+        metrics: ConsensusMetrics,
+        shard_assignment: ShardAssignment,
     ) -> ShardProposer {
         ShardProposer {
             shard_id,
             address,
-            chunks: vec![],
+            last_chunk: None,
             proposed_chunks: BTreeMap::new(),
+            proposal_ttl: DEFAULT_PROPOSAL_TTL,
             tx_decision,
             engine,
             propose_value_delay,
+            metrics,
+            shard_assignment,
+            header_hash_len: DEFAULT_HEADER_HASH_LEN,
+            timestamp_override: None,
+            commit_hook: None,
+            commit_log: None,
+            current_round: None,
+            checkpoint_height: None,
         }
     }
-}
 
-impl Proposer for ShardProposer {
-    async fn propose_value(
+    // Start from a confirmed height and chunk hash instead of the store tip, e.g. for checkpoint
+    // sync where earlier chunks aren't stored. The engine must already hold the checkpoint's state.
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_checkpoint(
+        address: Address,
+        shard_id: SnapchainShard,
+        engine: ShardEngine,
+        tx_decision: Option<TxDecision>,
+        propose_value_delay: Duration,
+        metrics: ConsensusMetrics,
+        shard_assignment: ShardAssignment,
+        height: Height,
+        parent_hash: Vec<u8>,
+        state_root: Vec<u8>,
+    ) -> Result<ShardProposer, CheckpointError> {
+        if height.shard_index != shard_id.shard_id() {
+            return Err(CheckpointError::WrongShard {
+                height,
+                shard_id: shard_id.shard_id(),
+            });
+        }
+        let tip = engine.get_confirmed_height();
+        if tip > height {
+            return Err(CheckpointError::StoreAhead {
+                checkpoint: height,
+                tip,
+            });
+        }
+        if tip == height {
+            if let Some(chunk) = engine.tip_chunk() {
+                if chunk.hash != parent_hash {
+                    return Err(CheckpointError::ParentMismatch { height });
+                }
+            }
+        }
+        let engine_root = engine.state_root();
+        if engine_root != state_root {
+            return Err(CheckpointError::StateRootMismatch {
+                expected: hex::encode(&state_root),
+                actual: hex::encode(&engine_root),
+            });
+        }
+
+        // Stands in for the checkpoint chunk, only its hash is needed to link the next one
+        let checkpoint_chunk = ShardChunk {
+            header: Some(ShardHeader {
+                height: Some(height),
+                shard_root: state_root,
+                ..Default::default()
+            }),
+            hash: parent_hash,
+            ..Default::default()
+        };
+        Ok(Self {
+            last_chunk: Some(checkpoint_chunk),
+            checkpoint_height: Some(height),
+            ..ShardProposer::new(
+                address,
+                shard_id,
+                engine,
+                tx_decision,
+                propose_value_delay,
+                metrics,
+                shard_assignment,
+            )
+        })
+    }
+
+    pub fn with_header_hash_len(self, header_hash_len: u32) -> Self {
+        Self {
+            header_hash_len,
+            ..self
+        }
+    }
+
+    pub fn with_proposal_ttl(self, proposal_ttl: Duration) -> Self {
+        Self {
+            proposal_ttl,
+            ..self
+        }
+    }
+
+    pub fn with_timestamp_override(self, timestamp_override: Option<u64>) -> Self {
+        Self {
+            timestamp_override,
+            ..self
+        }
+    }
+
+    pub fn with_commit_hook(self, commit_hook: Arc<dyn CommitHook>) -> Self {
+        Self {
+            commit_hook: Some(commit_hook),
+            ..self
+        }
+    }
+
+    pub fn with_commit_log(self, commit_log: CommitLog) -> Self {
+        Self {
+            commit_log: Some(commit_log),
+            ..self
+        }
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp_override.unwrap_or_else(current_time)
+    }
+
+    // Called as rounds go by, so proposals of abandoned rounds don't pile up
+    fn expire_proposals(&mut self, now: Instant) -> usize {
+        evict_expired_proposals(&mut self.proposed_chunks, self.proposal_ttl, now)
+    }
+
+    fn reject_proposal(&self, rejection: ProposalRejection) -> Validity {
+        self.metrics
+            .observe_proposal_rejected(self.shard_id.shard_id(), rejection);
+        Validity::Invalid
+    }
+
+    fn build_proposal(
         &mut self,
         height: Height,
         round: Round,
-        _timeout: Duration,
+        state_change: ShardStateChange,
     ) -> FullProposal {
-        // Sleep before proposing the value so we don't produce blocks too fast
-        // TODO: rethink/reconsider
-        tokio::time::sleep(self.propose_value_delay).await;
-
-        let previous_chunk = self.chunks.last();
-        let parent_hash = match previous_chunk {
+        // Nothing committed since this proposer started, build on whatever the store has
+        if self.last_chunk.is_none() {
+            self.last_chunk = self.engine.tip_chunk();
+        }
+        let parent_hash = match &self.last_chunk {
             Some(chunk) => chunk.hash.clone(),
             None => vec![0, 32],
         };
 
-        let state_change = self.engine.propose_state_change(self.shard_id.shard_id());
         let shard_header = ShardHeader {
             parent_hash,
-            timestamp: current_time(),
+            timestamp: self.timestamp(),
             height: Some(height.clone()),
             shard_root: state_change.new_state_root.clone(),
+            version: self.header_hash_len,
+            transactions_root: transactions_root(&state_change.transactions),
         };
-        let hash = blake3::hash(&shard_header.encode_to_vec())
-            .as_bytes()
-            .to_vec();
+        let hash = buffer_pool::with_encoded(&shard_header, |bytes| {
+            header_hash(bytes, self.header_hash_len)
+        });
 
         let chunk = ShardChunk {
             header: Some(shard_header),
-            hash: hash.clone(),
+            hash,
             transactions: state_change.transactions.clone(),
             votes: None,
         };
 
-        let shard_hash = ShardHash {
-            hash: hash.clone(),
-            shard_index: height.shard_index as u32,
-        };
-        let proposal = FullProposal {
-            height: Some(height.clone()),
-            round: round.as_i64(),
-            proposed_value: Some(proto::full_proposal::ProposedValue::Shard(chunk)),
-            proposer: self.address.to_vec(),
-        };
-        self.proposed_chunks.insert(shard_hash, proposal.clone());
+        let (proposal, shard_hash) = FullProposalBuilder::new()
+            .with_height(height)
+            .with_round(round)
+            .with_proposer(self.address.clone())
+            .with_shard_chunk(chunk)
+            .build()
+            .expect("shard chunk proposal is missing a field");
+        hold_proposal(&mut self.proposed_chunks, shard_hash, proposal.clone());
         proposal
     }
 
+    // Propose a chunk of exactly these transactions instead of the mempool's, for deterministic tests
+    // and tooling. The state root is still computed, transactions that fail to apply are dropped.
+    pub async fn propose_with_transactions(
+        &mut self,
+        height: Height,
+        round: Round,
+        transactions: Vec<Transaction>,
+    ) -> FullProposal {
+        self.current_round = Some((height, round));
+        let state_change = self
+            .engine
+            .propose_state_change_with_transactions(self.shard_id.shard_id(), transactions)
+            .await;
+        self.build_proposal(height, round, state_change)
+    }
+
+    // Returns the first fid in the chunk that isn't assigned to this shard at the chunk's height, if any
+    fn find_foreign_fid(&self, chunk: &ShardChunk) -> Option<u64> {
+        let shard_id = self.shard_id.shard_id();
+        let block_number = chunk
+            .header
+            .as_ref()
+            .and_then(|header| header.height)
+            .map_or(0, |height| height.block_number);
+        chunk
+            .fids()
+            .into_iter()
+            .find(|fid| (self.shard_assignment)(*fid, block_number) != shard_id)
+    }
+}
+
+impl Proposer for ShardProposer {
+    async fn propose_value(
+        &mut self,
+        height: Height,
+        round: Round,
+        _timeout: Duration,
+    ) -> Result<FullProposal, ProposeError> {
+        self.current_round = Some((height, round));
+        self.expire_proposals(Instant::now());
+
+        // Sleep before proposing the value so we don't produce blocks too fast
+        // TODO: rethink/reconsider
+        tokio::time::sleep(self.propose_value_delay).await;
+
+        let state_change = self
+            .engine
+            .propose_state_change(self.shard_id.shard_id())
+            .await;
+        Ok(self.build_proposal(height, round, state_change))
+    }
+
     fn add_proposed_value(&mut self, full_proposal: &FullProposal) -> Validity {
+        self.expire_proposals(Instant::now());
+        if let Some(height) = full_proposal.height {
+            self.current_round = Some((height, full_proposal.round()));
+        }
         if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) =
             full_proposal.proposed_value.clone()
         {
-            self.proposed_chunks
-                .insert(full_proposal.shard_hash(), full_proposal.clone());
+            if chunk.hash.len() != self.header_hash_len as usize {
+                error!(
+                    "Shard chunk hash length {} doesn't match configured length {}",
+                    chunk.hash.len(),
+                    self.header_hash_len
+                );
+                return self.reject_proposal(ProposalRejection::HashLength);
+            }
+            if let Some(fid) = self.find_foreign_fid(&chunk) {
+                error!(
+                    "Shard chunk for shard {} contains transaction for fid {} from another shard",
+                    self.shard_id.shard_id(),
+                    fid
+                );
+                return self.reject_proposal(ProposalRejection::ForeignFid);
+            }
+            if let Some(hash) = chunk.find_duplicate_message() {
+                error!(
+                    "Shard chunk contains message {} more than once",
+                    hex::encode(hash)
+                );
+                return self.reject_proposal(ProposalRejection::DuplicateMessage);
+            }
+            let expected_root = transactions_root(&chunk.transactions);
+            let header_root = chunk
+                .header
+                .as_ref()
+                .map_or(&[][..], |header| header.transactions_root.as_slice());
+            if header_root != expected_root.as_slice() {
+                error!(
+                    "Shard chunk transactions root {} doesn't match its transactions ({})",
+                    hex::encode(header_root),
+                    hex::encode(&expected_root)
+                );
+                return self.reject_proposal(ProposalRejection::RootMismatch);
+            }
+            let timestamp = chunk.header.as_ref().map_or(0, |header| header.timestamp);
+            if let Some(tip_timestamp) = self.engine.tip_timestamp() {
+                if timestamp < tip_timestamp {
+                    error!(
+                        "Shard chunk timestamp {} is before the previous chunk's timestamp {}",
+                        timestamp, tip_timestamp
+                    );
+                    return self.reject_proposal(ProposalRejection::TimestampBeforeTip);
+                }
+            }
+            // A chunk without transactions can't change the state
+            if chunk.transactions.is_empty() {
+                let header = chunk.header.clone().unwrap_or_default();
+                let block_number = header.height.map_or(0, |height| height.block_number);
+                let previous_root = self.engine.previous_shard_root(block_number);
+                if header.shard_root != previous_root {
+                    error!(
+                        "Empty shard chunk at height {} changes shard root from {} to {}",
+                        block_number,
+                        hex::encode(&previous_root),
+                        hex::encode(&header.shard_root)
+                    );
+                    return self.reject_proposal(ProposalRejection::EmptyChunkChangesRoot);
+                }
+            }
+            hold_proposal(
+                &mut self.proposed_chunks,
+                full_proposal.shard_hash(),
+                full_proposal.clone(),
+            );
             let state = ShardStateChange {
                 shard_id: chunk.header.clone().unwrap().height.unwrap().shard_index,
                 new_state_root: chunk.header.clone().unwrap().shard_root.clone(),
@@ -139,27 +535,64 @@ impl Proposer for ShardProposer {
                 Validity::Valid
             } else {
                 error!("Invalid state change for shard: {:?}", state.shard_id);
-                Validity::Invalid
+                self.reject_proposal(ProposalRejection::InvalidStateChange)
             };
         }
         error!("Invalid proposed value: {:?}", full_proposal.proposed_value);
-        Validity::Invalid // TODO: Validate proposer signature?
+        self.reject_proposal(ProposalRejection::UnexpectedValue) // TODO: Validate proposer signature?
     }
 
-    async fn decide(&mut self, _height: Height, _round: Round, value: ShardHash) {
-        if let Some(proposal) = self.proposed_chunks.get(&value) {
+    async fn decide(
+        &mut self,
+        height: Height,
+        round: Round,
+        value: ShardHash,
+        validator_set: &SnapchainValidatorSet,
+    ) {
+        if let Some(proposal) = decided_proposal(&self.proposed_chunks, &value, round) {
             if let Some(tx_decision) = &self.tx_decision {
-                let _ = tx_decision.send(proposal.clone()).await;
+                let decision = Decision {
+                    height,
+                    round,
+                    value: value.clone(),
+                    validator_set: validator_set.clone(),
+                    proposal: proposal.clone(),
+                };
+                let _ = tx_decision.send(decision).await;
+            }
+            self.metrics
+                .observe_decided_round(self.shard_id.shard_id(), round);
+            self.metrics.observe_decided_height(height);
+            let shard_chunk = proposal.shard_chunk().unwrap();
+            let committed = self.engine.commit_shard_chunk(shard_chunk.clone());
+            // Keep linking to the stored tip if the chunk didn't make it into the store
+            if committed {
+                self.last_chunk = Some(shard_chunk.clone());
+            }
+            if let (true, Some(commit_hook)) = (committed, &self.commit_hook) {
+                if let Err(err) = commit_hook.shard_chunk_committed(&shard_chunk) {
+                    error!("Commit hook failed for shard chunk {}: {}", height, err);
+                }
+            }
+            if let (true, Some(commit_log)) = (committed, &self.commit_log) {
+                if let Err(err) = commit_log.append_shard_chunk(&shard_chunk) {
+                    error!("Failed to log commit of shard chunk {}: {}", height, err);
+                }
             }
-            self.chunks.push(proposal.shard_chunk().unwrap());
-            self.engine
-                .commit_shard_chunk(proposal.shard_chunk().unwrap());
             self.proposed_chunks.remove(&value);
         }
     }
 
     fn get_confirmed_height(&self) -> Height {
-        self.engine.get_confirmed_height()
+        let confirmed = self.engine.get_confirmed_height();
+        match self.checkpoint_height {
+            Some(checkpoint_height) if checkpoint_height > confirmed => checkpoint_height,
+            _ => confirmed,
+        }
+    }
+
+    fn current_round(&self) -> Option<(Height, Round)> {
+        self.current_round
     }
 }
 
@@ -174,6 +607,21 @@ pub enum BlockProposerError {
     #[error("No peers")]
     NoPeers,
 
+    #[error("Shard decision channel closed")]
+    ShardDecisionsClosed,
+
+    #[error("Fork detected at height {height}")]
+    ForkDetected { height: u64 },
+
+    #[error("Sync gap too large: {gap} blocks, restore from a checkpoint instead")]
+    SyncGapTooLarge { gap: u64 },
+
+    #[error("Sync cancelled after block {synced_to}")]
+    SyncCancelled { synced_to: u64 },
+
+    #[error("No block number after {height}")]
+    HeightOverflow { height: Height },
+
     #[error(transparent)]
     RpcTransportError(#[from] tonic::transport::Error),
 
@@ -182,39 +630,312 @@ pub enum BlockProposerError {
 
     #[error(transparent)]
     BlockStorageError(#[from] BlockStorageError),
+
+    #[error("Invalid commit certificate for block {height}: {source}")]
+    InvalidCertificate {
+        height: u64,
+        source: CertificateError,
+    },
+}
+
+// Number of blocks fetched from a peer per request while syncing
+pub const SYNC_BATCH_SIZE: u64 = 100;
+
+// Syncs running at once across the proposers sharing a sync limiter, others wait for a slot
+pub const DEFAULT_MAX_CONCURRENT_SYNCS: usize = 2;
+
+// Where the block proposer fetches missing blocks from when syncing
+pub trait BlockSource {
+    // Blocks in [start_block_number, stop_block_number)
+    async fn get_blocks(
+        &mut self,
+        shard_id: u32,
+        start_block_number: u64,
+        stop_block_number: u64,
+    ) -> Result<Vec<Block>, BlockProposerError>;
+}
+
+impl BlockSource for SnapchainServiceClient<Channel> {
+    async fn get_blocks(
+        &mut self,
+        shard_id: u32,
+        start_block_number: u64,
+        stop_block_number: u64,
+    ) -> Result<Vec<Block>, BlockProposerError> {
+        let request = Request::new(BlocksRequest {
+            shard_id,
+            start_block_number,
+            stop_block_number: Some(stop_block_number),
+        });
+        let response = SnapchainServiceClient::get_blocks(self, request).await?;
+        Ok(response.into_inner().blocks)
+    }
+}
+
+/// Stop waiting for shard chunks before the timeout once enough shards have reported: the block is
+/// built `grace_period` after `min_fraction` of the shards have a chunk for the height, or as soon as
+/// all of them do
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkWaitPolicy {
+    pub min_fraction: f64,
+    #[serde(with = "humantime_serde")]
+    pub grace_period: Duration,
+}
+
+impl ChunkWaitPolicy {
+    // At least one chunk, and never more than there are shards
+    fn min_chunks(&self, num_shards: u32) -> usize {
+        let min_chunks = (self.min_fraction * num_shards as f64).ceil() as usize;
+        min_chunks.clamp(1, num_shards.max(1) as usize)
+    }
+}
+
+/// Shards that are currently producing chunks. Shared between the node, which pauses and resumes
+/// shards, and the block proposer, so blocks aren't held up waiting for a paused shard.
+#[derive(Clone, Debug, Default)]
+pub struct ActiveShards {
+    shard_ids: Arc<RwLock<BTreeSet<u32>>>,
+}
+
+impl ActiveShards {
+    pub fn new(shard_ids: impl IntoIterator<Item = u32>) -> Self {
+        Self {
+            shard_ids: Arc::new(RwLock::new(shard_ids.into_iter().collect())),
+        }
+    }
+
+    pub fn activate(&self, shard_id: u32) {
+        self.shard_ids.write().unwrap().insert(shard_id);
+    }
+
+    pub fn deactivate(&self, shard_id: u32) {
+        self.shard_ids.write().unwrap().remove(&shard_id);
+    }
+
+    pub fn shard_ids(&self) -> BTreeSet<u32> {
+        self.shard_ids.read().unwrap().clone()
+    }
 }
 
 pub struct BlockProposer {
     shard_id: SnapchainShard,
     address: Address,
     blocks: Vec<Block>,
-    proposed_blocks: BTreeMap<ShardHash, FullProposal>,
+    proposed_blocks: ProposalsByRound,
+    proposal_ttl: Duration,
     pending_chunks: BTreeMap<u64, Vec<ShardChunk>>,
     shard_decision_rx: RxDecision,
     num_shards: u32,
     block_tx: mpsc::Sender<Block>,
+    tx_decision: Option<TxDecision>,
     engine: BlockEngine,
+    max_sync_lookback: u64,
+    metrics: ConsensusMetrics,
+    header_hash_len: u32,
+    // Pinned header timestamp for tests and replays, current_time() is used when None
+    timestamp_override: Option<u64>,
+    sync_cancelled: Arc<AtomicBool>,
+    // Set while catching up from a peer, proposing on top of a stale tip would fork
+    syncing: bool,
+    commit_hook: Option<Arc<dyn CommitHook>>,
+    commit_log: Option<CommitLog>,
+    peer_scores: PeerScores,
+    // Wait for every shard (or the timeout) when None
+    chunk_wait_policy: Option<ChunkWaitPolicy>,
+    // Wait for chunks from these shards only instead of num_shards chunks, when set
+    active_shards: Option<ActiveShards>,
+    current_round: Option<(Height, Round)>,
+    // Blocks are announced here once this many blocks were decided on top of them, block_tx gets
+    // every block as soon as it's decided
+    confirmation_depth: u64,
+    confirmed_block_tx: Option<broadcast::Sender<Block>>,
+    // Decided blocks that aren't confirmation_depth deep yet, oldest first
+    unconfirmed_blocks: VecDeque<Block>,
+    sync_limiter: Arc<Semaphore>,
+    // Hash of the validator set the block is proposed to, committed in the header so the block's
+    // votes can be verified
+    validators_hash: Vec<u8>,
 }
 
 impl BlockProposer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         address: Address,
         shard_id: SnapchainShard,
         shard_decision_rx: RxDecision,
         num_shards: u32,
         block_tx: mpsc::Sender<Block>,
+        tx_decision: Option<TxDecision>,
         engine: BlockEngine,
+        max_sync_lookback: u64,
+        metrics: ConsensusMetrics,
     ) -> BlockProposer {
         BlockProposer {
             shard_id,
             address,
             blocks: vec![],
             proposed_blocks: BTreeMap::new(),
+            proposal_ttl: DEFAULT_PROPOSAL_TTL,
             pending_chunks: BTreeMap::new(),
             shard_decision_rx,
             num_shards,
             block_tx,
+            tx_decision,
             engine,
+            max_sync_lookback,
+            metrics,
+            header_hash_len: DEFAULT_HEADER_HASH_LEN,
+            timestamp_override: None,
+            sync_cancelled: Arc::new(AtomicBool::new(false)),
+            syncing: false,
+            commit_hook: None,
+            commit_log: None,
+            peer_scores: PeerScores::new(),
+            chunk_wait_policy: None,
+            active_shards: None,
+            current_round: None,
+            confirmation_depth: 0,
+            confirmed_block_tx: None,
+            unconfirmed_blocks: VecDeque::new(),
+            sync_limiter: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_SYNCS)),
+            validators_hash: vec![],
+        }
+    }
+
+    // Called whenever the validator set changes, blocks are proposed to the latest one
+    pub fn set_validator_set(&mut self, validator_set: &SnapchainValidatorSet) {
+        self.validators_hash = certificate::validators_hash(validator_set);
+    }
+
+    pub fn with_header_hash_len(self, header_hash_len: u32) -> Self {
+        Self {
+            header_hash_len,
+            ..self
+        }
+    }
+
+    pub fn with_proposal_ttl(self, proposal_ttl: Duration) -> Self {
+        Self {
+            proposal_ttl,
+            ..self
+        }
+    }
+
+    pub fn with_timestamp_override(self, timestamp_override: Option<u64>) -> Self {
+        Self {
+            timestamp_override,
+            ..self
+        }
+    }
+
+    pub fn with_commit_hook(self, commit_hook: Arc<dyn CommitHook>) -> Self {
+        Self {
+            commit_hook: Some(commit_hook),
+            ..self
+        }
+    }
+
+    pub fn with_commit_log(self, commit_log: CommitLog) -> Self {
+        Self {
+            commit_log: Some(commit_log),
+            ..self
+        }
+    }
+
+    // Record how peers behave when syncing from them
+    pub fn with_peer_scores(self, peer_scores: PeerScores) -> Self {
+        Self {
+            peer_scores,
+            ..self
+        }
+    }
+
+    pub fn with_chunk_wait_policy(self, chunk_wait_policy: Option<ChunkWaitPolicy>) -> Self {
+        Self {
+            chunk_wait_policy,
+            ..self
+        }
+    }
+
+    pub fn with_active_shards(self, active_shards: ActiveShards) -> Self {
+        Self {
+            active_shards: Some(active_shards),
+            ..self
+        }
+    }
+
+    pub fn with_confirmed_blocks(
+        self,
+        confirmation_depth: u64,
+        confirmed_block_tx: broadcast::Sender<Block>,
+    ) -> Self {
+        Self {
+            confirmation_depth,
+            confirmed_block_tx: Some(confirmed_block_tx),
+            ..self
+        }
+    }
+
+    // Syncs hold a permit while they run, so proposers sharing the semaphore queue instead of all
+    // replaying the chain at once
+    pub fn with_sync_limiter(self, sync_limiter: Arc<Semaphore>) -> Self {
+        Self {
+            sync_limiter,
+            ..self
+        }
+    }
+
+    // Set the flag to interrupt an in-progress sync between batches
+    pub fn with_sync_cancel(self, sync_cancelled: Arc<AtomicBool>) -> Self {
+        Self {
+            sync_cancelled,
+            ..self
+        }
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.timestamp_override.unwrap_or_else(current_time)
+    }
+
+    // Chunks for heights that are already confirmed will never be collected, e.g. when decides were
+    // missed while syncing, drop them so pending_chunks stays bounded
+    fn evict_stale_pending_chunks(&mut self) {
+        match self.next_height() {
+            Some(next_height) => {
+                self.pending_chunks = self.pending_chunks.split_off(&next_height.block_number);
+            }
+            None => self.pending_chunks.clear(),
+        }
+    }
+
+    // Called as rounds go by, so proposals of abandoned rounds don't pile up
+    fn expire_proposals(&mut self, now: Instant) -> usize {
+        evict_expired_proposals(&mut self.proposed_blocks, self.proposal_ttl, now)
+    }
+
+    fn reject_proposal(&self, rejection: ProposalRejection) -> Validity {
+        self.metrics
+            .observe_proposal_rejected(self.shard_id.shard_id(), rejection);
+        Validity::Invalid
+    }
+
+    // Number of shards chunks are expected from, and whether all of them have a chunk in `chunks`
+    fn shards_reported(&self, chunks: &[ShardChunk]) -> (u32, bool) {
+        match &self.active_shards {
+            None => (self.num_shards, chunks.len() == self.num_shards as usize),
+            Some(active_shards) => {
+                let active_shard_ids = active_shards.shard_ids();
+                let reported = active_shard_ids.iter().all(|shard_id| {
+                    chunks.iter().any(|chunk| {
+                        chunk
+                            .header
+                            .as_ref()
+                            .and_then(|header| header.height)
+                            .map_or(false, |height| height.shard_index == *shard_id)
+                    })
+                });
+                (active_shard_ids.len() as u32, reported)
+            }
         }
     }
 
@@ -222,33 +943,58 @@ impl BlockProposer {
         &mut self,
         height: Height,
         timeout: Duration,
-    ) -> Vec<ShardChunk> {
+    ) -> Result<Vec<ShardChunk>, BlockProposerError> {
         let requested_height = height.block_number;
+        self.evict_stale_pending_chunks();
+
+        // Block-only node, blocks are empty and there's nothing to wait for
+        if self.num_shards == 0 {
+            return Ok(vec![]);
+        }
 
         let mut poll_interval = time::interval(Duration::from_millis(10));
 
         // convert to deadline
         let deadline = Instant::now() + timeout;
+        // Set once enough shards reported under the chunk wait policy
+        let mut grace_deadline: Option<Instant> = None;
         loop {
             let timeout = time::sleep_until(deadline);
             select! {
                 _ = poll_interval.tick() => {
-                    if let Ok(decision) = self.shard_decision_rx.try_recv() {
-                       if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) = decision.proposed_value {
-                            let chunk_height = chunk.header.clone().unwrap().height.unwrap();
-                            let chunk_block_number = chunk_height.block_number;
-                            if self.pending_chunks.contains_key(&chunk_block_number) {
-                                self.pending_chunks.get_mut(&chunk_block_number).unwrap().push(chunk);
-                            } else {
-                                self.pending_chunks.insert(chunk_block_number, vec![chunk]);
+                    match self.shard_decision_rx.try_recv() {
+                        Ok(decision) => {
+                            if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) = decision.proposal.proposed_value {
+                                let chunk_height = chunk.header.clone().unwrap().height.unwrap();
+                                let chunk_block_number = chunk_height.block_number;
+                                if self.pending_chunks.contains_key(&chunk_block_number) {
+                                    self.pending_chunks.get_mut(&chunk_block_number).unwrap().push(chunk);
+                                } else {
+                                    self.pending_chunks.insert(chunk_block_number, vec![chunk]);
+                                }
                             }
                         }
+                        Err(TryRecvError::Empty) => {}
+                        Err(TryRecvError::Disconnected) => {
+                            // All shard proposers are gone, waiting any longer won't produce chunks
+                            return Err(BlockProposerError::ShardDecisionsClosed);
+                        }
                     }
-                    if let Some(chunks) = self.pending_chunks.get(&requested_height) {
-                        if chunks.len() == self.num_shards as usize {
-                            break;
+                    let chunks = self.pending_chunks.get(&requested_height).map_or(&[][..], |chunks| chunks.as_slice());
+                    let reported = chunks.len();
+                    let (expected_shards, all_reported) = self.shards_reported(chunks);
+                    if all_reported {
+                        break;
+                    }
+                    if let Some(policy) = &self.chunk_wait_policy {
+                        if grace_deadline.is_none() && reported >= policy.min_chunks(expected_shards) {
+                            grace_deadline = Some(Instant::now() + policy.grace_period);
                         }
                     }
+                    if grace_deadline.map_or(false, |grace_deadline| Instant::now() >= grace_deadline) {
+                        info!("Building block for height {:?} with chunks from {} of {} shards", requested_height, reported, expected_shards);
+                        break;
+                    }
                 }
                 _ = timeout => {
                     warn!("Block validator did not receive all shard chunks in time for height: {:?}", requested_height);
@@ -258,19 +1004,37 @@ impl BlockProposer {
         }
 
         if let Some(chunks) = self.pending_chunks.get(&requested_height) {
-            chunks.clone()
+            // Decisions arrive in whatever order the shards decide, order by shard so the block is deterministic
+            let mut chunks = chunks.clone();
+            chunks.sort_by_key(|chunk| {
+                chunk
+                    .header
+                    .as_ref()
+                    .and_then(|header| header.height)
+                    .map_or(0, |height| height.shard_index)
+            });
+            Ok(chunks)
         } else {
-            vec![]
+            Ok(vec![])
         }
     }
 
-    async fn publish_new_block(&self, block: Block) {
+    async fn publish_new_block(&mut self, block: Block) {
         match self.block_tx.send(block.clone()).await {
             Err(err) => {
                 error!("Erorr publishing new block {:#?}", err)
             }
             Ok(_) => {}
         }
+
+        if let Some(confirmed_block_tx) = &self.confirmed_block_tx {
+            self.unconfirmed_blocks.push_back(block);
+            while self.unconfirmed_blocks.len() as u64 > self.confirmation_depth {
+                let confirmed = self.unconfirmed_blocks.pop_front().unwrap();
+                // Sending only fails when nobody is subscribed
+                let _ = confirmed_block_tx.send(confirmed);
+            }
+        }
     }
 
     pub async fn register_validator(
@@ -294,103 +1058,1688 @@ impl BlockProposer {
         };
 
         if validator.current_height > prev_block_number {
+            let gap = validator.current_height - prev_block_number;
+            if gap > self.max_sync_lookback {
+                return Err(BlockProposerError::SyncGapTooLarge { gap });
+            }
+
             match &validator.rpc_address {
                 None => return Ok(()),
                 Some(rpc_address) => {
                     let destination_addr = format!("http://{}", rpc_address.clone());
-                    let mut rpc_client = SnapchainServiceClient::connect(destination_addr).await?;
-                    let request = Request::new(BlocksRequest {
-                        shard_id: self.shard_id.shard_id(),
-                        start_block_number: prev_block_number + 1,
-                        stop_block_number: None,
-                    });
-                    let missing_blocks = rpc_client.get_blocks(request).await?;
-                    for block in missing_blocks.get_ref().blocks.clone() {
-                        self.blocks.push(block.clone());
-                        self.publish_new_block(block).await;
-                    }
+                    let mut rpc_client =
+                        match SnapchainServiceClient::connect(destination_addr).await {
+                            Ok(rpc_client) => rpc_client,
+                            Err(err) => {
+                                let result = Err(err.into());
+                                self.peer_scores.record_sync(
+                                    &validator.address,
+                                    &result,
+                                    Duration::ZERO,
+                                );
+                                return result;
+                            }
+                        };
+                    let prev_height = Height::new(self.shard_id.shard_id(), prev_block_number);
+                    let start_height = prev_height.checked_increment().ok_or(
+                        BlockProposerError::HeightOverflow {
+                            height: prev_height,
+                        },
+                    )?;
+                    self.sync_from_peer(
+                        &validator.address,
+                        &mut rpc_client,
+                        start_height.block_number,
+                        validator.current_height,
+                    )
+                    .await?;
                 }
             }
         }
 
         Ok(())
     }
-}
 
-impl Proposer for BlockProposer {
-    async fn propose_value(
+    // Every peer can serve the blocks up to its own height, so syncing from the one furthest ahead
+    // covers all of them without downloading the same blocks several times
+    pub async fn register_validators(
         &mut self,
-        height: Height,
-        round: Round,
-        timeout: Duration,
-    ) -> FullProposal {
-        let shard_chunks = self.collect_confirmed_shard_chunks(height, timeout).await;
+        validators: &[SnapchainValidator],
+    ) -> Result<(), BlockProposerError> {
+        match validators
+            .iter()
+            .max_by_key(|validator| validator.current_height)
+        {
+            None => Ok(()),
+            Some(furthest) => self.register_validator(furthest).await,
+        }
+    }
 
-        let previous_block = self.blocks.last();
-        let parent_hash = match previous_block {
-            Some(block) => block.hash.clone(),
-            None => vec![0, 32],
-        };
-        let block_header = BlockHeader {
-            parent_hash,
-            chain_id: 0,
-            version: 0,
-            shard_headers_hash: vec![],
-            validators_hash: vec![],
-            timestamp: current_time(),
-            height: Some(height.clone()),
-        };
-        let hash = blake3::hash(&block_header.encode_to_vec())
-            .as_bytes()
-            .to_vec();
+    pub fn is_syncing(&self) -> bool {
+        self.syncing
+    }
+
+    // Sync from a specific peer, scoring it on how the sync went
+    pub async fn sync_from_peer<S: BlockSource>(
+        &mut self,
+        peer: &Address,
+        source: &mut S,
+        start_block_number: u64,
+        stop_block_number: u64,
+    ) -> Result<(), BlockProposerError> {
+        let _permit = self
+            .sync_limiter
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("sync limiter is never closed");
+        // Time spent waiting for a slot isn't the peer's fault
+        let started = Instant::now();
+        let result = self
+            .sync_blocks(source, start_block_number, stop_block_number)
+            .await;
+        self.peer_scores
+            .record_sync(peer, &result, started.elapsed());
+        result
+    }
+
+    // Fetch and apply [start_block_number, stop_block_number] in batches. Cancellation is checked
+    // between batches, everything applied up to that point is kept.
+    pub async fn sync_blocks<S: BlockSource>(
+        &mut self,
+        source: &mut S,
+        start_block_number: u64,
+        stop_block_number: u64,
+    ) -> Result<(), BlockProposerError> {
+        self.syncing = true;
+        let result = self
+            .sync_blocks_in_batches(source, start_block_number, stop_block_number)
+            .await;
+        // A failed or cancelled sync leaves us behind, keep syncing mode until a later sync catches up
+        self.syncing = self
+            .get_tip_block_number()
+            .map_or(true, |tip| tip < stop_block_number);
+        result
+    }
+
+    async fn sync_blocks_in_batches<S: BlockSource>(
+        &mut self,
+        source: &mut S,
+        start_block_number: u64,
+        stop_block_number: u64,
+    ) -> Result<(), BlockProposerError> {
+        let mut next_block_number = start_block_number;
+        while next_block_number <= stop_block_number {
+            if self.sync_cancelled.load(Ordering::SeqCst) {
+                return Err(BlockProposerError::SyncCancelled {
+                    synced_to: next_block_number - 1,
+                });
+            }
+
+            // Stops are exclusive, so a stop at u64::MAX leaves that last block out
+            let batch_stop = next_block_number
+                .saturating_add(SYNC_BATCH_SIZE)
+                .min(stop_block_number.saturating_add(1));
+            let blocks = source
+                .get_blocks(self.shard_id.shard_id(), next_block_number, batch_stop)
+                .await?;
+            if blocks.is_empty() {
+                break;
+            }
+            self.apply_synced_blocks(blocks).await?;
+            let tip = Height::new(self.shard_id.shard_id(), self.get_tip_block_number()?);
+            match tip.checked_increment() {
+                Some(next_height) => next_block_number = next_height.block_number,
+                // Synced up to the highest possible block
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    // Decide with the precommits the value was committed with, they're recorded in the block as its
+    // commit certificate
+    pub async fn decide_with_commits(
+        &mut self,
+        height: Height,
+        round: Round,
+        value: ShardHash,
+        validator_set: &SnapchainValidatorSet,
+        commits: &[(Address, Vec<u8>)],
+    ) {
+        if let Some(proposal) = decided_proposal(&self.proposed_blocks, &value, round) {
+            if let Some(tx_decision) = &self.tx_decision {
+                let decision = Decision {
+                    height,
+                    round,
+                    value: value.clone(),
+                    validator_set: validator_set.clone(),
+                    proposal: proposal.clone(),
+                };
+                let _ = tx_decision.send(decision).await;
+            }
+            self.metrics
+                .observe_decided_round(self.shard_id.shard_id(), round);
+            self.metrics.observe_decided_height(height);
+            let mut block = proposal.block().unwrap();
+            if !commits.is_empty() {
+                if let Err(err) =
+                    certificate::certify_block(&mut block, round.as_i64(), validator_set, commits)
+                {
+                    warn!(
+                        "Not recording the commit certificate of block {}: {}",
+                        height, err
+                    );
+                }
+            }
+            let committed = self.engine.commit_block(
+                block.clone(),
+                &proposal.proposer_address(),
+                self.commit_log.as_ref(),
+            );
+            if let (true, Some(commit_hook)) = (committed, &self.commit_hook) {
+                if let Err(err) = commit_hook.block_committed(&block) {
+                    error!("Commit hook failed for block {}: {}", height, err);
+                }
+            }
+
+            self.publish_new_block(block.clone()).await;
+
+            self.blocks.push(block);
+            self.proposed_blocks.remove(&value);
+            self.pending_chunks.remove(&height.block_number);
+        }
+    }
+
+    fn get_tip_block_number(&self) -> Result<u64, BlockProposerError> {
+        match self.blocks.last() {
+            None => Ok(0),
+            Some(block) => Ok(block
+                .header
+                .as_ref()
+                .ok_or(BlockProposerError::BlockMissingHeader)?
+                .height
+                .as_ref()
+                .ok_or(BlockProposerError::BlockMissingHeight)?
+                .block_number),
+        }
+    }
+
+    async fn apply_synced_blocks(&mut self, blocks: Vec<Block>) -> Result<(), BlockProposerError> {
+        // Make sure the peer's history extends our chain before applying anything
+        if let (Some(local_tip), Some(first_block)) = (self.blocks.last(), blocks.first()) {
+            let header = first_block
+                .header
+                .as_ref()
+                .ok_or(BlockProposerError::BlockMissingHeader)?;
+            if header.parent_hash != local_tip.hash {
+                let height = header
+                    .height
+                    .as_ref()
+                    .ok_or(BlockProposerError::BlockMissingHeight)?;
+                return Err(BlockProposerError::ForkDetected {
+                    height: height.block_number,
+                });
+            }
+        }
 
-        let block = Block {
+        // Stored as they're applied, so a sync cancelled between batches keeps what it got
+        for block in blocks {
+            if let Err(source) = certificate::verify_block_certificate(&block) {
+                let height = block
+                    .header
+                    .as_ref()
+                    .and_then(|header| header.height)
+                    .map_or(0, |height| height.block_number);
+                return Err(BlockProposerError::InvalidCertificate { height, source });
+            }
+            self.engine.commit_synced_block(block.clone())?;
+            self.blocks.push(block.clone());
+            self.publish_new_block(block).await;
+        }
+
+        Ok(())
+    }
+}
+
+impl Proposer for BlockProposer {
+    async fn propose_value(
+        &mut self,
+        height: Height,
+        round: Round,
+        timeout: Duration,
+    ) -> Result<FullProposal, ProposeError> {
+        // Blocks are tracked under shard 0 in their ShardHash, any other shard index collides with
+        // that shard's chunks
+        if height.shard_index != 0 {
+            return Err(ProposeError::WrongShard {
+                height,
+                shard_id: 0,
+            });
+        }
+        self.current_round = Some((height, round));
+        self.expire_proposals(Instant::now());
+
+        let shard_chunks = match self.collect_confirmed_shard_chunks(height, timeout).await {
+            Ok(shard_chunks) => shard_chunks,
+            Err(err) => {
+                error!(
+                    "Unable to collect shard chunks for height {}: {}",
+                    height, err
+                );
+                vec![]
+            }
+        };
+
+        let previous_block = self.blocks.last();
+        let parent_hash = match previous_block {
+            Some(block) => block.hash.clone(),
+            None => vec![0, 32],
+        };
+        let block_header = BlockHeader {
+            parent_hash,
+            chain_id: 0,
+            version: self.header_hash_len,
+            shard_headers_hash: vec![],
+            validators_hash: self.validators_hash.clone(),
+            timestamp: self.timestamp(),
+            height: Some(height.clone()),
+        };
+        let hash = buffer_pool::with_encoded(&block_header, |bytes| {
+            header_hash(bytes, self.header_hash_len)
+        });
+
+        let mut block = Block {
             header: Some(block_header),
-            hash: hash.clone(),
+            hash,
             validators: None,
             votes: None,
             shard_chunks,
+            full_hash: vec![],
         };
+        block.full_hash = block.full_hash();
 
-        let shard_hash = ShardHash {
-            hash: hash.clone(),
-            shard_index: height.shard_index as u32,
-        };
-
-        let proposal = FullProposal {
-            height: Some(height.clone()),
-            round: round.as_i64(),
-            proposed_value: Some(proto::full_proposal::ProposedValue::Block(block)),
-            proposer: self.address.to_vec(),
-        };
+        let (proposal, shard_hash) = FullProposalBuilder::new()
+            .with_height(height)
+            .with_round(round)
+            .with_proposer(self.address.clone())
+            .with_block(block)
+            .build()
+            .expect("block proposal is missing a field");
 
-        self.proposed_blocks.insert(shard_hash, proposal.clone());
-        proposal
+        hold_proposal(&mut self.proposed_blocks, shard_hash, proposal.clone());
+        Ok(proposal)
     }
 
     fn add_proposed_value(&mut self, full_proposal: &FullProposal) -> Validity {
-        if let Some(proto::full_proposal::ProposedValue::Block(_block)) =
+        self.expire_proposals(Instant::now());
+        if let Some(height) = full_proposal.height {
+            self.current_round = Some((height, full_proposal.round()));
+        }
+        if let Some(proto::full_proposal::ProposedValue::Block(block)) =
             full_proposal.proposed_value.clone()
         {
-            self.proposed_blocks
-                .insert(full_proposal.shard_hash(), full_proposal.clone());
+            if block.hash.len() != self.header_hash_len as usize {
+                error!(
+                    "Block hash length {} doesn't match configured length {}",
+                    block.hash.len(),
+                    self.header_hash_len
+                );
+                return self.reject_proposal(ProposalRejection::HashLength);
+            }
+            hold_proposal(
+                &mut self.proposed_blocks,
+                full_proposal.shard_hash(),
+                full_proposal.clone(),
+            );
         }
         Validity::Valid // TODO: Validate proposer signature?
     }
 
-    async fn decide(&mut self, height: Height, _round: Round, value: ShardHash) {
-        if let Some(proposal) = self.proposed_blocks.get(&value) {
-            self.engine.commit_block(proposal.block().unwrap());
+    async fn decide(
+        &mut self,
+        height: Height,
+        round: Round,
+        value: ShardHash,
+        validator_set: &SnapchainValidatorSet,
+    ) {
+        self.decide_with_commits(height, round, value, validator_set, &[])
+            .await
+    }
 
-            self.publish_new_block(proposal.block().unwrap()).await;
+    fn get_confirmed_height(&self) -> Height {
+        self.engine.get_confirmed_height()
+    }
 
-            self.blocks.push(proposal.block().unwrap());
-            self.proposed_blocks.remove(&value);
-            self.pending_chunks.remove(&height.block_number);
+    fn current_round(&self) -> Option<(Height, Round)> {
+        self.current_round
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::db::RocksDB;
+    use crate::storage::store::shard::{put_shard_chunk, ShardStore};
+    use crate::storage::store::BlockStore;
+    use libp2p::identity::ed25519::Keypair;
+
+    fn make_tmp_path() -> String {
+        tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn new_block_proposer(
+        shard_decision_rx: RxDecision,
+        max_sync_lookback: u64,
+    ) -> (BlockProposer, Arc<RocksDB>) {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let (block_tx, _block_rx) = mpsc::channel(1);
+        let keypair = Keypair::generate();
+        let proposer = BlockProposer::new(
+            Address(keypair.public().to_bytes()),
+            SnapchainShard::new(0),
+            shard_decision_rx,
+            1,
+            block_tx,
+            None,
+            BlockEngine::new(BlockStore::new(db.clone())),
+            max_sync_lookback,
+            ConsensusMetrics::new(),
+        );
+        (proposer, db)
+    }
+
+    fn new_shard_proposer(tx_decision: Option<TxDecision>) -> (ShardProposer, Arc<RocksDB>) {
+        new_shard_proposer_with_metrics(tx_decision, ConsensusMetrics::new())
+    }
+
+    fn new_shard_proposer_with_metrics(
+        tx_decision: Option<TxDecision>,
+        metrics: ConsensusMetrics,
+    ) -> (ShardProposer, Arc<RocksDB>) {
+        let db = RocksDB::new(&make_tmp_path());
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let db = shard_store.db.clone();
+        let keypair = Keypair::generate();
+        let proposer = ShardProposer::new(
+            Address(keypair.public().to_bytes()),
+            SnapchainShard::new(1),
+            ShardEngine::new(1, shard_store),
+            tx_decision,
+            Duration::from_millis(0),
+            metrics,
+            // Even fids belong to shard 1, odd fids to shard 2
+            Arc::new(|fid, _| if fid % 2 == 0 { 1 } else { 2 }),
+        );
+        (proposer, db)
+    }
+
+    #[tokio::test]
+    async fn test_decide_emits_decision() {
+        let (tx_decision, mut rx_decision) = mpsc::channel(1);
+        let (mut proposer, db) = new_shard_proposer(Some(tx_decision));
+        let validator_set = SnapchainValidatorSet::new(vec![]);
+
+        let height = Height::new(1, 1);
+        let round = Round::new(0);
+        let proposal = proposer
+            .propose_value(height, round, Duration::from_millis(0))
+            .await
+            .unwrap();
+        let value = proposal.shard_hash();
+        proposer
+            .decide(height, round, value.clone(), &validator_set)
+            .await;
+
+        let decision = rx_decision.try_recv().unwrap();
+        assert_eq!(decision.height, height);
+        assert_eq!(decision.round, round);
+        assert_eq!(decision.value, value);
+        assert_eq!(decision.validator_set, validator_set);
+        assert_eq!(decision.proposal.shard_chunk(), proposal.shard_chunk());
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_current_round_tracks_latest_proposal() {
+        let (mut proposer, db) = new_shard_proposer(None);
+        let (mut peer, peer_db) = new_shard_proposer(None);
+        assert_eq!(proposer.current_round(), None);
+
+        let height = Height::new(1, 1);
+        proposer
+            .propose_value(height, Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        assert_eq!(proposer.current_round(), Some((height, Round::new(0))));
+
+        // A proposal received from a peer moves it to the peer's round
+        let proposal = peer
+            .propose_value(height, Round::new(3), Duration::from_millis(0))
+            .await
+            .unwrap();
+        proposer.add_proposed_value(&proposal);
+        assert_eq!(proposer.current_round(), Some((height, Round::new(3))));
+
+        db.destroy().unwrap();
+        peer_db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_decide_records_decided_round() {
+        let metrics = ConsensusMetrics::new();
+        let (mut proposer, db) = new_shard_proposer_with_metrics(None, metrics.clone());
+        let validator_set = SnapchainValidatorSet::new(vec![]);
+
+        let height = Height::new(1, 1);
+        let round = Round::new(2);
+        let proposal = proposer
+            .propose_value(height, round, Duration::from_millis(0))
+            .await
+            .unwrap();
+        proposer
+            .decide(height, round, proposal.shard_hash(), &validator_set)
+            .await;
+
+        assert_eq!(metrics.decided_rounds(1), BTreeMap::from([(2, 1)]));
+        assert!(metrics.decided_rounds(2).is_empty());
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_decide_requires_proposal_for_decided_round() {
+        let (tx_decision, mut rx_decision) = mpsc::channel(1);
+        let (mut proposer, db) = new_shard_proposer(Some(tx_decision));
+        let validator_set = SnapchainValidatorSet::new(vec![]);
+
+        let height = Height::new(1, 1);
+        let proposal = proposer
+            .propose_value(height, Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+
+        // Only the round 0 proposal is held, so a round 2 decision isn't committed
+        proposer
+            .decide(height, Round::new(2), proposal.shard_hash(), &validator_set)
+            .await;
+        assert!(rx_decision.try_recv().is_err());
+        assert_eq!(proposer.get_confirmed_height(), Height::new(1, 0));
+
+        // Once the value is re-proposed in round 2, that proposal is the one committed
+        let mut reproposal = proposal.clone();
+        reproposal.round = 2;
+        assert!(matches!(
+            proposer.add_proposed_value(&reproposal),
+            Validity::Valid
+        ));
+        proposer
+            .decide(height, Round::new(2), proposal.shard_hash(), &validator_set)
+            .await;
+        let decision = rx_decision.try_recv().unwrap();
+        assert_eq!(decision.proposal, reproposal);
+        assert_eq!(proposer.get_confirmed_height(), height);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_next_height_matches_proposed_height() {
+        let (mut proposer, db) = new_shard_proposer(None);
+        let validator_set = SnapchainValidatorSet::new(vec![]);
+        assert_eq!(proposer.next_height(), Some(Height::new(1, 1)));
+
+        for _ in 0..3 {
+            let height = proposer.next_height().unwrap();
+            let round = Round::new(0);
+            let proposal = proposer
+                .propose_value(height, round, Duration::from_millis(0))
+                .await
+                .unwrap();
+            assert_eq!(proposal.height(), height);
+            proposer
+                .decide(height, round, proposal.shard_hash(), &validator_set)
+                .await;
+        }
+
+        assert_eq!(proposer.next_height(), Some(Height::new(1, 4)));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_parent_hash_links_without_keeping_chunks() {
+        let (mut proposer, db) = new_shard_proposer(None);
+        let validator_set = SnapchainValidatorSet::new(vec![]);
+
+        let mut previous_hash = None;
+        for _ in 0..50 {
+            let height = proposer.next_height().unwrap();
+            let round = Round::new(0);
+            let proposal = proposer
+                .propose_value(height, round, Duration::from_millis(0))
+                .await
+                .unwrap();
+            let chunk = proposal.shard_chunk().unwrap();
+            if let Some(previous_hash) = previous_hash {
+                assert_eq!(chunk.header.as_ref().unwrap().parent_hash, previous_hash);
+            }
+            previous_hash = Some(chunk.hash.clone());
+            proposer
+                .decide(height, round, proposal.shard_hash(), &validator_set)
+                .await;
+            assert_eq!(proposer.last_chunk.as_ref(), Some(&chunk));
         }
+
+        // A proposer that hasn't committed anything yet, e.g. after a restart, links to the stored tip
+        proposer.last_chunk = None;
+        let proposal = proposer
+            .propose_value(Height::new(1, 51), Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        assert_eq!(
+            proposal.shard_chunk().unwrap().header.unwrap().parent_hash,
+            previous_hash.clone().unwrap()
+        );
+
+        // A different chunk was stored at the height first, the decided one fails to commit
+        let conflicting_chunk = ShardChunk {
+            header: Some(ShardHeader {
+                height: Some(Height::new(1, 51)),
+                ..Default::default()
+            }),
+            hash: vec![5; 32],
+            ..Default::default()
+        };
+        put_shard_chunk(&db, conflicting_chunk).unwrap();
+        proposer
+            .decide(
+                Height::new(1, 51),
+                Round::new(0),
+                proposal.shard_hash(),
+                &validator_set,
+            )
+            .await;
+        assert_eq!(
+            proposer.last_chunk.as_ref().map(|chunk| &chunk.hash),
+            previous_hash.as_ref()
+        );
+
+        db.destroy().unwrap();
     }
 
-    fn get_confirmed_height(&self) -> Height {
-        self.engine.get_confirmed_height()
+    #[tokio::test]
+    async fn test_shard_proposer_from_checkpoint() {
+        let db = RocksDB::new(&make_tmp_path());
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let db = shard_store.db.clone();
+        let keypair = Keypair::generate();
+        let new_proposer = |height: Height, state_root: Vec<u8>| {
+            ShardProposer::from_checkpoint(
+                Address(keypair.public().to_bytes()),
+                SnapchainShard::new(1),
+                ShardEngine::new(1, shard_store.clone()),
+                None,
+                Duration::from_millis(0),
+                ConsensusMetrics::new(),
+                Arc::new(|_, _| 1),
+                height,
+                vec![7; 32],
+                state_root,
+            )
+        };
+
+        assert!(matches!(
+            new_proposer(Height::new(1, 10), vec![1; 20]),
+            Err(CheckpointError::StateRootMismatch { .. })
+        ));
+        assert!(matches!(
+            new_proposer(Height::new(2, 10), ShardEngine::empty_state_root()),
+            Err(CheckpointError::WrongShard { .. })
+        ));
+
+        let mut proposer =
+            new_proposer(Height::new(1, 10), ShardEngine::empty_state_root()).unwrap();
+        assert_eq!(proposer.get_confirmed_height(), Height::new(1, 10));
+        let height = proposer.next_height().unwrap();
+        assert_eq!(height, Height::new(1, 11));
+
+        let proposal = proposer
+            .propose_value(height, Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        let header = proposal.shard_chunk().unwrap().header.unwrap();
+        assert_eq!(header.height, Some(height));
+        assert_eq!(header.parent_hash, vec![7; 32]);
+        assert_eq!(header.shard_root, ShardEngine::empty_state_root());
+
+        proposer
+            .decide(
+                height,
+                Round::new(0),
+                proposal.shard_hash(),
+                &SnapchainValidatorSet::new(vec![]),
+            )
+            .await;
+        assert_eq!(proposer.get_confirmed_height(), height);
+
+        db.destroy().unwrap();
+    }
+
+    #[derive(Default)]
+    struct RecordingCommitHook {
+        blocks: std::sync::Mutex<Vec<Block>>,
+    }
+
+    impl CommitHook for RecordingCommitHook {
+        fn block_committed(&self, block: &Block) -> Result<(), CommitHookError> {
+            self.blocks.lock().unwrap().push(block.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_commit_hook_called_with_committed_block() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        let hook = Arc::new(RecordingCommitHook::default());
+        let mut proposer = proposer.with_commit_hook(hook.clone());
+        let validator_set = SnapchainValidatorSet::new(vec![]);
+
+        let height = Height::new(0, 1);
+        let round = Round::new(0);
+        let proposal = proposer
+            .propose_value(height, round, Duration::from_millis(0))
+            .await
+            .unwrap();
+        assert!(hook.blocks.lock().unwrap().is_empty());
+
+        proposer
+            .decide(height, round, proposal.shard_hash(), &validator_set)
+            .await;
+
+        assert_eq!(
+            *hook.blocks.lock().unwrap(),
+            vec![proposal.block().unwrap()]
+        );
+        assert_eq!(proposer.get_confirmed_height(), height);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_block_rejects_height_of_another_shard() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (mut proposer, db) = new_block_proposer(shard_decision_rx, 100);
+
+        let result = proposer
+            .propose_value(Height::new(1, 1), Round::new(0), Duration::from_millis(0))
+            .await;
+        assert!(matches!(
+            result,
+            Err(ProposeError::WrongShard { shard_id: 0, .. })
+        ));
+        assert_eq!(proposer.current_round(), None);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_decided_block_records_certificate() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (mut proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        let keypairs: Vec<Keypair> = (0..3).map(|_| Keypair::generate()).collect();
+        let validator_set = SnapchainValidatorSet::new(
+            keypairs
+                .iter()
+                .map(|k| SnapchainValidator::new(SnapchainShard::new(0), k.public(), None, 0))
+                .collect(),
+        );
+        proposer.set_validator_set(&validator_set);
+
+        let height = Height::new(0, 1);
+        let round = Round::new(1);
+        let proposal = proposer
+            .propose_value(height, round, Duration::from_millis(0))
+            .await
+            .unwrap();
+        let block_hash = proposal.block().unwrap().hash;
+        let commits: Vec<(Address, Vec<u8>)> = keypairs
+            .iter()
+            .map(|k| {
+                let address = Address(k.public().to_bytes());
+                let sign_bytes =
+                    certificate::block_precommit_sign_bytes(height, 1, &block_hash, &address);
+                (address, k.sign(&sign_bytes))
+            })
+            .collect();
+        proposer
+            .decide_with_commits(
+                height,
+                round,
+                proposal.shard_hash(),
+                &validator_set,
+                &commits,
+            )
+            .await;
+
+        let block = proposer.blocks.last().unwrap().clone();
+        assert_eq!(block.hash, block_hash);
+        assert!(block.votes.is_some());
+        assert_eq!(certificate::verify_block_certificate(&block), Ok(()));
+        assert_eq!(
+            BlockStore::new(db.clone())
+                .get_blocks_by_numbers(0, &[1])
+                .unwrap(),
+            vec![Some(block.clone())]
+        );
+
+        // Peers syncing the block check its certificate
+        let (_peer_decision_tx, peer_decision_rx) = mpsc::channel(1);
+        let (mut peer, peer_db) = new_block_proposer(peer_decision_rx, 100);
+        let mut tampered = block.clone();
+        tampered.votes.as_mut().unwrap().signer_bitmap[0] ^= 0b1;
+        assert!(matches!(
+            peer.apply_synced_blocks(vec![tampered]).await,
+            Err(BlockProposerError::InvalidCertificate { height: 1, .. })
+        ));
+        assert!(peer.blocks.is_empty());
+        peer.apply_synced_blocks(vec![block]).await.unwrap();
+        assert_eq!(peer.blocks.len(), 1);
+
+        db.destroy().unwrap();
+        peer_db.destroy().unwrap();
+    }
+
+    fn block(block_number: u64, hash: Vec<u8>, parent_hash: Vec<u8>) -> Block {
+        Block {
+            header: Some(BlockHeader {
+                height: Some(Height::new(0, block_number)),
+                parent_hash,
+                ..Default::default()
+            }),
+            hash,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_confirmed_blocks_lag_the_tip() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        let (block_tx, mut block_rx) = mpsc::channel(10);
+        let (confirmed_block_tx, mut confirmed_block_rx) = broadcast::channel(10);
+        let mut proposer = proposer.with_confirmed_blocks(2, confirmed_block_tx);
+        proposer.block_tx = block_tx;
+
+        let blocks: Vec<Block> = (1..=4)
+            .map(|block_number| block(block_number, vec![block_number as u8], vec![]))
+            .collect();
+        proposer.apply_synced_blocks(blocks.clone()).await.unwrap();
+
+        // The tip stream sees every block, the confirmed stream stops two heights behind
+        for block in &blocks {
+            assert_eq!(block_rx.try_recv().unwrap(), *block);
+        }
+        assert_eq!(confirmed_block_rx.try_recv().unwrap(), blocks[0]);
+        assert_eq!(confirmed_block_rx.try_recv().unwrap(), blocks[1]);
+        assert!(confirmed_block_rx.try_recv().is_err());
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_detects_fork() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (mut proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        proposer.blocks.push(block(1, vec![1], vec![]));
+
+        let result = proposer
+            .apply_synced_blocks(vec![block(2, vec![2], vec![9])])
+            .await;
+        assert!(matches!(
+            result,
+            Err(BlockProposerError::ForkDetected { height: 2 })
+        ));
+        assert_eq!(proposer.blocks.len(), 1);
+
+        proposer
+            .apply_synced_blocks(vec![block(2, vec![2], vec![1])])
+            .await
+            .unwrap();
+        assert_eq!(proposer.blocks.len(), 2);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_register_validator_sync_gap_too_large() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (mut proposer, db) = new_block_proposer(shard_decision_rx, 10);
+        let peer = SnapchainValidator::new(
+            SnapchainShard::new(0),
+            Keypair::generate().public(),
+            Some("0.0.0.0:0".to_string()),
+            11,
+        );
+
+        let result = proposer.register_validator(&peer).await;
+        assert!(matches!(
+            result,
+            Err(BlockProposerError::SyncGapTooLarge { gap: 11 })
+        ));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_register_validators_syncs_from_furthest_peer() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        let peer_scores = PeerScores::new();
+        let mut proposer = proposer.with_peer_scores(peer_scores.clone());
+        let peers: Vec<SnapchainValidator> = [5, 20, 12]
+            .into_iter()
+            .map(|current_height| {
+                SnapchainValidator::new(
+                    SnapchainShard::new(0),
+                    Keypair::generate().public(),
+                    Some("0.0.0.0:0".to_string()),
+                    current_height,
+                )
+            })
+            .collect();
+
+        // Nothing listens on the rpc address, so the one sync attempt fails and gets scored
+        let result = proposer.register_validators(&peers).await;
+        assert!(result.is_err());
+        assert!(peer_scores.stats(&peers[1].address).is_some());
+        assert!(peer_scores.stats(&peers[0].address).is_none());
+        assert!(peer_scores.stats(&peers[2].address).is_none());
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_shard_chunks_detects_closed_channel() {
+        let (shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (mut proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        drop(shard_decision_tx);
+
+        let result = time::timeout(
+            Duration::from_secs(1),
+            proposer.collect_confirmed_shard_chunks(Height::new(0, 1), Duration::from_secs(10)),
+        )
+        .await
+        .expect("should detect closed channel before the collection timeout");
+        assert!(matches!(
+            result,
+            Err(BlockProposerError::ShardDecisionsClosed)
+        ));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_shard_chunks_without_shards() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (mut proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        proposer.num_shards = 0;
+
+        let result = time::timeout(
+            Duration::from_millis(100),
+            proposer.collect_confirmed_shard_chunks(Height::new(0, 1), Duration::from_secs(10)),
+        )
+        .await
+        .expect("should not wait for chunks without shards");
+        assert!(result.unwrap().is_empty());
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_shard_chunks_sorted_by_shard() {
+        let (shard_decision_tx, shard_decision_rx) = mpsc::channel(3);
+        let (mut proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        proposer.num_shards = 3;
+
+        for shard_index in [3, 1, 2] {
+            let chunk = ShardChunk {
+                header: Some(ShardHeader {
+                    height: Some(Height::new(shard_index, 1)),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            shard_decision_tx
+                .send(Decision {
+                    height: Height::new(shard_index, 1),
+                    round: Round::new(0),
+                    value: ShardHash::default(),
+                    validator_set: SnapchainValidatorSet::new(vec![]),
+                    proposal: FullProposal {
+                        height: Some(Height::new(shard_index, 1)),
+                        proposed_value: Some(proto::full_proposal::ProposedValue::Shard(chunk)),
+                        ..Default::default()
+                    },
+                })
+                .await
+                .unwrap();
+        }
+
+        let chunks = proposer
+            .collect_confirmed_shard_chunks(Height::new(0, 1), Duration::from_secs(1))
+            .await
+            .unwrap();
+        let shard_indexes: Vec<u32> = chunks
+            .iter()
+            .map(|chunk| chunk.header.as_ref().unwrap().height.unwrap().shard_index)
+            .collect();
+        assert_eq!(shard_indexes, vec![1, 2, 3]);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_shard_chunks_with_wait_policy() {
+        let (shard_decision_tx, shard_decision_rx) = mpsc::channel(3);
+        let (proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        let mut proposer = proposer.with_chunk_wait_policy(Some(ChunkWaitPolicy {
+            min_fraction: 0.9,
+            grace_period: Duration::from_millis(20),
+        }));
+        proposer.num_shards = 3;
+
+        // Only shards 1 and 2 report
+        for shard_index in [1, 2] {
+            for block_number in [1, 2] {
+                let chunk = ShardChunk {
+                    header: Some(ShardHeader {
+                        height: Some(Height::new(shard_index, block_number)),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                };
+                shard_decision_tx
+                    .send(Decision {
+                        height: Height::new(shard_index, block_number),
+                        round: Round::new(0),
+                        value: ShardHash::default(),
+                        validator_set: SnapchainValidatorSet::new(vec![]),
+                        proposal: FullProposal {
+                            height: Some(Height::new(shard_index, block_number)),
+                            proposed_value: Some(proto::full_proposal::ProposedValue::Shard(chunk)),
+                            ..Default::default()
+                        },
+                    })
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // 2/3 is under the 0.9 threshold, so the full timeout is waited
+        let started = Instant::now();
+        let chunks = proposer
+            .collect_confirmed_shard_chunks(Height::new(0, 1), Duration::from_millis(300))
+            .await
+            .unwrap();
+        assert_eq!(chunks.len(), 2);
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        // At 0.6 the two chunks are enough, the block is built after the grace period
+        proposer.chunk_wait_policy = Some(ChunkWaitPolicy {
+            min_fraction: 0.6,
+            grace_period: Duration::from_millis(20),
+        });
+        let chunks = time::timeout(
+            Duration::from_secs(1),
+            proposer.collect_confirmed_shard_chunks(Height::new(0, 2), Duration::from_secs(10)),
+        )
+        .await
+        .expect("should stop waiting once the grace period elapsed")
+        .unwrap();
+        assert_eq!(chunks.len(), 2);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_collect_shard_chunks_skips_paused_shard() {
+        let (shard_decision_tx, shard_decision_rx) = mpsc::channel(3);
+        let (proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        let active_shards = ActiveShards::new([1, 2, 3]);
+        let mut proposer = proposer.with_active_shards(active_shards.clone());
+        proposer.num_shards = 3;
+
+        // Shard 3 is paused, only shards 1 and 2 decide
+        active_shards.deactivate(3);
+        for shard_index in [1, 2] {
+            let chunk = ShardChunk {
+                header: Some(ShardHeader {
+                    height: Some(Height::new(shard_index, 1)),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            shard_decision_tx
+                .send(Decision {
+                    height: Height::new(shard_index, 1),
+                    round: Round::new(0),
+                    value: ShardHash::default(),
+                    validator_set: SnapchainValidatorSet::new(vec![]),
+                    proposal: FullProposal {
+                        height: Some(Height::new(shard_index, 1)),
+                        proposed_value: Some(proto::full_proposal::ProposedValue::Shard(chunk)),
+                        ..Default::default()
+                    },
+                })
+                .await
+                .unwrap();
+        }
+
+        let chunks = time::timeout(
+            Duration::from_secs(1),
+            proposer.collect_confirmed_shard_chunks(Height::new(0, 1), Duration::from_secs(10)),
+        )
+        .await
+        .expect("should not wait for the paused shard")
+        .unwrap();
+        assert_eq!(chunks.len(), 2);
+
+        // Once resumed, its chunk is waited for again
+        active_shards.activate(3);
+        let result = time::timeout(
+            Duration::from_millis(200),
+            proposer.collect_confirmed_shard_chunks(Height::new(0, 2), Duration::from_secs(10)),
+        )
+        .await;
+        assert!(result.is_err());
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stale_pending_chunks_evicted() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (mut proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        for block_number in 1..=4 {
+            proposer
+                .pending_chunks
+                .insert(block_number, vec![ShardChunk::default()]);
+        }
+
+        // Blocks 1 and 2 were committed without going through decide, e.g. while syncing
+        let address = proposer.address.clone();
+        proposer
+            .engine
+            .commit_block(block(1, vec![1], vec![]), &address, None);
+        proposer
+            .engine
+            .commit_block(block(2, vec![2], vec![1]), &address, None);
+
+        proposer
+            .collect_confirmed_shard_chunks(Height::new(0, 3), Duration::from_millis(0))
+            .await
+            .unwrap();
+        assert_eq!(
+            proposer.pending_chunks.keys().cloned().collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+
+        db.destroy().unwrap();
+    }
+
+    fn shard_chunk_proposal(fids: Vec<u64>) -> FullProposal {
+        shard_chunk_proposal_at(Height::new(1, 1), fids)
+    }
+
+    fn shard_chunk_proposal_at(height: Height, fids: Vec<u64>) -> FullProposal {
+        let transactions: Vec<proto::Transaction> = fids
+            .into_iter()
+            .map(|fid| proto::Transaction {
+                fid,
+                ..Default::default()
+            })
+            .collect();
+        FullProposal {
+            height: Some(height),
+            round: 0,
+            proposed_value: Some(proto::full_proposal::ProposedValue::Shard(ShardChunk {
+                header: Some(ShardHeader {
+                    height: Some(height),
+                    transactions_root: transactions_root(&transactions),
+                    ..Default::default()
+                }),
+                hash: vec![1; DEFAULT_HEADER_HASH_LEN as usize],
+                transactions,
+                votes: None,
+            })),
+            proposer: vec![],
+            signature: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_chunk_with_foreign_fid() {
+        let (mut proposer, db) = new_shard_proposer(None);
+
+        let valid = proposer.add_proposed_value(&shard_chunk_proposal(vec![2, 4]));
+        assert!(matches!(valid, Validity::Valid));
+
+        let invalid = proposer.add_proposed_value(&shard_chunk_proposal(vec![2, 3]));
+        assert!(matches!(invalid, Validity::Invalid));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_foreign_fid_checked_at_chunk_height() {
+        let (proposer, db) = new_shard_proposer(None);
+        // Every fid was on shard 1 before height 10, odd fids moved to shard 2 from then on
+        let mut proposer = ShardProposer {
+            shard_assignment: Arc::new(|fid, block_number| {
+                if block_number < 10 || fid % 2 == 0 {
+                    1
+                } else {
+                    2
+                }
+            }),
+            ..proposer
+        };
+
+        let below =
+            proposer.add_proposed_value(&shard_chunk_proposal_at(Height::new(1, 9), vec![2, 3]));
+        assert!(matches!(below, Validity::Valid));
+
+        let above =
+            proposer.add_proposed_value(&shard_chunk_proposal_at(Height::new(1, 10), vec![2, 3]));
+        assert!(matches!(above, Validity::Invalid));
+
+        db.destroy().unwrap();
+    }
+
+    fn message(fid: u64, hash: Vec<u8>) -> crate::proto::message::Message {
+        crate::proto::message::Message {
+            data: Some(crate::proto::message::MessageData {
+                fid,
+                ..Default::default()
+            }),
+            hash,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_propose_deduplicates_messages() {
+        let (mut proposer, db) = new_shard_proposer(None);
+        let messages_tx = proposer.engine.messages_tx();
+        messages_tx.send(message(2, vec![2; 20])).await.unwrap();
+        messages_tx.send(message(2, vec![3; 20])).await.unwrap();
+        messages_tx.send(message(2, vec![2; 20])).await.unwrap();
+
+        let proposal = proposer
+            .propose_value(Height::new(1, 1), Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        let chunk = proposal.shard_chunk().unwrap();
+        let hashes: Vec<Vec<u8>> = chunk.iter_messages().map(|msg| msg.hash.clone()).collect();
+        assert_eq!(hashes, vec![vec![2; 20], vec![3; 20]]);
+        assert_eq!(chunk.find_duplicate_message(), None);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_undecided_proposals_expire() {
+        let (proposer, db) = new_shard_proposer(None);
+        let mut proposer = proposer.with_proposal_ttl(Duration::from_secs(60));
+        let proposal = shard_chunk_proposal(vec![2, 4]);
+        let value = proposal.shard_hash();
+        proposer.add_proposed_value(&proposal);
+        assert!(proposer.proposed_chunks.contains_key(&value));
+
+        let now = Instant::now();
+        assert_eq!(proposer.expire_proposals(now + Duration::from_secs(30)), 0);
+        assert!(proposer.proposed_chunks.contains_key(&value));
+
+        assert_eq!(proposer.expire_proposals(now + Duration::from_secs(61)), 1);
+        assert!(proposer.proposed_chunks.is_empty());
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_propose_with_transactions() {
+        let (mut proposer, db) = new_shard_proposer(None);
+        // Left in the mempool, must not end up in the chunk
+        let messages_tx = proposer.engine.messages_tx();
+        messages_tx.send(message(6, vec![6; 20])).await.unwrap();
+
+        let transactions: Vec<Transaction> = [(2, vec![2; 20]), (4, vec![4; 20])]
+            .into_iter()
+            .map(|(fid, hash)| Transaction {
+                fid,
+                account_root: vec![],
+                system_messages: vec![],
+                user_messages: vec![message(fid, hash)],
+            })
+            .collect();
+
+        let proposal = proposer
+            .propose_with_transactions(Height::new(1, 1), Round::new(0), transactions.clone())
+            .await;
+        let chunk = proposal.shard_chunk().unwrap();
+        assert_eq!(chunk.transactions, transactions);
+        let header = chunk.header.as_ref().unwrap();
+        assert_eq!(header.transactions_root, transactions_root(&transactions));
+        assert!(!header.shard_root.is_empty());
+
+        // The mempool was left alone
+        let proposal = proposer
+            .propose_value(Height::new(1, 1), Round::new(1), Duration::from_millis(0))
+            .await
+            .unwrap();
+        assert_eq!(proposal.shard_chunk().unwrap().fids(), vec![6]);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejects_chunk_with_wrong_transactions_root() {
+        let (mut proposer, db) = new_shard_proposer(None);
+
+        let mut proposal = shard_chunk_proposal(vec![2, 4]);
+        if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) =
+            proposal.proposed_value.as_mut()
+        {
+            chunk.transactions.pop();
+        }
+        assert!(matches!(
+            proposer.add_proposed_value(&proposal),
+            Validity::Invalid
+        ));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_proposal_rejections_counted_by_reason() {
+        let metrics = ConsensusMetrics::new();
+        let (mut proposer, db) = new_shard_proposer_with_metrics(None, metrics.clone());
+
+        let mut wrong_root = shard_chunk_proposal(vec![2, 4]);
+        if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) =
+            wrong_root.proposed_value.as_mut()
+        {
+            chunk.transactions.pop();
+        }
+        proposer.add_proposed_value(&wrong_root);
+        proposer.add_proposed_value(&wrong_root);
+        proposer.add_proposed_value(&shard_chunk_proposal(vec![2, 3]));
+
+        assert_eq!(
+            metrics.proposal_rejections(1, ProposalRejection::RootMismatch),
+            2
+        );
+        assert_eq!(
+            metrics.proposal_rejections(1, ProposalRejection::ForeignFid),
+            1
+        );
+        assert_eq!(
+            metrics.proposal_rejections(1, ProposalRejection::DuplicateMessage),
+            0
+        );
+        assert!(metrics.render_prometheus().contains(
+            "snapchain_proposal_rejections_total{shard=\"1\",reason=\"root_mismatch\"} 2"
+        ));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejects_chunk_with_timestamp_before_tip() {
+        let (tx_decision, _rx_decision) = mpsc::channel(1);
+        let (proposer, db) = new_shard_proposer(Some(tx_decision));
+        let mut proposer = proposer.with_timestamp_override(Some(100));
+        let validator_set = SnapchainValidatorSet::new(vec![]);
+
+        let height = Height::new(1, 1);
+        let proposal = proposer
+            .propose_value(height, Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        proposer
+            .decide(height, Round::new(0), proposal.shard_hash(), &validator_set)
+            .await;
+        assert_eq!(proposer.get_confirmed_height(), height);
+
+        let next_proposal = |timestamp| {
+            let mut proposal = shard_chunk_proposal(vec![]);
+            proposal.height = Some(Height::new(1, 2));
+            if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) =
+                proposal.proposed_value.as_mut()
+            {
+                let header = chunk.header.as_mut().unwrap();
+                header.height = Some(Height::new(1, 2));
+                header.timestamp = timestamp;
+                header.shard_root = proposer.engine.previous_shard_root(2);
+            }
+            proposal
+        };
+
+        // The proposer's clock stepped backwards
+        let backwards = next_proposal(99);
+        assert!(matches!(
+            proposer.add_proposed_value(&backwards),
+            Validity::Invalid
+        ));
+
+        let same_second = next_proposal(100);
+        assert!(matches!(
+            proposer.add_proposed_value(&same_second),
+            Validity::Valid
+        ));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejects_chunk_with_duplicate_messages() {
+        let (mut proposer, db) = new_shard_proposer(None);
+
+        let mut proposal = shard_chunk_proposal(vec![2, 4]);
+        if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) =
+            proposal.proposed_value.as_mut()
+        {
+            chunk.transactions[0].user_messages = vec![message(2, vec![2; 20])];
+            chunk.transactions[1].user_messages = vec![message(4, vec![2; 20])];
+        }
+        assert!(matches!(
+            proposer.add_proposed_value(&proposal),
+            Validity::Invalid
+        ));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rejects_empty_chunk_changing_shard_root() {
+        let (mut proposer, db) = new_shard_proposer(None);
+
+        let mut proposal = shard_chunk_proposal(vec![]);
+        if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) =
+            proposal.proposed_value.as_mut()
+        {
+            chunk.header.as_mut().unwrap().shard_root = vec![7; 20];
+        }
+        assert!(matches!(
+            proposer.add_proposed_value(&proposal),
+            Validity::Invalid
+        ));
+
+        // Keeping the previous root is fine
+        let previous_root = proposer.engine.previous_shard_root(1);
+        if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) =
+            proposal.proposed_value.as_mut()
+        {
+            chunk.header.as_mut().unwrap().shard_root = previous_root;
+        }
+        assert!(matches!(
+            proposer.add_proposed_value(&proposal),
+            Validity::Valid
+        ));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_default_header_hash_len() {
+        let (mut proposer, db) = new_shard_proposer(None);
+
+        let proposal = proposer
+            .propose_value(Height::new(1, 1), Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        let chunk = proposal.shard_chunk().unwrap();
+        assert_eq!(chunk.hash.len(), 32);
+        assert_eq!(chunk.header.unwrap().version, DEFAULT_HEADER_HASH_LEN);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_truncated_header_hash_len() {
+        let (tx_decision, mut rx_decision) = mpsc::channel(1);
+        let (proposer, db) = new_shard_proposer(Some(tx_decision));
+        let mut proposer = proposer.with_header_hash_len(20);
+        let validator_set = SnapchainValidatorSet::new(vec![]);
+
+        let height = Height::new(1, 1);
+        let round = Round::new(0);
+        let proposal = proposer
+            .propose_value(height, round, Duration::from_millis(0))
+            .await
+            .unwrap();
+        let chunk = proposal.shard_chunk().unwrap();
+        assert_eq!(chunk.hash.len(), 20);
+        assert_eq!(chunk.header.as_ref().unwrap().version, 20);
+        assert_eq!(proposal.shard_hash().hash.len(), 20);
+        assert!(matches!(
+            proposer.add_proposed_value(&proposal),
+            Validity::Valid
+        ));
+
+        // The 20 byte hash resolves the proposal when deciding
+        proposer
+            .decide(height, round, proposal.shard_hash(), &validator_set)
+            .await;
+        let decision = rx_decision.try_recv().unwrap();
+        assert_eq!(decision.proposal.shard_chunk(), Some(chunk));
+        assert_eq!(proposer.get_confirmed_height(), height);
+
+        // Full length hashes are rejected by a node configured for truncated hashes
+        assert!(matches!(
+            proposer.add_proposed_value(&shard_chunk_proposal(vec![2])),
+            Validity::Invalid
+        ));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_override_gives_reproducible_hash() {
+        let height = Height::new(1, 1);
+        let round = Round::new(0);
+        let mut hashes = vec![];
+        for _ in 0..2 {
+            let (proposer, db) = new_shard_proposer(None);
+            let mut proposer = proposer.with_timestamp_override(Some(12345));
+            let proposal = proposer
+                .propose_value(height, round, Duration::from_millis(0))
+                .await
+                .unwrap();
+            let chunk = proposal.shard_chunk().unwrap();
+            assert_eq!(chunk.header.unwrap().timestamp, 12345);
+            hashes.push(chunk.hash);
+            db.destroy().unwrap();
+        }
+
+        assert_eq!(hashes[0], hashes[1]);
+    }
+
+    // Serves a chain of blocks, cancelling the sync after the given number of batches
+    struct CancellingBlockSource {
+        sync_cancelled: Arc<AtomicBool>,
+        cancel_after_batches: usize,
+        batches: usize,
+    }
+
+    impl BlockSource for CancellingBlockSource {
+        async fn get_blocks(
+            &mut self,
+            _shard_id: u32,
+            start_block_number: u64,
+            stop_block_number: u64,
+        ) -> Result<Vec<Block>, BlockProposerError> {
+            self.batches += 1;
+            if self.batches == self.cancel_after_batches {
+                self.sync_cancelled.store(true, Ordering::SeqCst);
+            }
+            Ok((start_block_number..stop_block_number)
+                .map(|n| block(n, n.to_be_bytes().to_vec(), (n - 1).to_be_bytes().to_vec()))
+                .collect())
+        }
+    }
+
+    // Serves blocks that don't extend the local chain
+    struct ForkedBlockSource;
+
+    impl BlockSource for ForkedBlockSource {
+        async fn get_blocks(
+            &mut self,
+            _shard_id: u32,
+            start_block_number: u64,
+            stop_block_number: u64,
+        ) -> Result<Vec<Block>, BlockProposerError> {
+            Ok((start_block_number..stop_block_number)
+                .map(|n| block(n, vec![0xff, n as u8], vec![0xff, (n - 1) as u8]))
+                .collect())
+        }
+    }
+
+    // Serves a single block slowly, tracking how many sources are serving at once
+    struct SlowBlockSource {
+        active: Arc<std::sync::atomic::AtomicUsize>,
+        max_active: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl BlockSource for SlowBlockSource {
+        async fn get_blocks(
+            &mut self,
+            _shard_id: u32,
+            start_block_number: u64,
+            _stop_block_number: u64,
+        ) -> Result<Vec<Block>, BlockProposerError> {
+            let active = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_active.fetch_max(active, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.active.fetch_sub(1, Ordering::SeqCst);
+            Ok(vec![block(start_block_number, vec![1], vec![0])])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_syncs_limited() {
+        let sync_limiter = Arc::new(Semaphore::new(2));
+        let active = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let max_active = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+        let mut proposers = vec![];
+        let mut dbs = vec![];
+        for _ in 0..5 {
+            let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+            let (proposer, db) = new_block_proposer(shard_decision_rx, 100);
+            proposers.push(proposer.with_sync_limiter(sync_limiter.clone()));
+            dbs.push(db);
+        }
+
+        let syncs = proposers.iter_mut().map(|proposer| {
+            let mut source = SlowBlockSource {
+                active: active.clone(),
+                max_active: max_active.clone(),
+            };
+            async move {
+                proposer
+                    .sync_from_peer(&Address([1; 32]), &mut source, 1, 1)
+                    .await
+            }
+        });
+        for result in futures::future::join_all(syncs).await {
+            result.unwrap();
+        }
+
+        assert_eq!(max_active.load(Ordering::SeqCst), 2);
+        for db in dbs {
+            db.destroy().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_forked_peer_ranked_below_healthy_peer() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (proposer, db) = new_block_proposer(shard_decision_rx, 100);
+        let peer_scores = PeerScores::new();
+        let mut proposer = proposer.with_peer_scores(peer_scores.clone());
+        let healthy_peer = Address([1; 32]);
+        let forked_peer = Address([2; 32]);
+        let mut healthy_source = CancellingBlockSource {
+            sync_cancelled: Arc::new(AtomicBool::new(false)),
+            cancel_after_batches: 0,
+            batches: 0,
+        };
+
+        proposer
+            .sync_from_peer(&healthy_peer, &mut healthy_source, 1, 2)
+            .await
+            .unwrap();
+        let result = proposer
+            .sync_from_peer(&forked_peer, &mut ForkedBlockSource, 3, 4)
+            .await;
+        assert!(matches!(
+            result,
+            Err(BlockProposerError::ForkDetected { height: 3 })
+        ));
+
+        let healthy_stats = peer_scores.stats(&healthy_peer).unwrap();
+        let forked_stats = peer_scores.stats(&forked_peer).unwrap();
+        assert_eq!(healthy_stats.successful_syncs, 1);
+        assert_eq!(forked_stats.fork_detections, 1);
+        assert!(forked_stats.score() < healthy_stats.score());
+        assert!(peer_scores.best_peer() == Some(healthy_peer));
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_cancelled_between_batches() {
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (proposer, db) = new_block_proposer(shard_decision_rx, 100_000);
+        let sync_cancelled = Arc::new(AtomicBool::new(false));
+        let mut proposer = proposer.with_sync_cancel(sync_cancelled.clone());
+        let mut source = CancellingBlockSource {
+            sync_cancelled,
+            cancel_after_batches: 2,
+            batches: 0,
+        };
+
+        let result = time::timeout(
+            Duration::from_secs(1),
+            proposer.sync_blocks(&mut source, 1, 10 * SYNC_BATCH_SIZE),
+        )
+        .await
+        .expect("sync should stop promptly once cancelled");
+
+        let synced_to = 2 * SYNC_BATCH_SIZE;
+        assert!(matches!(
+            result,
+            Err(BlockProposerError::SyncCancelled { synced_to: s }) if s == synced_to
+        ));
+        assert_eq!(source.batches, 2);
+        assert_eq!(proposer.blocks.len() as u64, synced_to);
+        assert_eq!(proposer.get_tip_block_number().unwrap(), synced_to);
+        let block_store = BlockStore::new(db.clone());
+        assert_eq!(block_store.max_block_number(0).unwrap(), synced_to);
+
+        db.destroy().unwrap();
     }
 }
@@ -1,13 +1,80 @@
+use crate::consensus::metrics::{ConsensusMetrics, ProposalRejection};
 use crate::consensus::proposer::{BlockProposer, Proposer, ShardProposer};
 use crate::core::types::{
     Address, Height, ShardHash, ShardId, SnapchainShard, SnapchainValidator,
     SnapchainValidatorContext, SnapchainValidatorSet,
 };
 use crate::proto::snapchain::FullProposal;
-use malachite_common::{Round, ValidatorSet};
+use malachite_common::{Round, ValidatorSet, Validity};
 use malachite_consensus::ProposedValue;
+#[cfg(feature = "trace-proposals")]
+use prost::Message;
+use rand::Rng;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
-use tracing::error;
+use thiserror::Error;
+use tracing::{error, trace};
+
+#[derive(Error, Debug)]
+pub enum ProposeError {
+    #[error("Not proposing for height {height}, it isn't a height of shard {shard_id}")]
+    WrongShard { height: Height, shard_id: u32 },
+
+    #[error("Not proposing for height {height}, the shard is frozen at height {frozen_at}")]
+    Frozen { height: Height, frozen_at: u64 },
+
+    #[error("Not proposing for height {height} while syncing blocks from a peer")]
+    Syncing { height: Height },
+
+    #[error("Not proposing for height {height}, the next height to propose is {expected}")]
+    UnexpectedHeight { height: Height, expected: Height },
+
+    #[error("Not proposing for height {height}, the confirmed height is the last possible one")]
+    HeightOverflow { height: Height },
+
+    #[error("Giving up proposing for height {height} at round {round}, the limit is {max_rounds} rounds")]
+    MaxRoundsExceeded {
+        height: Height,
+        round: i64,
+        max_rounds: u32,
+    },
+}
+
+/// The latest validator set of every shard on this node, shared with the rpc server so peers can
+/// discover each other
+#[derive(Clone, Default)]
+pub struct ValidatorSets {
+    sets: Arc<RwLock<HashMap<u32, SnapchainValidatorSet>>>,
+}
+
+impl ValidatorSets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&self, shard_id: u32, validator_set: SnapchainValidatorSet) {
+        self.sets.write().unwrap().insert(shard_id, validator_set);
+    }
+
+    pub fn get(&self, shard_id: u32) -> Option<SnapchainValidatorSet> {
+        self.sets.read().unwrap().get(&shard_id).cloned()
+    }
+
+    // Lowest current height of the shard's validators other than `local`, None without peers
+    pub fn min_peer_height(&self, shard_id: u32, local: &Address) -> Option<u64> {
+        self.sets
+            .read()
+            .unwrap()
+            .get(&shard_id)?
+            .validators
+            .iter()
+            .filter(|validator| &validator.address != local)
+            .map(|validator| validator.current_height)
+            .min()
+    }
+}
 
 pub struct ShardValidator {
     pub(crate) shard_id: SnapchainShard,
@@ -22,6 +89,13 @@ pub struct ShardValidator {
     block_proposer: Option<BlockProposer>,
     shard_proposer: Option<ShardProposer>,
     pub started: bool,
+    paused: bool,
+    // No heights past this block number are proposed or accepted until unfrozen
+    frozen_at: Option<u64>,
+    max_rounds_per_height: Option<u32>,
+    propose_jitter: Option<Range<Duration>>,
+    metrics: ConsensusMetrics,
+    validator_sets: ValidatorSets,
 }
 
 impl ShardValidator {
@@ -30,6 +104,7 @@ impl ShardValidator {
         shard: SnapchainShard,
         block_proposer: Option<BlockProposer>,
         shard_proposer: Option<ShardProposer>,
+        metrics: ConsensusMetrics,
     ) -> ShardValidator {
         ShardValidator {
             shard_id: shard.clone(),
@@ -42,6 +117,36 @@ impl ShardValidator {
             block_proposer,
             shard_proposer,
             started: false,
+            paused: false,
+            frozen_at: None,
+            max_rounds_per_height: None,
+            propose_jitter: None,
+            metrics,
+            validator_sets: ValidatorSets::new(),
+        }
+    }
+
+    pub fn with_validator_sets(self, validator_sets: ValidatorSets) -> Self {
+        Self {
+            validator_sets,
+            ..self
+        }
+    }
+
+    // Stop proposing once a height has gone through this many rounds without a decision
+    pub fn with_max_rounds_per_height(self, max_rounds_per_height: Option<u32>) -> Self {
+        Self {
+            max_rounds_per_height,
+            ..self
+        }
+    }
+
+    // Sleep for a random duration within the range before proposing, so validators don't all
+    // propose in lockstep
+    pub fn with_propose_jitter(self, propose_jitter: Option<Range<Duration>>) -> Self {
+        Self {
+            propose_jitter,
+            ..self
         }
     }
 
@@ -53,6 +158,17 @@ impl ShardValidator {
         self.validator_set.count()
     }
 
+    // Peers that have registered with an rpc address, configured validators that haven't yet aren't counted
+    pub fn registered_peer_count(&self) -> usize {
+        self.validator_set
+            .validators
+            .iter()
+            .filter(|validator| {
+                validator.address != self.address && validator.rpc_address.is_some()
+            })
+            .count()
+    }
+
     pub fn get_current_height(&self) -> u64 {
         if let Some(p) = &self.block_proposer {
             return p.get_confirmed_height().block_number;
@@ -63,13 +179,48 @@ impl ShardValidator {
     }
 
     pub fn add_validator(&mut self, validator: SnapchainValidator) -> bool {
-        self.validator_set.add(validator)
+        let added = self.validator_set.add(validator);
+        // Published even when nothing was added, a re-registering peer refreshes its height
+        self.validator_sets
+            .update(self.shard_id.shard_id(), self.validator_set.clone());
+        if added {
+            if let Some(block_proposer) = &mut self.block_proposer {
+                block_proposer.set_validator_set(&self.validator_set);
+            }
+        }
+        added
     }
 
     pub fn start(&mut self) {
         self.started = true;
     }
 
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Stop the shard at a height agreed on by the network, e.g. for a coordinated upgrade
+    pub fn freeze_at(&mut self, block_number: u64) {
+        self.frozen_at = Some(block_number);
+    }
+
+    pub fn unfreeze(&mut self) {
+        self.frozen_at = None;
+    }
+
+    fn is_frozen_for(&self, height: Height) -> bool {
+        self.frozen_at
+            .map_or(false, |frozen_at| height.block_number > frozen_at)
+    }
+
     pub async fn sync_with_new_validator(&mut self, validator: &SnapchainValidator) {
         match &mut self.block_proposer {
             None => {}
@@ -80,20 +231,37 @@ impl ShardValidator {
         }
     }
 
+    pub async fn sync_with_new_validators(&mut self, validators: &[SnapchainValidator]) {
+        match &mut self.block_proposer {
+            None => {}
+            Some(block_proposer) => match block_proposer.register_validators(validators).await {
+                Ok(()) => {}
+                Err(err) => error!("Error registering validators {:#?}", err),
+            },
+        }
+    }
+
     pub fn start_round(&mut self, height: Height, round: Round, proposer: Address) {
         self.current_height = Some(height);
         self.current_round = round;
         self.current_proposer = Some(proposer);
     }
 
-    pub async fn decide(&mut self, height: Height, _: Round, value: ShardHash) {
+    // `commits` are the signed precommits the value was decided with, by validator address
+    pub async fn decide(
+        &mut self,
+        height: Height,
+        round: Round,
+        value: ShardHash,
+        commits: &[(Address, Vec<u8>)],
+    ) {
         if let Some(block_proposer) = &mut self.block_proposer {
             block_proposer
-                .decide(height, self.current_round, value)
+                .decide_with_commits(height, round, value, &self.validator_set, commits)
                 .await;
         } else if let Some(shard_proposer) = &mut self.shard_proposer {
             shard_proposer
-                .decide(height, self.current_round, value)
+                .decide(height, round, value, &self.validator_set)
                 .await;
         } else {
             panic!("No proposer set");
@@ -107,7 +275,40 @@ impl ShardValidator {
         full_proposal: FullProposal,
     ) -> ProposedValue<SnapchainValidatorContext> {
         let value = full_proposal.shard_hash();
-        let validity = if let Some(block_proposer) = &mut self.block_proposer {
+        trace!(
+            proposer = full_proposal.proposer_address().prefix(),
+            height = %full_proposal.height(),
+            round = full_proposal.round().as_i64(),
+            value = %value,
+            transactions = full_proposal.transaction_count(),
+            "Received full proposal"
+        );
+        #[cfg(feature = "trace-proposals")]
+        trace!(
+            proposal = hex::encode(full_proposal.encode_to_vec()),
+            "Encoded full proposal"
+        );
+
+        let validity = if !full_proposal.verify_proposer_signature() {
+            error!(
+                proposer = full_proposal.proposer_address().prefix(),
+                height = %full_proposal.height(),
+                round = full_proposal.round().as_i64(),
+                "Rejecting proposal not signed by its proposer for this height and round"
+            );
+            self.metrics.observe_proposal_rejected(
+                self.shard_id.shard_id(),
+                ProposalRejection::InvalidSignature,
+            );
+            Validity::Invalid
+        } else if self.is_frozen_for(full_proposal.height()) {
+            error!(
+                height = %full_proposal.height(),
+                frozen_at = self.frozen_at,
+                "Rejecting proposal past the frozen height"
+            );
+            Validity::Invalid
+        } else if let Some(block_proposer) = &mut self.block_proposer {
             block_proposer.add_proposed_value(&full_proposal)
         } else if let Some(shard_proposer) = &mut self.shard_proposer {
             shard_proposer.add_proposed_value(&full_proposal)
@@ -125,12 +326,62 @@ impl ShardValidator {
         }
     }
 
+    fn next_height(&self) -> Option<Height> {
+        if let Some(block_proposer) = &self.block_proposer {
+            block_proposer.next_height()
+        } else if let Some(shard_proposer) = &self.shard_proposer {
+            shard_proposer.next_height()
+        } else {
+            panic!("No proposer set");
+        }
+    }
+
     pub async fn propose_value(
         &mut self,
         height: Height,
         round: Round,
         timeout: Duration,
-    ) -> FullProposal {
+    ) -> Result<FullProposal, ProposeError> {
+        // The value would be tracked under another shard's ShardHash, e.g. a block at a height with
+        // a nonzero shard index collides with that shard's chunks
+        if height.shard_index != self.shard_id.shard_id() {
+            return Err(ProposeError::WrongShard {
+                height,
+                shard_id: self.shard_id.shard_id(),
+            });
+        }
+        if let (true, Some(frozen_at)) = (self.is_frozen_for(height), self.frozen_at) {
+            return Err(ProposeError::Frozen { height, frozen_at });
+        }
+        if let Some(block_proposer) = &self.block_proposer {
+            if block_proposer.is_syncing() {
+                return Err(ProposeError::Syncing { height });
+            }
+        }
+        // Proposing for any other height than the one after the confirmed one (e.g. before
+        // start_height) would build on the wrong parent. Synced blocks are stored before the sync
+        // returns, so they count as confirmed here.
+        match self.next_height() {
+            Some(expected) if expected == height => {}
+            Some(expected) => return Err(ProposeError::UnexpectedHeight { height, expected }),
+            None => return Err(ProposeError::HeightOverflow { height }),
+        }
+        if let Some(max_rounds) = self.max_rounds_per_height {
+            if round.as_i64() >= max_rounds as i64 {
+                self.metrics
+                    .observe_max_rounds_exceeded(self.shard_id.shard_id());
+                return Err(ProposeError::MaxRoundsExceeded {
+                    height,
+                    round: round.as_i64(),
+                    max_rounds,
+                });
+            }
+        }
+        if let Some(jitter) = &self.propose_jitter {
+            let delay = propose_jitter_delay(jitter, &mut rand::thread_rng());
+            tokio::time::sleep(delay).await;
+        }
+
         if let Some(block_proposer) = &mut self.block_proposer {
             block_proposer.propose_value(height, round, timeout).await
         } else if let Some(shard_proposer) = &mut self.shard_proposer {
@@ -140,3 +391,398 @@ impl ShardValidator {
         }
     }
 }
+
+// An empty range means a fixed delay of its start
+fn propose_jitter_delay<R: Rng>(jitter: &Range<Duration>, rng: &mut R) -> Duration {
+    if jitter.is_empty() {
+        return jitter.start;
+    }
+    rng.gen_range(jitter.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::consensus::{peers_needed_to_start, DEFAULT_PEERS_TO_START};
+    use crate::consensus::proposer::{BlockProposerError, BlockSource};
+    use crate::proto::snapchain::{Block, BlockHeader};
+    use crate::storage::db::RocksDB;
+    use crate::storage::store::engine::{BlockEngine, ShardEngine};
+    use crate::storage::store::shard::ShardStore;
+    use crate::storage::store::BlockStore;
+    use libp2p::identity::ed25519::Keypair;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::sync::mpsc;
+
+    fn make_tmp_path() -> String {
+        tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn new_shard_validator(metrics: ConsensusMetrics) -> (ShardValidator, Arc<RocksDB>) {
+        let db = RocksDB::new(&make_tmp_path());
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let db = shard_store.db.clone();
+        let address = Address(Keypair::generate().public().to_bytes());
+        let shard = SnapchainShard::new(1);
+        let shard_proposer = ShardProposer::new(
+            address.clone(),
+            shard.clone(),
+            ShardEngine::new(1, shard_store),
+            None,
+            Duration::from_millis(0),
+            metrics.clone(),
+            Arc::new(|_, _| 1),
+        );
+        let validator = ShardValidator::new(address, shard, None, Some(shard_proposer), metrics);
+        (validator, db)
+    }
+
+    #[test]
+    fn test_start_once_configured_validators_register() {
+        let (mut validator, db) = new_shard_validator(ConsensusMetrics::new());
+        let shard = validator.shard_id.clone();
+        let own_key = Keypair::generate().public();
+        validator.address = Address(own_key.to_bytes());
+        let peer_keys: Vec<_> = (0..3).map(|_| Keypair::generate().public()).collect();
+
+        // Seeded like post_start: every configured validator, then ourselves
+        let configured: Vec<_> = std::iter::once(own_key.clone())
+            .chain(peer_keys.iter().cloned())
+            .map(|key| SnapchainValidator::new(shard.clone(), key, None, 0))
+            .collect();
+        let initial_validator_set = SnapchainValidatorSet::new(configured.clone());
+        for v in configured {
+            validator.add_validator(v);
+        }
+        validator.add_validator(SnapchainValidator::new(shard.clone(), own_key, None, 0));
+        assert_eq!(validator.validator_count(), 4);
+        assert_eq!(validator.registered_peer_count(), 0);
+
+        let needed = peers_needed_to_start(&initial_validator_set);
+        assert_eq!(needed, 3);
+        for (i, key) in peer_keys.into_iter().enumerate() {
+            let registered = SnapchainValidator::new(
+                shard.clone(),
+                key,
+                Some(format!("127.0.0.1:{}", 3000 + i)),
+                0,
+            );
+            assert!(validator.add_validator(registered.clone()));
+            // Registering twice doesn't count again
+            assert!(!validator.add_validator(registered));
+            assert_eq!(validator.registered_peer_count(), i + 1);
+        }
+        assert_eq!(validator.registered_peer_count(), needed);
+
+        assert_eq!(
+            peers_needed_to_start(&SnapchainValidatorSet::new(vec![])),
+            DEFAULT_PEERS_TO_START
+        );
+
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_reregistering_refreshes_peer_height() {
+        let (validator, db) = new_shard_validator(ConsensusMetrics::new());
+        let validator_sets = ValidatorSets::new();
+        let mut validator = validator.with_validator_sets(validator_sets.clone());
+        let shard = validator.shard_id.clone();
+        let peer_key = Keypair::generate().public();
+        let peer = |current_height| {
+            SnapchainValidator::new(
+                shard.clone(),
+                peer_key.clone(),
+                Some("127.0.0.1:3000".to_string()),
+                current_height,
+            )
+        };
+
+        assert!(validator.add_validator(peer(10)));
+        assert_eq!(
+            validator_sets.min_peer_height(1, &validator.address),
+            Some(10)
+        );
+
+        // Not a new peer, but its height moves on
+        assert!(!validator.add_validator(peer(25)));
+        assert_eq!(
+            validator_sets.min_peer_height(1, &validator.address),
+            Some(25)
+        );
+        // A stale registration doesn't move it back
+        assert!(!validator.add_validator(peer(20)));
+        assert_eq!(
+            validator_sets.min_peer_height(1, &validator.address),
+            Some(25)
+        );
+        assert_eq!(validator_sets.min_peer_height(2, &validator.address), None);
+
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_propose_jitter_within_bounds() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let jitter = Duration::from_millis(10)..Duration::from_millis(50);
+        for _ in 0..1000 {
+            let delay = propose_jitter_delay(&jitter, &mut rng);
+            assert!(jitter.contains(&delay), "{:?} out of bounds", delay);
+        }
+
+        let fixed = Duration::from_millis(10)..Duration::from_millis(10);
+        assert_eq!(
+            propose_jitter_delay(&fixed, &mut rng),
+            Duration::from_millis(10)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_propose_stops_after_max_rounds() {
+        let metrics = ConsensusMetrics::new();
+        let (validator, db) = new_shard_validator(metrics.clone());
+        let mut validator = validator.with_max_rounds_per_height(Some(2));
+        let height = Height::new(1, 1);
+
+        let proposal = validator
+            .propose_value(height, Round::new(1), Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(proposal.height(), height);
+        assert_eq!(metrics.max_rounds_exceeded(1), 0);
+
+        let err = validator
+            .propose_value(height, Round::new(2), Duration::from_millis(10))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ProposeError::MaxRoundsExceeded {
+                round: 2,
+                max_rounds: 2,
+                ..
+            }
+        ));
+        assert_eq!(metrics.max_rounds_exceeded(1), 1);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_propose_rejects_non_sequential_height() {
+        let (mut validator, db) = new_shard_validator(ConsensusMetrics::new());
+
+        for height in [Height::new(1, 0), Height::new(1, 2)] {
+            let err = validator
+                .propose_value(height, Round::new(0), Duration::from_millis(10))
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                err,
+                ProposeError::UnexpectedHeight { expected, .. } if expected == Height::new(1, 1)
+            ));
+        }
+
+        let proposal = validator
+            .propose_value(Height::new(1, 1), Round::new(0), Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(proposal.height(), Height::new(1, 1));
+
+        db.destroy().unwrap();
+    }
+
+    // The proposal as if a peer had proposed and signed it
+    fn signed_by_peer(proposal: FullProposal) -> FullProposal {
+        let ctx = SnapchainValidatorContext::new(Keypair::generate());
+        ctx.sign_full_proposal(FullProposal {
+            proposer: ctx.public_key().to_bytes().to_vec(),
+            ..proposal
+        })
+    }
+
+    #[tokio::test]
+    async fn test_replayed_proposal_rejected() {
+        let metrics = ConsensusMetrics::new();
+        let (mut validator, db) = new_shard_validator(metrics.clone());
+        let proposal = validator
+            .propose_value(Height::new(1, 1), Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        let signed = signed_by_peer(proposal);
+
+        let proposed = validator.add_proposed_value(signed.clone());
+        assert!(matches!(proposed.validity, Validity::Valid));
+
+        // The same signed value replayed at a later round
+        let replayed = FullProposal { round: 1, ..signed };
+        let proposed = validator.add_proposed_value(replayed);
+        assert!(matches!(proposed.validity, Validity::Invalid));
+        assert_eq!(
+            metrics.proposal_rejections(1, ProposalRejection::InvalidSignature),
+            1
+        );
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_frozen_shard_does_not_advance() {
+        let (mut validator, db) = new_shard_validator(ConsensusMetrics::new());
+        validator.freeze_at(2);
+
+        for block_number in 1..=2 {
+            let height = Height::new(1, block_number);
+            let proposal = validator
+                .propose_value(height, Round::new(0), Duration::from_millis(0))
+                .await
+                .unwrap();
+            validator
+                .decide(height, Round::new(0), proposal.shard_hash(), &[])
+                .await;
+        }
+
+        let err = validator
+            .propose_value(Height::new(1, 3), Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProposeError::Frozen { frozen_at: 2, .. }));
+
+        validator.unfreeze();
+        let proposal = validator
+            .propose_value(Height::new(1, 3), Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        let proposal = signed_by_peer(proposal);
+
+        // Proposals from peers past the frozen height are refused too
+        validator.freeze_at(2);
+        let proposed = validator.add_proposed_value(proposal.clone());
+        assert!(matches!(proposed.validity, Validity::Invalid));
+        validator.unfreeze();
+        let proposed = validator.add_proposed_value(proposal);
+        assert!(matches!(proposed.validity, Validity::Valid));
+
+        db.destroy().unwrap();
+    }
+
+    struct TestBlockSource {
+        blocks: Vec<Block>,
+        fail: bool,
+    }
+
+    impl BlockSource for TestBlockSource {
+        async fn get_blocks(
+            &mut self,
+            _shard_id: u32,
+            start_block_number: u64,
+            stop_block_number: u64,
+        ) -> Result<Vec<Block>, BlockProposerError> {
+            if self.fail {
+                return Err(BlockProposerError::NoPeers);
+            }
+            Ok(self
+                .blocks
+                .iter()
+                .filter(|block| {
+                    let block_number = block.header.as_ref().unwrap().height.unwrap().block_number;
+                    block_number >= start_block_number && block_number < stop_block_number
+                })
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn new_block_validator(db: Arc<RocksDB>) -> ShardValidator {
+        let address = Address(Keypair::generate().public().to_bytes());
+        let shard = SnapchainShard::new(0);
+        let (_shard_decision_tx, shard_decision_rx) = mpsc::channel(1);
+        let (block_tx, _) = mpsc::channel(1);
+        let metrics = ConsensusMetrics::new();
+        let block_proposer = BlockProposer::new(
+            address.clone(),
+            shard.clone(),
+            shard_decision_rx,
+            1,
+            block_tx,
+            None,
+            BlockEngine::new(BlockStore::new(db)),
+            100,
+            metrics.clone(),
+        );
+        ShardValidator::new(address, shard, Some(block_proposer), None, metrics)
+    }
+
+    #[tokio::test]
+    async fn test_block_rejects_height_of_another_shard() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let mut validator = new_block_validator(db.clone());
+
+        let result = validator
+            .propose_value(Height::new(1, 1), Round::new(0), Duration::from_millis(0))
+            .await;
+        assert!(matches!(
+            result,
+            Err(ProposeError::WrongShard { shard_id: 0, .. })
+        ));
+
+        let proposal = validator
+            .propose_value(Height::new(0, 1), Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        assert_eq!(proposal.shard_hash().shard_index, 0);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_no_proposing_while_syncing() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let mut validator = new_block_validator(db.clone());
+
+        let blocks: Vec<Block> = (1..=3u8)
+            .map(|block_number| Block {
+                header: Some(BlockHeader {
+                    height: Some(Height::new(0, block_number as u64)),
+                    parent_hash: vec![block_number - 1],
+                    ..Default::default()
+                }),
+                hash: vec![block_number],
+                ..Default::default()
+            })
+            .collect();
+        let mut source = TestBlockSource { blocks, fail: true };
+
+        // The sync fails, leaving us behind
+        let block_proposer = validator.block_proposer.as_mut().unwrap();
+        assert!(block_proposer.sync_blocks(&mut source, 1, 3).await.is_err());
+        assert!(block_proposer.is_syncing());
+        let result = validator
+            .propose_value(Height::new(0, 1), Round::new(0), Duration::from_millis(0))
+            .await;
+        assert!(matches!(result, Err(ProposeError::Syncing { .. })));
+
+        // Once caught up, proposing resumes
+        source.fail = false;
+        let block_proposer = validator.block_proposer.as_mut().unwrap();
+        block_proposer.sync_blocks(&mut source, 1, 3).await.unwrap();
+        assert!(!block_proposer.is_syncing());
+        assert_eq!(validator.get_current_height(), 3);
+        let proposal = validator
+            .propose_value(Height::new(0, 4), Round::new(0), Duration::from_millis(0))
+            .await
+            .unwrap();
+        assert_eq!(proposal.height(), Height::new(0, 4));
+
+        db.destroy().unwrap();
+    }
+}
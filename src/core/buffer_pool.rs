@@ -0,0 +1,100 @@
+use prost::Message;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Buffers kept per thread once they're handed back, further ones are dropped
+pub const DEFAULT_MAX_POOLED_BUFFERS: usize = 16;
+
+// Larger buffers aren't kept, one oversized chunk shouldn't pin its memory for the thread's lifetime
+const MAX_POOLED_CAPACITY: usize = 4 * 1024 * 1024;
+
+static MAX_POOLED_BUFFERS: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_POOLED_BUFFERS);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    // Buffers that had to be allocated because the pool was empty
+    pub allocated: u64,
+    // Buffers served from the pool
+    pub reused: u64,
+}
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    static STATS: RefCell<PoolStats> = const { RefCell::new(PoolStats { allocated: 0, reused: 0 }) };
+}
+
+// Buffers kept per thread from now on, 0 turns pooling off
+pub fn set_max_pooled_buffers(max_pooled_buffers: usize) {
+    MAX_POOLED_BUFFERS.store(max_pooled_buffers, Ordering::Relaxed);
+}
+
+// Pool usage on the current thread
+pub fn stats() -> PoolStats {
+    STATS.with(|stats| *stats.borrow())
+}
+
+// An empty buffer with at least this capacity, reusing a pooled one when there is one
+pub(crate) fn take(capacity: usize) -> Vec<u8> {
+    match POOL.with(|pool| pool.borrow_mut().pop()) {
+        Some(mut buffer) => {
+            STATS.with(|stats| stats.borrow_mut().reused += 1);
+            buffer.reserve(capacity);
+            buffer
+        }
+        None => {
+            STATS.with(|stats| stats.borrow_mut().allocated += 1);
+            Vec::with_capacity(capacity)
+        }
+    }
+}
+
+// Hand a buffer that's no longer needed back to the pool
+pub(crate) fn recycle(mut buffer: Vec<u8>) {
+    if buffer.capacity() == 0 || buffer.capacity() > MAX_POOLED_CAPACITY {
+        return;
+    }
+    let max_pooled_buffers = MAX_POOLED_BUFFERS.load(Ordering::Relaxed);
+    POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < max_pooled_buffers {
+            buffer.clear();
+            pool.push(buffer);
+        }
+    });
+}
+
+// Like encode_to_vec for bytes that are only needed for the duration of `f`, e.g. to hash them
+pub(crate) fn with_encoded<M: Message, R>(message: &M, f: impl FnOnce(&[u8]) -> R) -> R {
+    let mut buffer = take(message.encoded_len());
+    message.encode(&mut buffer).unwrap(); // Vec grows as needed, encoding can't fail
+    let result = f(&buffer);
+    recycle(buffer);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::snapchain::ShardHeader;
+
+    #[test]
+    fn test_encoded_buffers_are_reused() {
+        let header = ShardHeader {
+            parent_hash: vec![1; 32],
+            ..Default::default()
+        };
+        let before = stats();
+        for _ in 0..100 {
+            let encoded = with_encoded(&header, |bytes| bytes.to_vec());
+            assert_eq!(encoded, header.encode_to_vec());
+        }
+        let after = stats();
+        assert_eq!(after.allocated - before.allocated, 1);
+        assert_eq!(after.reused - before.reused, 99);
+
+        // Oversized buffers aren't kept
+        recycle(Vec::with_capacity(MAX_POOLED_CAPACITY + 1));
+        let buffer = take(0);
+        assert!(buffer.capacity() <= MAX_POOLED_CAPACITY);
+    }
+}
@@ -0,0 +1,345 @@
+use crate::core::types::{proto, Address, Height, ShardHash, SnapchainValidatorSet, Vote};
+use malachite_common::{NilOrVal, Round};
+use thiserror::Error;
+
+const ED25519_SIGNATURE_LEN: usize = 64;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum CertificateError {
+    #[error("Validators hash does not match the validator set")]
+    ValidatorsHashMismatch,
+
+    #[error("Signer bitmap does not match the validator set")]
+    InvalidBitmap,
+
+    #[error("Signer {0} is not in the validator set")]
+    UnknownSigner(Address),
+
+    #[error("Aggregated signature has the wrong length")]
+    InvalidSignatureLength,
+
+    #[error("Invalid signature for validator {0}")]
+    InvalidSignature(Address),
+
+    #[error("Not enough signers for a quorum: {signers}/{validators}")]
+    InsufficientQuorum { signers: usize, validators: usize },
+
+    #[error("Block is missing its header or height")]
+    MissingHeader,
+
+    #[error("Block votes come without a valid validator set")]
+    InvalidValidatorSet,
+}
+
+pub fn validators_hash(validator_set: &SnapchainValidatorSet) -> Vec<u8> {
+    let mut hasher = blake3::Hasher::new();
+    for validator in &validator_set.validators {
+        hasher.update(&validator.address.0);
+    }
+    hasher.finalize().as_bytes().to_vec()
+}
+
+/// Compact commit certificate: a bitmap of the validators that signed and their combined signature.
+///
+/// With ed25519 keys signatures cannot be combined, so the aggregated signature is the concatenation
+/// of the individual signatures in validator set order.
+// TODO: Use real aggregation once validators move to BLS keys
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AggregatedCertificate {
+    pub signer_bitmap: Vec<u8>,
+    pub aggregated_signature: Vec<u8>,
+}
+
+impl AggregatedCertificate {
+    pub fn aggregate(
+        validator_set: &SnapchainValidatorSet,
+        signatures: &[(Address, Vec<u8>)],
+    ) -> Result<Self, CertificateError> {
+        let mut signer_bitmap = vec![0u8; validator_set.validators.len().div_ceil(8)];
+        let mut signatures_by_index = vec![None; validator_set.validators.len()];
+        for (address, signature) in signatures {
+            let index = validator_set
+                .validators
+                .iter()
+                .position(|v| v.address == *address)
+                .ok_or(CertificateError::UnknownSigner(address.clone()))?;
+            if signature.len() != ED25519_SIGNATURE_LEN {
+                return Err(CertificateError::InvalidSignatureLength);
+            }
+            signer_bitmap[index / 8] |= 1 << (index % 8);
+            signatures_by_index[index] = Some(signature.clone());
+        }
+
+        Ok(Self {
+            signer_bitmap,
+            aggregated_signature: signatures_by_index
+                .into_iter()
+                .flatten()
+                .flatten()
+                .collect(),
+        })
+    }
+
+    pub fn signer_indexes(&self) -> Vec<usize> {
+        (0..self.signer_bitmap.len() * 8)
+            .filter(|i| self.signer_bitmap[i / 8] & (1 << (i % 8)) != 0)
+            .collect()
+    }
+
+    /// Verify the certificate against the `validators_hash` committed in the block header.
+    /// `sign_bytes` returns the bytes each validator was expected to sign.
+    pub fn verify<F>(
+        &self,
+        validators_hash: &[u8],
+        validator_set: &SnapchainValidatorSet,
+        sign_bytes: F,
+    ) -> Result<(), CertificateError>
+    where
+        F: Fn(&Address) -> Vec<u8>,
+    {
+        if self::validators_hash(validator_set) != validators_hash {
+            return Err(CertificateError::ValidatorsHashMismatch);
+        }
+
+        let num_validators = validator_set.validators.len();
+        if self.signer_bitmap.len() != num_validators.div_ceil(8) {
+            return Err(CertificateError::InvalidBitmap);
+        }
+
+        let signer_indexes = self.signer_indexes();
+        if signer_indexes.iter().any(|i| *i >= num_validators) {
+            return Err(CertificateError::InvalidBitmap);
+        }
+        if self.aggregated_signature.len() != signer_indexes.len() * ED25519_SIGNATURE_LEN {
+            return Err(CertificateError::InvalidSignatureLength);
+        }
+
+        for (signature, index) in self
+            .aggregated_signature
+            .chunks(ED25519_SIGNATURE_LEN)
+            .zip(signer_indexes.iter())
+        {
+            let validator = &validator_set.validators[*index];
+            if !validator
+                .public_key
+                .verify(&sign_bytes(&validator.address), signature)
+            {
+                return Err(CertificateError::InvalidSignature(
+                    validator.address.clone(),
+                ));
+            }
+        }
+
+        if signer_indexes.len() * 3 <= num_validators * 2 {
+            return Err(CertificateError::InsufficientQuorum {
+                signers: signer_indexes.len(),
+                validators: num_validators,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub fn to_confirmed_votes(&self) -> proto::ConfirmedVotes {
+        proto::ConfirmedVotes {
+            votes: vec![],
+            signatures: vec![],
+            signer_bitmap: self.signer_bitmap.clone(),
+            aggregated_signature: self.aggregated_signature.clone(),
+            round: 0,
+        }
+    }
+
+    pub fn from_confirmed_votes(votes: &proto::ConfirmedVotes) -> Self {
+        Self {
+            signer_bitmap: votes.signer_bitmap.clone(),
+            aggregated_signature: votes.aggregated_signature.clone(),
+        }
+    }
+}
+
+// Bytes a validator signed when precommitting to the block with this hash
+pub fn block_precommit_sign_bytes(
+    height: Height,
+    round: i64,
+    block_hash: &[u8],
+    voter: &Address,
+) -> Vec<u8> {
+    Vote::new_precommit(
+        height,
+        Round::new(round),
+        NilOrVal::Val(ShardHash {
+            shard_index: height.shard_index,
+            hash: block_hash.to_vec(),
+        }),
+        voter.clone(),
+    )
+    .to_sign_bytes()
+}
+
+// Record the precommits a block was decided with, along with the validator set they verify against.
+// The validator set must be the one the block header's validators_hash was computed from.
+pub fn certify_block(
+    block: &mut proto::Block,
+    round: i64,
+    validator_set: &SnapchainValidatorSet,
+    commits: &[(Address, Vec<u8>)],
+) -> Result<(), CertificateError> {
+    let header = block
+        .header
+        .as_ref()
+        .ok_or(CertificateError::MissingHeader)?;
+    if header.validators_hash != validators_hash(validator_set) {
+        return Err(CertificateError::ValidatorsHashMismatch);
+    }
+    let certificate = AggregatedCertificate::aggregate(validator_set, commits)?;
+    block.votes = Some(proto::ConfirmedVotes {
+        round,
+        ..certificate.to_confirmed_votes()
+    });
+    block.validators = Some(validator_set.into());
+    block.full_hash = block.full_hash();
+    Ok(())
+}
+
+// Check the certificate a block was decided with. Blocks without one (e.g. from before they were
+// recorded) pass.
+pub fn verify_block_certificate(block: &proto::Block) -> Result<(), CertificateError> {
+    let votes = match &block.votes {
+        Some(votes) if !votes.signer_bitmap.is_empty() => votes,
+        _ => return Ok(()),
+    };
+    let header = block
+        .header
+        .as_ref()
+        .ok_or(CertificateError::MissingHeader)?;
+    let height = header.height.ok_or(CertificateError::MissingHeader)?;
+    let validator_set = block
+        .validators
+        .clone()
+        .ok_or(CertificateError::InvalidValidatorSet)
+        .and_then(|validators| {
+            SnapchainValidatorSet::try_from(validators)
+                .map_err(|_| CertificateError::InvalidValidatorSet)
+        })?;
+    AggregatedCertificate::from_confirmed_votes(votes).verify(
+        &header.validators_hash,
+        &validator_set,
+        |address| block_precommit_sign_bytes(height, votes.round, &block.hash, address),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{ShardId, SnapchainShard, SnapchainValidator};
+    use libp2p::identity::ed25519::Keypair;
+
+    const MESSAGE: &[u8] = b"block hash";
+
+    fn validators(n: usize) -> (Vec<Keypair>, SnapchainValidatorSet) {
+        let keypairs: Vec<Keypair> = (0..n).map(|_| Keypair::generate()).collect();
+        let validator_set = SnapchainValidatorSet::new(
+            keypairs
+                .iter()
+                .map(|k| SnapchainValidator::new(SnapchainShard::new(0), k.public(), None, 0))
+                .collect(),
+        );
+        (keypairs, validator_set)
+    }
+
+    fn sign_all(keypairs: &[Keypair]) -> Vec<(Address, Vec<u8>)> {
+        keypairs
+            .iter()
+            .map(|k| (Address(k.public().to_bytes()), k.sign(MESSAGE)))
+            .collect()
+    }
+
+    #[test]
+    fn test_aggregate_and_verify() {
+        let (keypairs, validator_set) = validators(3);
+        let hash = validators_hash(&validator_set);
+
+        let certificate =
+            AggregatedCertificate::aggregate(&validator_set, &sign_all(&keypairs)).unwrap();
+
+        assert_eq!(certificate.signer_indexes(), vec![0, 1, 2]);
+        assert_eq!(
+            certificate.verify(&hash, &validator_set, |_| MESSAGE.to_vec()),
+            Ok(())
+        );
+
+        let roundtrip =
+            AggregatedCertificate::from_confirmed_votes(&certificate.to_confirmed_votes());
+        assert_eq!(roundtrip, certificate);
+    }
+
+    #[test]
+    fn test_certify_and_verify_block() {
+        let (keypairs, validator_set) = validators(3);
+        let height = Height::new(0, 5);
+        let mut block = proto::Block {
+            header: Some(proto::BlockHeader {
+                height: Some(height),
+                validators_hash: validators_hash(&validator_set),
+                ..Default::default()
+            }),
+            hash: vec![5; 32],
+            ..Default::default()
+        };
+        let commits: Vec<(Address, Vec<u8>)> = keypairs
+            .iter()
+            .map(|k| {
+                let address = Address(k.public().to_bytes());
+                let sign_bytes = block_precommit_sign_bytes(height, 2, &block.hash, &address);
+                (address, k.sign(&sign_bytes))
+            })
+            .collect();
+
+        certify_block(&mut block, 2, &validator_set, &commits).unwrap();
+        assert_eq!(block.votes.as_ref().unwrap().round, 2);
+        assert!(block.verify_full_hash());
+        assert_eq!(verify_block_certificate(&block), Ok(()));
+
+        // The signatures were for round 2
+        let mut wrong_round = block.clone();
+        wrong_round.votes.as_mut().unwrap().round = 3;
+        assert!(matches!(
+            verify_block_certificate(&wrong_round),
+            Err(CertificateError::InvalidSignature(_))
+        ));
+
+        // The validator set has to match the header
+        let (_, other_set) = validators(3);
+        let mut wrong_set = block.clone();
+        wrong_set.validators = Some((&other_set).into());
+        assert_eq!(
+            verify_block_certificate(&wrong_set),
+            Err(CertificateError::ValidatorsHashMismatch)
+        );
+        assert_eq!(
+            certify_block(&mut block.clone(), 2, &other_set, &commits),
+            Err(CertificateError::ValidatorsHashMismatch)
+        );
+
+        // Blocks without a certificate pass
+        let mut uncertified = block.clone();
+        uncertified.votes = None;
+        assert_eq!(verify_block_certificate(&uncertified), Ok(()));
+    }
+
+    #[test]
+    fn test_tampered_bitmap_fails_verification() {
+        let (keypairs, validator_set) = validators(4);
+        let hash = validators_hash(&validator_set);
+
+        let mut certificate =
+            AggregatedCertificate::aggregate(&validator_set, &sign_all(&keypairs[0..3])).unwrap();
+        // Swap one of the signers for the validator that didn't sign
+        certificate.signer_bitmap[0] ^= 0b1001;
+
+        assert!(certificate
+            .verify(&hash, &validator_set, |_| MESSAGE.to_vec())
+            .is_err());
+    }
+}
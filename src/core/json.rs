@@ -0,0 +1,356 @@
+use crate::consensus::proposer::FARCASTER_EPOCH;
+use crate::proto::snapchain::{
+    Block, BlockHeader, ConfirmedVotes, Height, ShardChunk, ShardHeader, Transaction, Validator,
+};
+use serde::Serialize;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Canonical JSON layout for blocks and chunks. Fields are serialized in declaration order, so
+// the order below is part of the format.
+
+// Unix seconds for 9999-12-31T23:59:59Z, the last time RFC 3339 can represent
+const MAX_RFC3339_UNIX_SECS: u64 = 253_402_300_799;
+
+#[derive(Serialize)]
+struct TimestampJson {
+    // Seconds since the Farcaster epoch, as stored in the header
+    raw: u64,
+    // None when the timestamp is past what RFC 3339 can represent
+    utc: Option<String>,
+}
+
+impl TimestampJson {
+    fn new(raw: u64) -> Self {
+        let utc = raw
+            .checked_add(FARCASTER_EPOCH)
+            .filter(|secs| *secs <= MAX_RFC3339_UNIX_SECS)
+            .map(|secs| {
+                humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(secs))
+                    .to_string()
+            });
+        Self { raw, utc }
+    }
+}
+
+#[derive(Serialize)]
+struct HeightJson {
+    shard_index: u32,
+    block_number: u64,
+}
+
+impl From<&Height> for HeightJson {
+    fn from(height: &Height) -> Self {
+        Self {
+            shard_index: height.shard_index,
+            block_number: height.block_number,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct VotesJson {
+    votes: usize,
+    signatures: usize,
+    signer_bitmap: String,
+    aggregated_signature: String,
+    round: i64,
+}
+
+impl From<&ConfirmedVotes> for VotesJson {
+    fn from(votes: &ConfirmedVotes) -> Self {
+        Self {
+            votes: votes.votes.len(),
+            signatures: votes.signatures.len(),
+            signer_bitmap: hex::encode(&votes.signer_bitmap),
+            aggregated_signature: hex::encode(&votes.aggregated_signature),
+            round: votes.round,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TransactionJson {
+    fid: u64,
+    account_root: String,
+    // Messages are identified by their hashes, the full bodies are available from the hub
+    user_messages: Vec<String>,
+    system_messages: usize,
+}
+
+impl From<&Transaction> for TransactionJson {
+    fn from(transaction: &Transaction) -> Self {
+        Self {
+            fid: transaction.fid,
+            account_root: hex::encode(&transaction.account_root),
+            user_messages: transaction
+                .user_messages
+                .iter()
+                .map(|message| hex::encode(&message.hash))
+                .collect(),
+            system_messages: transaction.system_messages.len(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ShardHeaderJson {
+    height: Option<HeightJson>,
+    timestamp: TimestampJson,
+    parent_hash: String,
+    shard_root: String,
+    version: u32,
+    transactions_root: String,
+}
+
+impl From<&ShardHeader> for ShardHeaderJson {
+    fn from(header: &ShardHeader) -> Self {
+        Self {
+            height: header.height.as_ref().map(HeightJson::from),
+            timestamp: TimestampJson::new(header.timestamp),
+            parent_hash: hex::encode(&header.parent_hash),
+            shard_root: hex::encode(&header.shard_root),
+            version: header.version,
+            transactions_root: hex::encode(&header.transactions_root),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ShardChunkJson {
+    hash: String,
+    header: Option<ShardHeaderJson>,
+    transactions: Vec<TransactionJson>,
+    votes: Option<VotesJson>,
+}
+
+impl From<&ShardChunk> for ShardChunkJson {
+    fn from(chunk: &ShardChunk) -> Self {
+        Self {
+            hash: hex::encode(&chunk.hash),
+            header: chunk.header.as_ref().map(ShardHeaderJson::from),
+            transactions: chunk
+                .transactions
+                .iter()
+                .map(TransactionJson::from)
+                .collect(),
+            votes: chunk.votes.as_ref().map(VotesJson::from),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BlockHeaderJson {
+    height: Option<HeightJson>,
+    timestamp: TimestampJson,
+    version: u32,
+    chain_id: u32,
+    parent_hash: String,
+    shard_headers_hash: String,
+    validators_hash: String,
+}
+
+impl From<&BlockHeader> for BlockHeaderJson {
+    fn from(header: &BlockHeader) -> Self {
+        Self {
+            height: header.height.as_ref().map(HeightJson::from),
+            timestamp: TimestampJson::new(header.timestamp),
+            version: header.version,
+            chain_id: header.chain_id,
+            parent_hash: hex::encode(&header.parent_hash),
+            shard_headers_hash: hex::encode(&header.shard_headers_hash),
+            validators_hash: hex::encode(&header.validators_hash),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ValidatorJson {
+    fid: u64,
+    signer: String,
+    rpc_address: String,
+    shard_index: u32,
+    current_height: u64,
+}
+
+impl From<&Validator> for ValidatorJson {
+    fn from(validator: &Validator) -> Self {
+        Self {
+            fid: validator.fid,
+            signer: hex::encode(&validator.signer),
+            rpc_address: validator.rpc_address.clone(),
+            shard_index: validator.shard_index,
+            current_height: validator.current_height,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BlockJson {
+    hash: String,
+    full_hash: String,
+    header: Option<BlockHeaderJson>,
+    shard_chunks: Vec<ShardChunkJson>,
+    validators: Option<Vec<ValidatorJson>>,
+    votes: Option<VotesJson>,
+}
+
+impl From<&Block> for BlockJson {
+    fn from(block: &Block) -> Self {
+        Self {
+            hash: hex::encode(&block.hash),
+            full_hash: hex::encode(&block.full_hash),
+            header: block.header.as_ref().map(BlockHeaderJson::from),
+            shard_chunks: block
+                .shard_chunks
+                .iter()
+                .map(ShardChunkJson::from)
+                .collect(),
+            validators: block
+                .validators
+                .as_ref()
+                .map(|set| set.validators.iter().map(ValidatorJson::from).collect()),
+            votes: block.votes.as_ref().map(VotesJson::from),
+        }
+    }
+}
+
+fn to_pretty_json<T: Serialize>(value: &T) -> String {
+    // Serializing plain structs of strings and integers can't fail
+    serde_json::to_string_pretty(value).expect("canonical json is always serializable")
+}
+
+impl Block {
+    // Stable, human readable encoding with hex hashes and both raw and UTC timestamps
+    pub fn to_json(&self) -> String {
+        to_pretty_json(&BlockJson::from(self))
+    }
+}
+
+impl ShardChunk {
+    // Stable, human readable encoding with hex hashes and both raw and UTC timestamps
+    pub fn to_json(&self) -> String {
+        to_pretty_json(&ShardChunkJson::from(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::message;
+
+    fn fixture_chunk() -> ShardChunk {
+        ShardChunk {
+            header: Some(ShardHeader {
+                height: Some(Height::new(1, 5)),
+                timestamp: 90061,
+                parent_hash: vec![0xaa],
+                shard_root: vec![0xbb],
+                version: 32,
+                transactions_root: vec![0xcc],
+            }),
+            hash: vec![0x01, 0x02],
+            transactions: vec![Transaction {
+                fid: 7,
+                user_messages: vec![message::Message {
+                    hash: vec![0xde, 0xad],
+                    ..Default::default()
+                }],
+                system_messages: vec![],
+                account_root: vec![0x05],
+            }],
+            votes: None,
+        }
+    }
+
+    fn fixture_block(shard_chunks: Vec<ShardChunk>) -> Block {
+        Block {
+            header: Some(BlockHeader {
+                height: Some(Height::new(0, 3)),
+                timestamp: 0,
+                version: 32,
+                chain_id: 0,
+                validators_hash: vec![],
+                shard_headers_hash: vec![],
+                parent_hash: vec![0x11],
+            }),
+            hash: vec![0x22],
+            shard_chunks,
+            validators: None,
+            votes: None,
+            full_hash: vec![],
+        }
+    }
+
+    #[test]
+    fn test_shard_chunk_to_json() {
+        let expected = r#"{
+  "hash": "0102",
+  "header": {
+    "height": {
+      "shard_index": 1,
+      "block_number": 5
+    },
+    "timestamp": {
+      "raw": 90061,
+      "utc": "2021-01-02T01:01:01Z"
+    },
+    "parent_hash": "aa",
+    "shard_root": "bb",
+    "version": 32,
+    "transactions_root": "cc"
+  },
+  "transactions": [
+    {
+      "fid": 7,
+      "account_root": "05",
+      "user_messages": [
+        "dead"
+      ],
+      "system_messages": 0
+    }
+  ],
+  "votes": null
+}"#;
+        assert_eq!(fixture_chunk().to_json(), expected);
+    }
+
+    #[test]
+    fn test_block_to_json() {
+        let expected = r#"{
+  "hash": "22",
+  "full_hash": "",
+  "header": {
+    "height": {
+      "shard_index": 0,
+      "block_number": 3
+    },
+    "timestamp": {
+      "raw": 0,
+      "utc": "2021-01-01T00:00:00Z"
+    },
+    "version": 32,
+    "chain_id": 0,
+    "parent_hash": "11",
+    "shard_headers_hash": "",
+    "validators_hash": ""
+  },
+  "shard_chunks": [],
+  "validators": null,
+  "votes": null
+}"#;
+        assert_eq!(fixture_block(vec![]).to_json(), expected);
+
+        // Chunks inside a block use the same layout as on their own
+        let block: serde_json::Value =
+            serde_json::from_str(&fixture_block(vec![fixture_chunk()]).to_json()).unwrap();
+        let chunk: serde_json::Value = serde_json::from_str(&fixture_chunk().to_json()).unwrap();
+        assert_eq!(block["shard_chunks"][0], chunk);
+    }
+
+    #[test]
+    fn test_timestamp_out_of_rfc3339_range() {
+        let timestamp = TimestampJson::new(u64::MAX);
+        assert_eq!(timestamp.raw, u64::MAX);
+        assert!(timestamp.utc.is_none());
+    }
+}
@@ -0,0 +1,134 @@
+use crate::proto::snapchain::Transaction;
+use prost::Message;
+
+// Domain separation between leaves and inner nodes, so an inner node can't be passed off as a leaf
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MerkleSibling {
+    Left(Vec<u8>),
+    Right(Vec<u8>),
+}
+
+/// Sibling hashes from a leaf up to the root. Levels where the node had no sibling (the last node of
+/// a level with an odd count is carried up unchanged) have no entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub siblings: Vec<MerkleSibling>,
+}
+
+fn hash_leaf(data: &[u8]) -> Vec<u8> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(data);
+    hasher.finalize().as_bytes().to_vec()
+}
+
+fn hash_node(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().as_bytes().to_vec()
+}
+
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => hash_node(left, right),
+            [last] => last.clone(),
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn transaction_leaves(transactions: &[Transaction]) -> Vec<Vec<u8>> {
+    transactions
+        .iter()
+        .map(|tx| hash_leaf(&tx.encode_to_vec()))
+        .collect()
+}
+
+/// Merkle root over the encoded transactions of a chunk. A chunk without transactions has the
+/// hash of an empty input as its root.
+pub fn transactions_root(transactions: &[Transaction]) -> Vec<u8> {
+    let mut level = transaction_leaves(transactions);
+    if level.is_empty() {
+        return blake3::hash(&[]).as_bytes().to_vec();
+    }
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+/// Proof for the transaction at `index`, None if there's no transaction there
+pub fn prove_transaction(transactions: &[Transaction], index: usize) -> Option<MerkleProof> {
+    if index >= transactions.len() {
+        return None;
+    }
+
+    let mut siblings = vec![];
+    let mut level = transaction_leaves(transactions);
+    let mut index = index;
+    while level.len() > 1 {
+        if index % 2 == 1 {
+            siblings.push(MerkleSibling::Left(level[index - 1].clone()));
+        } else if index + 1 < level.len() {
+            siblings.push(MerkleSibling::Right(level[index + 1].clone()));
+        }
+        level = next_level(&level);
+        index /= 2;
+    }
+    Some(MerkleProof { siblings })
+}
+
+pub fn verify_transaction_proof(root: &[u8], proof: &MerkleProof, tx: &Transaction) -> bool {
+    let computed =
+        proof.siblings.iter().fold(
+            hash_leaf(&tx.encode_to_vec()),
+            |hash, sibling| match sibling {
+                MerkleSibling::Left(left) => hash_node(left, &hash),
+                MerkleSibling::Right(right) => hash_node(&hash, right),
+            },
+        );
+    computed == root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transactions(count: u64) -> Vec<Transaction> {
+        (1..=count)
+            .map(|fid| Transaction {
+                fid,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_every_transaction_proves_against_root() {
+        for count in 1..=9 {
+            let transactions = transactions(count);
+            let root = transactions_root(&transactions);
+            for (index, tx) in transactions.iter().enumerate() {
+                let proof = prove_transaction(&transactions, index).unwrap();
+                assert!(verify_transaction_proof(&root, &proof, tx));
+            }
+            assert_eq!(prove_transaction(&transactions, count as usize), None);
+        }
+    }
+
+    #[test]
+    fn test_proof_does_not_verify_other_transaction() {
+        let transactions = transactions(5);
+        let root = transactions_root(&transactions);
+        let proof = prove_transaction(&transactions, 2).unwrap();
+
+        assert!(!verify_transaction_proof(&root, &proof, &transactions[3]));
+    }
+}
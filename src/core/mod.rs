@@ -1 +1,5 @@
+pub mod buffer_pool;
+pub mod certificate;
+pub mod json;
+pub mod merkle;
 pub mod types;
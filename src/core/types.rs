@@ -1,5 +1,6 @@
 use core::fmt;
 use libp2p::identity::ed25519::Keypair;
+use libp2p::identity::DecodingError;
 use malachite_common;
 use malachite_common::{
     Extension, NilOrVal, Round, SignedProposal, SignedProposalPart, SignedVote, Validator,
@@ -9,10 +10,12 @@ use prost::Message;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Display};
 use std::sync::Arc;
+use thiserror::Error;
 use tracing::warn;
 
 pub use crate::proto::snapchain as proto; // TODO: reconsider how this is imported
 
+use crate::proto::message;
 use crate::proto::snapchain::full_proposal::ProposedValue;
 use crate::proto::snapchain::{Block, FullProposal, ShardChunk};
 pub use proto::Height;
@@ -44,6 +47,9 @@ pub trait ShardedContext {
 
 pub trait SnapchainContext: malachite_common::Context + ShardedContext {}
 
+// Hex characters of the address shown in logs by prefix()
+pub const DEFAULT_ADDRESS_PREFIX_LEN: usize = 4;
+
 // TODO: Should validator keys be ECDSA?
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Address(pub [u8; 32]);
@@ -64,7 +70,13 @@ impl Address {
     }
 
     pub fn prefix(&self) -> String {
-        format!("0x{}", &self.to_hex()[0..4])
+        self.prefix_len(DEFAULT_ADDRESS_PREFIX_LEN)
+    }
+
+    // The first n hex characters, wider prefixes tell nodes apart when short ones collide
+    pub fn prefix_len(&self, n: usize) -> String {
+        let hex = self.to_hex();
+        format!("0x{}", &hex[..n.min(hex.len())])
     }
 }
 
@@ -147,6 +159,14 @@ impl Height {
         }
     }
 
+    // The next height on the same shard, None instead of wrapping past u64::MAX
+    pub fn checked_increment(&self) -> Option<Self> {
+        self.block_number.checked_add(1).map(|block_number| Self {
+            shard_index: self.shard_index,
+            block_number,
+        })
+    }
+
     pub fn decrement(&self) -> Option<Self> {
         self.block_number.checked_sub(1).map(|block_number| Self {
             shard_index: self.shard_index,
@@ -183,6 +203,23 @@ impl fmt::Display for ShardHash {
     }
 }
 
+// Proposals are kept in maps keyed by ShardHash, so this order is the order they're iterated and
+// exported in: by shard index, then by hash bytes lexicographically. Spelled out rather than derived
+// so it doesn't change if the generated fields are reordered.
+impl Ord for ShardHash {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.shard_index
+            .cmp(&other.shard_index)
+            .then_with(|| self.hash.cmp(&other.hash))
+    }
+}
+
+impl PartialOrd for ShardHash {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 // impl ShardHash {
 //     pub fn new(shard_id: u8, hash: Hash) -> Self {
 //         Self { shard_id, hash }
@@ -239,6 +276,182 @@ impl FullProposal {
     pub fn round(&self) -> Round {
         Round::new(self.round)
     }
+
+    // The proposer signs the height (which includes the shard), round and ShardHash of the proposed
+    // value, so a signed proposal can't be replayed at another height or round
+    pub fn to_sign_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.height().encode_length_delimited_to_vec();
+        bytes.extend_from_slice(&self.round.to_be_bytes());
+        bytes.extend(self.shard_hash().encode_to_vec());
+        bytes
+    }
+
+    pub fn verify_signature(&self, public_key: &PublicKey) -> bool {
+        public_key.verify(&self.to_sign_bytes(), &self.signature)
+    }
+
+    // Addresses are the proposer's public key, so the signature can be checked without a lookup
+    pub fn verify_proposer_signature(&self) -> bool {
+        PublicKey::try_from_bytes(&self.proposer)
+            .map_or(false, |public_key| self.verify_signature(&public_key))
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        match &self.proposed_value {
+            Some(ProposedValue::Block(block)) => block
+                .shard_chunks
+                .iter()
+                .map(|chunk| chunk.transaction_count())
+                .sum(),
+            Some(ProposedValue::Shard(chunk)) => chunk.transaction_count(),
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum FullProposalBuildError {
+    #[error("Full proposal is missing {0}")]
+    MissingField(&'static str),
+
+    #[error("Proposed value is for height {value}, proposal is for height {proposal}")]
+    HeightMismatch { proposal: Height, value: Height },
+}
+
+// Builds a FullProposal and the ShardHash it's tracked under, refusing to produce one with a
+// missing field
+#[derive(Default)]
+pub struct FullProposalBuilder {
+    height: Option<Height>,
+    round: Option<Round>,
+    proposer: Option<Address>,
+    proposed_value: Option<ProposedValue>,
+}
+
+impl FullProposalBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_height(self, height: Height) -> Self {
+        Self {
+            height: Some(height),
+            ..self
+        }
+    }
+
+    pub fn with_round(self, round: Round) -> Self {
+        Self {
+            round: Some(round),
+            ..self
+        }
+    }
+
+    pub fn with_proposer(self, proposer: Address) -> Self {
+        Self {
+            proposer: Some(proposer),
+            ..self
+        }
+    }
+
+    pub fn with_block(self, block: Block) -> Self {
+        Self {
+            proposed_value: Some(ProposedValue::Block(block)),
+            ..self
+        }
+    }
+
+    pub fn with_shard_chunk(self, chunk: ShardChunk) -> Self {
+        Self {
+            proposed_value: Some(ProposedValue::Shard(chunk)),
+            ..self
+        }
+    }
+
+    pub fn build(self) -> Result<(FullProposal, ShardHash), FullProposalBuildError> {
+        let height = self
+            .height
+            .ok_or(FullProposalBuildError::MissingField("height"))?;
+        let round = self
+            .round
+            .ok_or(FullProposalBuildError::MissingField("round"))?;
+        let proposer = self
+            .proposer
+            .ok_or(FullProposalBuildError::MissingField("proposer"))?;
+        let proposed_value = self
+            .proposed_value
+            .ok_or(FullProposalBuildError::MissingField("proposed value"))?;
+
+        let value_height = match &proposed_value {
+            ProposedValue::Block(block) => block.header.as_ref().and_then(|header| header.height),
+            ProposedValue::Shard(chunk) => chunk.header.as_ref().and_then(|header| header.height),
+        };
+        match value_height {
+            None => return Err(FullProposalBuildError::MissingField("value height")),
+            Some(value) if value != height => {
+                return Err(FullProposalBuildError::HeightMismatch {
+                    proposal: height,
+                    value,
+                })
+            }
+            Some(_) => {}
+        }
+
+        let proposal = FullProposal {
+            height: Some(height),
+            round: round.as_i64(),
+            proposed_value: Some(proposed_value),
+            proposer: proposer.to_vec(),
+            signature: vec![],
+        };
+        let shard_hash = proposal.shard_hash();
+        Ok((proposal, shard_hash))
+    }
+}
+
+impl Block {
+    // Unlike the header hash this commits to the shard chunks, validators and votes directly
+    pub fn full_hash(&self) -> Vec<u8> {
+        let block = Block {
+            full_hash: vec![],
+            ..self.clone()
+        };
+        blake3::hash(&block.encode_to_vec()).as_bytes().to_vec()
+    }
+
+    // Blocks without a stored full hash (e.g. from before it was introduced) pass
+    pub fn verify_full_hash(&self) -> bool {
+        self.full_hash.is_empty() || self.full_hash == self.full_hash()
+    }
+}
+
+impl ShardChunk {
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    // Size of the protobuf encoding, computed without encoding the chunk
+    pub fn encoded_size(&self) -> usize {
+        self.encoded_len()
+    }
+
+    pub fn fids(&self) -> Vec<u64> {
+        self.transactions.iter().map(|tx| tx.fid).collect()
+    }
+
+    pub fn iter_messages(&self) -> impl Iterator<Item = &message::Message> {
+        self.transactions
+            .iter()
+            .flat_map(|tx| tx.user_messages.iter())
+    }
+
+    // Hash of the first message that appears more than once in the chunk
+    pub fn find_duplicate_message(&self) -> Option<&[u8]> {
+        let mut seen = std::collections::HashSet::new();
+        self.iter_messages()
+            .map(|msg| msg.hash.as_slice())
+            .find(|hash| !seen.insert(*hash))
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
@@ -267,6 +480,34 @@ impl SnapchainValidator {
     }
 }
 
+impl From<&SnapchainValidator> for proto::Validator {
+    fn from(validator: &SnapchainValidator) -> Self {
+        proto::Validator {
+            fid: 0,
+            signer: validator.public_key.to_bytes().to_vec(),
+            rpc_address: validator.rpc_address.clone().unwrap_or_default(),
+            shard_index: validator.shard_index,
+            current_height: validator.current_height,
+        }
+    }
+}
+
+impl TryFrom<proto::Validator> for SnapchainValidator {
+    type Error = DecodingError;
+
+    fn try_from(validator: proto::Validator) -> Result<Self, Self::Error> {
+        let public_key = PublicKey::try_from_bytes(&validator.signer)?;
+        // An empty rpc address means the validator hasn't registered yet
+        let rpc_address = Some(validator.rpc_address).filter(|address| !address.is_empty());
+        Ok(SnapchainValidator::new(
+            SnapchainShard::new(validator.shard_index),
+            public_key,
+            rpc_address,
+            validator.current_height,
+        ))
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SnapchainValidatorSet {
     pub validators: Vec<SnapchainValidator>,
@@ -282,7 +523,18 @@ impl SnapchainValidatorSet {
     }
 
     pub fn add(&mut self, validator: SnapchainValidator) -> bool {
-        if self.exists(&validator.address) {
+        if let Some(existing) = self
+            .validators
+            .iter_mut()
+            .find(|v| v.address == validator.address)
+        {
+            // Peers re-register as they advance, keep their latest height
+            existing.current_height = existing.current_height.max(validator.current_height);
+            // Configured validators are known before they register, fill in their rpc address once they do
+            if existing.rpc_address.is_none() && validator.rpc_address.is_some() {
+                existing.rpc_address = validator.rpc_address;
+                return true;
+            }
             return false;
         }
 
@@ -310,6 +562,27 @@ impl SnapchainValidatorSet {
     }
 }
 
+impl From<&SnapchainValidatorSet> for proto::ValidatorSet {
+    fn from(validator_set: &SnapchainValidatorSet) -> Self {
+        proto::ValidatorSet {
+            validators: validator_set.validators.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl TryFrom<proto::ValidatorSet> for SnapchainValidatorSet {
+    type Error = DecodingError;
+
+    fn try_from(validator_set: proto::ValidatorSet) -> Result<Self, Self::Error> {
+        let validators = validator_set
+            .validators
+            .into_iter()
+            .map(SnapchainValidator::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SnapchainValidatorSet::new(validators))
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Vote {
     pub vote_type: VoteType,
@@ -460,21 +733,75 @@ pub enum ProposalPart {
     FullProposal(SinglePartProposal),
 }
 
+/// Chooses the proposer for a given height and round. Must be deterministic across all validators.
+pub trait ProposerSelector: Debug + Send + Sync {
+    fn select_proposer<'a>(
+        &self,
+        validator_set: &'a SnapchainValidatorSet,
+        height: Height,
+        round: Round,
+    ) -> &'a SnapchainValidator;
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RoundRobinProposerSelector;
+
+impl ProposerSelector for RoundRobinProposerSelector {
+    fn select_proposer<'a>(
+        &self,
+        validator_set: &'a SnapchainValidatorSet,
+        height: Height,
+        round: Round,
+    ) -> &'a SnapchainValidator {
+        assert!(validator_set.validators.len() > 0);
+        assert!(round != Round::Nil && round.as_i64() >= 0);
+
+        let proposer_index = {
+            let height = height.as_u64() as usize;
+            let round = round.as_i64() as usize;
+
+            (height - 1 + round) % validator_set.validators.len()
+        };
+
+        validator_set
+            .validators
+            .get(proposer_index)
+            .expect("proposer_index is valid")
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SnapchainValidatorContext {
     keypair: Arc<Keypair>,
+    proposer_selector: Arc<dyn ProposerSelector>,
 }
 
 impl SnapchainValidatorContext {
     pub fn new(keypair: Keypair) -> Self {
+        Self::new_with_proposer_selector(keypair, Arc::new(RoundRobinProposerSelector))
+    }
+
+    pub fn new_with_proposer_selector(
+        keypair: Keypair,
+        proposer_selector: Arc<dyn ProposerSelector>,
+    ) -> Self {
         Self {
             keypair: Arc::new(keypair),
+            proposer_selector,
         }
     }
 
     pub fn public_key(&self) -> PublicKey {
         self.keypair.public()
     }
+
+    pub fn sign_full_proposal(&self, full_proposal: FullProposal) -> FullProposal {
+        let signature = self.keypair.sign(&full_proposal.to_sign_bytes());
+        FullProposal {
+            signature,
+            ..full_proposal
+        }
+    }
 }
 
 impl ShardedContext for SnapchainValidatorContext {
@@ -498,20 +825,8 @@ impl malachite_common::Context for SnapchainValidatorContext {
         height: Self::Height,
         round: Round,
     ) -> &'a Self::Validator {
-        assert!(validator_set.validators.len() > 0);
-        assert!(round != Round::Nil && round.as_i64() >= 0);
-
-        let proposer_index = {
-            let height = height.as_u64() as usize;
-            let round = round.as_i64() as usize;
-
-            (height - 1 + round) % validator_set.validators.len()
-        };
-
-        validator_set
-            .validators
-            .get(proposer_index)
-            .expect("proposer_index is valid")
+        self.proposer_selector
+            .select_proposer(validator_set, height, round)
     }
 
     fn sign_vote(&self, vote: Self::Vote) -> SignedVote<Self> {
@@ -709,3 +1024,347 @@ impl malachite_common::Validator<SnapchainValidatorContext> for SnapchainValidat
         1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shard_hash_ordering() {
+        let shard_hash = |shard_index, hash: Vec<u8>| ShardHash { shard_index, hash };
+        let mut hashes = vec![
+            shard_hash(2, vec![0]),
+            shard_hash(1, vec![2]),
+            shard_hash(1, vec![1, 5]),
+            shard_hash(0, vec![9]),
+            shard_hash(1, vec![1]),
+            shard_hash(1, vec![]),
+        ];
+        hashes.sort();
+        assert_eq!(
+            hashes,
+            vec![
+                shard_hash(0, vec![9]),
+                shard_hash(1, vec![]),
+                shard_hash(1, vec![1]),
+                shard_hash(1, vec![1, 5]),
+                shard_hash(1, vec![2]),
+                shard_hash(2, vec![0]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_address_prefix_len() {
+        let address = Address([0xab; 32]);
+        assert_eq!(address.prefix(), "0xabab");
+        assert_eq!(address.prefix_len(6), "0xababab");
+        assert_eq!(address.prefix_len(12).len(), 2 + 12);
+        // Capped at the full address
+        assert_eq!(address.prefix_len(100), format!("0x{}", address.to_hex()));
+    }
+
+    fn message_with_hash(hash: Vec<u8>) -> message::Message {
+        message::Message {
+            hash,
+            ..Default::default()
+        }
+    }
+
+    fn transaction(fid: u64, hashes: Vec<Vec<u8>>) -> proto::Transaction {
+        proto::Transaction {
+            fid,
+            user_messages: hashes.into_iter().map(message_with_hash).collect(),
+            system_messages: vec![],
+            account_root: vec![],
+        }
+    }
+
+    #[test]
+    fn test_height_checked_increment() {
+        assert_eq!(
+            Height::new(2, 7).checked_increment(),
+            Some(Height::new(2, 8))
+        );
+        assert_eq!(
+            Height::new(2, u64::MAX - 1).checked_increment(),
+            Some(Height::new(2, u64::MAX))
+        );
+        assert_eq!(Height::new(2, u64::MAX).checked_increment(), None);
+    }
+
+    #[test]
+    fn test_shard_chunk_accessors() {
+        let chunk = ShardChunk {
+            header: None,
+            hash: vec![],
+            transactions: vec![
+                transaction(1, vec![vec![1], vec![2]]),
+                transaction(2, vec![]),
+                transaction(3, vec![vec![3]]),
+            ],
+            votes: None,
+        };
+
+        assert_eq!(chunk.transaction_count(), 3);
+        assert_eq!(chunk.fids(), vec![1, 2, 3]);
+        let hashes: Vec<Vec<u8>> = chunk.iter_messages().map(|m| m.hash.clone()).collect();
+        assert_eq!(hashes, vec![vec![1], vec![2], vec![3]]);
+    }
+
+    #[test]
+    fn test_round_robin_proposer_selector_cycles() {
+        let validator_set = SnapchainValidatorSet::new(
+            (0..3)
+                .map(|_| {
+                    SnapchainValidator::new(
+                        SnapchainShard::new(1),
+                        Keypair::generate().public(),
+                        None,
+                        0,
+                    )
+                })
+                .collect(),
+        );
+        let selector = RoundRobinProposerSelector;
+
+        let proposers: Vec<Address> = (0..6)
+            .map(|round| {
+                selector
+                    .select_proposer(&validator_set, Height::new(1, 1), Round::new(round))
+                    .address
+                    .clone()
+            })
+            .collect();
+        let expected: Vec<Address> = validator_set
+            .validators
+            .iter()
+            .cycle()
+            .take(6)
+            .map(|v| v.address.clone())
+            .collect();
+        assert_eq!(proposers, expected);
+
+        // The next height starts one validator further along
+        let next = selector.select_proposer(&validator_set, Height::new(1, 2), Round::new(0));
+        assert_eq!(next.address, validator_set.validators[1].address);
+    }
+
+    #[test]
+    fn test_shard_chunk_accessors_empty() {
+        let chunk = ShardChunk::default();
+
+        assert_eq!(chunk.transaction_count(), 0);
+        assert!(chunk.fids().is_empty());
+        assert_eq!(chunk.iter_messages().count(), 0);
+    }
+
+    fn test_shard_chunk(height: Height) -> ShardChunk {
+        ShardChunk {
+            header: Some(proto::ShardHeader {
+                height: Some(height),
+                parent_hash: vec![1, 2, 3],
+                ..Default::default()
+            }),
+            hash: vec![4, 5, 6],
+            transactions: vec![transaction(1, vec![vec![7]])],
+            votes: None,
+        }
+    }
+
+    #[test]
+    fn test_shard_chunk_encoded_size() {
+        let mut large = test_shard_chunk(Height::new(2, u64::MAX));
+        large.transactions = (0..50)
+            .map(|fid| transaction(fid, vec![vec![fid as u8; 32]; 3]))
+            .collect();
+        for chunk in [
+            ShardChunk::default(),
+            test_shard_chunk(Height::new(1, 1)),
+            large,
+        ] {
+            assert_eq!(chunk.encoded_size(), chunk.encode_to_vec().len());
+        }
+    }
+
+    #[test]
+    fn test_full_proposal_builder_matches_manual_construction() {
+        let height = Height::new(1, 5);
+        let round = Round::new(2);
+        let address = Address(Keypair::generate().public().to_bytes());
+        let chunk = test_shard_chunk(height);
+
+        let manual = FullProposal {
+            height: Some(height),
+            round: round.as_i64(),
+            proposed_value: Some(ProposedValue::Shard(chunk.clone())),
+            proposer: address.to_vec(),
+            signature: vec![],
+        };
+        let (built, shard_hash) = FullProposalBuilder::new()
+            .with_height(height)
+            .with_round(round)
+            .with_proposer(address.clone())
+            .with_shard_chunk(chunk)
+            .build()
+            .unwrap();
+
+        assert_eq!(built.encode_to_vec(), manual.encode_to_vec());
+        assert_eq!(
+            shard_hash,
+            ShardHash {
+                shard_index: 1,
+                hash: vec![4, 5, 6],
+            }
+        );
+
+        let block = Block {
+            header: Some(proto::BlockHeader {
+                height: Some(Height::new(0, 5)),
+                ..Default::default()
+            }),
+            hash: vec![8, 9],
+            ..Default::default()
+        };
+        let manual = FullProposal {
+            height: Some(Height::new(0, 5)),
+            round: round.as_i64(),
+            proposed_value: Some(ProposedValue::Block(block.clone())),
+            proposer: address.to_vec(),
+            signature: vec![],
+        };
+        let (built, shard_hash) = FullProposalBuilder::new()
+            .with_height(Height::new(0, 5))
+            .with_round(round)
+            .with_proposer(address)
+            .with_block(block)
+            .build()
+            .unwrap();
+
+        assert_eq!(built.encode_to_vec(), manual.encode_to_vec());
+        assert_eq!(shard_hash, manual.shard_hash());
+    }
+
+    #[test]
+    fn test_full_proposal_builder_rejects_incomplete_proposals() {
+        let height = Height::new(1, 5);
+        let address = Address(Keypair::generate().public().to_bytes());
+
+        let result = FullProposalBuilder::new()
+            .with_height(height)
+            .with_proposer(address.clone())
+            .with_shard_chunk(test_shard_chunk(height))
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            FullProposalBuildError::MissingField("round")
+        );
+
+        let result = FullProposalBuilder::new()
+            .with_height(height)
+            .with_round(Round::new(0))
+            .with_proposer(address.clone())
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            FullProposalBuildError::MissingField("proposed value")
+        );
+
+        let result = FullProposalBuilder::new()
+            .with_height(height)
+            .with_round(Round::new(0))
+            .with_proposer(address)
+            .with_shard_chunk(test_shard_chunk(Height::new(1, 6)))
+            .build();
+        assert_eq!(
+            result.unwrap_err(),
+            FullProposalBuildError::HeightMismatch {
+                proposal: height,
+                value: Height::new(1, 6),
+            }
+        );
+    }
+
+    #[test]
+    fn test_full_proposal_signature_round_trip() {
+        let ctx = SnapchainValidatorContext::new(Keypair::generate());
+        let (proposal, _) = FullProposalBuilder::new()
+            .with_height(Height::new(1, 5))
+            .with_round(Round::new(0))
+            .with_proposer(Address(ctx.public_key().to_bytes()))
+            .with_shard_chunk(test_shard_chunk(Height::new(1, 5)))
+            .build()
+            .unwrap();
+
+        let signed = ctx.sign_full_proposal(proposal.clone());
+        assert!(!signed.signature.is_empty());
+        assert!(signed.verify_signature(&ctx.public_key()));
+
+        // Survives encoding for gossip
+        let decoded = FullProposal::decode(signed.encode_to_vec().as_slice()).unwrap();
+        assert!(decoded.verify_signature(&ctx.public_key()));
+
+        assert!(!proposal.verify_signature(&ctx.public_key()));
+        assert!(!signed.verify_signature(&Keypair::generate().public()));
+        assert!(signed.verify_proposer_signature());
+    }
+
+    #[test]
+    fn test_full_proposal_signature_bound_to_height_and_round() {
+        let ctx = SnapchainValidatorContext::new(Keypair::generate());
+        let (proposal, _) = FullProposalBuilder::new()
+            .with_height(Height::new(1, 5))
+            .with_round(Round::new(0))
+            .with_proposer(Address(ctx.public_key().to_bytes()))
+            .with_shard_chunk(test_shard_chunk(Height::new(1, 5)))
+            .build()
+            .unwrap();
+        let signed = ctx.sign_full_proposal(proposal);
+        assert!(signed.verify_proposer_signature());
+
+        let replayed_round = FullProposal {
+            round: 1,
+            ..signed.clone()
+        };
+        assert!(!replayed_round.verify_proposer_signature());
+
+        let replayed_height = FullProposal {
+            height: Some(Height::new(1, 6)),
+            ..signed.clone()
+        };
+        assert!(!replayed_height.verify_proposer_signature());
+
+        let replayed_shard = FullProposal {
+            height: Some(Height::new(2, 5)),
+            ..signed
+        };
+        assert!(!replayed_shard.verify_proposer_signature());
+    }
+
+    #[test]
+    fn test_block_full_hash_covers_shard_chunks() {
+        let mut block = Block {
+            header: Some(proto::BlockHeader {
+                height: Some(Height::new(0, 1)),
+                ..Default::default()
+            }),
+            hash: vec![1, 2, 3],
+            shard_chunks: vec![test_shard_chunk(Height::new(1, 1))],
+            ..Default::default()
+        };
+        block.full_hash = block.full_hash();
+        assert!(block.verify_full_hash());
+        let header_bytes = block.header.as_ref().unwrap().encode_to_vec();
+
+        block.shard_chunks[0]
+            .transactions
+            .push(transaction(2, vec![]));
+
+        assert_eq!(block.header.as_ref().unwrap().encode_to_vec(), header_bytes);
+        assert_ne!(block.full_hash(), block.full_hash);
+        assert!(!block.verify_full_hash());
+
+        block.full_hash = vec![];
+        assert!(block.verify_full_hash());
+    }
+}
@@ -1,4 +1,4 @@
-use malachite_metrics::{Metrics, SharedRegistry};
+use malachite_metrics::SharedRegistry;
 use snapchain::proto::snapchain::Block;
 use snapchain::storage::store::BlockStore;
 use std::error::Error;
@@ -16,6 +16,7 @@ use snapchain::consensus::consensus::SystemMessage;
 use snapchain::core::types::proto;
 use snapchain::network::gossip::GossipEvent;
 use snapchain::network::gossip::SnapchainGossip;
+use snapchain::network::metrics_server::serve_metrics;
 use snapchain::network::server::MySnapchainService;
 use snapchain::node::snapchain_node::SnapchainNode;
 use snapchain::proto::rpc::snapchain_service_server::SnapchainServiceServer;
@@ -42,6 +43,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     if app_config.id == 0 {
         return Err("node id must be specified greater than 0".into());
     }
+    app_config.consensus.validate()?;
     if app_config.clear_db {
         let db_dir = format!("{}", app_config.rocksdb_dir);
         std::fs::remove_dir_all(db_dir.clone()).unwrap();
@@ -114,10 +116,6 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let (shutdown_tx, mut shutdown_rx) = mpsc::channel::<()>(1);
 
-    let registry = SharedRegistry::global();
-    // Use the new non-global metrics registry when we upgrade to newer version of malachite
-    let _ = Metrics::register(registry);
-
     let (block_tx, mut block_rx) = mpsc::channel::<Block>(100);
 
     let write_block_store = block_store.clone();
@@ -141,15 +139,26 @@ async fn main() -> Result<(), Box<dyn Error>> {
         block_store.clone(),
         app_config.rocksdb_dir,
     )
-    .await;
+    .await?;
 
-    //TODO: don't assume shard
-    //TODO: remove/redo unwrap
-    let messages_tx = node.messages_tx_by_shard.get(&1u32).unwrap().clone();
+    let messages_tx_by_shard = node.messages_tx_by_shard.clone();
+    let shard_assignment = node.shard_assignment.clone();
 
     let rpc_block_store = block_store.clone();
+    let rpc_shard_stores = node.shard_stores.clone();
+    let rpc_address = node.address.clone();
+    let rpc_validator_sets = node.validator_sets.clone();
+    let max_chunks_per_request = app_config.max_chunks_per_request;
     tokio::spawn(async move {
-        let service = MySnapchainService::new(rpc_block_store, messages_tx);
+        let service = MySnapchainService::new(
+            rpc_address,
+            rpc_block_store,
+            rpc_shard_stores,
+            rpc_validator_sets,
+            messages_tx_by_shard,
+            shard_assignment,
+        )
+        .with_max_chunks_per_request(max_chunks_per_request);
 
         let resp = Server::builder()
             .add_service(SnapchainServiceServer::new(service))
@@ -165,6 +174,21 @@ async fn main() -> Result<(), Box<dyn Error>> {
         shutdown_tx.send(()).await.ok();
     });
 
+    if let Some(metrics_address) = &app_config.metrics_address {
+        let listener = tokio::net::TcpListener::bind(metrics_address).await?;
+        let metrics = node.consensus_metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(listener, metrics, SharedRegistry::global().clone()).await
+            {
+                error!(error = ?e, "metrics server stopped");
+            }
+        });
+    }
+
+    let registration_shard_stores = node.shard_stores.clone();
+    let mut liveness_rx = node.start_liveness_watchdog(app_config.consensus.liveness_stall_timeout);
+    node.start_retention(app_config.retention_policy.clone());
+
     // Create a timer for block creation
     let mut block_interval = time::interval(Duration::from_secs(2));
 
@@ -183,13 +207,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 node.stop();
                 return Ok(());
             }
+            Some(stall) = liveness_rx.recv() => {
+                warn!(
+                    shard_id = stall.shard_id,
+                    last_height = stall.last_height,
+                    elapsed = ?stall.elapsed,
+                    "Shard has not decided a height within the stall timeout"
+                );
+            }
             _ = block_interval.tick() => {
                 tick_count += 1;
                 // Every 5 ticks, re-register the validators so that new nodes can discover each other
                 if tick_count % 5 == 0 {
                     let nonce = tick_count as u64;
                     for i in 0..=app_config.consensus.num_shards() {
-                        let current_height = block_store.max_block_number(i).unwrap_or_else(|_| 0);
+                        // Shard heights come from the shard stores, only the block shard is in the block store
+                        let current_height = match registration_shard_stores.get(&i) {
+                            Some(shard_store) if i != 0 => shard_store.max_block_number().unwrap_or(0),
+                            _ => block_store.max_block_number(i).unwrap_or_else(|_| 0),
+                        };
 
                         let register_validator = proto::RegisterValidator {
                             validator: Some(proto::Validator {
@@ -211,7 +247,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 match msg {
                     SystemMessage::Consensus(consensus_msg) => {
                         // Forward to apropriate consesnsus actors
-                        node.dispatch(consensus_msg);
+                        node.dispatch(consensus_msg).await;
                     }
                 }
             }
@@ -0,0 +1,91 @@
+use crate::consensus::metrics::ConsensusMetrics;
+use malachite_metrics::SharedRegistry;
+use prometheus_client::encoding::text::encode;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{info, warn};
+
+// Prometheus scrape endpoint. Every request gets the rendered metrics, whatever its path: ours and
+// malachite's consensus metrics from `registry`.
+pub async fn serve_metrics(
+    listener: TcpListener,
+    metrics: ConsensusMetrics,
+    registry: SharedRegistry,
+) -> std::io::Result<()> {
+    info!(address = ?listener.local_addr()?, "Serving metrics");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &metrics, &registry).await {
+                warn!("Error serving metrics: {}", err);
+            }
+        });
+    }
+}
+
+fn render(metrics: &ConsensusMetrics, registry: &SharedRegistry) -> String {
+    let mut body = metrics.render_prometheus();
+    registry.read(|registry| {
+        if let Err(err) = encode(&mut body, registry) {
+            warn!("Unable to encode malachite metrics: {}", err);
+        }
+    });
+    body
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    metrics: &ConsensusMetrics,
+    registry: &SharedRegistry,
+) -> std::io::Result<()> {
+    // The request itself doesn't matter, read it so the client doesn't see a reset
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request).await?;
+
+    let body = render(metrics, registry);
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::Height;
+    use malachite_metrics::Metrics;
+
+    #[tokio::test]
+    async fn test_serve_metrics() {
+        let metrics = ConsensusMetrics::new();
+        metrics.observe_decided_height(Height::new(1, 3));
+        metrics.observe_decided_height(Height::new(2, 5));
+        // Registered once for all the shards' actors, like the node does
+        let _malachite_metrics = Metrics::register(SharedRegistry::global());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_metrics(
+            listener,
+            metrics,
+            SharedRegistry::global().clone(),
+        ));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("snapchain_last_decided_height{shard=\"1\"} 3"));
+        assert!(response.contains("snapchain_last_decided_height{shard=\"2\"} 5"));
+        assert!(response.contains("malachite_consensus_"));
+    }
+}
@@ -1,2 +1,3 @@
 pub mod gossip;
+pub mod metrics_server;
 pub mod server;
@@ -1,23 +1,180 @@
-use crate::core::types::ShardId;
+use crate::consensus::proposer::ShardAssignment;
+use crate::consensus::validator::ValidatorSets;
+use crate::core::types::{proto, Address, ShardId};
+use crate::node::consistency::verify_store_consistency;
 use crate::proto::message;
 use crate::proto::rpc::snapchain_service_server::SnapchainService;
-use crate::proto::rpc::{BlocksRequest, BlocksResponse};
-use crate::storage::store::BlockStore;
+use crate::proto::rpc::{
+    submit_message_result, BlockAtHeight, BlocksByHeightsRequest, BlocksByHeightsResponse,
+    BlocksRequest, BlocksResponse, ConsistencyRequest, ConsistencyResponse, GetShardHeadersRequest,
+    GetShardHeadersResponse, GetValidatorSetRequest, PeerStatusRequest, PeerStatusResponse,
+    ShardChunksRequest, ShardChunksResponse, ShardStatus, SubmitMessageResult,
+    SubmitMessagesBatchRequest, SubmitMessagesBatchResponse, TransactionCountRequest,
+    TransactionCountResponse,
+};
+use crate::storage::store::shard::ShardStore;
+use crate::storage::store::{BlockStore, PAGE_SIZE_MAX};
 use hex::ToHex;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 use tonic::{Request, Response, Status};
 use tracing::info;
 
+#[derive(Error, Debug, PartialEq)]
+pub enum SubmitMessageError {
+    #[error("Message has no data")]
+    MissingData,
+
+    #[error("Message has no hash")]
+    MissingHash,
+
+    #[error("Shard {shard_id} for fid {fid} isn't hosted on this node")]
+    ShardNotHosted { fid: u64, shard_id: u32 },
+
+    #[error("Shard {shard_id} is no longer accepting messages")]
+    ShardUnavailable { shard_id: u32 },
+
+    #[error("Shard {shard_id} is busy applying earlier messages, retry later")]
+    Busy { shard_id: u32 },
+}
+
+impl From<SubmitMessageError> for Status {
+    fn from(err: SubmitMessageError) -> Self {
+        match err {
+            SubmitMessageError::ShardUnavailable { .. } => Status::unavailable(err.to_string()),
+            SubmitMessageError::Busy { .. } => Status::resource_exhausted(err.to_string()),
+            _ => Status::invalid_argument(err.to_string()),
+        }
+    }
+}
+
+// Most shard chunks returned by a single GetShardChunks call
+pub const DEFAULT_MAX_CHUNKS_PER_REQUEST: usize = 1000;
+
+/// Message senders of the shard engines on this node, shared between the node and the rpc server so
+/// submitted messages reach a shard's new engine after it's restarted
+#[derive(Clone, Default)]
+pub struct ShardMessageSenders {
+    senders: Arc<RwLock<HashMap<u32, mpsc::Sender<message::Message>>>>,
+}
+
+impl ShardMessageSenders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, shard_id: u32, messages_tx: mpsc::Sender<message::Message>) {
+        self.senders.write().unwrap().insert(shard_id, messages_tx);
+    }
+
+    pub fn get(&self, shard_id: u32) -> Option<mpsc::Sender<message::Message>> {
+        self.senders.read().unwrap().get(&shard_id).cloned()
+    }
+
+    pub fn contains(&self, shard_id: u32) -> bool {
+        self.senders.read().unwrap().contains_key(&shard_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.senders.read().unwrap().is_empty()
+    }
+}
+
+impl From<HashMap<u32, mpsc::Sender<message::Message>>> for ShardMessageSenders {
+    fn from(senders: HashMap<u32, mpsc::Sender<message::Message>>) -> Self {
+        Self {
+            senders: Arc::new(RwLock::new(senders)),
+        }
+    }
+}
+
 pub struct MySnapchainService {
-    message_tx: mpsc::Sender<message::Message>,
+    address: Address,
+    messages_tx_by_shard: ShardMessageSenders,
+    shard_assignment: ShardAssignment,
     block_store: BlockStore,
+    shard_stores: HashMap<u32, ShardStore>,
+    validator_sets: ValidatorSets,
+    max_chunks_per_request: usize,
 }
 
 impl MySnapchainService {
-    pub fn new(block_store: BlockStore, message_tx: mpsc::Sender<message::Message>) -> Self {
+    pub fn new(
+        address: Address,
+        block_store: BlockStore,
+        shard_stores: HashMap<u32, ShardStore>,
+        validator_sets: ValidatorSets,
+        messages_tx_by_shard: ShardMessageSenders,
+        shard_assignment: ShardAssignment,
+    ) -> Self {
         Self {
+            address,
             block_store,
-            message_tx,
+            shard_stores,
+            validator_sets,
+            messages_tx_by_shard,
+            shard_assignment,
+            max_chunks_per_request: DEFAULT_MAX_CHUNKS_PER_REQUEST,
+        }
+    }
+
+    pub fn with_max_chunks_per_request(self, max_chunks_per_request: usize) -> Self {
+        Self {
+            max_chunks_per_request,
+            ..self
+        }
+    }
+
+    // Results to return for a requested page size, capped at what the store and this server allow
+    fn page_size(&self, requested: Option<u32>) -> Result<usize, Status> {
+        let max_page_size = self.max_chunks_per_request.min(PAGE_SIZE_MAX);
+        match requested {
+            None => Ok(max_page_size),
+            Some(0) => Err(Status::invalid_argument("page_size must be positive")),
+            Some(page_size) => Ok((page_size as usize).min(max_page_size)),
+        }
+    }
+
+    // Hand the message to the engine of the shard its fid is assigned to
+    async fn route_message(&self, message: message::Message) -> Result<(), SubmitMessageError> {
+        let fid = message
+            .data
+            .as_ref()
+            .ok_or(SubmitMessageError::MissingData)?
+            .fid;
+        if message.hash.is_empty() {
+            return Err(SubmitMessageError::MissingHash);
+        }
+        let shard_id = (self.shard_assignment)(fid, self.proposing_height());
+        let messages_tx = self
+            .messages_tx_by_shard
+            .get(shard_id)
+            .ok_or(SubmitMessageError::ShardNotHosted { fid, shard_id })?;
+        // A full mempool means the engine is behind, turn the message away instead of queueing it
+        messages_tx.try_send(message).map_err(|err| match err {
+            TrySendError::Full(_) => SubmitMessageError::Busy { shard_id },
+            TrySendError::Closed(_) => SubmitMessageError::ShardUnavailable { shard_id },
+        })
+    }
+
+    // Shard height the message will be proposed at. Hosted shards move in step, so the highest tip
+    // is used, without hosted shards the latest assignment applies.
+    fn proposing_height(&self) -> u64 {
+        self.shard_stores
+            .values()
+            .filter_map(|store| store.max_block_number().ok())
+            .max()
+            .map_or(u64::MAX, |tip| tip + 1)
+    }
+
+    fn shard_status(&self, shard_id: u32, confirmed_height: u64) -> ShardStatus {
+        ShardStatus {
+            shard_id,
+            confirmed_height,
+            validator_address: self.address.to_vec(),
         }
     }
 }
@@ -32,12 +189,35 @@ impl SnapchainService for MySnapchainService {
         info!(hash, "Received a message");
 
         let message = request.into_inner();
-        self.message_tx.send(message.clone()).await.unwrap(); // Do we need clone here? I think yes?
+        self.route_message(message.clone()).await?;
 
         let response = Response::new(message);
         Ok(response)
     }
 
+    async fn submit_messages_batch(
+        &self,
+        request: Request<SubmitMessagesBatchRequest>,
+    ) -> Result<Response<SubmitMessagesBatchResponse>, Status> {
+        let messages = request.into_inner().messages;
+        info!(count = messages.len(), "Received a batch of messages");
+
+        // Every message is routed on its own, a bad message doesn't fail the rest of the batch
+        let mut results = Vec::with_capacity(messages.len());
+        for message in messages {
+            let hash = message.hash.clone();
+            let result = match self.route_message(message).await {
+                Ok(()) => submit_message_result::Result::AcceptedHash(hash),
+                Err(err) => submit_message_result::Result::Error(err.to_string()),
+            };
+            results.push(SubmitMessageResult {
+                result: Some(result),
+            });
+        }
+
+        Ok(Response::new(SubmitMessagesBatchResponse { results }))
+    }
+
     async fn get_blocks(
         &self,
         request: Request<BlocksRequest>,
@@ -45,6 +225,29 @@ impl SnapchainService for MySnapchainService {
         let shard_index = request.get_ref().shard_id;
         let start_block_number = request.get_ref().start_block_number;
         let stop_block_number = request.get_ref().stop_block_number;
+
+        // Refuse ranges with holes (e.g. pruned blocks), blocks past our tip just aren't returned
+        let max_block_number = self
+            .block_store
+            .max_block_number(shard_index)
+            .map_err(|err| Status::from_error(Box::new(err)))?;
+        let check_stop = stop_block_number
+            .unwrap_or(u64::MAX)
+            .min(max_block_number + 1);
+        let check_start = start_block_number.max(1);
+        if check_start < check_stop {
+            let gaps = self
+                .block_store
+                .find_gaps(shard_index, check_start, check_stop)
+                .map_err(|err| Status::from_error(Box::new(err)))?;
+            if !gaps.is_empty() {
+                return Err(Status::failed_precondition(format!(
+                    "Missing blocks in requested range: {:?}",
+                    gaps
+                )));
+            }
+        }
+
         match self
             .block_store
             .get_blocks(start_block_number, stop_block_number, shard_index)
@@ -56,4 +259,675 @@ impl SnapchainService for MySnapchainService {
             }
         }
     }
+
+    async fn get_blocks_by_heights(
+        &self,
+        request: Request<BlocksByHeightsRequest>,
+    ) -> Result<Response<BlocksByHeightsResponse>, Status> {
+        let request = request.into_inner();
+        match self
+            .block_store
+            .get_blocks_by_numbers(request.shard_id, &request.heights)
+        {
+            Err(err) => Err(Status::from_error(Box::new(err))),
+            Ok(blocks) => {
+                let blocks = request
+                    .heights
+                    .into_iter()
+                    .zip(blocks)
+                    .map(|(block_number, block)| BlockAtHeight {
+                        block_number,
+                        block,
+                    })
+                    .collect();
+                Ok(Response::new(BlocksByHeightsResponse { blocks }))
+            }
+        }
+    }
+
+    async fn get_shard_chunks(
+        &self,
+        request: Request<ShardChunksRequest>,
+    ) -> Result<Response<ShardChunksResponse>, Status> {
+        let shard_id = request.get_ref().shard_id;
+        let shard_store = self
+            .shard_stores
+            .get(&shard_id)
+            .ok_or_else(|| Status::not_found("shard not hosted"))?;
+
+        let request = request.into_inner();
+        let page_size = self.page_size(request.page_size)?;
+        match shard_store.get_shard_chunks_page(
+            request.start_block_number,
+            request.stop_block_number,
+            page_size,
+            request.page_token,
+        ) {
+            Err(err) => Err(Status::from_error(Box::new(err))),
+            Ok(page) => Ok(Response::new(ShardChunksResponse {
+                shard_chunks: page.shard_chunks,
+                next_page_token: page.next_page_token,
+            })),
+        }
+    }
+
+    async fn get_shard_headers(
+        &self,
+        request: Request<GetShardHeadersRequest>,
+    ) -> Result<Response<GetShardHeadersResponse>, Status> {
+        let shard_id = request.get_ref().shard_id;
+        let shard_store = self
+            .shard_stores
+            .get(&shard_id)
+            .ok_or_else(|| Status::not_found("shard not hosted"))?;
+
+        let request = request.into_inner();
+        let page_size = self.page_size(request.page_size)?;
+        match shard_store.get_shard_headers_page(
+            request.start_block_number,
+            request.stop_block_number,
+            page_size,
+            request.page_token,
+        ) {
+            Err(err) => Err(Status::from_error(Box::new(err))),
+            Ok(page) => Ok(Response::new(GetShardHeadersResponse {
+                headers: page.headers,
+                next_page_token: page.next_page_token,
+            })),
+        }
+    }
+
+    async fn get_transaction_count(
+        &self,
+        request: Request<TransactionCountRequest>,
+    ) -> Result<Response<TransactionCountResponse>, Status> {
+        let shard_id = request.get_ref().shard_id;
+        let shard_store = self
+            .shard_stores
+            .get(&shard_id)
+            .ok_or_else(|| Status::not_found("shard not hosted"))?;
+
+        match shard_store.transaction_count_in_range(
+            request.get_ref().start_block_number,
+            request.get_ref().stop_block_number,
+        ) {
+            Err(err) => Err(Status::from_error(Box::new(err))),
+            Ok(transaction_count) => Ok(Response::new(TransactionCountResponse {
+                transaction_count,
+            })),
+        }
+    }
+
+    async fn verify_store_consistency(
+        &self,
+        request: Request<ConsistencyRequest>,
+    ) -> Result<Response<ConsistencyResponse>, Status> {
+        let start_block_number = request.get_ref().start_block_number;
+        let stop_block_number = request.get_ref().stop_block_number;
+        match verify_store_consistency(
+            &self.block_store,
+            &self.shard_stores,
+            start_block_number,
+            stop_block_number,
+        ) {
+            Err(err) => Err(Status::from_error(Box::new(err))),
+            Ok(report) => Ok(Response::new(report.into())),
+        }
+    }
+
+    async fn get_peer_status(
+        &self,
+        _request: Request<PeerStatusRequest>,
+    ) -> Result<Response<PeerStatusResponse>, Status> {
+        let block_height = self
+            .block_store
+            .max_block_number(0)
+            .map_err(|err| Status::from_error(Box::new(err)))?;
+        let mut shards = vec![self.shard_status(0, block_height)];
+
+        let mut shard_ids: Vec<&u32> = self.shard_stores.keys().collect();
+        shard_ids.sort();
+        for shard_id in shard_ids {
+            let height = self.shard_stores[shard_id]
+                .max_block_number()
+                .map_err(|err| Status::from_error(Box::new(err)))?;
+            shards.push(self.shard_status(*shard_id, height));
+        }
+
+        Ok(Response::new(PeerStatusResponse { shards }))
+    }
+
+    async fn get_validator_set(
+        &self,
+        request: Request<GetValidatorSetRequest>,
+    ) -> Result<Response<proto::ValidatorSet>, Status> {
+        let shard_id = request.get_ref().shard_id;
+        match self.validator_sets.get(shard_id) {
+            None => Err(Status::not_found(format!(
+                "No validator set for shard {}",
+                shard_id
+            ))),
+            Some(validator_set) => Ok(Response::new((&validator_set).into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{Height, SnapchainShard, SnapchainValidator, SnapchainValidatorSet};
+    use crate::proto::snapchain::{Block, BlockHeader, ShardChunk, ShardHeader};
+    use crate::storage::db::RocksDB;
+    use libp2p::identity::ed25519::Keypair;
+    use std::sync::Arc;
+
+    fn make_tmp_path() -> String {
+        tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn new_shard_store(shard_id: u32, num_chunks: u64) -> ShardStore {
+        let db = RocksDB::new(&make_tmp_path());
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        for block_number in 1..=num_chunks {
+            shard_store
+                .put_shard_chunk(ShardChunk {
+                    header: Some(ShardHeader {
+                        height: Some(Height::new(shard_id, block_number)),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        shard_store
+    }
+
+    // A service that hosts no mempools and assigns every fid to shard 1
+    fn new_service(
+        block_store: BlockStore,
+        shard_stores: HashMap<u32, ShardStore>,
+    ) -> MySnapchainService {
+        MySnapchainService::new(
+            Address([1; 32]),
+            block_store,
+            shard_stores,
+            ValidatorSets::new(),
+            ShardMessageSenders::new(),
+            Arc::new(|_, _| 1),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_submit_messages_batch_partial_success() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let (messages_tx, mut messages_rx) = mpsc::channel(10);
+        // Even fids live on shard 2, which this node doesn't host
+        let service = MySnapchainService {
+            messages_tx_by_shard: HashMap::from([(1, messages_tx)]).into(),
+            shard_assignment: Arc::new(|fid, _| if fid % 2 == 0 { 2 } else { 1 }),
+            ..new_service(BlockStore::new(db.clone()), HashMap::new())
+        };
+        let message = |fid, hash: Vec<u8>| message::Message {
+            data: Some(message::MessageData {
+                fid,
+                ..Default::default()
+            }),
+            hash,
+            ..Default::default()
+        };
+        let messages = vec![
+            message(1, vec![1]),
+            message::Message {
+                hash: vec![2],
+                ..Default::default()
+            },
+            message(3, vec![]),
+            message(4, vec![4]),
+            message(5, vec![5]),
+        ];
+
+        let results: Vec<submit_message_result::Result> = service
+            .submit_messages_batch(Request::new(SubmitMessagesBatchRequest { messages }))
+            .await
+            .unwrap()
+            .into_inner()
+            .results
+            .into_iter()
+            .map(|result| result.result.unwrap())
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                submit_message_result::Result::AcceptedHash(vec![1]),
+                submit_message_result::Result::Error(SubmitMessageError::MissingData.to_string()),
+                submit_message_result::Result::Error(SubmitMessageError::MissingHash.to_string()),
+                submit_message_result::Result::Error(
+                    SubmitMessageError::ShardNotHosted {
+                        fid: 4,
+                        shard_id: 2
+                    }
+                    .to_string()
+                ),
+                submit_message_result::Result::AcceptedHash(vec![5]),
+            ]
+        );
+
+        assert_eq!(messages_rx.recv().await.unwrap().hash, vec![1]);
+        assert_eq!(messages_rx.recv().await.unwrap().hash, vec![5]);
+        assert!(messages_rx.try_recv().is_err());
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_submit_message_routes_by_proposing_height() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let (shard_1_tx, mut shard_1_rx) = mpsc::channel(10);
+        let (shard_2_tx, mut shard_2_rx) = mpsc::channel(10);
+        let shard_store = new_shard_store(1, 8);
+        // Every fid was on shard 1 before height 10, odd fids moved to shard 2 from then on
+        let service = MySnapchainService {
+            messages_tx_by_shard: HashMap::from([(1, shard_1_tx), (2, shard_2_tx)]).into(),
+            shard_assignment: Arc::new(|fid, block_number| {
+                if block_number < 10 || fid % 2 == 0 {
+                    1
+                } else {
+                    2
+                }
+            }),
+            ..new_service(
+                BlockStore::new(db.clone()),
+                HashMap::from([(1, shard_store.clone())]),
+            )
+        };
+        let message = |hash: Vec<u8>| message::Message {
+            data: Some(message::MessageData {
+                fid: 3,
+                ..Default::default()
+            }),
+            hash,
+            ..Default::default()
+        };
+
+        // The next chunk is at height 9, below the rebalance
+        service
+            .submit_message(Request::new(message(vec![1])))
+            .await
+            .unwrap();
+        assert_eq!(shard_1_rx.try_recv().unwrap().hash, vec![1]);
+        assert!(shard_2_rx.try_recv().is_err());
+
+        shard_store
+            .put_shard_chunk(ShardChunk {
+                header: Some(ShardHeader {
+                    height: Some(Height::new(1, 9)),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .unwrap();
+        service
+            .submit_message(Request::new(message(vec![2])))
+            .await
+            .unwrap();
+        assert_eq!(shard_2_rx.try_recv().unwrap().hash, vec![2]);
+        assert!(shard_1_rx.try_recv().is_err());
+
+        shard_store.db.destroy().unwrap();
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_blocks_by_heights() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let block_store = BlockStore::new(db.clone());
+        let block = |block_number| Block {
+            header: Some(BlockHeader {
+                height: Some(Height::new(0, block_number)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        for block_number in 1..=10 {
+            block_store.put_block(block(block_number)).unwrap();
+        }
+        let service = new_service(block_store, HashMap::new());
+
+        let blocks = service
+            .get_blocks_by_heights(Request::new(BlocksByHeightsRequest {
+                shard_id: 0,
+                heights: vec![7, 2, 42, 9],
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .blocks;
+        assert_eq!(
+            blocks,
+            vec![
+                BlockAtHeight {
+                    block_number: 7,
+                    block: Some(block(7))
+                },
+                BlockAtHeight {
+                    block_number: 2,
+                    block: Some(block(2))
+                },
+                BlockAtHeight {
+                    block_number: 42,
+                    block: None
+                },
+                BlockAtHeight {
+                    block_number: 9,
+                    block: Some(block(9))
+                },
+            ]
+        );
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_blocks_refuses_range_with_gaps() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let block_store = BlockStore::new(db.clone());
+        for block_number in [1, 2, 4] {
+            block_store
+                .put_block(Block {
+                    header: Some(BlockHeader {
+                        height: Some(Height::new(0, block_number)),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        let service = new_service(block_store, HashMap::new());
+        let request = |start_block_number, stop_block_number| {
+            Request::new(BlocksRequest {
+                shard_id: 0,
+                start_block_number,
+                stop_block_number,
+            })
+        };
+
+        let response = service.get_blocks(request(1, Some(3))).await.unwrap();
+        assert_eq!(response.into_inner().blocks.len(), 2);
+
+        let err = service.get_blocks(request(1, None)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::FailedPrecondition);
+
+        // Past the tip isn't a gap
+        let response = service.get_blocks(request(4, Some(10))).await.unwrap();
+        assert_eq!(response.into_inner().blocks.len(), 1);
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_submit_message_busy_when_mempool_full() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        // Nothing drains the mempool, like an engine that can't keep up
+        let (messages_tx, mut messages_rx) = mpsc::channel(2);
+        let service = MySnapchainService {
+            messages_tx_by_shard: HashMap::from([(1, messages_tx)]).into(),
+            ..new_service(BlockStore::new(db.clone()), HashMap::new())
+        };
+        let message = |hash: u8| {
+            Request::new(message::Message {
+                data: Some(message::MessageData {
+                    fid: 1,
+                    ..Default::default()
+                }),
+                hash: vec![hash],
+                ..Default::default()
+            })
+        };
+
+        service.submit_message(message(1)).await.unwrap();
+        service.submit_message(message(2)).await.unwrap();
+        let err = service.submit_message(message(3)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::ResourceExhausted);
+        assert_eq!(
+            err.message(),
+            SubmitMessageError::Busy { shard_id: 1 }.to_string()
+        );
+
+        // Accepted again once the engine catches up
+        messages_rx.recv().await.unwrap();
+        service.submit_message(message(3)).await.unwrap();
+
+        db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_shard_chunks_for_unhosted_shard() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let shard_stores = HashMap::from([(1, new_shard_store(1, 3))]);
+        let service = new_service(BlockStore::new(db.clone()), shard_stores.clone());
+        let request = |shard_id| {
+            Request::new(ShardChunksRequest {
+                shard_id,
+                start_block_number: 1,
+                stop_block_number: None,
+                page_token: None,
+                page_size: None,
+            })
+        };
+
+        let response = service.get_shard_chunks(request(1)).await.unwrap();
+        assert_eq!(response.into_inner().shard_chunks.len(), 3);
+
+        let err = service.get_shard_chunks(request(2)).await.unwrap_err();
+        assert_eq!(err.code(), tonic::Code::NotFound);
+        assert_eq!(err.message(), "shard not hosted");
+
+        db.destroy().unwrap();
+        shard_stores[&1].db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_shard_chunks_capped_per_request() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let shard_stores = HashMap::from([(1, new_shard_store(1, 3))]);
+        let service = new_service(BlockStore::new(db.clone()), shard_stores.clone())
+            .with_max_chunks_per_request(2);
+        let request = |page_token| {
+            Request::new(ShardChunksRequest {
+                shard_id: 1,
+                start_block_number: 0,
+                stop_block_number: None,
+                page_token,
+                page_size: None,
+            })
+        };
+
+        // An unbounded request gets the first page and a token to continue from
+        let first = service.get_shard_chunks(request(None)).await.unwrap();
+        let first = first.into_inner();
+        assert_eq!(first.shard_chunks.len(), 2);
+        assert!(first.next_page_token.is_some());
+
+        let second = service
+            .get_shard_chunks(request(first.next_page_token))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(second.shard_chunks.len(), 1);
+        assert_eq!(second.next_page_token, None);
+
+        let block_numbers: Vec<u64> = first
+            .shard_chunks
+            .iter()
+            .chain(&second.shard_chunks)
+            .map(|chunk| chunk.header.as_ref().unwrap().height.unwrap().block_number)
+            .collect();
+        assert_eq!(block_numbers, vec![1, 2, 3]);
+
+        db.destroy().unwrap();
+        shard_stores[&1].db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_shard_chunks_page_size() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let shard_stores = HashMap::from([(1, new_shard_store(1, 3))]);
+        let service = new_service(BlockStore::new(db.clone()), shard_stores.clone());
+        let request = |page_size| {
+            Request::new(ShardChunksRequest {
+                shard_id: 1,
+                start_block_number: 0,
+                stop_block_number: None,
+                page_token: None,
+                page_size,
+            })
+        };
+
+        let err = service
+            .get_shard_chunks(request(Some(0)))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+        let err = service
+            .get_shard_headers(Request::new(GetShardHeadersRequest {
+                shard_id: 1,
+                page_size: Some(0),
+                ..Default::default()
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), tonic::Code::InvalidArgument);
+
+        let response = service
+            .get_shard_chunks(request(Some(2)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.shard_chunks.len(), 2);
+        assert!(response.next_page_token.is_some());
+
+        let response = service
+            .get_shard_chunks(request(Some(u32::MAX)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.shard_chunks.len(), 3);
+        assert_eq!(service.page_size(Some(u32::MAX)).unwrap(), PAGE_SIZE_MAX);
+        assert_eq!(
+            service
+                .with_max_chunks_per_request(2)
+                .page_size(Some(u32::MAX))
+                .unwrap(),
+            2
+        );
+
+        db.destroy().unwrap();
+        shard_stores[&1].db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_get_peer_status() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let block_store = BlockStore::new(db.clone());
+        for block_number in 1..=4 {
+            block_store
+                .put_block(Block {
+                    header: Some(BlockHeader {
+                        height: Some(Height::new(0, block_number)),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let shard_stores = HashMap::from([(1, new_shard_store(1, 3)), (2, new_shard_store(2, 1))]);
+        let service = new_service(block_store, shard_stores.clone());
+
+        let response = service
+            .get_peer_status(Request::new(PeerStatusRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let heights: Vec<(u32, u64)> = response
+            .shards
+            .iter()
+            .map(|status| (status.shard_id, status.confirmed_height))
+            .collect();
+        assert_eq!(heights, vec![(0, 4), (1, 3), (2, 1)]);
+        assert!(response
+            .shards
+            .iter()
+            .all(|status| status.validator_address == Address([1; 32]).to_vec()));
+
+        db.destroy().unwrap();
+        for shard_store in shard_stores.values() {
+            shard_store.db.destroy().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_validator_set() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let validator_set = SnapchainValidatorSet::new(
+            ["127.0.0.1:3383", "127.0.0.1:3384"]
+                .into_iter()
+                .map(|rpc_address| {
+                    SnapchainValidator::new(
+                        SnapchainShard::new(1),
+                        Keypair::generate().public(),
+                        Some(rpc_address.to_string()),
+                        5,
+                    )
+                })
+                .chain([SnapchainValidator::new(
+                    SnapchainShard::new(1),
+                    Keypair::generate().public(),
+                    None,
+                    0,
+                )])
+                .collect(),
+        );
+        let validator_sets = ValidatorSets::new();
+        validator_sets.update(1, validator_set.clone());
+        let service = MySnapchainService {
+            validator_sets,
+            ..new_service(BlockStore::new(db.clone()), HashMap::new())
+        };
+
+        let response = service
+            .get_validator_set(Request::new(GetValidatorSetRequest { shard_id: 1 }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.validators.len(), 3);
+        assert_eq!(
+            SnapchainValidatorSet::try_from(response).unwrap(),
+            validator_set
+        );
+
+        let missing = service
+            .get_validator_set(Request::new(GetValidatorSetRequest { shard_id: 2 }))
+            .await;
+        assert_eq!(missing.unwrap_err().code(), tonic::Code::NotFound);
+
+        db.destroy().unwrap();
+    }
 }
@@ -0,0 +1,236 @@
+use crate::proto::rpc;
+use crate::storage::store::shard::{ShardStorageError, ShardStore};
+use crate::storage::store::{BlockStorageError, BlockStore};
+use std::collections::HashMap;
+use std::fmt;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ConsistencyError {
+    #[error(transparent)]
+    BlockStorageError(#[from] BlockStorageError),
+
+    #[error(transparent)]
+    ShardStorageError(#[from] ShardStorageError),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiscrepancyKind {
+    // The block references a shard this node doesn't have a store for
+    UnknownShard,
+    // The embedded shard chunk has no header/height
+    ChunkMissingHeight,
+    // No chunk at that height in the shard store
+    MissingShardChunk,
+    // The shard store has a chunk at that height, but with a different hash
+    HashMismatch,
+}
+
+impl fmt::Display for DiscrepancyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            DiscrepancyKind::UnknownShard => "unknown shard",
+            DiscrepancyKind::ChunkMissingHeight => "shard chunk missing height",
+            DiscrepancyKind::MissingShardChunk => "shard chunk missing from shard store",
+            DiscrepancyKind::HashMismatch => "shard chunk hash mismatch",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShardChunkDiscrepancy {
+    pub block_number: u64,
+    pub shard_id: u32,
+    pub shard_block_number: u64,
+    pub kind: DiscrepancyKind,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    pub blocks_checked: u64,
+    pub shard_chunks_checked: u64,
+    pub discrepancies: Vec<ShardChunkDiscrepancy>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+impl From<ConsistencyReport> for rpc::ConsistencyResponse {
+    fn from(report: ConsistencyReport) -> Self {
+        rpc::ConsistencyResponse {
+            blocks_checked: report.blocks_checked,
+            shard_chunks_checked: report.shard_chunks_checked,
+            discrepancies: report
+                .discrepancies
+                .into_iter()
+                .map(|discrepancy| rpc::ShardChunkDiscrepancy {
+                    block_number: discrepancy.block_number,
+                    shard_id: discrepancy.shard_id,
+                    shard_block_number: discrepancy.shard_block_number,
+                    reason: discrepancy.kind.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// Walk the committed blocks in [start_block_number, stop_block_number) and check that every embedded
+// shard chunk is present in its shard store at the same height with the same hash
+pub fn verify_store_consistency(
+    block_store: &BlockStore,
+    shard_stores: &HashMap<u32, ShardStore>,
+    start_block_number: u64,
+    stop_block_number: u64,
+) -> Result<ConsistencyReport, ConsistencyError> {
+    let mut report = ConsistencyReport::default();
+    let blocks = block_store.get_blocks(start_block_number, Some(stop_block_number), 0)?;
+
+    for block in blocks {
+        report.blocks_checked += 1;
+        let block_number = block
+            .header
+            .as_ref()
+            .and_then(|header| header.height.as_ref())
+            .map_or(0, |height| height.block_number);
+
+        for chunk in &block.shard_chunks {
+            report.shard_chunks_checked += 1;
+            let Some(height) = chunk.header.as_ref().and_then(|header| header.height) else {
+                report.discrepancies.push(ShardChunkDiscrepancy {
+                    block_number,
+                    shard_id: 0,
+                    shard_block_number: 0,
+                    kind: DiscrepancyKind::ChunkMissingHeight,
+                });
+                continue;
+            };
+
+            let mut discrepancy = ShardChunkDiscrepancy {
+                block_number,
+                shard_id: height.shard_index,
+                shard_block_number: height.block_number,
+                kind: DiscrepancyKind::UnknownShard,
+            };
+            let Some(shard_store) = shard_stores.get(&height.shard_index) else {
+                report.discrepancies.push(discrepancy);
+                continue;
+            };
+
+            match shard_store.get_shard_chunk(height.block_number)? {
+                None => {
+                    discrepancy.kind = DiscrepancyKind::MissingShardChunk;
+                    report.discrepancies.push(discrepancy);
+                }
+                Some(stored_chunk) if stored_chunk.hash != chunk.hash => {
+                    discrepancy.kind = DiscrepancyKind::HashMismatch;
+                    report.discrepancies.push(discrepancy);
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::Height;
+    use crate::proto::snapchain::{Block, BlockHeader, ShardChunk, ShardHeader};
+    use crate::storage::db::RocksDB;
+    use std::sync::Arc;
+
+    fn make_tmp_path() -> String {
+        tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn shard_chunk(shard_id: u32, block_number: u64, hash: Vec<u8>) -> ShardChunk {
+        ShardChunk {
+            header: Some(ShardHeader {
+                height: Some(Height::new(shard_id, block_number)),
+                ..Default::default()
+            }),
+            hash,
+            ..Default::default()
+        }
+    }
+
+    fn block(block_number: u64, shard_chunks: Vec<ShardChunk>) -> Block {
+        Block {
+            header: Some(BlockHeader {
+                height: Some(Height::new(0, block_number)),
+                ..Default::default()
+            }),
+            hash: vec![block_number as u8],
+            shard_chunks,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_missing_shard_chunk_is_reported() {
+        let block_db = Arc::new(RocksDB::new(&make_tmp_path()));
+        block_db.open().unwrap();
+        let block_store = BlockStore::new(block_db.clone());
+
+        let shard_db = RocksDB::new(&make_tmp_path());
+        shard_db.open().unwrap();
+        let shard_store = ShardStore::new(shard_db);
+
+        shard_store
+            .put_shard_chunk(shard_chunk(1, 1, vec![1]))
+            .unwrap();
+        shard_store
+            .put_shard_chunk(shard_chunk(1, 2, vec![2]))
+            .unwrap();
+
+        block_store
+            .put_block(block(1, vec![shard_chunk(1, 1, vec![1])]))
+            .unwrap();
+        block_store
+            .put_block(block(2, vec![shard_chunk(1, 2, vec![9])]))
+            .unwrap();
+        block_store
+            .put_block(block(3, vec![shard_chunk(1, 3, vec![3])]))
+            .unwrap();
+
+        let shard_stores = HashMap::from([(1, shard_store.clone())]);
+        let report = verify_store_consistency(&block_store, &shard_stores, 1, 4).unwrap();
+
+        assert_eq!(report.blocks_checked, 3);
+        assert_eq!(report.shard_chunks_checked, 3);
+        assert_eq!(
+            report.discrepancies,
+            vec![
+                ShardChunkDiscrepancy {
+                    block_number: 2,
+                    shard_id: 1,
+                    shard_block_number: 2,
+                    kind: DiscrepancyKind::HashMismatch,
+                },
+                ShardChunkDiscrepancy {
+                    block_number: 3,
+                    shard_id: 1,
+                    shard_block_number: 3,
+                    kind: DiscrepancyKind::MissingShardChunk,
+                },
+            ]
+        );
+
+        let report = verify_store_consistency(&block_store, &shard_stores, 1, 2).unwrap();
+        assert!(report.is_consistent());
+
+        block_db.destroy().unwrap();
+        shard_store.db.destroy().unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+use crate::consensus::consensus::ConsensusMsg;
+use crate::core::types::SnapchainValidatorContext;
+use prost::Message;
+use std::collections::{HashSet, VecDeque};
+
+type MessageKey = [u8; 32];
+
+// Only gossiped consensus messages are deduplicated, anything else is always forwarded
+fn message_key(msg: &ConsensusMsg<SnapchainValidatorContext>) -> Option<MessageKey> {
+    let mut hasher = blake3::Hasher::new();
+    match msg {
+        ConsensusMsg::ReceivedSignedVote(vote) => {
+            hasher.update(&[0]);
+            hasher.update(&vote.message.to_sign_bytes());
+            hasher.update(&vote.signature.0);
+        }
+        ConsensusMsg::ReceivedSignedProposal(proposal) => {
+            hasher.update(&[1]);
+            hasher.update(&proposal.message.to_sign_bytes());
+            hasher.update(&proposal.signature.0);
+        }
+        ConsensusMsg::ReceivedFullProposal(full_proposal) => {
+            hasher.update(&[2]);
+            hasher.update(&full_proposal.encode_to_vec());
+        }
+        _ => return None,
+    }
+    Some(*hasher.finalize().as_bytes())
+}
+
+/// Remembers the last `capacity` consensus messages, so ones gossip delivers again are dropped. The
+/// oldest message is forgotten first. A capacity of 0 disables deduplication.
+#[derive(Debug, Default)]
+pub struct MessageDedup {
+    capacity: usize,
+    seen: HashSet<MessageKey>,
+    order: VecDeque<MessageKey>,
+    duplicates_dropped: u64,
+}
+
+impl MessageDedup {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    // Records the message, returns true if it was seen recently and should be dropped
+    pub fn is_duplicate(&mut self, msg: &ConsensusMsg<SnapchainValidatorContext>) -> bool {
+        if self.capacity == 0 {
+            return false;
+        }
+        let Some(key) = message_key(msg) else {
+            return false;
+        };
+        if self.seen.contains(&key) {
+            self.duplicates_dropped += 1;
+            return true;
+        }
+
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(key);
+        self.order.push_back(key);
+        false
+    }
+
+    pub fn duplicates_dropped(&self) -> u64 {
+        self.duplicates_dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::Height;
+    use crate::proto::snapchain::FullProposal;
+
+    fn full_proposal(block_number: u64) -> ConsensusMsg<SnapchainValidatorContext> {
+        ConsensusMsg::ReceivedFullProposal(FullProposal {
+            height: Some(Height::new(1, block_number)),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_oldest_message_forgotten_at_capacity() {
+        let mut dedup = MessageDedup::new(2);
+        assert!(!dedup.is_duplicate(&full_proposal(1)));
+        assert!(!dedup.is_duplicate(&full_proposal(2)));
+        assert!(dedup.is_duplicate(&full_proposal(1)));
+
+        // Proposal 1 is pushed out by proposal 3
+        assert!(!dedup.is_duplicate(&full_proposal(3)));
+        assert!(!dedup.is_duplicate(&full_proposal(1)));
+        assert_eq!(dedup.duplicates_dropped(), 1);
+
+        let mut disabled = MessageDedup::new(0);
+        assert!(!disabled.is_duplicate(&full_proposal(1)));
+        assert!(!disabled.is_duplicate(&full_proposal(1)));
+    }
+}
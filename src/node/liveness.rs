@@ -0,0 +1,103 @@
+use crate::consensus::metrics::ConsensusMetrics;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// Emitted when a shard hasn't decided a height for longer than the configured stall timeout
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LivenessStall {
+    pub shard_id: u32,
+    pub last_height: u64,
+    pub elapsed: Duration,
+}
+
+// Periodically checks the last decide of every shard. A stall is reported once per height, so a
+// partitioned node doesn't flood the channel, and again if the shard recovers and stalls later.
+pub fn spawn_liveness_watchdog(
+    metrics: ConsensusMetrics,
+    shard_ids: Vec<u32>,
+    stall_timeout: Duration,
+    check_interval: Duration,
+    stall_tx: mpsc::Sender<LivenessStall>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let started_at = Instant::now();
+        let mut reported: HashMap<u32, u64> = HashMap::new();
+        let mut interval = time::interval(check_interval);
+        loop {
+            interval.tick().await;
+            for shard_id in shard_ids.iter() {
+                // Shards that never decided are measured from when the watchdog started
+                let (last_height, last_decided_at) =
+                    metrics.last_decided(*shard_id).unwrap_or((0, started_at));
+                let elapsed = last_decided_at.elapsed();
+                if elapsed < stall_timeout || reported.get(shard_id) == Some(&last_height) {
+                    continue;
+                }
+
+                metrics.observe_liveness_stall(*shard_id);
+                reported.insert(*shard_id, last_height);
+                let stall = LivenessStall {
+                    shard_id: *shard_id,
+                    last_height,
+                    elapsed,
+                };
+                if stall_tx.send(stall).await.is_err() {
+                    // Nobody is listening anymore
+                    return;
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::Height;
+
+    #[tokio::test]
+    async fn test_stall_reported_when_decisions_withheld() {
+        let metrics = ConsensusMetrics::new();
+        let (stall_tx, mut stall_rx) = mpsc::channel(10);
+        let watchdog = spawn_liveness_watchdog(
+            metrics.clone(),
+            vec![1, 2],
+            Duration::from_millis(100),
+            Duration::from_millis(10),
+            stall_tx,
+        );
+
+        // Shard 1 keeps deciding, shard 2 has its decisions withheld after height 3
+        metrics.observe_decided_height(Height::new(2, 3));
+        let deciding = tokio::spawn({
+            let metrics = metrics.clone();
+            async move {
+                for block_number in 1.. {
+                    metrics.observe_decided_height(Height::new(1, block_number));
+                    time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+        });
+
+        let stall = time::timeout(Duration::from_secs(1), stall_rx.recv())
+            .await
+            .expect("stall should be reported")
+            .unwrap();
+        assert_eq!(stall.shard_id, 2);
+        assert_eq!(stall.last_height, 3);
+        assert!(stall.elapsed >= Duration::from_millis(100));
+
+        // Reported only once for the same height
+        assert!(time::timeout(Duration::from_millis(200), stall_rx.recv())
+            .await
+            .is_err());
+        assert_eq!(metrics.liveness_stalls(1), 0);
+        assert_eq!(metrics.liveness_stalls(2), 1);
+
+        deciding.abort();
+        watchdog.abort();
+    }
+}
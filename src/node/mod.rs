@@ -1 +1,5 @@
+pub mod consistency;
+pub mod dedup;
+pub mod liveness;
+pub mod retention;
 pub mod snapchain_node;
@@ -0,0 +1,218 @@
+use crate::consensus::proposer::current_time;
+use crate::storage::store::shard::{ShardStorageError, ShardStore};
+use crate::storage::store::{BlockStorageError, BlockStore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{error, info};
+
+/// How much block and shard chunk history a node keeps, e.g. everything on archive nodes
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetentionPolicy {
+    #[default]
+    KeepAll,
+    /// Keep only the latest n blocks/chunks of every shard
+    KeepLast(u64),
+    /// Keep blocks/chunks with a header timestamp within this duration
+    KeepSince(#[serde(with = "humantime_serde")] Duration),
+}
+
+#[derive(Error, Debug)]
+pub enum RetentionError {
+    #[error(transparent)]
+    BlockStorageError(#[from] BlockStorageError),
+
+    #[error(transparent)]
+    ShardStorageError(#[from] ShardStorageError),
+}
+
+impl RetentionPolicy {
+    // The oldest timestamp to keep, `now` is in seconds since the farcaster epoch like header timestamps
+    fn cutoff_timestamp(duration: &Duration, now: u64) -> u64 {
+        now.saturating_sub(duration.as_secs())
+    }
+
+    // The oldest block number to keep given the highest stored one
+    fn first_kept_block_number(n: u64, max_block_number: u64) -> u64 {
+        (max_block_number + 1).saturating_sub(n)
+    }
+
+    // `safe_height` is the lowest height lagging peers may still need, nothing from it on is pruned
+    // whatever the policy. No floor when None.
+    pub fn prune_shard_store(
+        &self,
+        shard_store: &ShardStore,
+        now: u64,
+        safe_height: Option<u64>,
+    ) -> Result<u64, RetentionError> {
+        let floor = safe_height.unwrap_or(u64::MAX);
+        let pruned = match self {
+            RetentionPolicy::KeepAll => 0,
+            RetentionPolicy::KeepLast(n) => {
+                let max_block_number = shard_store.max_block_number()?;
+                shard_store.prune_chunks_before(
+                    Self::first_kept_block_number(*n, max_block_number).min(floor),
+                )?
+            }
+            RetentionPolicy::KeepSince(duration) => shard_store
+                .prune_chunks_older_than_below(Self::cutoff_timestamp(duration, now), floor)?,
+        };
+        Ok(pruned)
+    }
+
+    pub fn prune_block_store(
+        &self,
+        block_store: &BlockStore,
+        shard_index: u32,
+        now: u64,
+        safe_height: Option<u64>,
+    ) -> Result<u64, RetentionError> {
+        let floor = safe_height.unwrap_or(u64::MAX);
+        let pruned = match self {
+            RetentionPolicy::KeepAll => 0,
+            RetentionPolicy::KeepLast(n) => {
+                let max_block_number = block_store.max_block_number(shard_index)?;
+                block_store.prune_blocks_before(
+                    shard_index,
+                    Self::first_kept_block_number(*n, max_block_number).min(floor),
+                )?
+            }
+            RetentionPolicy::KeepSince(duration) => block_store.prune_blocks_older_than_below(
+                shard_index,
+                Self::cutoff_timestamp(duration, now),
+                floor,
+            )?,
+        };
+        Ok(pruned)
+    }
+}
+
+// Periodically prunes the block shard and every shard store. Each store is pruned on its own, a failure
+// in one doesn't hold back the others. `safe_height` is asked for each shard before every round, as
+// peers catch up.
+pub fn spawn_retention_task<F>(
+    policy: RetentionPolicy,
+    block_store: BlockStore,
+    shard_stores: HashMap<u32, ShardStore>,
+    interval: Duration,
+    safe_height: F,
+) -> JoinHandle<()>
+where
+    F: Fn(u32) -> Option<u64> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if policy == RetentionPolicy::KeepAll {
+            return;
+        }
+        let mut interval = time::interval(interval);
+        loop {
+            interval.tick().await;
+            let now = current_time();
+            match policy.prune_block_store(&block_store, 0, now, safe_height(0)) {
+                Ok(0) => {}
+                Ok(pruned) => info!(shard_id = 0, pruned, "Pruned blocks"),
+                Err(err) => error!(shard_id = 0, "Unable to prune blocks: {}", err),
+            }
+            for (shard_id, shard_store) in shard_stores.iter() {
+                match policy.prune_shard_store(shard_store, now, safe_height(*shard_id)) {
+                    Ok(0) => {}
+                    Ok(pruned) => info!(shard_id, pruned, "Pruned shard chunks"),
+                    Err(err) => error!(shard_id, "Unable to prune shard chunks: {}", err),
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::Height;
+    use crate::proto::snapchain::{ShardChunk, ShardHeader};
+    use crate::storage::db::RocksDB;
+
+    fn make_tmp_path() -> String {
+        tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn shard_chunk(block_number: u64) -> ShardChunk {
+        ShardChunk {
+            header: Some(ShardHeader {
+                height: Some(Height::new(1, block_number)),
+                timestamp: block_number,
+                ..Default::default()
+            }),
+            hash: block_number.to_be_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_keep_last_prunes_oldest_chunks() {
+        let db = RocksDB::new(&make_tmp_path());
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let policy = RetentionPolicy::KeepLast(100);
+
+        let chunks: Vec<ShardChunk> = (1..=100).map(shard_chunk).collect();
+        shard_store.put_shard_chunks_batch(&chunks).unwrap();
+        assert_eq!(policy.prune_shard_store(&shard_store, 0, None).unwrap(), 0);
+
+        let chunks: Vec<ShardChunk> = (101..=150).map(shard_chunk).collect();
+        shard_store.put_shard_chunks_batch(&chunks).unwrap();
+        assert_eq!(policy.prune_shard_store(&shard_store, 0, None).unwrap(), 50);
+
+        let remaining = shard_store.get_shard_chunks(0, None).unwrap();
+        assert_eq!(remaining.len(), 100);
+        assert_eq!(remaining[0], shard_chunk(51));
+        assert_eq!(shard_store.max_block_number().unwrap(), 150);
+
+        // KeepSince compares header timestamps, which are the block numbers here
+        let policy = RetentionPolicy::KeepSince(Duration::from_secs(10));
+        assert_eq!(
+            policy.prune_shard_store(&shard_store, 150, None).unwrap(),
+            89
+        );
+        assert_eq!(shard_store.get_shard_chunks(0, None).unwrap().len(), 11);
+
+        shard_store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_prune_stops_at_safe_height() {
+        let db = RocksDB::new(&make_tmp_path());
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let chunks: Vec<ShardChunk> = (1..=100).map(shard_chunk).collect();
+        shard_store.put_shard_chunks_batch(&chunks).unwrap();
+
+        // A peer at height 30 still needs chunks from 30 on
+        let policy = RetentionPolicy::KeepLast(10);
+        assert_eq!(
+            policy.prune_shard_store(&shard_store, 0, Some(30)).unwrap(),
+            29
+        );
+        let policy = RetentionPolicy::KeepSince(Duration::from_secs(10));
+        assert_eq!(
+            policy
+                .prune_shard_store(&shard_store, 100, Some(40))
+                .unwrap(),
+            10
+        );
+        assert_eq!(
+            shard_store.get_shard_chunks(0, None).unwrap()[0],
+            shard_chunk(40)
+        );
+
+        shard_store.db.destroy().unwrap();
+    }
+}
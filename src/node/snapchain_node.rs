@@ -1,32 +1,317 @@
 use crate::consensus::consensus::{Config, Consensus, ConsensusMsg, ConsensusParams, Decision};
-use crate::consensus::proposer::{BlockProposer, ShardProposer};
-use crate::consensus::validator::ShardValidator;
+use crate::consensus::metrics::ConsensusMetrics;
+use crate::consensus::peer_scores::PeerScores;
+use crate::consensus::proposer::{ActiveShards, BlockProposer, ShardAssignment, ShardProposer};
+use crate::consensus::validator::{ShardValidator, ValidatorSets};
+use crate::core::buffer_pool;
 use crate::core::types::{
     Address, Height, ShardId, SnapchainShard, SnapchainValidator, SnapchainValidatorContext,
     SnapchainValidatorSet,
 };
 use crate::network::gossip::GossipEvent;
+use crate::network::server::ShardMessageSenders;
+use crate::node::consistency::{self, ConsistencyError, ConsistencyReport};
+use crate::node::dedup::MessageDedup;
+use crate::node::liveness::{spawn_liveness_watchdog, LivenessStall};
+use crate::node::retention::{spawn_retention_task, RetentionPolicy};
 use crate::proto::message;
 use crate::proto::snapchain::Block;
-use crate::storage::db::RocksDB;
+use crate::storage::db::{RocksDB, RocksdbError};
+use crate::storage::store::commit_log::{CommitLog, CommitLogError};
 use crate::storage::store::engine::{BlockEngine, ShardEngine};
-use crate::storage::store::shard::ShardStore;
+use crate::storage::store::shard::{ShardStorageError, ShardStore};
 use crate::storage::store::BlockStore;
-use libp2p::identity::ed25519::Keypair;
+use libp2p::identity::ed25519::{Keypair, PublicKey};
 use malachite_config::TimeoutConfig;
-use malachite_metrics::Metrics;
-use ractor::ActorRef;
+use malachite_metrics::{Metrics, SharedRegistry};
+use ractor::{ActorRef, ActorStatus, MessagingErr};
 use std::collections::{BTreeMap, HashMap};
-use std::sync::Arc;
-use tokio::sync::mpsc;
-use tracing::warn;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{error, warn};
 
 const MAX_SHARDS: u32 = 3;
 
+// A failed cast to an actor that is still running is retried a few times before the message is dropped
+const CAST_RETRIES: u32 = 3;
+const CAST_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CastOutcome {
+    Delivered { attempts: u32 },
+    // The actor kept rejecting the message until retries ran out
+    Dropped { attempts: u32 },
+    // Stopped or stopping, retrying can't help
+    ActorDead,
+    NoActor,
+}
+
+async fn cast_with_retry<M, F, A>(mut msg: M, mut send: F, is_alive: A) -> CastOutcome
+where
+    F: FnMut(M) -> Result<(), MessagingErr<M>>,
+    A: Fn() -> bool,
+{
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match send(msg) {
+            Ok(()) => return CastOutcome::Delivered { attempts },
+            // The rejected message is handed back, so it can be sent again
+            Err(MessagingErr::SendErr(rejected)) if is_alive() => {
+                if attempts > CAST_RETRIES {
+                    return CastOutcome::Dropped { attempts };
+                }
+                msg = rejected;
+                tokio::time::sleep(CAST_RETRY_DELAY).await;
+            }
+            Err(_) => return CastOutcome::ActorDead,
+        }
+    }
+}
+
+async fn cast_to_actor(
+    actor: &ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
+    msg: ConsensusMsg<SnapchainValidatorContext>,
+) -> CastOutcome {
+    cast_with_retry(
+        msg,
+        |msg| actor.cast(msg),
+        || {
+            !matches!(
+                actor.get_status(),
+                ActorStatus::Stopping | ActorStatus::Stopped
+            )
+        },
+    )
+    .await
+}
+
+#[derive(Error, Debug)]
+pub enum SnapchainNodeError {
+    #[error("Unable to create data directory {path}: {source}")]
+    DataDirCreateError {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Data directory {path} is not writable: {source}")]
+    DataDirNotWritable {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("Unable to open db at {path}: {source}")]
+    DbOpenError { path: String, source: RocksdbError },
+
+    #[error("Shard {shard_id} isn't running on this node")]
+    ShardNotRunning { shard_id: u32 },
+
+    #[error("Unable to spawn consensus actor for shard {shard_id}: {source}")]
+    ActorSpawnError {
+        shard_id: u32,
+        source: ractor::SpawnErr,
+    },
+
+    #[error("Unable to open commit log: {0}")]
+    CommitLogError(#[from] CommitLogError),
+
+    #[error("Unable to recover shard {shard_id} from a partial commit: {source}")]
+    RecoveryError {
+        shard_id: u32,
+        source: ShardStorageError,
+    },
+}
+
+// Where a committed message is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxLocation {
+    pub shard_id: u32,
+    pub block_number: u64,
+    pub tx_index: usize,
+}
+
+pub fn shard_db_path(rocksdb_dir: &str, shard_id: u32) -> String {
+    format!("{}/shard{}", rocksdb_dir, shard_id)
+}
+
+// Make sure the data directory and the per-shard subdirectories exist and are writable
+pub fn prepare_data_dir(rocksdb_dir: &str, shard_ids: &[u32]) -> Result<(), SnapchainNodeError> {
+    fs::create_dir_all(rocksdb_dir).map_err(|source| SnapchainNodeError::DataDirCreateError {
+        path: rocksdb_dir.to_string(),
+        source,
+    })?;
+
+    let probe_path = Path::new(rocksdb_dir).join(".write_probe");
+    fs::write(&probe_path, []).map_err(|source| SnapchainNodeError::DataDirNotWritable {
+        path: rocksdb_dir.to_string(),
+        source,
+    })?;
+    let _ = fs::remove_file(&probe_path);
+
+    for shard_id in shard_ids {
+        let path = shard_db_path(rocksdb_dir, *shard_id);
+        fs::create_dir_all(&path)
+            .map_err(|source| SnapchainNodeError::DataDirCreateError { path, source })?;
+    }
+
+    Ok(())
+}
+
+// The configured validators plus the local validator. Peers are added without an rpc address, which is
+// filled in once they register.
+pub fn validator_set_for_shard(
+    shard: &SnapchainShard,
+    public_key: PublicKey,
+    rpc_address: Option<String>,
+    current_height: u64,
+    validator_public_keys: &[PublicKey],
+) -> SnapchainValidatorSet {
+    let mut validator_set = SnapchainValidatorSet::new(vec![SnapchainValidator::new(
+        shard.clone(),
+        public_key,
+        rpc_address,
+        current_height,
+    )]);
+    for peer_public_key in validator_public_keys {
+        validator_set.add(SnapchainValidator::new(
+            shard.clone(),
+            peer_public_key.clone(),
+            None,
+            0,
+        ));
+    }
+    validator_set
+}
+
+// The quorum threshold is derived by malachite from the voting power of the full validator set
+pub fn consensus_params_for_shard(
+    shard: &SnapchainShard,
+    validator_set: SnapchainValidatorSet,
+    address: &Address,
+) -> ConsensusParams<SnapchainValidatorContext> {
+    ConsensusParams {
+        start_height: Height::new(shard.shard_id(), 1),
+        initial_validator_set: validator_set,
+        address: address.clone(),
+        threshold_params: Default::default(),
+    }
+}
+
+// Everything needed to (re)spawn the consensus actor of a shard from its store
+struct ShardActorSpawner {
+    keypair: Keypair,
+    config: Config,
+    rpc_address: Option<String>,
+    validator_address: Address,
+    validator_public_keys: Vec<PublicKey>,
+    gossip_tx: mpsc::Sender<GossipEvent<SnapchainValidatorContext>>,
+    shard_decision_tx: mpsc::Sender<Decision>,
+    metrics: Metrics,
+    consensus_metrics: ConsensusMetrics,
+    shard_assignment: ShardAssignment,
+    validator_sets: ValidatorSets,
+}
+
+impl ShardActorSpawner {
+    async fn spawn(
+        &self,
+        shard_id: u32,
+        shard_store: ShardStore,
+        current_height: u64,
+    ) -> Result<
+        (
+            ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
+            mpsc::Sender<message::Message>,
+        ),
+        SnapchainNodeError,
+    > {
+        let shard = SnapchainShard::new(shard_id);
+        let shard_validator_set = validator_set_for_shard(
+            &shard,
+            self.keypair.public(),
+            self.rpc_address.clone(),
+            current_height,
+            &self.validator_public_keys,
+        );
+        let shard_consensus_params =
+            consensus_params_for_shard(&shard, shard_validator_set, &self.validator_address);
+        let ctx = SnapchainValidatorContext::new(self.keypair.clone());
+        let mut engine = ShardEngine::new(shard_id, shard_store)
+            .with_mempool_capacity(self.config.mempool_capacity);
+        if self.config.profiling {
+            engine = engine.with_profiling(self.consensus_metrics.clone());
+        }
+        // Finish or roll back a commit the last run crashed in the middle of
+        engine
+            .recover()
+            .map_err(|source| SnapchainNodeError::RecoveryError { shard_id, source })?;
+
+        let messages_tx = engine.messages_tx();
+
+        let shard_proposer = ShardProposer::new(
+            self.validator_address.clone(),
+            shard.clone(),
+            engine,
+            Some(self.shard_decision_tx.clone()),
+            self.config.propose_value_delay,
+            self.consensus_metrics.clone(),
+            self.shard_assignment.clone(),
+        )
+        .with_header_hash_len(self.config.header_hash_len)
+        .with_proposal_ttl(self.config.proposal_ttl);
+
+        let shard_validator = ShardValidator::new(
+            self.validator_address.clone(),
+            shard.clone(),
+            None,
+            Some(shard_proposer),
+            self.consensus_metrics.clone(),
+        )
+        .with_max_rounds_per_height(self.config.max_rounds_per_height)
+        .with_propose_jitter(self.config.propose_jitter.clone())
+        .with_validator_sets(self.validator_sets.clone());
+        let consensus_actor = Consensus::spawn(
+            ctx,
+            shard,
+            shard_consensus_params,
+            TimeoutConfig::default(),
+            self.metrics.clone(),
+            self.gossip_tx.clone(),
+            shard_validator,
+        )
+        .await
+        .map_err(|source| SnapchainNodeError::ActorSpawnError { shard_id, source })?;
+
+        Ok((consensus_actor, messages_tx))
+    }
+}
+
 pub struct SnapchainNode {
     pub consensus_actors: BTreeMap<u32, ActorRef<ConsensusMsg<SnapchainValidatorContext>>>,
-    pub messages_tx_by_shard: HashMap<u32, mpsc::Sender<message::Message>>,
+    pub messages_tx_by_shard: ShardMessageSenders,
+    pub shard_assignment: ShardAssignment,
     pub address: Address,
+    pub consensus_metrics: ConsensusMetrics,
+    pub block_store: BlockStore,
+    pub shard_stores: HashMap<u32, ShardStore>,
+    pub validator_sets: ValidatorSets,
+    /// How peers behaved when the block proposer synced from them
+    pub peer_scores: PeerScores,
+    /// Shards that couldn't be started, only non-empty when partial shards are allowed
+    pub failed_shards: Vec<u32>,
+    sync_cancelled: Arc<AtomicBool>,
+    shard_spawner: ShardActorSpawner,
+    active_shards: ActiveShards,
+    dedup: Mutex<MessageDedup>,
+    confirmed_block_tx: broadcast::Sender<Block>,
+    commit_log: CommitLog,
+    address_prefix_len: usize,
 }
 
 impl SnapchainNode {
@@ -38,14 +323,45 @@ impl SnapchainNode {
         block_tx: mpsc::Sender<Block>,
         block_store: BlockStore,
         rocksdb_dir: String,
-    ) -> Self {
+    ) -> Result<Self, SnapchainNodeError> {
         let validator_address = Address(keypair.public().to_bytes());
+        let validator_public_keys = config.validator_public_keys();
+
+        prepare_data_dir(&rocksdb_dir, &config.shard_ids())?;
+        buffer_pool::set_max_pooled_buffers(config.encode_buffer_pool_size);
 
         let mut consensus_actors = BTreeMap::new();
+        let consensus_metrics = ConsensusMetrics::new();
+        // One set of consensus metrics for all the actors, so they're aggregated in one registry
+        let metrics = Metrics::register(SharedRegistry::global());
+        let validator_sets = ValidatorSets::new();
+        let peer_scores = PeerScores::new();
+        let sync_cancelled = Arc::new(AtomicBool::new(false));
 
         let (shard_decision_tx, shard_decision_rx) = mpsc::channel::<Decision>(100);
 
-        let mut shard_messages: HashMap<u32, mpsc::Sender<message::Message>> = HashMap::new();
+        let shard_messages = ShardMessageSenders::new();
+        let mut shard_stores: HashMap<u32, ShardStore> = HashMap::new();
+        let mut failed_shards = vec![];
+
+        let assignment_config = config.clone();
+        let shard_assignment: ShardAssignment = Arc::new(move |fid, block_number| {
+            assignment_config.shard_for_fid_at_height(fid, block_number)
+        });
+
+        let shard_spawner = ShardActorSpawner {
+            keypair: keypair.clone(),
+            config: config.clone(),
+            rpc_address: rpc_address.clone(),
+            validator_address: validator_address.clone(),
+            validator_public_keys: validator_public_keys.clone(),
+            gossip_tx: gossip_tx.clone(),
+            shard_decision_tx,
+            metrics: metrics.clone(),
+            consensus_metrics: consensus_metrics.clone(),
+            shard_assignment: shard_assignment.clone(),
+            validator_sets: validator_sets.clone(),
+        };
 
         // Create the shard validators
         for shard_id in config.shard_ids() {
@@ -59,56 +375,33 @@ impl SnapchainNode {
                 Err(_) => 0,
                 Ok(height) => height,
             };
-            let shard = SnapchainShard::new(shard_id);
-            let shard_validator = SnapchainValidator::new(
-                shard.clone(),
-                keypair.public().clone(),
-                rpc_address.clone(),
-                current_height,
-            );
-            let shard_validator_set = SnapchainValidatorSet::new(vec![shard_validator]);
-            let shard_consensus_params = ConsensusParams {
-                start_height: Height::new(shard.shard_id(), 1),
-                initial_validator_set: shard_validator_set,
-                address: validator_address.clone(),
-                threshold_params: Default::default(),
+            let db_path = shard_db_path(&rocksdb_dir, shard_id);
+            let db = match RocksDB::open_with_options(&db_path, config.store_tuning.clone()) {
+                Ok(db) => db,
+                Err(source) if config.allow_partial_shards => {
+                    error!(
+                        shard_id,
+                        path = db_path,
+                        "Unable to open shard db, starting without this shard: {source}"
+                    );
+                    failed_shards.push(shard_id);
+                    continue;
+                }
+                Err(source) => {
+                    return Err(SnapchainNodeError::DbOpenError {
+                        path: db_path.clone(),
+                        source,
+                    })
+                }
             };
-            let ctx = SnapchainValidatorContext::new(keypair.clone());
-            let db = RocksDB::new(format!("{}/shard{}", rocksdb_dir, shard_id).as_str());
-            db.open().unwrap();
-            let shard_store = ShardStore::new(db);
-            let engine = ShardEngine::new(shard_id, shard_store);
-
-            let messages_tx = engine.messages_tx();
-
-            let shard_proposer = ShardProposer::new(
-                validator_address.clone(),
-                shard.clone(),
-                engine,
-                Some(shard_decision_tx.clone()),
-                config.propose_value_delay,
-            );
+            let shard_store =
+                ShardStore::new(db).with_skip_corrupt_chunks(config.skip_corrupt_chunks);
+            shard_stores.insert(shard_id, shard_store.clone());
+            let (consensus_actor, messages_tx) = shard_spawner
+                .spawn(shard_id, shard_store, current_height)
+                .await?;
 
             shard_messages.insert(shard_id, messages_tx);
-
-            let shard_validator = ShardValidator::new(
-                validator_address.clone(),
-                shard.clone(),
-                None,
-                Some(shard_proposer),
-            );
-            let consensus_actor = Consensus::spawn(
-                ctx,
-                shard.clone(),
-                shard_consensus_params,
-                TimeoutConfig::default(),
-                Metrics::new(),
-                gossip_tx.clone(),
-                shard_validator,
-            )
-            .await
-            .unwrap();
-
             consensus_actors.insert(shard_id, consensus_actor);
         }
 
@@ -120,44 +413,59 @@ impl SnapchainNode {
             Ok(height) => height,
         };
         // We might want to use different keys for the block shard so signatures are different and cannot be accidentally used in the wrong shard
-        let block_validator = SnapchainValidator::new(
-            block_shard.clone(),
-            keypair.public().clone(),
+        let block_validator_set = validator_set_for_shard(
+            &block_shard,
+            keypair.public(),
             rpc_address.clone(),
             current_height,
+            &validator_public_keys,
         );
-        let block_validator_set = SnapchainValidatorSet::new(vec![block_validator]);
-
-        let block_consensus_params = ConsensusParams {
-            start_height: Height::new(block_shard.shard_id(), 1),
-            initial_validator_set: block_validator_set,
-            address: validator_address.clone(),
-            threshold_params: Default::default(),
-        };
+        let block_consensus_params =
+            consensus_params_for_shard(&block_shard, block_validator_set, &validator_address);
 
         let engine = BlockEngine::new(block_store.clone());
+        let (confirmed_block_tx, _) = broadcast::channel(100);
+        let commit_log = block_store.commit_log()?;
+        let active_shards = ActiveShards::new(shard_stores.keys().cloned());
 
         let block_proposer = BlockProposer::new(
             validator_address.clone(),
             block_shard.clone(),
             shard_decision_rx,
-            config.num_shards(),
+            // Only the shards that started will send chunks for blocks
+            shard_stores.len() as u32,
             block_tx,
+            None,
             engine,
-        );
+            config.max_sync_lookback,
+            consensus_metrics.clone(),
+        )
+        .with_header_hash_len(config.header_hash_len)
+        .with_proposal_ttl(config.proposal_ttl)
+        .with_peer_scores(peer_scores.clone())
+        .with_chunk_wait_policy(config.chunk_wait_policy.clone())
+        .with_active_shards(active_shards.clone())
+        .with_confirmed_blocks(config.confirmation_depth, confirmed_block_tx.clone())
+        .with_commit_log(commit_log.clone())
+        .with_sync_limiter(Arc::new(Semaphore::new(config.max_concurrent_syncs)))
+        .with_sync_cancel(sync_cancelled.clone());
         let block_validator = ShardValidator::new(
             validator_address.clone(),
             block_shard.clone(),
             Some(block_proposer),
             None,
-        );
+            consensus_metrics.clone(),
+        )
+        .with_max_rounds_per_height(config.max_rounds_per_height)
+        .with_propose_jitter(config.propose_jitter.clone())
+        .with_validator_sets(validator_sets.clone());
         let ctx = SnapchainValidatorContext::new(keypair.clone());
         let block_consensus_actor = Consensus::spawn(
             ctx,
             block_shard,
             block_consensus_params,
             TimeoutConfig::default(),
-            Metrics::new(),
+            metrics,
             gossip_tx.clone(),
             block_validator,
         )
@@ -165,42 +473,703 @@ impl SnapchainNode {
         .unwrap();
         consensus_actors.insert(0, block_consensus_actor);
 
-        Self {
+        Ok(Self {
             consensus_actors,
             messages_tx_by_shard: shard_messages,
+            shard_assignment,
             address: validator_address,
-        }
+            consensus_metrics,
+            block_store,
+            shard_stores,
+            validator_sets,
+            peer_scores,
+            failed_shards,
+            sync_cancelled,
+            shard_spawner,
+            active_shards,
+            dedup: Mutex::new(MessageDedup::new(config.dedup_cache_size)),
+            confirmed_block_tx,
+            commit_log,
+            address_prefix_len: config.address_prefix_len,
+        })
     }
 
     pub fn id(&self) -> String {
-        self.address.prefix()
+        self.address.prefix_len(self.address_prefix_len)
     }
 
     pub fn stop(&self) {
+        // Interrupt any in-progress sync, blocks applied so far are kept
+        self.sync_cancelled.store(true, Ordering::SeqCst);
+
         // Stop all actors
         for (_, actor) in self.consensus_actors.iter() {
             actor.stop(None);
         }
     }
 
-    pub fn start_height(&self, block_number: u64) {
+    pub async fn start_height(&self, block_number: u64) -> BTreeMap<u32, CastOutcome> {
+        let mut outcomes = BTreeMap::new();
         for (shard, actor) in self.consensus_actors.iter() {
-            let result = actor.cast(ConsensusMsg::StartHeight(Height::new(*shard, block_number)));
-            if let Err(e) = result {
-                warn!("Failed to start height: {:?}", e);
+            let msg = ConsensusMsg::StartHeight(Height::new(*shard, block_number));
+            let outcome = cast_to_actor(actor, msg).await;
+            if !matches!(outcome, CastOutcome::Delivered { .. }) {
+                warn!("Failed to start height on shard {}: {:?}", shard, outcome);
             }
+            outcomes.insert(*shard, outcome);
         }
+        outcomes
     }
 
-    pub fn dispatch(&self, msg: ConsensusMsg<SnapchainValidatorContext>) {
-        let shard_id = msg.shard_id();
+    // Register a known set of peers, e.g. the genesis validators, syncing each shard once from its
+    // furthest ahead peer instead of once per peer
+    pub fn register_validators(&self, validators: Vec<SnapchainValidator>) {
+        let mut by_shard: BTreeMap<u32, Vec<SnapchainValidator>> = BTreeMap::new();
+        for validator in validators {
+            by_shard
+                .entry(validator.shard_index)
+                .or_default()
+                .push(validator);
+        }
+        for (shard_id, validators) in by_shard {
+            self.cast_to_shard(shard_id, ConsensusMsg::RegisterValidators(validators));
+        }
+    }
+
+    // Stop producing new heights on a single shard, other shards keep running
+    // The block proposer stops waiting for its chunks until it's resumed
+    pub fn pause_shard(&self, shard_id: u32) {
+        self.cast_to_shard(shard_id, ConsensusMsg::PauseProposing);
+        self.active_shards.deactivate(shard_id);
+    }
+
+    pub fn resume_shard(&self, shard_id: u32) {
+        self.cast_to_shard(shard_id, ConsensusMsg::ResumeProposing);
+        if self.shard_stores.contains_key(&shard_id) {
+            self.active_shards.activate(shard_id);
+        }
+    }
+
+    // Replace the consensus actor of a shard, e.g. after it panicked, without restarting the node. The
+    // new actor is rebuilt from the shard store and starts at the height after the confirmed one.
+    pub async fn restart_shard(&mut self, shard_id: u32) -> Result<Height, SnapchainNodeError> {
+        let shard_store = self
+            .shard_stores
+            .get(&shard_id)
+            .cloned()
+            .ok_or(SnapchainNodeError::ShardNotRunning { shard_id })?;
+
+        if let Some(actor) = self.consensus_actors.remove(&shard_id) {
+            // Make sure the old actor no longer touches the store before the new one starts
+            if let Err(e) = actor
+                .stop_and_wait(Some("restarting shard".to_string()), None)
+                .await
+            {
+                warn!("Failed to stop actor for shard {}: {:?}", shard_id, e);
+            }
+        }
+
+        let confirmed_height = Height::new(shard_id, shard_store.max_block_number().unwrap_or(0));
+        let (consensus_actor, messages_tx) = self
+            .shard_spawner
+            .spawn(shard_id, shard_store, confirmed_height.block_number)
+            .await?;
+        self.consensus_actors.insert(shard_id, consensus_actor);
+        self.messages_tx_by_shard.insert(shard_id, messages_tx);
+
+        let next_height = confirmed_height.increment();
+        self.cast_to_shard(shard_id, ConsensusMsg::StartHeight(next_height));
+        Ok(next_height)
+    }
+
+    // Stop the shard once it reaches the block number, until unfreeze_shard is called
+    pub fn freeze_at_height(&self, shard_id: u32, block_number: u64) {
+        self.cast_to_shard(shard_id, ConsensusMsg::FreezeAtHeight(block_number));
+    }
+
+    pub fn unfreeze_shard(&self, shard_id: u32) {
+        self.cast_to_shard(shard_id, ConsensusMsg::Unfreeze);
+    }
+
+    fn cast_to_shard(&self, shard_id: u32, msg: ConsensusMsg<SnapchainValidatorContext>) {
         if let Some(actor) = self.consensus_actors.get(&shard_id) {
-            let result = actor.cast(msg);
-            if let Err(e) = result {
-                warn!("Failed to forward message to actor: {:?}", e);
+            if let Err(e) = actor.cast(msg) {
+                warn!("Failed to send message to shard {}: {:?}", shard_id, e);
+            }
+        } else {
+            warn!("No actor found for shard {}", shard_id);
+        }
+    }
+
+    // Check that every shard chunk embedded in the blocks in [start_block_number, stop_block_number)
+    // is present in the matching shard store
+    pub fn verify_store_consistency(
+        &self,
+        start_block_number: u64,
+        stop_block_number: u64,
+    ) -> Result<ConsistencyReport, ConsistencyError> {
+        consistency::verify_store_consistency(
+            &self.block_store,
+            &self.shard_stores,
+            start_block_number,
+            stop_block_number,
+        )
+    }
+
+    // Report shards (including the block shard) that stop deciding heights, e.g. during a partition
+    pub fn start_liveness_watchdog(
+        &self,
+        stall_timeout: Duration,
+    ) -> mpsc::Receiver<LivenessStall> {
+        let (stall_tx, stall_rx) = mpsc::channel(100);
+        spawn_liveness_watchdog(
+            self.consensus_metrics.clone(),
+            self.consensus_actors.keys().cloned().collect(),
+            stall_timeout,
+            Duration::from_secs(1),
+            stall_tx,
+        );
+        stall_rx
+    }
+
+    // Receipt for a submitted message: the shard chunk and transaction that committed it, looked up in
+    // every local shard store. None when no local shard has committed it (yet).
+    pub fn find_transaction(&self, message_hash: &[u8]) -> Option<TxLocation> {
+        let mut shard_ids: Vec<u32> = self.shard_stores.keys().cloned().collect();
+        shard_ids.sort();
+        shard_ids.into_iter().find_map(|shard_id| {
+            match self.shard_stores[&shard_id].find_message(message_hash) {
+                Ok(location) => location.map(|(block_number, tx_index)| TxLocation {
+                    shard_id,
+                    block_number,
+                    tx_index,
+                }),
+                Err(err) => {
+                    warn!(shard_id, "Unable to look up message: {}", err);
+                    None
+                }
             }
+        })
+    }
+
+    // Lowest height a known peer of the shard reported, pruning the shard below it is safe as lagging
+    // peers can still sync everything from it on. None when no other validators are known.
+    pub fn min_safe_prune_height(&self, shard_id: u32) -> Option<u64> {
+        self.validator_sets.min_peer_height(shard_id, &self.address)
+    }
+
+    // Prune old blocks and shard chunks according to the policy, every store is pruned independently.
+    // Nothing from min_safe_prune_height on is pruned.
+    pub fn start_retention(&self, policy: RetentionPolicy) -> JoinHandle<()> {
+        let validator_sets = self.validator_sets.clone();
+        let address = self.address.clone();
+        spawn_retention_task(
+            policy,
+            self.block_store.clone(),
+            self.shard_stores.clone(),
+            Duration::from_secs(60),
+            move |shard_id| validator_sets.min_peer_height(shard_id, &address),
+        )
+    }
+
+    // Blocks once they're `confirmation_depth` blocks behind the tip. Blocks decided before
+    // subscribing aren't replayed, and a subscriber falling too far behind misses blocks.
+    pub fn subscribe_confirmed_blocks(&self) -> broadcast::Receiver<Block> {
+        self.confirmed_block_tx.subscribe()
+    }
+
+    // Durable log of committed blocks, for consumers that need to resume after a restart
+    pub fn commit_log(&self) -> &CommitLog {
+        &self.commit_log
+    }
+
+    // Consensus messages dropped by dispatch because they were already received
+    pub fn duplicate_messages_dropped(&self) -> u64 {
+        self.dedup.lock().unwrap().duplicates_dropped()
+    }
+
+    // None when the message is dropped as a duplicate
+    pub async fn dispatch(
+        &self,
+        msg: ConsensusMsg<SnapchainValidatorContext>,
+    ) -> Option<CastOutcome> {
+        if self.dedup.lock().unwrap().is_duplicate(&msg) {
+            return None;
+        }
+        let shard_id = msg.shard_id();
+        let outcome = if let Some(actor) = self.consensus_actors.get(&shard_id) {
+            cast_to_actor(actor, msg).await
         } else {
-            warn!("No actor found for shard, could not forward message");
+            CastOutcome::NoActor
+        };
+        if !matches!(outcome, CastOutcome::Delivered { .. }) {
+            warn!(
+                "Failed to forward message to actor for shard {}: {:?}",
+                shard_id, outcome
+            );
+        }
+        Some(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::server::MySnapchainService;
+    use crate::proto::rpc::snapchain_service_client::SnapchainServiceClient;
+    use crate::proto::rpc::snapchain_service_server::{SnapchainService, SnapchainServiceServer};
+    use crate::proto::snapchain::{BlockHeader, FullProposal, ShardChunk, ShardHeader};
+    use malachite_common::ValidatorSet;
+    use ractor::ActorStatus;
+    use tonic::transport::Server;
+
+    #[test]
+    fn test_prepare_data_dir_creates_shard_dirs() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let rocksdb_dir = tmp_dir.path().join("rocks");
+        let rocksdb_dir = rocksdb_dir.to_str().unwrap();
+
+        prepare_data_dir(rocksdb_dir, &[1, 2]).unwrap();
+
+        assert!(Path::new(&shard_db_path(rocksdb_dir, 1)).is_dir());
+        assert!(Path::new(&shard_db_path(rocksdb_dir, 2)).is_dir());
+    }
+
+    async fn create_node(
+        config: Config,
+        rocksdb_dir: &str,
+    ) -> Result<SnapchainNode, SnapchainNodeError> {
+        let (gossip_tx, _gossip_rx) = mpsc::channel(100);
+        let (block_tx, _block_rx) = mpsc::channel(100);
+        let block_dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(RocksDB::new(block_dir.path().to_str().unwrap()));
+        db.open().unwrap();
+        SnapchainNode::create(
+            Keypair::generate(),
+            config,
+            None,
+            gossip_tx,
+            block_tx,
+            BlockStore::new(db),
+            rocksdb_dir.to_string(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_partial_shards_skip_failed_shard_db() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let rocksdb_dir = tmp_dir.path().to_str().unwrap();
+        prepare_data_dir(rocksdb_dir, &[1, 2]).unwrap();
+        // Holding the lock on shard 2's db makes opening it again fail
+        let locked_db = RocksDB::new(&shard_db_path(rocksdb_dir, 2));
+        locked_db.open().unwrap();
+
+        let config = Config::default().with_shard_ids(vec![2]);
+        let result = create_node(config, rocksdb_dir).await;
+        assert!(matches!(
+            result,
+            Err(SnapchainNodeError::DbOpenError { .. })
+        ));
+
+        let config = Config {
+            allow_partial_shards: true,
+            ..Config::default().with_shard_ids(vec![1, 2])
+        };
+        let node = create_node(config, rocksdb_dir).await.unwrap();
+        assert_eq!(node.failed_shards, vec![2]);
+        assert_eq!(node.shard_stores.keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(
+            node.consensus_actors.keys().collect::<Vec<_>>(),
+            vec![&0, &1]
+        );
+        assert!(!node.messages_tx_by_shard.contains(2));
+
+        node.stop();
+        locked_db.close();
+    }
+
+    #[tokio::test]
+    async fn test_restart_shard_resumes_at_confirmed_height() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = Config::default().with_shard_ids(vec![1]);
+        let mut node = create_node(config, tmp_dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+        for block_number in 1..=3 {
+            node.shard_stores[&1]
+                .put_shard_chunk(ShardChunk {
+                    header: Some(ShardHeader {
+                        height: Some(Height::new(1, block_number)),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+
+        let old_actor = node.consensus_actors[&1].clone();
+        old_actor.stop_and_wait(None, None).await.unwrap();
+
+        let next_height = node.restart_shard(1).await.unwrap();
+        assert_eq!(next_height, Height::new(1, 4));
+        assert_eq!(old_actor.get_status(), ActorStatus::Stopped);
+        assert_eq!(node.consensus_actors[&1].get_status(), ActorStatus::Running);
+        assert_ne!(node.consensus_actors[&1].get_id(), old_actor.get_id());
+        assert!(!node.messages_tx_by_shard.get(1).unwrap().is_closed());
+
+        assert!(matches!(
+            node.restart_shard(2).await,
+            Err(SnapchainNodeError::ShardNotRunning { shard_id: 2 })
+        ));
+
+        node.stop();
+    }
+
+    #[tokio::test]
+    async fn test_submit_message_after_restart_shard() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = Config::default().with_shard_ids(vec![1]);
+        let mut node = create_node(config, tmp_dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+        // Built once from the node, like the rpc server in main
+        let service = MySnapchainService::new(
+            node.address.clone(),
+            node.block_store.clone(),
+            node.shard_stores.clone(),
+            node.validator_sets.clone(),
+            node.messages_tx_by_shard.clone(),
+            node.shard_assignment.clone(),
+        );
+        let old_messages_tx = node.messages_tx_by_shard.get(1).unwrap();
+
+        node.restart_shard(1).await.unwrap();
+        assert!(old_messages_tx.is_closed());
+
+        let message = message::Message {
+            data: Some(message::MessageData {
+                fid: 1,
+                ..Default::default()
+            }),
+            hash: vec![1],
+            ..Default::default()
+        };
+        service
+            .submit_message(tonic::Request::new(message))
+            .await
+            .unwrap();
+
+        node.stop();
+    }
+
+    #[tokio::test]
+    async fn test_find_transaction() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = Config::default().with_shard_ids(vec![1, 2]);
+        let node = create_node(config, tmp_dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+
+        // Consensus isn't started, commit through an engine of its own on shard 2's store
+        let mut engine = ShardEngine::new(2, node.shard_stores[&2].clone());
+        let message = message::Message {
+            data: Some(message::MessageData {
+                fid: 2,
+                ..Default::default()
+            }),
+            hash: vec![7; 20],
+            ..Default::default()
+        };
+        engine.messages_tx().send(message).await.unwrap();
+        let state_change = engine.propose_state_change(2).await;
+        assert!(engine.commit_shard_chunk(ShardChunk {
+            header: Some(ShardHeader {
+                height: Some(Height::new(2, 1)),
+                shard_root: state_change.new_state_root,
+                ..Default::default()
+            }),
+            transactions: state_change.transactions,
+            ..Default::default()
+        }));
+
+        assert_eq!(
+            node.find_transaction(&[7; 20]),
+            Some(TxLocation {
+                shard_id: 2,
+                block_number: 1,
+                tx_index: 0,
+            })
+        );
+        assert_eq!(node.find_transaction(&[8; 20]), None);
+
+        node.stop();
+    }
+
+    #[tokio::test]
+    async fn test_register_validators_syncs_from_furthest_peer() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = Config::default().with_shard_ids(vec![1]);
+        let node = create_node(config, tmp_dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+        let peers: Vec<SnapchainValidator> = [5, 20, 12]
+            .into_iter()
+            .map(|current_height| {
+                SnapchainValidator::new(
+                    SnapchainShard::new(0),
+                    Keypair::generate().public(),
+                    Some("0.0.0.0:0".to_string()),
+                    current_height,
+                )
+            })
+            .collect();
+
+        node.register_validators(peers.clone());
+
+        // Nothing listens on the rpc address, the failed sync attempt still shows up in the scores
+        let furthest = peers[1].address.clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node.peer_scores.stats(&furthest).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no sync from the furthest ahead peer");
+        assert!(node.peer_scores.stats(&peers[0].address).is_none());
+        assert!(node.peer_scores.stats(&peers[2].address).is_none());
+
+        node.stop();
+    }
+
+    // Serves blocks 1 to num_blocks over rpc like a peer that's far ahead, returns its rpc address
+    async fn serve_blocks(num_blocks: u64) -> (String, Arc<RocksDB>) {
+        let db_dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(RocksDB::new(db_dir.path().to_str().unwrap()));
+        db.open().unwrap();
+        let block_store = BlockStore::new(db.clone());
+        for block_number in 1..=num_blocks {
+            block_store
+                .put_block(Block {
+                    header: Some(BlockHeader {
+                        height: Some(Height::new(0, block_number)),
+                        parent_hash: (block_number - 1).to_be_bytes().to_vec(),
+                        ..Default::default()
+                    }),
+                    hash: block_number.to_be_bytes().to_vec(),
+                    ..Default::default()
+                })
+                .unwrap();
         }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let rpc_address = listener.local_addr().unwrap();
+        drop(listener);
+        let service = MySnapchainService::new(
+            Address([9; 32]),
+            block_store,
+            HashMap::new(),
+            ValidatorSets::new(),
+            ShardMessageSenders::new(),
+            Arc::new(|_, _| 1),
+        );
+        tokio::spawn(
+            Server::builder()
+                .add_service(SnapchainServiceServer::new(service))
+                .serve(rpc_address),
+        );
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while SnapchainServiceClient::connect(format!("http://{}", rpc_address))
+                .await
+                .is_err()
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("rpc server didn't start");
+        (rpc_address.to_string(), db)
+    }
+
+    #[tokio::test]
+    async fn test_stop_interrupts_sync() {
+        let num_blocks = 200 * crate::consensus::proposer::SYNC_BATCH_SIZE;
+        let (rpc_address, peer_db) = serve_blocks(num_blocks).await;
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let node = create_node(Config::default(), tmp_dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+
+        node.register_validators(vec![SnapchainValidator::new(
+            SnapchainShard::new(0),
+            Keypair::generate().public(),
+            Some(rpc_address),
+            num_blocks,
+        )]);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while node.block_store.max_block_number(0).unwrap() == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("sync didn't start");
+
+        node.stop();
+        // The block actor only stops once the sync returns, which happens at the next batch boundary
+        let block_actor = node.consensus_actors[&0].clone();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while block_actor.get_status() != ActorStatus::Stopped {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("block actor didn't stop");
+        assert!(node.block_store.max_block_number(0).unwrap() < num_blocks);
+
+        peer_db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_drops_duplicate_messages() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let config = Config::default().with_shard_ids(vec![1]);
+        let node = create_node(config, tmp_dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+        // Addressed to a shard without an actor, so nothing is processed past the dedup check
+        let msg = ConsensusMsg::ReceivedFullProposal(FullProposal {
+            height: Some(Height::new(2, 1)),
+            ..Default::default()
+        });
+
+        assert_eq!(node.dispatch(msg.clone()).await, Some(CastOutcome::NoActor));
+        assert_eq!(node.duplicate_messages_dropped(), 0);
+        assert_eq!(node.dispatch(msg).await, None);
+        assert_eq!(node.duplicate_messages_dropped(), 1);
+
+        node.stop();
+    }
+
+    #[tokio::test]
+    async fn test_min_safe_prune_height() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let node = create_node(Config::default(), tmp_dir.path().to_str().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(node.min_safe_prune_height(1), None);
+
+        let validator = |shard_id, current_height| {
+            SnapchainValidator::new(
+                SnapchainShard::new(shard_id),
+                Keypair::generate().public(),
+                None,
+                current_height,
+            )
+        };
+        let mut local = validator(1, 0);
+        local.address = node.address.clone();
+        node.validator_sets.update(
+            1,
+            SnapchainValidatorSet::new(vec![local, validator(1, 40), validator(1, 25)]),
+        );
+        node.validator_sets
+            .update(0, SnapchainValidatorSet::new(vec![validator(0, 90)]));
+
+        // Our own height doesn't hold back pruning, the furthest behind peer of the shard does
+        assert_eq!(node.min_safe_prune_height(1), Some(25));
+        assert_eq!(node.min_safe_prune_height(0), Some(90));
+
+        node.stop();
+    }
+
+    #[tokio::test]
+    async fn test_cast_retried_while_actor_alive() {
+        let mut attempts = 0;
+        let outcome = cast_with_retry(
+            1u32,
+            |msg| {
+                attempts += 1;
+                // Rejected once, e.g. while the mailbox is backed up
+                if attempts == 1 {
+                    Err(MessagingErr::SendErr(msg))
+                } else {
+                    Ok(())
+                }
+            },
+            || true,
+        )
+        .await;
+        assert_eq!(outcome, CastOutcome::Delivered { attempts: 2 });
+
+        let outcome = cast_with_retry(1u32, |msg| Err(MessagingErr::SendErr(msg)), || true).await;
+        assert_eq!(
+            outcome,
+            CastOutcome::Dropped {
+                attempts: CAST_RETRIES + 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cast_not_retried_when_actor_dead() {
+        let mut attempts = 0;
+        let outcome = cast_with_retry(
+            1u32,
+            |msg| {
+                attempts += 1;
+                Err(MessagingErr::SendErr(msg))
+            },
+            || false,
+        )
+        .await;
+        assert_eq!(outcome, CastOutcome::ActorDead);
+        assert_eq!(attempts, 1);
+
+        let outcome = cast_with_retry(1u32, |_| Err(MessagingErr::ChannelClosed), || true).await;
+        assert_eq!(outcome, CastOutcome::ActorDead);
+    }
+
+    #[test]
+    fn test_consensus_params_with_multiple_validators() {
+        let keypair = Keypair::generate();
+        let peers: Vec<PublicKey> = (0..3).map(|_| Keypair::generate().public()).collect();
+        // Listing ourselves among the configured validators must not count twice
+        let mut validator_public_keys = peers.clone();
+        validator_public_keys.push(keypair.public());
+
+        let shard = SnapchainShard::new(1);
+        let validator_set = validator_set_for_shard(
+            &shard,
+            keypair.public(),
+            Some("0.0.0.0:3383".to_string()),
+            5,
+            &validator_public_keys,
+        );
+        let address = Address(keypair.public().to_bytes());
+        let params = consensus_params_for_shard(&shard, validator_set, &address);
+
+        assert_eq!(params.initial_validator_set.count(), 4);
+        assert_eq!(params.initial_validator_set.total_voting_power(), 4);
+        assert!(params.initial_validator_set.exists(&address));
+        for peer in peers {
+            assert!(params
+                .initial_validator_set
+                .exists(&Address(peer.to_bytes())));
+        }
+        assert_eq!(params.start_height, Height::new(1, 1));
+    }
+
+    #[test]
+    fn test_prepare_data_dir_invalid_path() {
+        // A path nested under a regular file can never be created, even when running as root
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let rocksdb_dir = file.path().join("rocks");
+
+        let result = prepare_data_dir(rocksdb_dir.to_str().unwrap(), &[1]);
+
+        assert!(matches!(
+            result,
+            Err(SnapchainNodeError::DataDirCreateError { .. })
+        ));
     }
 }
@@ -1,7 +1,9 @@
+use crate::core::buffer_pool;
 use crate::storage::store::increment_vec_u8;
 use prost::DecodeError;
 use rocksdb::{Options, TransactionDB};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs::{self};
 use std::path::Path;
 use std::sync::{RwLock, RwLockReadGuard};
@@ -19,20 +21,68 @@ pub enum RocksdbError {
 
     #[error("DB is not open")]
     DbNotOpen,
+
+    #[error("Unknown column family {0}")]
+    UnknownColumnFamily(String),
+
+    #[error("Unknown record version {0}")]
+    UnknownRecordVersion(u8),
 }
 
+// Shard chunks, keyed by block number
+pub const SHARD_CHUNKS_CF: &str = "shard_chunks";
+// Shard chunk hash -> number of the block that committed it
+pub const CHUNK_BLOCK_INDEX_CF: &str = "chunk_block_index";
+// Application metadata attached to shard chunks, keyed like SHARD_CHUNKS_CF
+pub const CHUNK_METADATA_CF: &str = "chunk_metadata";
+// Message hash -> height and transaction index of the chunk that committed it
+pub const MESSAGE_LOCATIONS_CF: &str = "message_locations";
+// Address of the validator that proposed each committed block
+pub const BLOCK_PROPOSERS_CF: &str = "block_proposers";
+
+// Column families created when the db is opened. Everything else lives in the default column family.
+const COLUMN_FAMILIES: [&str; 5] = [
+    SHARD_CHUNKS_CF,
+    CHUNK_BLOCK_INDEX_CF,
+    CHUNK_METADATA_CF,
+    MESSAGE_LOCATIONS_CF,
+    BLOCK_PROPOSERS_CF,
+];
+
 /** Hold a transaction. List of key/value pairs that will be committed together */
 pub struct RocksDbTransactionBatch {
     pub batch: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    pub cf_batch: HashMap<&'static str, HashMap<Vec<u8>, Option<Vec<u8>>>>,
+    // Values taken from the buffer pool, by column family and key, handed back once they're written
+    pooled: HashSet<(&'static str, Vec<u8>)>,
 }
 
 impl RocksDbTransactionBatch {
     pub fn new() -> RocksDbTransactionBatch {
         RocksDbTransactionBatch {
             batch: HashMap::new(),
+            cf_batch: HashMap::new(),
+            pooled: HashSet::new(),
         }
     }
 
+    // Like put_cf for a value from buffer_pool::take, the buffer goes back to the pool on commit
+    pub(crate) fn put_cf_pooled(&mut self, cf: &'static str, key: Vec<u8>, value: Vec<u8>) {
+        self.pooled.insert((cf, key.clone()));
+        self.put_cf(cf, key, value);
+    }
+
+    pub fn put_cf(&mut self, cf: &'static str, key: Vec<u8>, value: Vec<u8>) {
+        self.cf_batch
+            .entry(cf)
+            .or_default()
+            .insert(key, Some(value));
+    }
+
+    pub fn delete_cf(&mut self, cf: &'static str, key: Vec<u8>) {
+        self.cf_batch.entry(cf).or_default().insert(key, None);
+    }
+
     pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
         self.batch.insert(key, Some(value));
     }
@@ -45,10 +95,19 @@ impl RocksDbTransactionBatch {
         for (key, value) in other.batch {
             self.batch.insert(key, value);
         }
+        for (cf, batch) in other.cf_batch {
+            self.cf_batch.entry(cf).or_default().extend(batch);
+        }
+        self.pooled.extend(other.pooled);
     }
 
     pub fn len(&self) -> usize {
         self.batch.len()
+            + self
+                .cf_batch
+                .values()
+                .map(|batch| batch.len())
+                .sum::<usize>()
     }
 }
 
@@ -57,6 +116,58 @@ pub struct IteratorOptions {
     pub reverse: bool,
 }
 
+// FIFO compaction isn't offered, it deletes the oldest sst files once the db outgrows its size limit and
+// with them committed blocks and chunks
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactionStyle {
+    Level,
+    Universal,
+}
+
+impl From<CompactionStyle> for rocksdb::DBCompactionStyle {
+    fn from(style: CompactionStyle) -> Self {
+        match style {
+            CompactionStyle::Level => rocksdb::DBCompactionStyle::Level,
+            CompactionStyle::Universal => rocksdb::DBCompactionStyle::Universal,
+        }
+    }
+}
+
+/// Knobs applied to the db and all its column families when it's opened. Anything left unset keeps
+/// rocksdb's default, so `StoreTuning::default()` opens the db exactly like `RocksDB::open`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoreTuning {
+    /// Size in bytes of a memtable before it's flushed to disk
+    pub write_buffer_size: Option<usize>,
+
+    pub compaction_style: Option<CompactionStyle>,
+
+    /// Build bloom filters over the first `n` bytes of each key, so point lookups and prefix scans
+    /// for absent keys can skip most sst files
+    pub prefix_bloom_len: Option<usize>,
+}
+
+impl StoreTuning {
+    fn apply(&self, opts: &mut Options) {
+        if let Some(write_buffer_size) = self.write_buffer_size {
+            opts.set_write_buffer_size(write_buffer_size);
+        }
+        if let Some(compaction_style) = self.compaction_style {
+            opts.set_compaction_style(compaction_style.into());
+        }
+        if let Some(prefix_len) = self.prefix_bloom_len {
+            opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(prefix_len));
+            opts.set_memtable_prefix_bloom_ratio(0.1);
+
+            let mut block_opts = rocksdb::BlockBasedOptions::default();
+            block_opts.set_bloom_filter(10.0, false);
+            opts.set_block_based_table_factory(&block_opts);
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct RocksDB {
     pub db: RwLock<Option<rocksdb::TransactionDB>>,
@@ -86,18 +197,35 @@ impl RocksDB {
         }
     }
 
+    /// Create and open a db at `path` with the given tuning
+    pub fn open_with_options(path: &str, tuning: StoreTuning) -> Result<RocksDB, RocksdbError> {
+        let db = RocksDB::new(path);
+        db.open_with_tuning(&tuning)?;
+        Ok(db)
+    }
+
     pub fn open(&self) -> Result<(), RocksdbError> {
+        self.open_with_tuning(&StoreTuning::default())
+    }
+
+    fn open_with_tuning(&self, tuning: &StoreTuning) -> Result<(), RocksdbError> {
         let mut db_lock = self.db.write().unwrap();
 
         // Create RocksDB options
         let mut opts = Options::default();
         opts.create_if_missing(true); // Creates a database if it does not exist
+        opts.create_missing_column_families(true);
+        tuning.apply(&mut opts);
 
         let mut tx_db_opts = rocksdb::TransactionDBOptions::default();
         tx_db_opts.set_default_lock_timeout(5000); // 5 seconds
 
         // Open the database with multi-threaded support
-        let db = rocksdb::TransactionDB::open(&opts, &tx_db_opts, &self.path)?;
+        // Column families opened by name get rocksdb's default options, so pass the tuned ones
+        let cfs = COLUMN_FAMILIES
+            .iter()
+            .map(|name| rocksdb::ColumnFamilyDescriptor::new(*name, opts.clone()));
+        let db = rocksdb::TransactionDB::open_cf_descriptors(&opts, &tx_db_opts, &self.path, cfs)?;
         *db_lock = Some(db);
 
         // We put the db in a RwLock to make the compiler happy, but it is strictly not required.
@@ -154,6 +282,14 @@ impl RocksDB {
         self.db.read().unwrap()
     }
 
+    fn column_family<'a>(
+        db: &'a TransactionDB,
+        cf: &str,
+    ) -> Result<&'a rocksdb::ColumnFamily, RocksdbError> {
+        db.cf_handle(cf)
+            .ok_or_else(|| RocksdbError::UnknownColumnFamily(cf.to_string()))
+    }
+
     pub fn keys_exist(&self, keys: &Vec<Vec<u8>>) -> Vec<bool> {
         let db = self.db();
         let db = db.as_ref().unwrap();
@@ -176,6 +312,13 @@ impl RocksDB {
             .map_err(|e| RocksdbError::InternalError(e))
     }
 
+    pub fn get_cf(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, RocksdbError> {
+        let db = self.db();
+        let db = db.as_ref().ok_or(RocksdbError::DbNotOpen)?;
+        db.get_cf(RocksDB::column_family(db, cf)?, key)
+            .map_err(|e| RocksdbError::InternalError(e))
+    }
+
     pub fn get_many(&self, keys: &Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, RocksdbError> {
         let results = self.db().as_ref().unwrap().multi_get(keys);
 
@@ -189,6 +332,20 @@ impl RocksDB {
         Ok(results)
     }
 
+    // Same as get_many, but missing keys are None instead of an empty value
+    pub fn get_many_optional(
+        &self,
+        keys: &Vec<Vec<u8>>,
+    ) -> Result<Vec<Option<Vec<u8>>>, RocksdbError> {
+        let db = self.db();
+        let db = db.as_ref().ok_or(RocksdbError::DbNotOpen)?;
+        let results = db
+            .multi_get(keys)
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(results)
+    }
+
     pub fn put(&self, key: &[u8], value: &[u8]) -> Result<(), RocksdbError> {
         self.db()
             .as_ref()
@@ -223,6 +380,21 @@ impl RocksDB {
                 txn.put(key, value.unwrap())?;
             }
         }
+        for (cf, cf_batch) in batch.cf_batch {
+            let handle = RocksDB::column_family(db.as_ref().unwrap(), cf)?;
+            for (key, value) in cf_batch {
+                match value {
+                    None => txn.delete_cf(handle, key)?,
+                    Some(value) => {
+                        txn.put_cf(handle, &key, &value)?;
+                        // Written values are copied into the transaction, pooled buffers go back
+                        if batch.pooled.contains(&(cf, key)) {
+                            buffer_pool::recycle(value);
+                        }
+                    }
+                }
+            }
+        }
 
         txn.commit().map_err(|e| RocksdbError::InternalError(e))
     }
@@ -242,19 +414,18 @@ impl RocksDB {
             Some(prefix) => prefix,
         };
 
-        let upper_bound = match &page_options.page_token {
-            None => stop_iterator_prefix,
-            Some(page_token) => page_token.clone(),
-        };
-
-        let lower_bound = match &page_options.page_token {
-            None => start_iterator_prefix,
-            Some(page_token) => increment_vec_u8(page_token),
+        // The page token is the last key returned, so the next page resumes just past it
+        let (lower_bound, upper_bound) = match &page_options.page_token {
+            None => (start_iterator_prefix, stop_iterator_prefix),
+            Some(page_token) if page_options.reverse => (start_iterator_prefix, page_token.clone()),
+            Some(page_token) => (increment_vec_u8(page_token), stop_iterator_prefix),
         };
 
         let mut opts = rocksdb::ReadOptions::default();
         opts.set_iterate_lower_bound(lower_bound);
         opts.set_iterate_upper_bound(upper_bound);
+        // Scan ranges can span several key prefixes, so don't let a prefix extractor cut them short
+        opts.set_total_order_seek(true);
 
         IteratorOptions {
             opts,
@@ -271,6 +442,35 @@ impl RocksDB {
         start_prefix: Option<Vec<u8>>,
         stop_prefix: Option<Vec<u8>>,
         page_options: &PageOptions,
+        f: F,
+    ) -> Result<bool, RocksdbError>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool, RocksdbError>,
+    {
+        self.for_each_iterator_paged(None, start_prefix, stop_prefix, page_options, f)
+    }
+
+    // Same as for_each_iterator_by_prefix_paged, over the keys of a column family
+    pub fn for_each_iterator_by_prefix_paged_cf<F>(
+        &self,
+        cf: &str,
+        start_prefix: Option<Vec<u8>>,
+        stop_prefix: Option<Vec<u8>>,
+        page_options: &PageOptions,
+        f: F,
+    ) -> Result<bool, RocksdbError>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool, RocksdbError>,
+    {
+        self.for_each_iterator_paged(Some(cf), start_prefix, stop_prefix, page_options, f)
+    }
+
+    fn for_each_iterator_paged<F>(
+        &self,
+        cf: Option<&str>,
+        start_prefix: Option<Vec<u8>>,
+        stop_prefix: Option<Vec<u8>>,
+        page_options: &PageOptions,
         mut f: F,
     ) -> Result<bool, RocksdbError>
     where
@@ -279,7 +479,11 @@ impl RocksDB {
         let iter_opts = RocksDB::get_iterator_options(start_prefix, stop_prefix, page_options);
 
         let db = self.db();
-        let mut iter = db.as_ref().unwrap().raw_iterator_opt(iter_opts.opts);
+        let db = db.as_ref().ok_or(RocksdbError::DbNotOpen)?;
+        let mut iter = match cf {
+            None => db.raw_iterator_opt(iter_opts.opts),
+            Some(cf) => db.raw_iterator_cf_opt(RocksDB::column_family(db, cf)?, iter_opts.opts),
+        };
 
         if iter_opts.reverse {
             iter.seek_to_last();
@@ -359,6 +563,19 @@ impl RocksDB {
                     deleted += 1;
                 }
             }
+            for cf in COLUMN_FAMILIES {
+                let handle = RocksDB::column_family(db.as_ref().unwrap(), cf)?;
+                for item in db
+                    .as_ref()
+                    .unwrap()
+                    .iterator_cf(handle, rocksdb::IteratorMode::Start)
+                {
+                    if let Ok((key, _)) = item {
+                        txn.delete_cf(cf, key.to_vec());
+                        deleted += 1;
+                    }
+                }
+            }
 
             self.commit(txn)?;
 
@@ -384,7 +601,11 @@ impl RocksDB {
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::db::RocksDbTransactionBatch;
+    use crate::core::buffer_pool;
+    use crate::storage::db::{
+        CompactionStyle, PageOptions, RocksDB, RocksDbTransactionBatch, RocksdbError, StoreTuning,
+        CHUNK_BLOCK_INDEX_CF, SHARD_CHUNKS_CF,
+    };
 
     #[test]
     fn test_merge_rocksdb_transaction() {
@@ -538,4 +759,156 @@ mod tests {
         // Cleanup
         db.destroy().unwrap();
     }
+
+    #[test]
+    fn test_column_family_reads_and_writes() {
+        let tmp_path = tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string();
+        let db = crate::storage::db::RocksDB::new(&tmp_path);
+        db.open().unwrap();
+
+        let mut txn = db.txn();
+        txn.put(b"key1".to_vec(), b"default".to_vec());
+        txn.put_cf(SHARD_CHUNKS_CF, b"key1".to_vec(), b"chunks".to_vec());
+        txn.put_cf(SHARD_CHUNKS_CF, b"key2".to_vec(), b"chunks".to_vec());
+        assert_eq!(txn.len(), 3);
+        db.commit(txn).unwrap();
+
+        // The same key lives independently in each column family
+        assert_eq!(db.get(b"key1").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(
+            db.get_cf(SHARD_CHUNKS_CF, b"key1").unwrap(),
+            Some(b"chunks".to_vec())
+        );
+        assert_eq!(db.get(b"key2").unwrap(), None);
+        assert_eq!(db.get_cf(CHUNK_BLOCK_INDEX_CF, b"key1").unwrap(), None);
+        assert!(matches!(
+            db.get_cf("unknown", b"key1"),
+            Err(RocksdbError::UnknownColumnFamily(_))
+        ));
+
+        let mut keys = vec![];
+        db.for_each_iterator_by_prefix_paged_cf(
+            SHARD_CHUNKS_CF,
+            None,
+            None,
+            &PageOptions::default(),
+            |key, _| {
+                keys.push(key.to_vec());
+                Ok(false)
+            },
+        )
+        .unwrap();
+        assert_eq!(keys, vec![b"key1".to_vec(), b"key2".to_vec()]);
+
+        let mut txn = db.txn();
+        txn.delete_cf(SHARD_CHUNKS_CF, b"key1".to_vec());
+        db.commit(txn).unwrap();
+        assert_eq!(db.get_cf(SHARD_CHUNKS_CF, b"key1").unwrap(), None);
+        assert_eq!(db.get(b"key1").unwrap(), Some(b"default".to_vec()));
+
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_only_pooled_values_are_recycled() {
+        let tmp_path = tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string();
+        let db = crate::storage::db::RocksDB::new(&tmp_path);
+        db.open().unwrap();
+
+        // A value allocated by the caller isn't kept by the pool
+        let mut txn = db.txn();
+        let mut value = Vec::with_capacity(1000);
+        value.extend_from_slice(b"index");
+        txn.put_cf(SHARD_CHUNKS_CF, b"key1".to_vec(), value);
+        db.commit(txn).unwrap();
+        assert_eq!(buffer_pool::take(0).capacity(), 0);
+
+        // A value taken from the pool goes back once it's written
+        let mut txn = db.txn();
+        let mut value = buffer_pool::take(2000);
+        value.extend_from_slice(b"chunk");
+        txn.put_cf_pooled(SHARD_CHUNKS_CF, b"key2".to_vec(), value);
+        db.commit(txn).unwrap();
+        assert!(buffer_pool::take(0).capacity() >= 2000);
+        assert_eq!(
+            db.get_cf(SHARD_CHUNKS_CF, b"key2").unwrap(),
+            Some(b"chunk".to_vec())
+        );
+
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_fifo_compaction_rejected() {
+        assert_eq!(
+            serde_json::from_str::<CompactionStyle>("\"universal\"").unwrap(),
+            CompactionStyle::Universal
+        );
+        assert!(serde_json::from_str::<CompactionStyle>("\"fifo\"").is_err());
+    }
+
+    #[test]
+    fn test_open_with_custom_tuning() {
+        let tmp_path = tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string();
+        let tuning = StoreTuning {
+            write_buffer_size: Some(1 << 20),
+            compaction_style: Some(CompactionStyle::Universal),
+            prefix_bloom_len: Some(2),
+        };
+        let db = RocksDB::open_with_options(&tmp_path, tuning).unwrap();
+
+        let mut txn = db.txn();
+        for key in [b"aa1", b"aa2", b"ab1", b"ba1"] {
+            txn.put(key.to_vec(), b"default".to_vec());
+            txn.put_cf(SHARD_CHUNKS_CF, key.to_vec(), b"chunks".to_vec());
+        }
+        db.commit(txn).unwrap();
+
+        assert_eq!(db.get(b"ab1").unwrap(), Some(b"default".to_vec()));
+        assert_eq!(db.get(b"zz1").unwrap(), None);
+        assert_eq!(
+            db.get_cf(SHARD_CHUNKS_CF, b"aa2").unwrap(),
+            Some(b"chunks".to_vec())
+        );
+
+        // Scans crossing prefix boundaries still see every key
+        let mut keys = vec![];
+        db.for_each_iterator_by_prefix_paged_cf(
+            SHARD_CHUNKS_CF,
+            Some(b"aa".to_vec()),
+            Some(b"bb".to_vec()),
+            &PageOptions::default(),
+            |key, _| {
+                keys.push(key.to_vec());
+                Ok(false)
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            keys,
+            vec![
+                b"aa1".to_vec(),
+                b"aa2".to_vec(),
+                b"ab1".to_vec(),
+                b"ba1".to_vec()
+            ]
+        );
+
+        db.destroy().unwrap();
+    }
 }
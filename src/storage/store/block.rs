@@ -1,10 +1,15 @@
-use crate::proto::snapchain::Block;
-use crate::storage::db::{PageOptions, RocksDB, RocksdbError};
+use crate::core::types::Address;
+use crate::proto::snapchain::{Block, ShardChunk};
+use crate::storage::db::{
+    PageOptions, RocksDB, RocksDbTransactionBatch, RocksdbError, BLOCK_PROPOSERS_CF,
+    CHUNK_BLOCK_INDEX_CF,
+};
+use crate::storage::store::commit_log::{CommitLog, CommitLogError};
 use prost::Message;
 use std::sync::Arc;
 use thiserror::Error;
 
-use super::PAGE_SIZE_MAX;
+use super::{GapFinder, PAGE_SIZE_MAX};
 
 static PAGE_SIZE: usize = 100;
 
@@ -12,6 +17,18 @@ static PAGE_SIZE: usize = 100;
 pub enum RootPrefix {
     Block = 1,
     Shard = 2,
+    /* Index from shard chunk hash to the number of the block that committed it, stored in [CHUNK_BLOCK_INDEX_CF] */
+    ChunkBlockIndex = 3,
+    /* Append-only log of commit events, by offset */
+    CommitLog = 4,
+    /* Address of the validator that proposed each committed block, by shard index and block number, stored in [BLOCK_PROPOSERS_CF] */
+    BlockProposer = 5,
+    /* Shard chunk being committed, cleared once both the trie and the chunk are written */
+    ShardCommitWal = 6,
+    /* Height and transaction index of each committed message in a shard, by message hash, stored in [MESSAGE_LOCATIONS_CF] */
+    MessageLocation = 7,
+    /* Index from shard id and chunk height to the number of the block that committed the chunk, stored in [CHUNK_BLOCK_INDEX_CF] */
+    ShardHeightBlockIndex = 8,
 }
 
 // TODO(aditi): This code definitely needs unit tests
@@ -28,6 +45,15 @@ pub enum BlockStorageError {
 
     #[error("Too many blocks in result")]
     TooManyBlocksInResult,
+
+    #[error("Invalid index entry")]
+    InvalidIndexEntry,
+
+    #[error("Block {block_number} doesn't match its full hash")]
+    FullHashMismatch { block_number: u64 },
+
+    #[error(transparent)]
+    CommitLogError(#[from] CommitLogError),
 }
 
 /** A page of messages returned from various APIs */
@@ -47,6 +73,47 @@ fn make_block_key(shard_index: u32, block_number: u64) -> Vec<u8> {
     key
 }
 
+fn block_number_from_block_key(key: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = key.get(5..13)?.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+fn make_block_stop_key(shard_index: u32) -> Vec<u8> {
+    // Bound scans to the blocks of one shard, indexes live under higher prefixes in the same db
+    let mut key = make_block_key(shard_index, u64::MAX);
+    key.push(u8::MAX);
+    key
+}
+
+fn make_block_proposer_key(shard_index: u32, block_number: u64) -> Vec<u8> {
+    let mut key = vec![RootPrefix::BlockProposer as u8];
+    key.extend_from_slice(&shard_index.to_be_bytes());
+    key.extend_from_slice(&block_number.to_be_bytes());
+    key
+}
+
+fn make_chunk_block_index_key(chunk_hash: &[u8]) -> Vec<u8> {
+    let mut key = vec![RootPrefix::ChunkBlockIndex as u8];
+    key.extend_from_slice(chunk_hash);
+    key
+}
+
+fn make_shard_height_block_index_key(shard_id: u32, shard_block_number: u64) -> Vec<u8> {
+    let mut key = vec![RootPrefix::ShardHeightBlockIndex as u8];
+    key.extend_from_slice(&shard_id.to_be_bytes());
+    key.extend_from_slice(&shard_block_number.to_be_bytes());
+    key
+}
+
+// Key of the shard height index entry for a chunk, None for a chunk without a height
+fn shard_height_block_index_key(shard_chunk: &ShardChunk) -> Option<Vec<u8>> {
+    let height = shard_chunk.header.as_ref()?.height?;
+    Some(make_shard_height_block_index_key(
+        height.shard_index,
+        height.block_number,
+    ))
+}
+
 fn get_block_page_by_prefix(
     db: &RocksDB,
     page_options: &PageOptions,
@@ -55,9 +122,14 @@ fn get_block_page_by_prefix(
 ) -> Result<BlockPage, BlockStorageError> {
     let mut blocks = Vec::new();
     let mut last_key = vec![];
+    let mut corrupted_block_number = None;
 
     db.for_each_iterator_by_prefix_paged(start_prefix, stop_prefix, page_options, |key, value| {
         let block = Block::decode(value)?;
+        if !block.verify_full_hash() {
+            corrupted_block_number = Some(block_number_from_block_key(key).unwrap_or_default());
+            return Ok(true); // Stop iterating
+        }
         blocks.push(block);
 
         if blocks.len() >= page_options.page_size.unwrap_or(PAGE_SIZE_MAX) {
@@ -68,6 +140,10 @@ fn get_block_page_by_prefix(
         Ok(false) // Continue iterating
     })?;
 
+    if let Some(block_number) = corrupted_block_number {
+        return Err(BlockStorageError::FullHashMismatch { block_number });
+    }
+
     let next_page_token = if last_key.len() > 0 {
         Some(last_key)
     } else {
@@ -93,7 +169,7 @@ pub fn get_current_height(
             page_token: None,
         },
         Some(start_block_key),
-        None,
+        Some(make_block_stop_key(shard_index)),
     )?;
 
     if block_page.blocks.len() > 1 {
@@ -120,15 +196,38 @@ pub fn get_blocks_in_range(
     stop_block_number: Option<u64>,
 ) -> Result<BlockPage, BlockStorageError> {
     let start_primary_key = make_block_key(shard_index, start_block_number);
-    let stop_prefix =
-        stop_block_number.map(|block_number| make_block_key(shard_index, block_number));
+    let stop_prefix = match stop_block_number {
+        Some(block_number) => make_block_key(shard_index, block_number),
+        None => make_block_stop_key(shard_index),
+    };
 
-    get_block_page_by_prefix(db, page_options, Some(start_primary_key), stop_prefix)
+    get_block_page_by_prefix(db, page_options, Some(start_primary_key), Some(stop_prefix))
 }
 
 pub fn put_block(db: &RocksDB, block: Block) -> Result<(), BlockStorageError> {
+    put_block_with_proposer(db, block, None)
+}
+
+// Blocks synced from peers don't come with their proposal, so the proposer is only known for blocks
+// decided by this node
+pub fn put_block_with_proposer(
+    db: &RocksDB,
+    block: Block,
+    proposer: Option<&Address>,
+) -> Result<(), BlockStorageError> {
     // TODO: We need to introduce a transaction model
     let mut txn = db.txn();
+    put_block_with_proposer_in_txn(&mut txn, &block, proposer)?;
+    db.commit(txn)?;
+    Ok(())
+}
+
+// Adds the block's writes to the batch, for callers that commit other data along with it
+pub fn put_block_with_proposer_in_txn(
+    txn: &mut RocksDbTransactionBatch,
+    block: &Block,
+    proposer: Option<&Address>,
+) -> Result<(), BlockStorageError> {
     let header = block
         .header
         .as_ref()
@@ -138,11 +237,129 @@ pub fn put_block(db: &RocksDB, block: Block) -> Result<(), BlockStorageError> {
         .as_ref()
         .ok_or(BlockStorageError::BlockMissingHeight)?;
     let primary_key = make_block_key(height.shard_index, height.block_number);
+    // Index the embedded chunks in the same transaction so the index never points at a missing block
+    for shard_chunk in &block.shard_chunks {
+        txn.put_cf(
+            CHUNK_BLOCK_INDEX_CF,
+            make_chunk_block_index_key(&shard_chunk.hash),
+            height.block_number.to_be_bytes().to_vec(),
+        );
+        if let Some(index_key) = shard_height_block_index_key(shard_chunk) {
+            txn.put_cf(
+                CHUNK_BLOCK_INDEX_CF,
+                index_key,
+                height.block_number.to_be_bytes().to_vec(),
+            );
+        }
+    }
+    if let Some(proposer) = proposer {
+        txn.put_cf(
+            BLOCK_PROPOSERS_CF,
+            make_block_proposer_key(height.shard_index, height.block_number),
+            proposer.0.to_vec(),
+        );
+    }
     txn.put(primary_key, block.encode_to_vec());
-    db.commit(txn)?;
     Ok(())
 }
 
+pub fn get_block_proposer(
+    db: &RocksDB,
+    shard_index: u32,
+    block_number: u64,
+) -> Result<Option<Address>, BlockStorageError> {
+    match db.get_cf(
+        BLOCK_PROPOSERS_CF,
+        &make_block_proposer_key(shard_index, block_number),
+    )? {
+        None => Ok(None),
+        Some(bytes) => {
+            let bytes: [u8; 32] = bytes
+                .try_into()
+                .map_err(|_| BlockStorageError::InvalidIndexEntry)?;
+            Ok(Some(Address(bytes)))
+        }
+    }
+}
+
+pub fn get_block_number_for_chunk(
+    db: &RocksDB,
+    chunk_hash: &[u8],
+) -> Result<Option<u64>, BlockStorageError> {
+    get_block_number_by_index_key(db, &make_chunk_block_index_key(chunk_hash))
+}
+
+pub fn get_block_number_for_shard_height(
+    db: &RocksDB,
+    shard_id: u32,
+    shard_block_number: u64,
+) -> Result<Option<u64>, BlockStorageError> {
+    get_block_number_by_index_key(
+        db,
+        &make_shard_height_block_index_key(shard_id, shard_block_number),
+    )
+}
+
+fn get_block_number_by_index_key(
+    db: &RocksDB,
+    index_key: &[u8],
+) -> Result<Option<u64>, BlockStorageError> {
+    match db.get_cf(CHUNK_BLOCK_INDEX_CF, index_key)? {
+        None => Ok(None),
+        Some(bytes) => {
+            let bytes: [u8; 8] = bytes
+                .try_into()
+                .map_err(|_| BlockStorageError::InvalidIndexEntry)?;
+            Ok(Some(u64::from_be_bytes(bytes)))
+        }
+    }
+}
+
+// Delete the blocks of a shard from the oldest one up to the first block `keep` returns true for, along
+// with the index entries of their chunks. Returns the number of deleted blocks.
+pub fn prune_blocks<F>(
+    db: &RocksDB,
+    shard_index: u32,
+    mut keep: F,
+) -> Result<u64, BlockStorageError>
+where
+    F: FnMut(&Block) -> bool,
+{
+    let mut txn = db.txn();
+    let mut pruned = 0;
+    db.for_each_iterator_by_prefix_paged(
+        Some(make_block_key(shard_index, 0)),
+        Some(make_block_stop_key(shard_index)),
+        &PageOptions::default(),
+        |key, value| {
+            let block = Block::decode(value)?;
+            if keep(&block) {
+                return Ok(true); // Stop iterating
+            }
+            for shard_chunk in &block.shard_chunks {
+                txn.delete_cf(
+                    CHUNK_BLOCK_INDEX_CF,
+                    make_chunk_block_index_key(&shard_chunk.hash),
+                );
+                if let Some(index_key) = shard_height_block_index_key(shard_chunk) {
+                    txn.delete_cf(CHUNK_BLOCK_INDEX_CF, index_key);
+                }
+            }
+            if let Some(block_number) = block_number_from_block_key(key) {
+                txn.delete_cf(
+                    BLOCK_PROPOSERS_CF,
+                    make_block_proposer_key(shard_index, block_number),
+                );
+            }
+            txn.delete(key.to_vec());
+            pruned += 1;
+            Ok(false) // Continue iterating
+        },
+    )?;
+    db.commit(txn)?;
+    Ok(pruned)
+}
+
 #[derive(Default, Clone)]
 pub struct BlockStore {
     db: Arc<RocksDB>,
@@ -157,6 +374,116 @@ impl BlockStore {
         put_block(&self.db, block)
     }
 
+    // Store a block along with the address of the validator that proposed it
+    pub fn put_block_with_proposer(
+        &self,
+        block: Block,
+        proposer: &Address,
+    ) -> Result<(), BlockStorageError> {
+        put_block_with_proposer(&self.db, block, Some(proposer))
+    }
+
+    // Like put_block_with_proposer, with the block's commit log entry written in the same
+    // transaction. Returns the entry's offset.
+    pub fn put_block_with_proposer_logged(
+        &self,
+        block: Block,
+        proposer: &Address,
+        commit_log: &CommitLog,
+    ) -> Result<u64, BlockStorageError> {
+        let mut txn = self.db.txn();
+        put_block_with_proposer_in_txn(&mut txn, &block, Some(proposer))?;
+        Ok(commit_log.commit_block(txn, &block)?)
+    }
+
+    // None when the block isn't stored or came from a peer without its proposal
+    pub fn proposer_of(&self, block_number: u64) -> Result<Option<Address>, BlockStorageError> {
+        get_block_proposer(&self.db, 0, block_number)
+    }
+
+    // The commit log lives in the same db as the blocks
+    pub fn commit_log(&self) -> Result<CommitLog, CommitLogError> {
+        CommitLog::new(self.db.clone())
+    }
+
+    pub fn block_number_for_chunk(
+        &self,
+        chunk_hash: &[u8],
+    ) -> Result<Option<u64>, BlockStorageError> {
+        get_block_number_for_chunk(&self.db, chunk_hash)
+    }
+
+    // Number of the block that included the shard's chunk at this height, None when no stored block
+    // includes it. Blocks and the chunks they embed don't have to share a height.
+    pub fn block_for_shard_height(
+        &self,
+        shard_id: u32,
+        shard_block_number: u64,
+    ) -> Result<Option<u64>, BlockStorageError> {
+        get_block_number_for_shard_height(&self.db, shard_id, shard_block_number)
+    }
+
+    pub fn prune_blocks_before(
+        &self,
+        shard_index: u32,
+        block_number: u64,
+    ) -> Result<u64, BlockStorageError> {
+        prune_blocks(&self.db, shard_index, |block| {
+            block
+                .header
+                .as_ref()
+                .and_then(|header| header.height)
+                .map_or(true, |height| height.block_number >= block_number)
+        })
+    }
+
+    pub fn prune_blocks_older_than(
+        &self,
+        shard_index: u32,
+        timestamp: u64,
+    ) -> Result<u64, BlockStorageError> {
+        self.prune_blocks_older_than_below(shard_index, timestamp, u64::MAX)
+    }
+
+    // Only blocks that are both older than the timestamp and below the block number are pruned
+    pub fn prune_blocks_older_than_below(
+        &self,
+        shard_index: u32,
+        timestamp: u64,
+        block_number: u64,
+    ) -> Result<u64, BlockStorageError> {
+        prune_blocks(&self.db, shard_index, |block| {
+            block.header.as_ref().map_or(true, |header| {
+                header.timestamp >= timestamp
+                    || header
+                        .height
+                        .map_or(true, |height| height.block_number >= block_number)
+            })
+        })
+    }
+
+    // Ranges of block numbers in [from, to) without a stored block, as inclusive (first, last) pairs
+    pub fn find_gaps(
+        &self,
+        shard_index: u32,
+        from: u64,
+        to: u64,
+    ) -> Result<Vec<(u64, u64)>, BlockStorageError> {
+        let mut gap_finder = GapFinder::new(from);
+        self.db.for_each_iterator_by_prefix_paged(
+            Some(make_block_key(shard_index, from)),
+            Some(make_block_key(shard_index, to)),
+            &PageOptions::default(),
+            |key, _| {
+                if let Some(block_number) = block_number_from_block_key(key) {
+                    gap_finder.observe(block_number);
+                }
+                Ok(false) // Continue iterating
+            },
+        )?;
+        Ok(gap_finder.finish(to))
+    }
+
     pub fn max_block_number(&self, shard_index: u32) -> Result<u64, BlockStorageError> {
         let current_height = get_current_height(&self.db, shard_index)?;
         match current_height {
@@ -165,6 +492,36 @@ impl BlockStore {
         }
     }
 
+    // The blocks at the given block numbers in one multi-get, in the same order, None where there's no
+    // block stored
+    pub fn get_blocks_by_numbers(
+        &self,
+        shard_index: u32,
+        block_numbers: &[u64],
+    ) -> Result<Vec<Option<Block>>, BlockStorageError> {
+        let keys = block_numbers
+            .iter()
+            .map(|block_number| make_block_key(shard_index, *block_number))
+            .collect();
+        let values = self.db.get_many_optional(&keys)?;
+        block_numbers
+            .iter()
+            .zip(values)
+            .map(|(block_number, value)| match value {
+                None => Ok(None),
+                Some(bytes) => {
+                    let block = Block::decode(bytes.as_slice()).map_err(RocksdbError::from)?;
+                    if !block.verify_full_hash() {
+                        return Err(BlockStorageError::FullHashMismatch {
+                            block_number: *block_number,
+                        });
+                    }
+                    Ok(Some(block))
+                }
+            })
+            .collect()
+    }
+
     pub fn get_blocks(
         &self,
         start_block_number: u64,
@@ -196,3 +553,182 @@ impl BlockStore {
         Ok(blocks)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::Height;
+    use crate::proto::snapchain::{BlockHeader, ShardChunk, ShardHeader};
+
+    fn make_tmp_path() -> String {
+        tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn block(block_number: u64, chunk_hashes: Vec<Vec<u8>>) -> Block {
+        Block {
+            header: Some(BlockHeader {
+                height: Some(Height::new(0, block_number)),
+                ..Default::default()
+            }),
+            hash: vec![block_number as u8],
+            shard_chunks: chunk_hashes
+                .into_iter()
+                .map(|hash| ShardChunk {
+                    hash,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_proposer_of_committed_block() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let store = BlockStore::new(db.clone());
+
+        let proposer = Address([7; 32]);
+        store
+            .put_block_with_proposer(block(1, vec![vec![1]]), &proposer)
+            .unwrap();
+        store.put_block(block(2, vec![vec![2]])).unwrap();
+
+        assert_eq!(store.proposer_of(1).unwrap(), Some(proposer));
+        assert_eq!(store.proposer_of(2).unwrap(), None);
+        assert_eq!(store.proposer_of(3).unwrap(), None);
+        // Proposers live in their own column family, not the default one
+        assert!(db.get(&make_block_proposer_key(0, 1)).unwrap().is_none());
+
+        store.prune_blocks_before(0, 2).unwrap();
+        assert_eq!(store.proposer_of(1).unwrap(), None);
+
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_block_and_commit_log_entry_written_together() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let store = BlockStore::new(db.clone());
+        let commit_log = store.commit_log().unwrap();
+
+        let proposer = Address([7; 32]);
+        let offset = store
+            .put_block_with_proposer_logged(block(1, vec![vec![1]]), &proposer, &commit_log)
+            .unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(store.proposer_of(1).unwrap(), Some(proposer));
+        let entries = commit_log.read_from(0).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].event.height, Some(Height::new(0, 1)));
+
+        // A block that can't be stored doesn't take an offset
+        let mut headerless = block(2, vec![vec![2]]);
+        headerless.header = None;
+        assert!(store
+            .put_block_with_proposer_logged(headerless, &proposer, &commit_log)
+            .is_err());
+        assert_eq!(commit_log.next_offset(), 1);
+        assert_eq!(commit_log.read_from(1).unwrap(), vec![]);
+
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_block_for_shard_height() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let store = BlockStore::new(db.clone());
+
+        let chunk = |shard_index: u32, block_number: u64| ShardChunk {
+            header: Some(ShardHeader {
+                height: Some(Height::new(shard_index, block_number)),
+                ..Default::default()
+            }),
+            hash: vec![shard_index as u8, block_number as u8],
+            ..Default::default()
+        };
+        let mut first = block(1, vec![]);
+        first.shard_chunks = vec![chunk(1, 1), chunk(2, 1)];
+        store.put_block(first).unwrap();
+        // Shard 2 fell behind, its second chunk only made it into block 3
+        let mut second = block(2, vec![]);
+        second.shard_chunks = vec![chunk(1, 2)];
+        store.put_block(second).unwrap();
+        let mut third = block(3, vec![]);
+        third.shard_chunks = vec![chunk(1, 3), chunk(2, 2)];
+        store.put_block(third).unwrap();
+
+        assert_eq!(store.block_for_shard_height(1, 1).unwrap(), Some(1));
+        assert_eq!(store.block_for_shard_height(2, 1).unwrap(), Some(1));
+        assert_eq!(store.block_for_shard_height(1, 2).unwrap(), Some(2));
+        assert_eq!(store.block_for_shard_height(2, 2).unwrap(), Some(3));
+        assert_eq!(store.block_for_shard_height(2, 3).unwrap(), None);
+        assert_eq!(store.block_for_shard_height(3, 1).unwrap(), None);
+
+        store.prune_blocks_before(0, 2).unwrap();
+        assert_eq!(store.block_for_shard_height(1, 1).unwrap(), None);
+        assert_eq!(store.block_for_shard_height(1, 2).unwrap(), Some(2));
+
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_get_blocks_checks_full_hash() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let store = BlockStore::new(db.clone());
+
+        let mut verified = block(1, vec![vec![1]]);
+        verified.full_hash = verified.full_hash();
+        store.put_block(verified).unwrap();
+        let mut corrupted = block(2, vec![vec![2]]);
+        corrupted.full_hash = corrupted.full_hash();
+        corrupted.shard_chunks[0].hash = vec![3];
+        store.put_block(corrupted).unwrap();
+
+        assert_eq!(store.get_blocks(1, Some(2), 0).unwrap().len(), 1);
+        assert!(matches!(
+            store.get_blocks(1, None, 0),
+            Err(BlockStorageError::FullHashMismatch { block_number: 2 })
+        ));
+
+        db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_block_number_for_chunk() {
+        let db = Arc::new(RocksDB::new(&make_tmp_path()));
+        db.open().unwrap();
+        let store = BlockStore::new(db.clone());
+
+        store
+            .put_block(block(1, vec![vec![1, 1], vec![1, 2]]))
+            .unwrap();
+        store.put_block(block(2, vec![vec![2, 1]])).unwrap();
+
+        assert_eq!(store.block_number_for_chunk(&[1, 2]).unwrap(), Some(1));
+        assert_eq!(store.block_number_for_chunk(&[2, 1]).unwrap(), Some(2));
+        assert_eq!(store.block_number_for_chunk(&[3, 1]).unwrap(), None);
+
+        // The index doesn't leak into block scans
+        assert_eq!(store.max_block_number(0).unwrap(), 2);
+        assert_eq!(store.get_blocks(0, None, 0).unwrap().len(), 2);
+
+        // Index entries live in their own column family
+        let index_key = make_chunk_block_index_key(&[2, 1]);
+        assert!(db.get(&index_key).unwrap().is_none());
+        assert!(db
+            .get_cf(CHUNK_BLOCK_INDEX_CF, &index_key)
+            .unwrap()
+            .is_some());
+
+        db.destroy().unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+use crate::proto::snapchain::{Block, CommitEvent, ShardChunk};
+use crate::storage::db::{PageOptions, RocksDB, RocksDbTransactionBatch, RocksdbError};
+use crate::storage::store::block::RootPrefix;
+use prost::Message;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+use super::PAGE_SIZE_MAX;
+
+#[derive(Error, Debug)]
+pub enum CommitLogError {
+    #[error(transparent)]
+    RocksdbError(#[from] RocksdbError),
+
+    #[error("Commit missing header")]
+    MissingHeader,
+
+    #[error("Invalid commit log key")]
+    InvalidKey,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitLogEntry {
+    pub offset: u64,
+    pub event: CommitEvent,
+}
+
+fn make_commit_log_key(offset: u64) -> Vec<u8> {
+    // Store the prefix in the first byte so there's no overlap across different stores
+    let mut key = vec![RootPrefix::CommitLog as u8];
+    // Store the offset in the next 8 bytes
+    key.extend_from_slice(&offset.to_be_bytes());
+    key
+}
+
+fn offset_from_commit_log_key(key: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = key.get(1..9)?.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+fn make_commit_log_stop_key() -> Vec<u8> {
+    vec![RootPrefix::CommitLog as u8 + 1]
+}
+
+fn read_entries(
+    db: &RocksDB,
+    offset: u64,
+    page_options: &PageOptions,
+) -> Result<Vec<CommitLogEntry>, CommitLogError> {
+    let page_size = page_options.page_size.unwrap_or(PAGE_SIZE_MAX);
+    let mut entries = Vec::new();
+    let mut invalid_key = false;
+
+    db.for_each_iterator_by_prefix_paged(
+        Some(make_commit_log_key(offset)),
+        Some(make_commit_log_stop_key()),
+        page_options,
+        |key, value| {
+            let Some(offset) = offset_from_commit_log_key(key) else {
+                invalid_key = true;
+                return Ok(true); // Stop iterating
+            };
+            entries.push(CommitLogEntry {
+                offset,
+                event: CommitEvent::decode(value)?,
+            });
+            Ok(entries.len() >= page_size)
+        },
+    )?;
+
+    if invalid_key {
+        return Err(CommitLogError::InvalidKey);
+    }
+    Ok(entries)
+}
+
+// Durable, replayable log of committed blocks or shard chunks, for consumers that restart and
+// resume from the last offset they processed instead of following the live block channel.
+// Offsets start at 0 and grow by one per commit. Clones share the next offset, so use a single log
+// (and its clones) per db.
+#[derive(Clone)]
+pub struct CommitLog {
+    db: Arc<RocksDB>,
+    next_offset: Arc<Mutex<u64>>,
+}
+
+impl CommitLog {
+    pub fn new(db: Arc<RocksDB>) -> Result<CommitLog, CommitLogError> {
+        let last = read_entries(
+            &db,
+            0,
+            &PageOptions {
+                reverse: true,
+                page_size: Some(1),
+                page_token: None,
+            },
+        )?;
+        let next_offset = last.first().map_or(0, |entry| entry.offset + 1);
+        Ok(CommitLog {
+            db,
+            next_offset: Arc::new(Mutex::new(next_offset)),
+        })
+    }
+
+    pub fn append_block(&self, block: &Block) -> Result<u64, CommitLogError> {
+        self.commit_block(self.db.txn(), block)
+    }
+
+    // Commits the batch along with the block's entry, so a crash can't leave one without the other
+    pub fn commit_block(
+        &self,
+        txn: RocksDbTransactionBatch,
+        block: &Block,
+    ) -> Result<u64, CommitLogError> {
+        let header = block.header.as_ref().ok_or(CommitLogError::MissingHeader)?;
+        self.commit(
+            txn,
+            CommitEvent {
+                height: header.height,
+                hash: block.hash.clone(),
+                transaction_count: block
+                    .shard_chunks
+                    .iter()
+                    .map(|chunk| chunk.transaction_count() as u64)
+                    .sum(),
+                timestamp: header.timestamp,
+            },
+        )
+    }
+
+    pub fn append_shard_chunk(&self, shard_chunk: &ShardChunk) -> Result<u64, CommitLogError> {
+        let header = shard_chunk
+            .header
+            .as_ref()
+            .ok_or(CommitLogError::MissingHeader)?;
+        self.commit(
+            self.db.txn(),
+            CommitEvent {
+                height: header.height,
+                hash: shard_chunk.hash.clone(),
+                transaction_count: shard_chunk.transaction_count() as u64,
+                timestamp: header.timestamp,
+            },
+        )
+    }
+
+    fn commit(
+        &self,
+        mut txn: RocksDbTransactionBatch,
+        event: CommitEvent,
+    ) -> Result<u64, CommitLogError> {
+        // Held across the write so offsets are never reused or skipped
+        let mut next_offset = self.next_offset.lock().unwrap();
+        let offset = *next_offset;
+        txn.put(make_commit_log_key(offset), event.encode_to_vec());
+        self.db.commit(txn)?;
+        *next_offset += 1;
+        Ok(offset)
+    }
+
+    // Offset the next commit will be written at
+    pub fn next_offset(&self) -> u64 {
+        *self.next_offset.lock().unwrap()
+    }
+
+    // Entries from the offset on, at most PAGE_SIZE_MAX per call. Continue from the offset after the
+    // last one returned, an empty result means the consumer has caught up.
+    pub fn read_from(&self, offset: u64) -> Result<Vec<CommitLogEntry>, CommitLogError> {
+        read_entries(
+            &self.db,
+            offset,
+            &PageOptions {
+                reverse: false,
+                page_size: Some(PAGE_SIZE_MAX),
+                page_token: None,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::Height;
+    use crate::proto::snapchain::BlockHeader;
+
+    fn block(block_number: u64) -> Block {
+        Block {
+            header: Some(BlockHeader {
+                height: Some(Height::new(0, block_number)),
+                timestamp: 100 + block_number,
+                ..Default::default()
+            }),
+            hash: vec![block_number as u8],
+            shard_chunks: vec![ShardChunk {
+                transactions: vec![Default::default(); block_number as usize],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_replay_commit_log_from_offset() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let db = Arc::new(RocksDB::new(tmp_dir.path().to_str().unwrap()));
+        db.open().unwrap();
+
+        let commit_log = CommitLog::new(db.clone()).unwrap();
+        for block_number in 1..=5 {
+            let offset = commit_log.append_block(&block(block_number)).unwrap();
+            assert_eq!(offset, block_number - 1);
+        }
+
+        let entries = commit_log.read_from(2).unwrap();
+        assert_eq!(
+            entries.iter().map(|entry| entry.offset).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert_eq!(
+            entries[0].event,
+            CommitEvent {
+                height: Some(Height::new(0, 3)),
+                hash: vec![3],
+                transaction_count: 3,
+                timestamp: 103,
+            }
+        );
+        assert!(commit_log.read_from(5).unwrap().is_empty());
+
+        // A reopened log keeps appending after the last entry
+        let reopened = CommitLog::new(db.clone()).unwrap();
+        assert_eq!(reopened.next_offset(), 5);
+        assert_eq!(reopened.append_block(&block(6)).unwrap(), 5);
+
+        db.destroy().unwrap();
+    }
+}
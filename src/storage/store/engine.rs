@@ -1,16 +1,61 @@
-use crate::core::types::{proto, Height};
+use crate::consensus::metrics::ConsensusMetrics;
+use crate::core::types::{proto, Address, Height};
 use crate::proto::snapchain::{Block, ShardChunk};
 use crate::proto::{message, snapchain};
 use crate::storage::db::RocksDB;
-use crate::storage::store::BlockStore;
+use crate::storage::store::commit_log::CommitLog;
+use crate::storage::store::{BlockStorageError, BlockStore};
 use crate::storage::trie::merkle_trie;
-use std::collections::HashMap;
+use crate::storage::util::blake3_20;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::iter;
+use std::time::Instant;
+use thiserror::Error;
+use tokio::runtime::{Handle, RuntimeFlavor};
 use tokio::sync::mpsc;
 use tracing::{error, event, info, warn, Level};
 
 use super::shard::{self, ShardStore};
 
+// Every shard trie starts out holding only this key, so all shards share the same genesis state root
+const GENESIS_TRIE_KEY: [u8; 10] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+
+// Messages waiting to be applied before submitters are turned away as busy
+pub const DEFAULT_MEMPOOL_CAPACITY: usize = 10_000;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    #[error("Transaction has no messages")]
+    EmptyTransaction,
+
+    #[error("Message is missing its hash")]
+    MissingMessageHash,
+
+    #[error("Message {hash} is for fid {message_fid}, not the transaction fid {fid}")]
+    FidMismatch {
+        fid: u64,
+        message_fid: u64,
+        hash: String,
+    },
+
+    #[error("Message {hash} was already applied")]
+    DuplicateMessage { hash: String },
+
+    #[error("Trie error: {0}")]
+    TrieError(String),
+}
+
+// What recover() did with a commit left unfinished by a crash
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommitRecovery {
+    // Nothing was mid-commit
+    Clean,
+    // The trie already had the chunk applied, the chunk was written to match
+    Completed { block_number: u64 },
+    // The trie was never committed, the chunk is dropped and will come again from consensus or sync
+    RolledBack { block_number: u64 },
+}
+
 // Shard state root and the transactions
 pub struct ShardStateChange {
     pub shard_id: u32,
@@ -24,6 +69,32 @@ pub struct ShardEngine {
     messages_rx: mpsc::Receiver<message::Message>,
     messages_tx: mpsc::Sender<message::Message>,
     trie: merkle_trie::MerkleTrie,
+    // Per transaction apply times are recorded here when profiling is on
+    profiling: Option<ConsensusMetrics>,
+}
+
+// Run blocking db/trie work without stalling the other tasks on this worker. block_in_place is
+// not supported on the current thread runtime, so the closure is run inline there.
+fn run_blocking<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+// Label for a transaction's apply time, transactions are grouped under their first message's type
+fn transaction_message_type(transaction: &proto::Transaction) -> &'static str {
+    transaction
+        .user_messages
+        .first()
+        .and_then(|msg| msg.data.as_ref())
+        .and_then(|data| message::MessageType::try_from(data.r#type).ok())
+        .map_or("unknown", |message_type| message_type.as_str_name())
 }
 
 fn encode_vec(data: &[Vec<u8>]) -> String {
@@ -42,50 +113,188 @@ impl ShardEngine {
         trie.initialize().unwrap();
 
         // TODO: The empty trie currently has some issues with the newly added commit/rollback code. Remove when we can.
-        trie.insert(vec![vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9]])
-            .unwrap();
+        trie.insert(vec![GENESIS_TRIE_KEY.to_vec()]).unwrap();
         trie.commit().unwrap();
         trie.reload().unwrap();
 
-        let (messages_tx, messages_rx) = mpsc::channel::<message::Message>(10_000);
+        let (messages_tx, messages_rx) =
+            mpsc::channel::<message::Message>(DEFAULT_MEMPOOL_CAPACITY);
         ShardEngine {
             shard_id,
             shard_store,
             messages_rx,
             messages_tx,
             trie,
+            profiling: None,
         }
     }
 
+    // Replaces the mempool, so senders taken with messages_tx() before this don't reach the engine
+    pub fn with_mempool_capacity(self, mempool_capacity: usize) -> Self {
+        let (messages_tx, messages_rx) = mpsc::channel::<message::Message>(mempool_capacity);
+        Self {
+            messages_tx,
+            messages_rx,
+            ..self
+        }
+    }
+
+    pub fn with_profiling(self, metrics: ConsensusMetrics) -> Self {
+        Self {
+            profiling: Some(metrics),
+            ..self
+        }
+    }
+
+    fn observe_transaction_applied(&self, transaction: &proto::Transaction, started: Instant) {
+        if let Some(metrics) = &self.profiling {
+            metrics.observe_transaction_applied(
+                transaction_message_type(transaction),
+                started.elapsed(),
+            );
+        }
+    }
+
+    /// State root of a shard with no transactions applied, i.e. the `shard_root` a genesis chunk
+    /// commits to. The genesis key is stored in a leaf one level per key byte below the root, with a
+    /// single child at every level, so the root is the leaf hash re-hashed once per level.
+    pub fn empty_state_root() -> Vec<u8> {
+        let mut hash = blake3_20(&GENESIS_TRIE_KEY);
+        for _ in 0..GENESIS_TRIE_KEY.len() {
+            hash = blake3_20(&hash);
+        }
+        hash
+    }
+
+    /// The shard root a chunk at `block_number` builds on, i.e. the root of the chunk before it. Falls
+    /// back to the current trie root when that chunk isn't stored, e.g. for the first chunk.
+    pub fn previous_shard_root(&self, block_number: u64) -> Vec<u8> {
+        let previous_chunk = match block_number.checked_sub(1) {
+            Some(previous) if previous > 0 => self.shard_store.get_shard_chunk(previous),
+            _ => Ok(None),
+        };
+        match previous_chunk {
+            Ok(Some(ShardChunk {
+                header: Some(header),
+                ..
+            })) => header.shard_root,
+            Ok(_) => self.trie.root_hash().unwrap(),
+            Err(err) => {
+                error!("Unable to read previous shard chunk {}", err);
+                self.trie.root_hash().unwrap()
+            }
+        }
+    }
+
+    /// Root of the trie with every committed chunk applied
+    pub fn state_root(&self) -> Vec<u8> {
+        self.trie.root_hash().unwrap()
+    }
+
+    /// The highest stored chunk, None when nothing is stored yet
+    pub fn tip_chunk(&self) -> Option<ShardChunk> {
+        let tip = self
+            .shard_store
+            .max_block_number()
+            .and_then(|block_number| self.shard_store.get_shard_chunk(block_number));
+        match tip {
+            Ok(tip) => tip,
+            Err(err) => {
+                error!("Unable to read tip shard chunk {}", err);
+                None
+            }
+        }
+    }
+
+    /// Timestamp of the highest stored chunk, None when nothing is stored yet
+    pub fn tip_timestamp(&self) -> Option<u64> {
+        self.tip_chunk()
+            .and_then(|chunk| chunk.header)
+            .map(|header| header.timestamp)
+    }
+
     pub fn messages_tx(&self) -> mpsc::Sender<message::Message> {
         self.messages_tx.clone()
     }
 
-    pub fn propose_state_change(&mut self, shard: u32) -> ShardStateChange {
-        //TODO: return Result instead of .unwrap() ?
+    pub async fn propose_state_change(&mut self, shard: u32) -> ShardStateChange {
+        run_blocking(|| self.propose_state_change_blocking(shard))
+    }
+
+    // Like propose_state_change for exactly these transactions, leaving the mempool alone. For tests
+    // and tooling that need a deterministic chunk. Transactions that fail to apply are still dropped.
+    pub async fn propose_state_change_with_transactions(
+        &mut self,
+        shard: u32,
+        transactions: Vec<snapchain::Transaction>,
+    ) -> ShardStateChange {
+        run_blocking(|| self.state_change_for_transactions(shard, transactions))
+    }
+
+    fn propose_state_change_blocking(&mut self, shard: u32) -> ShardStateChange {
         let it = iter::from_fn(|| self.messages_rx.try_recv().ok());
-        let user_messages: Vec<message::Message> = it.collect();
 
-        let mut hashes: Vec<Vec<u8>> = vec![];
-        for msg in &user_messages {
-            hashes.push(msg.hash.clone());
+        // One transaction per fid. The mempool can hold the same message more than once, only the first
+        // occurrence is kept so the chunk stays deterministic.
+        let mut messages_by_fid: BTreeMap<u64, Vec<message::Message>> = BTreeMap::new();
+        let mut seen_hashes = HashSet::new();
+        for msg in it {
+            if !seen_hashes.insert(msg.hash.clone()) {
+                continue;
+            }
+            let fid = msg.data.as_ref().map_or(0, |data| data.fid);
+            messages_by_fid.entry(fid).or_default().push(msg);
         }
 
-        let transactions = vec![snapchain::Transaction {
-            fid: 1234,                      //TODO
-            account_root: vec![5, 5, 6, 6], //TODO
-            system_messages: vec![],        //TODO
-            user_messages,
-        }];
+        let transactions: Vec<snapchain::Transaction> = messages_by_fid
+            .into_iter()
+            .map(|(fid, user_messages)| snapchain::Transaction {
+                fid,
+                account_root: vec![5, 5, 6, 6], //TODO
+                system_messages: vec![],        //TODO
+                user_messages,
+            })
+            .collect();
+
+        self.state_change_for_transactions(shard, transactions)
+    }
+
+    fn state_change_for_transactions(
+        &mut self,
+        shard: u32,
+        transactions: Vec<snapchain::Transaction>,
+    ) -> ShardStateChange {
+        //TODO: return Result instead of .unwrap() ?
+        let old_root_hash = self.trie.root_hash().unwrap();
+
+        // Drop the transactions that fail to apply instead of failing the whole chunk
+        let results = self.apply_transactions(&transactions);
+        let transactions: Vec<snapchain::Transaction> = transactions
+            .into_iter()
+            .zip(results)
+            .filter_map(|(tx, (index, result))| match result {
+                Ok(()) => Some(tx),
+                Err(err) => {
+                    warn!(shard, index, fid = tx.fid, "dropping transaction: {}", err);
+                    None
+                }
+            })
+            .collect();
+
+        let mut hashes: Vec<Vec<u8>> = vec![];
+        for tx in &transactions {
+            for msg in &tx.user_messages {
+                hashes.push(msg.hash.clone());
+            }
+        }
 
         warn!(
             shard,
             insert = encode_vec(&hashes.clone()),
-            old_root_hash = hex::encode(self.trie.root_hash().unwrap()),
+            old_root_hash = hex::encode(old_root_hash),
             "propose – before insert",
         );
 
-        self.trie.insert(hashes);
         let new_root_hash = self.trie.root_hash().unwrap();
         let count = self.trie.items().unwrap();
 
@@ -123,6 +332,76 @@ impl ShardEngine {
         // Return the state change
     }
 
+    fn check_transaction(
+        &self,
+        transaction: &proto::Transaction,
+        seen_hashes: &HashSet<Vec<u8>>,
+    ) -> Result<(), TxError> {
+        if transaction.user_messages.is_empty() && transaction.system_messages.is_empty() {
+            return Err(TxError::EmptyTransaction);
+        }
+
+        let mut tx_hashes = HashSet::new();
+        for msg in &transaction.user_messages {
+            if msg.hash.is_empty() {
+                return Err(TxError::MissingMessageHash);
+            }
+            let hash = hex::encode(&msg.hash);
+            if let Some(data) = &msg.data {
+                if data.fid != transaction.fid {
+                    return Err(TxError::FidMismatch {
+                        fid: transaction.fid,
+                        message_fid: data.fid,
+                        hash,
+                    });
+                }
+            }
+            let exists = self
+                .trie
+                .exists(&msg.hash)
+                .map_err(|e| TxError::TrieError(e.message))?;
+            if exists || seen_hashes.contains(&msg.hash) || !tx_hashes.insert(msg.hash.clone()) {
+                return Err(TxError::DuplicateMessage { hash });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Apply the transactions to the (uncommitted) trie in order, reporting the outcome of each by
+    /// its index. Failed transactions leave the trie untouched.
+    pub fn apply_transactions(
+        &mut self,
+        transactions: &[proto::Transaction],
+    ) -> Vec<(usize, Result<(), TxError>)> {
+        let mut seen_hashes = HashSet::new();
+        let mut results = Vec::with_capacity(transactions.len());
+        for (index, transaction) in transactions.iter().enumerate() {
+            let started = Instant::now();
+            let result = self
+                .check_transaction(transaction, &seen_hashes)
+                .and_then(|()| {
+                    let hashes: Vec<Vec<u8>> = transaction
+                        .user_messages
+                        .iter()
+                        .map(|msg| msg.hash.clone())
+                        .collect();
+                    if !hashes.is_empty() {
+                        self.trie
+                            .insert(hashes.clone())
+                            .map_err(|e| TxError::TrieError(e.message))?;
+                    }
+                    seen_hashes.extend(hashes);
+                    Ok(())
+                });
+            if result.is_ok() {
+                self.observe_transaction_applied(transaction, started);
+            }
+            results.push((index, result));
+        }
+        results
+    }
+
     pub fn validate_state_change(&mut self, shard_state_change: &ShardStateChange) -> bool {
         // TODO: actually validate
 
@@ -134,15 +413,36 @@ impl ShardEngine {
         true // TODO
     }
 
-    pub fn commit_shard_chunk(&mut self, shard_chunk: ShardChunk) {
+    // Returns whether the chunk was written to the shard store
+    pub fn commit_shard_chunk(&mut self, shard_chunk: ShardChunk) -> bool {
         let shard_root = shard_chunk.clone().header.unwrap().shard_root; // TODO: without clone?
 
-        let mut hashes: Vec<Vec<u8>> = vec![];
-        // TODO! don't assume 1 transaction
-        for msg in &shard_chunk.transactions[0].user_messages {
-            hashes.push(msg.hash.clone());
+        // Checked before the trie is touched: the same chunk again (e.g. while catching up) is
+        // already applied, and a different one at its height couldn't be written after the trie commit
+        match self.shard_store.contains_shard_chunk(&shard_chunk) {
+            Ok(false) => {}
+            Ok(true) => {
+                let height = shard_chunk.header.as_ref().and_then(|header| header.height);
+                info!(?height, "Shard chunk is already committed");
+                return false;
+            }
+            Err(err) => {
+                error!("Not committing shard chunk {}", err);
+                return false;
+            }
         }
 
+        // Recorded first so a crash between the trie commit and the chunk write can be reconciled
+        if let Err(err) = self.shard_store.begin_commit(&shard_chunk) {
+            error!("Unable to record shard chunk commit {}", err);
+            return false;
+        }
+
+        let hashes: Vec<Vec<u8>> = shard_chunk
+            .iter_messages()
+            .map(|msg| msg.hash.clone())
+            .collect();
+
         let root0 = self.trie.root_hash().unwrap();
 
         warn!(
@@ -151,7 +451,23 @@ impl ShardEngine {
             "commit insert"
         );
 
-        self.trie.insert(hashes.clone()).unwrap();
+        if self.profiling.is_some() {
+            // Insert transaction by transaction to time each one, the trie ends up the same
+            for transaction in &shard_chunk.transactions {
+                let started = Instant::now();
+                let tx_hashes: Vec<Vec<u8>> = transaction
+                    .user_messages
+                    .iter()
+                    .map(|msg| msg.hash.clone())
+                    .collect();
+                if !tx_hashes.is_empty() {
+                    self.trie.insert(tx_hashes).unwrap();
+                }
+                self.observe_transaction_applied(transaction, started);
+            }
+        } else {
+            self.trie.insert(hashes.clone()).unwrap();
+        }
         let root1 = self.trie.root_hash().unwrap();
 
         let hashes_match = &root1 == &shard_root;
@@ -187,14 +503,58 @@ impl ShardEngine {
         // write the events to the db
         // Commit the transaction
         // Emit events
-        match self.shard_store.put_shard_chunk(shard_chunk) {
+        // On failure the commit record stays, so recover() finishes the write on the next start or
+        // reports a different chunk that got stored at the height in the meantime
+        match self.shard_store.put_shard_chunk_if_absent(shard_chunk) {
             Err(err) => {
-                error!("Unable to write shard chunk to store {}", err)
+                error!("Unable to write shard chunk to store {}", err);
+                false
+            }
+            Ok(()) => {
+                if let Err(err) = self.shard_store.end_commit() {
+                    error!("Unable to clear shard chunk commit record {}", err);
+                }
+                true
             }
-            Ok(()) => {}
         }
     }
 
+    /// Reconcile a commit interrupted by a crash, run before the engine is used. The trie commit is a
+    /// single db write, so the trie either has the pending chunk applied or is still at its parent.
+    pub fn recover(&mut self) -> Result<CommitRecovery, shard::ShardStorageError> {
+        let shard_chunk = match self.shard_store.pending_commit()? {
+            None => return Ok(CommitRecovery::Clean),
+            Some(shard_chunk) => shard_chunk,
+        };
+        let header = shard_chunk
+            .header
+            .as_ref()
+            .ok_or(shard::ShardStorageError::ShardMissingHeader)?;
+        let block_number = header
+            .height
+            .as_ref()
+            .ok_or(shard::ShardStorageError::ShardMissingHeight)?
+            .block_number;
+
+        let recovery = if self.trie.root_hash().unwrap() == header.shard_root {
+            // A different chunk stored at the height means the trie holds state the store doesn't
+            // match, that needs an operator. The record is kept so every start reports it.
+            if let Err(err) = self.shard_store.put_shard_chunk_if_absent(shard_chunk) {
+                error!(
+                    shard = self.shard_id,
+                    block_number, "unable to complete partial commit {}", err
+                );
+                return Err(err);
+            }
+            CommitRecovery::Completed { block_number }
+        } else {
+            CommitRecovery::RolledBack { block_number }
+        };
+        self.shard_store.end_commit()?;
+        warn!(shard = self.shard_id, ?recovery, "recovered partial commit");
+        Ok(recovery)
+    }
+
     pub fn get_confirmed_height(&self) -> Height {
         match self.shard_store.max_block_number() {
             Ok(block_num) => Height::new(self.shard_id, block_num),
@@ -212,11 +572,31 @@ impl BlockEngine {
         BlockEngine { block_store }
     }
 
-    pub fn commit_block(&mut self, block: Block) {
-        let result = self.block_store.put_block(block);
+    // Returns whether the block was written to the block store, along with its commit log entry
+    // when a log is given
+    pub fn commit_block(
+        &mut self,
+        block: Block,
+        proposer: &Address,
+        commit_log: Option<&CommitLog>,
+    ) -> bool {
+        let result = match commit_log {
+            Some(commit_log) => self
+                .block_store
+                .put_block_with_proposer_logged(block, proposer, commit_log)
+                .map(|_| ()),
+            None => self.block_store.put_block_with_proposer(block, proposer),
+        };
         if result.is_err() {
             error!("Failed to store block: {:?}", result.err());
+            return false;
         }
+        true
+    }
+
+    // Blocks synced from a peer, the proposer isn't known for those
+    pub fn commit_synced_block(&mut self, block: Block) -> Result<(), BlockStorageError> {
+        self.block_store.put_block(block)
     }
 
     pub fn get_confirmed_height(&self) -> Height {
@@ -228,3 +608,388 @@ impl BlockEngine {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_run_blocking_does_not_stall_runtime() {
+        let ticks = Arc::new(AtomicU64::new(0));
+        let ticker_ticks = ticks.clone();
+        tokio::spawn(async move {
+            loop {
+                ticker_ticks.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        });
+
+        // Simulate a slow propose on the only worker thread
+        let ticks_during_propose = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                let before = ticks.load(Ordering::SeqCst);
+                run_blocking(|| std::thread::sleep(Duration::from_millis(200)));
+                ticks.load(Ordering::SeqCst) - before
+            }
+        })
+        .await
+        .unwrap();
+
+        assert!(ticks_during_propose > 0);
+    }
+
+    #[test]
+    fn test_fresh_engine_has_empty_state_root() {
+        let db = RocksDB::new(
+            tempfile::tempdir()
+                .unwrap()
+                .path()
+                .as_os_str()
+                .to_str()
+                .unwrap(),
+        );
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let engine = ShardEngine::new(1, shard_store.clone());
+
+        assert_eq!(
+            engine.trie.root_hash().unwrap(),
+            ShardEngine::empty_state_root()
+        );
+        assert_eq!(ShardEngine::empty_state_root().len(), 20);
+
+        shard_store.db.destroy().unwrap();
+    }
+
+    fn message(fid: u64, hash: Vec<u8>) -> message::Message {
+        message::Message {
+            data: Some(message::MessageData {
+                fid,
+                ..Default::default()
+            }),
+            hash,
+            ..Default::default()
+        }
+    }
+
+    fn transaction(fid: u64, user_messages: Vec<message::Message>) -> proto::Transaction {
+        proto::Transaction {
+            fid,
+            user_messages,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_transactions_reports_per_transaction() {
+        let db = RocksDB::new(
+            tempfile::tempdir()
+                .unwrap()
+                .path()
+                .as_os_str()
+                .to_str()
+                .unwrap(),
+        );
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let mut engine = ShardEngine::new(1, shard_store.clone());
+
+        let transactions = vec![
+            transaction(1, vec![message(1, vec![1; 20])]),
+            transaction(2, vec![message(3, vec![2; 20])]),
+            transaction(4, vec![message(4, vec![1; 20])]),
+            transaction(5, vec![]),
+            transaction(6, vec![message(6, vec![6; 20])]),
+        ];
+        let results = engine.apply_transactions(&transactions);
+
+        assert_eq!(
+            results,
+            vec![
+                (0, Ok(())),
+                (
+                    1,
+                    Err(TxError::FidMismatch {
+                        fid: 2,
+                        message_fid: 3,
+                        hash: hex::encode(vec![2; 20]),
+                    })
+                ),
+                (
+                    2,
+                    Err(TxError::DuplicateMessage {
+                        hash: hex::encode(vec![1; 20]),
+                    })
+                ),
+                (3, Err(TxError::EmptyTransaction)),
+                (4, Ok(())),
+            ]
+        );
+        assert!(!engine.trie.exists(&vec![2; 20]).unwrap());
+        engine.trie.reload().unwrap();
+
+        // Failing transactions are dropped from a proposed chunk, duplicate messages are deduplicated first
+        let messages_tx = engine.messages_tx();
+        messages_tx.send(message(7, vec![7; 20])).await.unwrap();
+        messages_tx.send(message(8, vec![8; 20])).await.unwrap();
+        messages_tx.send(message(8, vec![8; 20])).await.unwrap();
+        messages_tx.send(message(9, vec![1; 20])).await.unwrap();
+        let state_change = engine.propose_state_change(1).await;
+        let fids: Vec<u64> = state_change.transactions.iter().map(|tx| tx.fid).collect();
+        assert_eq!(fids, vec![7, 8]);
+        assert_eq!(state_change.transactions[1].user_messages.len(), 1);
+
+        shard_store.db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_profiling_records_apply_times() {
+        let db = RocksDB::new(
+            tempfile::tempdir()
+                .unwrap()
+                .path()
+                .as_os_str()
+                .to_str()
+                .unwrap(),
+        );
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let metrics = ConsensusMetrics::new();
+        let mut engine = ShardEngine::new(1, shard_store.clone()).with_profiling(metrics.clone());
+
+        let cast_add = |fid: u64, hash: Vec<u8>| {
+            let mut msg = message(fid, hash);
+            msg.data.as_mut().unwrap().r#type = message::MessageType::CastAdd as i32;
+            msg
+        };
+        let messages_tx = engine.messages_tx();
+        messages_tx.send(cast_add(1, vec![1; 20])).await.unwrap();
+        messages_tx.send(cast_add(2, vec![2; 20])).await.unwrap();
+        messages_tx.send(message(3, vec![3; 20])).await.unwrap();
+        let state_change = engine.propose_state_change(1).await;
+
+        let cast_adds = metrics
+            .transaction_apply_times("MESSAGE_TYPE_CAST_ADD")
+            .unwrap();
+        assert_eq!(cast_adds.count, 2);
+        assert_eq!(cast_adds.buckets.last(), Some(&2));
+        let untyped = metrics
+            .transaction_apply_times("MESSAGE_TYPE_NONE")
+            .unwrap();
+        assert_eq!(untyped.count, 1);
+
+        // Committing applies the same transactions again
+        engine.commit_shard_chunk(ShardChunk {
+            header: Some(snapchain::ShardHeader {
+                height: Some(Height::new(1, 1)),
+                shard_root: state_change.new_state_root,
+                ..Default::default()
+            }),
+            transactions: state_change.transactions,
+            ..Default::default()
+        });
+        assert_eq!(
+            metrics
+                .transaction_apply_times("MESSAGE_TYPE_CAST_ADD")
+                .unwrap()
+                .count,
+            4
+        );
+        assert!(metrics.render_prometheus().contains(
+            "snapchain_transaction_apply_seconds_count{message_type=\"MESSAGE_TYPE_CAST_ADD\"} 4"
+        ));
+
+        shard_store.db.destroy().unwrap();
+    }
+
+    async fn proposed_chunk(engine: &mut ShardEngine, block_number: u64) -> ShardChunk {
+        let fid = block_number * 2;
+        let state_change = engine
+            .propose_state_change_with_transactions(
+                1,
+                vec![transaction(fid, vec![message(fid, vec![fid as u8; 20])])],
+            )
+            .await;
+        ShardChunk {
+            header: Some(snapchain::ShardHeader {
+                height: Some(Height::new(1, block_number)),
+                shard_root: state_change.new_state_root,
+                ..Default::default()
+            }),
+            hash: vec![block_number as u8; 32],
+            transactions: state_change.transactions,
+            votes: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recover_partial_commit() {
+        let db = RocksDB::new(
+            tempfile::tempdir()
+                .unwrap()
+                .path()
+                .as_os_str()
+                .to_str()
+                .unwrap(),
+        );
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let mut engine = ShardEngine::new(1, shard_store.clone());
+        assert_eq!(engine.recover().unwrap(), CommitRecovery::Clean);
+
+        // Crash after the trie commit, before the chunk is written
+        let chunk = proposed_chunk(&mut engine, 1).await;
+        shard_store.begin_commit(&chunk).unwrap();
+        let hashes: Vec<Vec<u8>> = chunk.iter_messages().map(|msg| msg.hash.clone()).collect();
+        engine.trie.insert(hashes).unwrap();
+        engine.trie.commit().unwrap();
+        drop(engine);
+
+        let mut engine = ShardEngine::new(1, shard_store.clone());
+        assert_eq!(
+            engine.recover().unwrap(),
+            CommitRecovery::Completed { block_number: 1 }
+        );
+        assert_eq!(shard_store.get_shard_chunk(1).unwrap(), Some(chunk.clone()));
+        assert_eq!(shard_store.pending_commit().unwrap(), None);
+        assert_eq!(
+            engine.state_root(),
+            chunk.header.as_ref().unwrap().shard_root
+        );
+
+        // Crash before the trie commit
+        let chunk = proposed_chunk(&mut engine, 2).await;
+        let root_before = engine.state_root();
+        shard_store.begin_commit(&chunk).unwrap();
+        drop(engine);
+
+        let mut engine = ShardEngine::new(1, shard_store.clone());
+        assert_eq!(
+            engine.recover().unwrap(),
+            CommitRecovery::RolledBack { block_number: 2 }
+        );
+        assert_eq!(shard_store.get_shard_chunk(2).unwrap(), None);
+        assert_eq!(shard_store.pending_commit().unwrap(), None);
+        assert_eq!(engine.state_root(), root_before);
+
+        // The rolled back chunk commits cleanly when it comes around again
+        assert!(engine.commit_shard_chunk(chunk.clone()));
+        assert_eq!(shard_store.get_shard_chunk(2).unwrap(), Some(chunk));
+        assert_eq!(shard_store.pending_commit().unwrap(), None);
+        assert_eq!(engine.recover().unwrap(), CommitRecovery::Clean);
+
+        shard_store.db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_commit_same_chunk_twice() {
+        let db = RocksDB::new(
+            tempfile::tempdir()
+                .unwrap()
+                .path()
+                .as_os_str()
+                .to_str()
+                .unwrap(),
+        );
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let mut engine = ShardEngine::new(1, shard_store.clone());
+
+        let chunk = proposed_chunk(&mut engine, 1).await;
+        assert!(engine.commit_shard_chunk(chunk.clone()));
+        let root = engine.state_root();
+
+        // Already applied, the trie isn't touched again and nothing is rewritten
+        assert!(!engine.commit_shard_chunk(chunk.clone()));
+        assert_eq!(engine.state_root(), root);
+        assert_eq!(shard_store.get_shard_chunk(1).unwrap(), Some(chunk));
+        assert_eq!(shard_store.pending_commit().unwrap(), None);
+
+        shard_store.db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_commit_conflicting_chunk() {
+        let db = RocksDB::new(
+            tempfile::tempdir()
+                .unwrap()
+                .path()
+                .as_os_str()
+                .to_str()
+                .unwrap(),
+        );
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let mut engine = ShardEngine::new(1, shard_store.clone());
+
+        let chunk = proposed_chunk(&mut engine, 1).await;
+        let conflicting = ShardChunk {
+            hash: vec![9; 32],
+            ..chunk.clone()
+        };
+        shard_store.put_shard_chunk(conflicting.clone()).unwrap();
+
+        // Refused before the trie is touched, trie and store still agree and there's nothing to recover
+        let root_before = engine.state_root();
+        assert!(!engine.commit_shard_chunk(chunk.clone()));
+        assert_eq!(engine.state_root(), root_before);
+        assert_eq!(shard_store.get_shard_chunk(1).unwrap(), Some(conflicting));
+        assert_eq!(shard_store.pending_commit().unwrap(), None);
+        assert_eq!(engine.recover().unwrap(), CommitRecovery::Clean);
+
+        // The engine carries on from the unchanged trie at the next height
+        let next_chunk = proposed_chunk(&mut engine, 2).await;
+        assert!(engine.commit_shard_chunk(next_chunk.clone()));
+        assert_eq!(shard_store.get_shard_chunk(2).unwrap(), Some(next_chunk));
+
+        shard_store.db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recover_conflicting_partial_commit() {
+        let db = RocksDB::new(
+            tempfile::tempdir()
+                .unwrap()
+                .path()
+                .as_os_str()
+                .to_str()
+                .unwrap(),
+        );
+        db.open().unwrap();
+        let shard_store = ShardStore::new(db);
+        let mut engine = ShardEngine::new(1, shard_store.clone());
+
+        let chunk = proposed_chunk(&mut engine, 1).await;
+        let conflicting = ShardChunk {
+            hash: vec![9; 32],
+            ..chunk.clone()
+        };
+        shard_store.put_shard_chunk(conflicting.clone()).unwrap();
+
+        // Crash after the trie commit, with the different chunk written in the meantime
+        shard_store.begin_commit(&chunk).unwrap();
+        let hashes: Vec<Vec<u8>> = chunk.iter_messages().map(|msg| msg.hash.clone()).collect();
+        engine.trie.insert(hashes).unwrap();
+        engine.trie.commit().unwrap();
+        drop(engine);
+
+        let mut engine = ShardEngine::new(1, shard_store.clone());
+        assert!(matches!(
+            engine.recover(),
+            Err(shard::ShardStorageError::AlreadyExists {
+                block_number: 1,
+                ..
+            })
+        ));
+        // Still reported on the next start, the stored chunk isn't replaced
+        assert_eq!(shard_store.pending_commit().unwrap(), Some(chunk));
+        assert_eq!(shard_store.get_shard_chunk(1).unwrap(), Some(conflicting));
+        assert!(engine.recover().is_err());
+
+        shard_store.db.destroy().unwrap();
+    }
+}
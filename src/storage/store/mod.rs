@@ -2,6 +2,7 @@ pub use self::block::*;
 pub use self::utils::*;
 
 pub mod block;
+pub mod commit_log;
 pub mod engine;
 pub mod shard;
 pub mod utils;
@@ -1,11 +1,20 @@
-use crate::proto::snapchain::{Block, ShardChunk, Transaction};
-use crate::storage::db::{PageOptions, RocksDB, RocksdbError};
+use crate::consensus::proposer::{header_hash, header_hash_len_for_version};
+use crate::core::buffer_pool;
+use crate::core::merkle::{self, MerkleProof};
+use crate::proto::snapchain::{Block, ShardChunk, ShardChunkHeader, ShardHeader, Transaction};
+use crate::storage::db::{
+    PageOptions, RocksDB, RocksDbTransactionBatch, RocksdbError, CHUNK_METADATA_CF,
+    MESSAGE_LOCATIONS_CF, SHARD_CHUNKS_CF,
+};
 use crate::storage::store::block::RootPrefix;
 use prost::Message;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use thiserror::Error;
+use tracing::warn;
 
-use super::utils::PAGE_SIZE_MAX;
+use super::utils::{GapFinder, PAGE_SIZE_MAX};
+
+static PAGE_SIZE: usize = 100;
 
 // TODO(aditi): This code definitely needs unit tests
 #[derive(Error, Debug)]
@@ -21,12 +30,128 @@ pub enum ShardStorageError {
 
     #[error("Too many shards in result")]
     TooManyShardsInResult,
+
+    #[error("Shard chunks are not contiguous: expected height {expected}, got {actual}")]
+    NonContiguousChunks { expected: u64, actual: u64 },
+
+    #[error("Shard store is read only")]
+    ReadOnly,
+
+    #[error("No shard chunk stored at height {block_number}")]
+    ChunkNotFound { block_number: u64 },
+
+    #[error("Shard chunk at height {block_number} has no transaction at index {tx_index}")]
+    TransactionNotFound { block_number: u64, tx_index: usize },
+
+    #[error("A different shard chunk already exists at height {block_number} (stored hash {})", hex::encode(.stored_hash))]
+    AlreadyExists {
+        block_number: u64,
+        stored_hash: Vec<u8>,
+    },
+
+    #[error("Blocking read task failed: {0}")]
+    ReadTaskFailed(#[from] tokio::task::JoinError),
+}
+
+// Only the header of a stored ShardChunk, decoding skips over the transactions
+#[derive(Clone, PartialEq, Message)]
+struct StoredShardChunkHeader {
+    #[prost(message, optional, tag = "1")]
+    header: Option<ShardHeader>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChunkCorruption {
+    Undecodable,
+    MissingHeader,
+    // The header doesn't hash to the stored hash, for the hash length recorded in its version (the
+    // default length when unversioned)
+    HashMismatch,
+    ParentHashMismatch,
+    HeightGap { expected: u64 },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    pub chunks_checked: u64,
+    /// Height of the first corrupted chunk and what's wrong with it
+    pub first_corruption: Option<(u64, ChunkCorruption)>,
+    /// Heights deleted by a destructive repair, the corrupted chunk and everything after it
+    pub truncated: Vec<u64>,
+}
+
+fn check_chunk(
+    value: &[u8],
+    block_number: u64,
+    previous: Option<&(u64, Vec<u8>)>,
+) -> Result<Vec<u8>, ChunkCorruption> {
+    let shard_chunk = decode_chunk_record(value).map_err(|_| ChunkCorruption::Undecodable)?;
+    let header = shard_chunk
+        .header
+        .as_ref()
+        .ok_or(ChunkCorruption::MissingHeader)?;
+    let hash_len = header_hash_len_for_version(header.version);
+    if hash_len as usize > blake3::OUT_LEN
+        || shard_chunk.hash.len() != hash_len as usize
+        || buffer_pool::with_encoded(header, |bytes| header_hash(bytes, hash_len))
+            != shard_chunk.hash
+    {
+        return Err(ChunkCorruption::HashMismatch);
+    }
+    // The oldest chunk may have had its parent pruned, only chunks after it are linked
+    if let Some((previous_block_number, previous_hash)) = previous {
+        if block_number != previous_block_number + 1 {
+            return Err(ChunkCorruption::HeightGap {
+                expected: previous_block_number + 1,
+            });
+        }
+        if &header.parent_hash != previous_hash {
+            return Err(ChunkCorruption::ParentHashMismatch);
+        }
+    }
+    Ok(shard_chunk.hash)
 }
 
 /** A page of messages returned from various APIs */
 pub struct ShardPage {
     pub shard_chunks: Vec<ShardChunk>,
     pub next_page_token: Option<Vec<u8>>,
+    /// Keys of records that couldn't be decoded and were skipped, only when skipping is enabled
+    pub corrupt_keys: Vec<Vec<u8>>,
+}
+
+pub struct ShardHeaderPage {
+    pub headers: Vec<ShardChunkHeader>,
+    pub next_page_token: Option<Vec<u8>>,
+}
+
+// Version byte stored in front of every encoded chunk, so the encoding can be migrated later. Records
+// written before versioning are bare encoded chunks, read as version 0. Those can't be mistaken for a
+// versioned record: a protobuf never starts with a byte below 8, that would be a tag for field 0.
+const CHUNK_RECORD_VERSION: u8 = 1;
+const MIN_PROTOBUF_TAG_BYTE: u8 = 8;
+
+fn encode_chunk_record(shard_chunk: &ShardChunk) -> Vec<u8> {
+    let mut record = buffer_pool::take(1 + shard_chunk.encoded_size());
+    record.push(CHUNK_RECORD_VERSION);
+    shard_chunk.encode(&mut record).unwrap(); // Vec grows as needed, encoding can't fail
+    record
+}
+
+// The encoded chunk inside a stored record, whatever version it was written with
+fn chunk_record_payload(record: &[u8]) -> Result<&[u8], RocksdbError> {
+    match record.first() {
+        Some(&CHUNK_RECORD_VERSION) => Ok(&record[1..]),
+        Some(&version) if version < MIN_PROTOBUF_TAG_BYTE => {
+            Err(RocksdbError::UnknownRecordVersion(version))
+        }
+        // Version 0, stored without a version byte
+        _ => Ok(record),
+    }
+}
+
+fn decode_chunk_record(record: &[u8]) -> Result<ShardChunk, RocksdbError> {
+    Ok(ShardChunk::decode(chunk_record_payload(record)?)?)
 }
 
 fn make_shard_key(block_number: u64) -> Vec<u8> {
@@ -39,26 +164,100 @@ fn make_shard_key(block_number: u64) -> Vec<u8> {
     key
 }
 
+fn make_commit_wal_key() -> Vec<u8> {
+    // One pending commit at a time per shard, each shard has its own db
+    vec![RootPrefix::ShardCommitWal as u8]
+}
+
+fn make_message_location_key(message_hash: &[u8]) -> Vec<u8> {
+    let mut key = vec![RootPrefix::MessageLocation as u8];
+    key.extend_from_slice(message_hash);
+    key
+}
+
+// Index every message of the chunk by hash, in the same db transaction as the chunk itself
+fn put_message_locations(
+    txn: &mut RocksDbTransactionBatch,
+    shard_chunk: &ShardChunk,
+    block_number: u64,
+) {
+    for (tx_index, transaction) in shard_chunk.transactions.iter().enumerate() {
+        for message in transaction
+            .user_messages
+            .iter()
+            .filter(|m| !m.hash.is_empty())
+        {
+            let mut location = block_number.to_be_bytes().to_vec();
+            location.extend_from_slice(&(tx_index as u32).to_be_bytes());
+            txn.put_cf(
+                MESSAGE_LOCATIONS_CF,
+                make_message_location_key(&message.hash),
+                location,
+            );
+        }
+    }
+}
+
+fn decode_message_location(value: &[u8]) -> Option<(u64, usize)> {
+    let block_number: [u8; 8] = value.get(0..8)?.try_into().ok()?;
+    let tx_index: [u8; 4] = value.get(8..12)?.try_into().ok()?;
+    Some((
+        u64::from_be_bytes(block_number),
+        u32::from_be_bytes(tx_index) as usize,
+    ))
+}
+
+fn block_number_from_shard_key(key: &[u8]) -> Option<u64> {
+    let bytes: [u8; 8] = key.get(1..9)?.try_into().ok()?;
+    Some(u64::from_be_bytes(bytes))
+}
+
+fn make_shard_stop_key() -> Vec<u8> {
+    // Chunks live in their own column family, this just bounds scans past the last block number
+    vec![RootPrefix::Shard as u8 + 1]
+}
+
+// With skip_corrupt, records that don't decode are logged and left out of the page instead of
+// failing the whole scan
 fn get_shard_page_by_prefix(
     db: &RocksDB,
     page_options: &PageOptions,
     start_prefix: Option<Vec<u8>>,
     stop_prefix: Option<Vec<u8>>,
+    skip_corrupt: bool,
 ) -> Result<ShardPage, ShardStorageError> {
     let mut shard_chunks = Vec::new();
+    let mut corrupt_keys = Vec::new();
     let mut last_key = vec![];
 
-    db.for_each_iterator_by_prefix_paged(start_prefix, stop_prefix, page_options, |key, value| {
-        let block = ShardChunk::decode(value)?;
-        shard_chunks.push(block);
+    db.for_each_iterator_by_prefix_paged_cf(
+        SHARD_CHUNKS_CF,
+        start_prefix,
+        stop_prefix,
+        page_options,
+        |key, value| {
+            let block = match decode_chunk_record(value) {
+                Ok(block) => block,
+                Err(err) if skip_corrupt => {
+                    warn!(
+                        key = hex::encode(key),
+                        "Skipping corrupt shard chunk: {}", err
+                    );
+                    corrupt_keys.push(key.to_vec());
+                    return Ok(false); // Continue iterating
+                }
+                Err(err) => return Err(err),
+            };
+            shard_chunks.push(block);
 
-        if shard_chunks.len() >= page_options.page_size.unwrap_or(PAGE_SIZE_MAX) {
-            last_key = key.to_vec();
-            return Ok(true); // Stop iterating
-        }
+            if shard_chunks.len() >= page_options.page_size.unwrap_or(PAGE_SIZE_MAX) {
+                last_key = key.to_vec();
+                return Ok(true); // Stop iterating
+            }
 
-        Ok(false) // Continue iterating
-    })?;
+            Ok(false) // Continue iterating
+        },
+    )?;
 
     let next_page_token = if last_key.len() > 0 {
         Some(last_key)
@@ -69,6 +268,7 @@ fn get_shard_page_by_prefix(
     Ok(ShardPage {
         shard_chunks,
         next_page_token,
+        corrupt_keys,
     })
 }
 
@@ -82,7 +282,8 @@ pub fn get_current_height(db: &RocksDB) -> Result<Option<u64>, ShardStorageError
             page_token: None,
         },
         Some(start_block_key),
-        None,
+        Some(make_shard_stop_key()),
+        false,
     )?;
 
     if shard_page.shard_chunks.len() > 1 {
@@ -101,9 +302,29 @@ pub fn get_current_height(db: &RocksDB) -> Result<Option<u64>, ShardStorageError
     }
 }
 
-pub fn put_shard_chunk(db: &RocksDB, shard_chunk: ShardChunk) -> Result<(), ShardStorageError> {
-    // TODO: We need to introduce a transaction model
-    let mut txn = db.txn();
+pub fn get_shard_chunks_in_range(
+    db: &RocksDB,
+    page_options: &PageOptions,
+    start_block_number: u64,
+    stop_block_number: Option<u64>,
+    skip_corrupt: bool,
+) -> Result<ShardPage, ShardStorageError> {
+    let start_primary_key = make_shard_key(start_block_number);
+    let stop_prefix = match stop_block_number {
+        Some(block_number) => make_shard_key(block_number),
+        None => make_shard_stop_key(),
+    };
+
+    get_shard_page_by_prefix(
+        db,
+        page_options,
+        Some(start_primary_key),
+        Some(stop_prefix),
+        skip_corrupt,
+    )
+}
+
+fn shard_chunk_block_number(shard_chunk: &ShardChunk) -> Result<u64, ShardStorageError> {
     let header = shard_chunk
         .header
         .as_ref()
@@ -112,31 +333,1328 @@ pub fn put_shard_chunk(db: &RocksDB, shard_chunk: ShardChunk) -> Result<(), Shar
         .height
         .as_ref()
         .ok_or(ShardStorageError::ShardMissingHeight)?;
-    let primary_key = make_shard_key(height.block_number);
-    txn.put(primary_key, shard_chunk.encode_to_vec());
+    Ok(height.block_number)
+}
+
+pub fn put_shard_chunk(db: &RocksDB, shard_chunk: ShardChunk) -> Result<(), ShardStorageError> {
+    // TODO: We need to introduce a transaction model
+    let mut txn = db.txn();
+    let block_number = shard_chunk_block_number(&shard_chunk)?;
+    put_message_locations(&mut txn, &shard_chunk, block_number);
+    txn.put_cf_pooled(
+        SHARD_CHUNKS_CF,
+        make_shard_key(block_number),
+        encode_chunk_record(&shard_chunk),
+    );
+    db.commit(txn)?;
+    Ok(())
+}
+
+// Whether this exact chunk is already stored, a different chunk at its height is an AlreadyExists error
+pub fn contains_shard_chunk(
+    db: &RocksDB,
+    shard_chunk: &ShardChunk,
+) -> Result<bool, ShardStorageError> {
+    let block_number = shard_chunk_block_number(shard_chunk)?;
+    let stored = match db.get_cf(SHARD_CHUNKS_CF, &make_shard_key(block_number))? {
+        None => return Ok(false),
+        Some(stored) => stored,
+    };
+    // Compare the encoded chunks, a legacy record of the same chunk is still the same chunk
+    let payload = chunk_record_payload(&stored)?;
+    if buffer_pool::with_encoded(shard_chunk, |encoded| payload == encoded) {
+        return Ok(true);
+    }
+    let stored_chunk = decode_chunk_record(&stored)?;
+    Err(ShardStorageError::AlreadyExists {
+        block_number,
+        stored_hash: stored_chunk.hash,
+    })
+}
+
+// Write the chunk unless one is already stored at its height. Re-putting the identical chunk is a no-op,
+// so retries are safe, but a committed chunk is never replaced by a different one.
+pub fn put_shard_chunk_if_absent(
+    db: &RocksDB,
+    shard_chunk: ShardChunk,
+) -> Result<(), ShardStorageError> {
+    if contains_shard_chunk(db, &shard_chunk)? {
+        return Ok(());
+    }
+    let block_number = shard_chunk_block_number(&shard_chunk)?;
+    let primary_key = make_shard_key(block_number);
+
+    let mut txn = db.txn();
+    put_message_locations(&mut txn, &shard_chunk, block_number);
+    txn.put_cf_pooled(
+        SHARD_CHUNKS_CF,
+        primary_key,
+        encode_chunk_record(&shard_chunk),
+    );
     db.commit(txn)?;
     Ok(())
 }
 
-#[derive(Default)]
+pub fn put_shard_chunks_batch(
+    db: &RocksDB,
+    shard_chunks: &[ShardChunk],
+) -> Result<(), ShardStorageError> {
+    let mut txn = db.txn();
+    let mut expected_block_number = None;
+    for shard_chunk in shard_chunks {
+        let block_number = shard_chunk_block_number(shard_chunk)?;
+        if let Some(expected) = expected_block_number {
+            if block_number != expected {
+                return Err(ShardStorageError::NonContiguousChunks {
+                    expected,
+                    actual: block_number,
+                });
+            }
+        }
+        expected_block_number = Some(block_number + 1);
+        put_message_locations(&mut txn, shard_chunk, block_number);
+        txn.put_cf_pooled(
+            SHARD_CHUNKS_CF,
+            make_shard_key(block_number),
+            encode_chunk_record(shard_chunk),
+        );
+    }
+    db.commit(txn)?;
+    Ok(())
+}
+
+// Delete chunks from the oldest one up to the first chunk `keep` returns true for. Chunks are visited in
+// height order, so everything after that chunk is kept too. Returns the number of deleted chunks.
+pub fn prune_shard_chunks<F>(db: &RocksDB, mut keep: F) -> Result<u64, ShardStorageError>
+where
+    F: FnMut(&ShardChunk) -> bool,
+{
+    let mut txn = db.txn();
+    let mut pruned = 0;
+    db.for_each_iterator_by_prefix_paged_cf(
+        SHARD_CHUNKS_CF,
+        Some(make_shard_key(0)),
+        Some(make_shard_stop_key()),
+        &PageOptions::default(),
+        |key, value| {
+            let shard_chunk = decode_chunk_record(value)?;
+            if keep(&shard_chunk) {
+                return Ok(true); // Stop iterating
+            }
+            txn.delete_cf(SHARD_CHUNKS_CF, key.to_vec());
+            txn.delete_cf(CHUNK_METADATA_CF, key.to_vec());
+            for message in shard_chunk.iter_messages() {
+                txn.delete_cf(
+                    MESSAGE_LOCATIONS_CF,
+                    make_message_location_key(&message.hash),
+                );
+            }
+            pruned += 1;
+            Ok(false) // Continue iterating
+        },
+    )?;
+    db.commit(txn)?;
+    Ok(pruned)
+}
+
+#[derive(Default, Clone)]
 pub struct ShardStore {
     pub db: Arc<RocksDB>, // TODO: pub and Arc are temporary to allow trie to use
+    read_only: bool,
+    // Highest stored block number, None until the first scan after open
+    max_block_number: Arc<Mutex<Option<u64>>>,
+    skip_corrupt_chunks: bool,
 }
 
 impl ShardStore {
     pub fn new(db: RocksDB) -> ShardStore {
-        ShardStore { db: Arc::new(db) }
+        ShardStore {
+            db: Arc::new(db),
+            read_only: false,
+            max_block_number: Arc::new(Mutex::new(None)),
+            skip_corrupt_chunks: false,
+        }
+    }
+
+    // For replicas that only serve reads, all writes are rejected with [ShardStorageError::ReadOnly]
+    pub fn new_read_only(db: RocksDB) -> ShardStore {
+        ShardStore {
+            db: Arc::new(db),
+            read_only: true,
+            max_block_number: Arc::new(Mutex::new(None)),
+            skip_corrupt_chunks: false,
+        }
+    }
+
+    // Range reads skip chunks that can't be decoded and report their keys in the page, so the good
+    // chunks around a corrupt record can still be served. Off by default, the read fails instead.
+    pub fn with_skip_corrupt_chunks(self, skip_corrupt_chunks: bool) -> Self {
+        Self {
+            skip_corrupt_chunks,
+            ..self
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<(), ShardStorageError> {
+        if self.read_only {
+            return Err(ShardStorageError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    // Only raise a cached value, an unknown max is left for the next scan
+    fn observe_block_number(&self, block_number: u64) {
+        let mut max_block_number = self.max_block_number.lock().unwrap();
+        if let Some(max) = *max_block_number {
+            *max_block_number = Some(max.max(block_number));
+        }
     }
 
     pub fn put_shard_chunk(&self, shard_chunk: ShardChunk) -> Result<(), ShardStorageError> {
-        put_shard_chunk(&self.db, shard_chunk)
+        self.check_writable()?;
+        let block_number = shard_chunk_block_number(&shard_chunk)?;
+        put_shard_chunk(&self.db, shard_chunk)?;
+        self.observe_block_number(block_number);
+        Ok(())
+    }
+
+    pub fn contains_shard_chunk(
+        &self,
+        shard_chunk: &ShardChunk,
+    ) -> Result<bool, ShardStorageError> {
+        contains_shard_chunk(&self.db, shard_chunk)
+    }
+
+    pub fn put_shard_chunk_if_absent(
+        &self,
+        shard_chunk: ShardChunk,
+    ) -> Result<(), ShardStorageError> {
+        self.check_writable()?;
+        let block_number = shard_chunk_block_number(&shard_chunk)?;
+        put_shard_chunk_if_absent(&self.db, shard_chunk)?;
+        self.observe_block_number(block_number);
+        Ok(())
+    }
+
+    pub fn put_shard_chunks_batch(
+        &self,
+        shard_chunks: &[ShardChunk],
+    ) -> Result<(), ShardStorageError> {
+        self.check_writable()?;
+        put_shard_chunks_batch(&self.db, shard_chunks)?;
+        if let Some(last_chunk) = shard_chunks.last() {
+            self.observe_block_number(shard_chunk_block_number(last_chunk)?);
+        }
+        Ok(())
+    }
+
+    pub fn prune_chunks_before(&self, block_number: u64) -> Result<u64, ShardStorageError> {
+        self.check_writable()?;
+        prune_shard_chunks(&self.db, |shard_chunk| {
+            shard_chunk_block_number(shard_chunk).map_or(true, |number| number >= block_number)
+        })
+    }
+
+    pub fn prune_chunks_older_than(&self, timestamp: u64) -> Result<u64, ShardStorageError> {
+        self.prune_chunks_older_than_below(timestamp, u64::MAX)
+    }
+
+    // Only chunks that are both older than the timestamp and below the block number are pruned
+    pub fn prune_chunks_older_than_below(
+        &self,
+        timestamp: u64,
+        block_number: u64,
+    ) -> Result<u64, ShardStorageError> {
+        self.check_writable()?;
+        prune_shard_chunks(&self.db, |shard_chunk| {
+            shard_chunk.header.as_ref().map_or(true, |header| {
+                header.timestamp >= timestamp
+                    || header
+                        .height
+                        .map_or(true, |height| height.block_number >= block_number)
+            })
+        })
+    }
+
+    // Check every stored chunk's header hash and link to its parent, stopping at the first corrupted
+    // one. With `truncate`, that chunk and all chunks after it are deleted, otherwise nothing is
+    // changed.
+    pub fn verify_and_repair(&self, truncate: bool) -> Result<RepairReport, ShardStorageError> {
+        if truncate {
+            self.check_writable()?;
+        }
+
+        let mut report = RepairReport::default();
+        let mut previous: Option<(u64, Vec<u8>)> = None;
+        self.db.for_each_iterator_by_prefix_paged_cf(
+            SHARD_CHUNKS_CF,
+            Some(make_shard_key(0)),
+            Some(make_shard_stop_key()),
+            &PageOptions::default(),
+            |key, value| {
+                let block_number = block_number_from_shard_key(key).unwrap_or_default();
+                report.chunks_checked += 1;
+                match check_chunk(value, block_number, previous.as_ref()) {
+                    Ok(hash) => {
+                        previous = Some((block_number, hash));
+                        Ok(false) // Continue iterating
+                    }
+                    Err(corruption) => {
+                        report.first_corruption = Some((block_number, corruption));
+                        Ok(true) // Stop iterating
+                    }
+                }
+            },
+        )?;
+
+        let corrupted_block_number = match &report.first_corruption {
+            Some((block_number, _)) if truncate => *block_number,
+            _ => return Ok(report),
+        };
+        let mut txn = self.db.txn();
+        self.db.for_each_iterator_by_prefix_paged_cf(
+            SHARD_CHUNKS_CF,
+            Some(make_shard_key(corrupted_block_number)),
+            Some(make_shard_stop_key()),
+            &PageOptions::default(),
+            |key, _| {
+                if let Some(block_number) = block_number_from_shard_key(key) {
+                    report.truncated.push(block_number);
+                }
+                txn.delete_cf(SHARD_CHUNKS_CF, key.to_vec());
+                txn.delete_cf(CHUNK_METADATA_CF, key.to_vec());
+                Ok(false) // Continue iterating
+            },
+        )?;
+        self.db.commit(txn)?;
+        // The tip moved down, rescan on the next read
+        *self.max_block_number.lock().unwrap() = None;
+        Ok(report)
     }
 
     pub fn max_block_number(&self) -> Result<u64, ShardStorageError> {
-        let current_height = get_current_height(&self.db)?;
-        match current_height {
-            None => Ok(0),
-            Some(height) => Ok(height),
+        let mut max_block_number = self.max_block_number.lock().unwrap();
+        if let Some(max) = *max_block_number {
+            return Ok(max);
+        }
+
+        let current_height = get_current_height(&self.db)?.unwrap_or(0);
+        *max_block_number = Some(current_height);
+        Ok(current_height)
+    }
+
+    pub fn get_shard_chunk(
+        &self,
+        block_number: u64,
+    ) -> Result<Option<ShardChunk>, ShardStorageError> {
+        match self
+            .db
+            .get_cf(SHARD_CHUNKS_CF, &make_shard_key(block_number))?
+        {
+            None => Ok(None),
+            Some(bytes) => Ok(Some(decode_chunk_record(&bytes)?)),
+        }
+    }
+
+    // Opaque application data for the chunk at this height, stored apart so the chunk itself is never
+    // rewritten. Replaces any metadata already stored for the height.
+    pub fn put_chunk_metadata(
+        &self,
+        block_number: u64,
+        metadata: Vec<u8>,
+    ) -> Result<(), ShardStorageError> {
+        self.check_writable()?;
+        let mut txn = self.db.txn();
+        txn.put_cf(CHUNK_METADATA_CF, make_shard_key(block_number), metadata);
+        self.db.commit(txn)?;
+        Ok(())
+    }
+
+    pub fn get_chunk_metadata(
+        &self,
+        block_number: u64,
+    ) -> Result<Option<Vec<u8>>, ShardStorageError> {
+        Ok(self
+            .db
+            .get_cf(CHUNK_METADATA_CF, &make_shard_key(block_number))?)
+    }
+
+    // Record the chunk as about to be committed, before touching the trie or the chunk store
+    pub fn begin_commit(&self, shard_chunk: &ShardChunk) -> Result<(), ShardStorageError> {
+        self.check_writable()?;
+        let record = encode_chunk_record(shard_chunk);
+        self.db.put(&make_commit_wal_key(), &record)?;
+        buffer_pool::recycle(record);
+        Ok(())
+    }
+
+    // Clear the commit record once the trie and the chunk are both written
+    pub fn end_commit(&self) -> Result<(), ShardStorageError> {
+        self.check_writable()?;
+        self.db.del(&make_commit_wal_key())?;
+        Ok(())
+    }
+
+    // Chunk whose commit was started but never finished, e.g. because the node crashed mid-commit
+    pub fn pending_commit(&self) -> Result<Option<ShardChunk>, ShardStorageError> {
+        match self.db.get(&make_commit_wal_key())? {
+            None => Ok(None),
+            Some(bytes) => Ok(Some(decode_chunk_record(&bytes)?)),
+        }
+    }
+
+    // Height and index of the transaction that committed this message, None when it isn't stored. Entries
+    // left behind by chunks removed without being decoded, e.g. by a truncating repair, are ignored.
+    pub fn find_message(
+        &self,
+        message_hash: &[u8],
+    ) -> Result<Option<(u64, usize)>, ShardStorageError> {
+        let location = match self.db.get_cf(
+            MESSAGE_LOCATIONS_CF,
+            &make_message_location_key(message_hash),
+        )? {
+            None => return Ok(None),
+            Some(value) => decode_message_location(&value),
+        };
+        let (block_number, tx_index) = match location {
+            None => return Ok(None),
+            Some(location) => location,
+        };
+        let committed = self
+            .get_shard_chunk(block_number)?
+            .and_then(|chunk| chunk.transactions.into_iter().nth(tx_index))
+            .map_or(false, |transaction| {
+                transaction
+                    .user_messages
+                    .iter()
+                    .any(|message| message.hash == message_hash)
+            });
+        Ok(committed.then_some((block_number, tx_index)))
+    }
+
+    // State root committed by the chunk at this height, None if there's no chunk stored there
+    pub fn state_root_at(&self, block_number: u64) -> Result<Option<Vec<u8>>, ShardStorageError> {
+        match self
+            .db
+            .get_cf(SHARD_CHUNKS_CF, &make_shard_key(block_number))?
+        {
+            None => Ok(None),
+            Some(bytes) => {
+                let stored = StoredShardChunkHeader::decode(chunk_record_payload(&bytes)?)
+                    .map_err(RocksdbError::from)?;
+                let header = stored.header.ok_or(ShardStorageError::ShardMissingHeader)?;
+                Ok(Some(header.shard_root))
+            }
         }
     }
+
+    // Proof that the transaction is committed to by the chunk's transactions root
+    pub fn prove_transaction(
+        &self,
+        block_number: u64,
+        tx_index: usize,
+    ) -> Result<MerkleProof, ShardStorageError> {
+        let chunk = self
+            .get_shard_chunk(block_number)?
+            .ok_or(ShardStorageError::ChunkNotFound { block_number })?;
+        merkle::prove_transaction(&chunk.transactions, tx_index).ok_or(
+            ShardStorageError::TransactionNotFound {
+                block_number,
+                tx_index,
+            },
+        )
+    }
+
+    // Ranges of heights in [from, to) without a stored chunk, as inclusive (first, last) pairs
+    pub fn find_gaps(&self, from: u64, to: u64) -> Result<Vec<(u64, u64)>, ShardStorageError> {
+        let mut gap_finder = GapFinder::new(from);
+        self.db.for_each_iterator_by_prefix_paged_cf(
+            SHARD_CHUNKS_CF,
+            Some(make_shard_key(from)),
+            Some(make_shard_key(to)),
+            &PageOptions::default(),
+            |key, _| {
+                if let Some(block_number) = block_number_from_shard_key(key) {
+                    gap_finder.observe(block_number);
+                }
+                Ok(false) // Continue iterating
+            },
+        )?;
+        Ok(gap_finder.finish(to))
+    }
+
+    // Total transactions across the chunks in [start, stop), decoding one chunk at a time
+    pub fn transaction_count_in_range(
+        &self,
+        start_block_number: u64,
+        stop_block_number: u64,
+    ) -> Result<u64, ShardStorageError> {
+        let mut count = 0;
+        self.db.for_each_iterator_by_prefix_paged_cf(
+            SHARD_CHUNKS_CF,
+            Some(make_shard_key(start_block_number)),
+            Some(make_shard_key(stop_block_number)),
+            &PageOptions::default(),
+            |_, value| {
+                let shard_chunk = decode_chunk_record(value)?;
+                count += shard_chunk.transactions.len() as u64;
+                Ok(false) // Continue iterating
+            },
+        )?;
+        Ok(count)
+    }
+
+    pub fn get_shard_chunks(
+        &self,
+        start_block_number: u64,
+        stop_block_number: Option<u64>,
+    ) -> Result<Vec<ShardChunk>, ShardStorageError> {
+        self.iter_chunks(start_block_number, stop_block_number, PAGE_SIZE)
+            .collect()
+    }
+
+    // Same as get_shard_chunks for async callers. Each page is read on the blocking pool, yielding
+    // in between, so a large range doesn't hold up the other tasks on the worker.
+    pub async fn get_shard_chunks_async(
+        &self,
+        start_block_number: u64,
+        stop_block_number: Option<u64>,
+    ) -> Result<Vec<ShardChunk>, ShardStorageError> {
+        let mut shard_chunks = vec![];
+        let mut page_token = None;
+        loop {
+            let store = self.clone();
+            let page = tokio::task::spawn_blocking(move || {
+                store.get_shard_chunks_page(
+                    start_block_number,
+                    stop_block_number,
+                    PAGE_SIZE,
+                    page_token,
+                )
+            })
+            .await??;
+            shard_chunks.extend(page.shard_chunks);
+            match page.next_page_token {
+                None => break,
+                Some(token) => page_token = Some(token),
+            }
+            tokio::task::yield_now().await;
+        }
+        Ok(shard_chunks)
+    }
+
+    // A single page of the chunks in [start_block_number, stop_block_number), continuing after
+    // page_token when set
+    pub fn get_shard_chunks_page(
+        &self,
+        start_block_number: u64,
+        stop_block_number: Option<u64>,
+        page_size: usize,
+        page_token: Option<Vec<u8>>,
+    ) -> Result<ShardPage, ShardStorageError> {
+        get_shard_chunks_in_range(
+            &self.db,
+            &PageOptions {
+                page_size: Some(page_size),
+                page_token,
+                reverse: false,
+            },
+            start_block_number,
+            stop_block_number,
+            self.skip_corrupt_chunks,
+        )
+    }
+
+    // Same as get_shard_chunks_page with only the header and hash of each chunk. Only those fields are
+    // decoded, the transactions are skipped over.
+    pub fn get_shard_headers_page(
+        &self,
+        start_block_number: u64,
+        stop_block_number: Option<u64>,
+        page_size: usize,
+        page_token: Option<Vec<u8>>,
+    ) -> Result<ShardHeaderPage, ShardStorageError> {
+        let stop_prefix = match stop_block_number {
+            Some(block_number) => make_shard_key(block_number),
+            None => make_shard_stop_key(),
+        };
+        let mut headers = Vec::new();
+        let mut last_key = vec![];
+        self.db.for_each_iterator_by_prefix_paged_cf(
+            SHARD_CHUNKS_CF,
+            Some(make_shard_key(start_block_number)),
+            Some(stop_prefix),
+            &PageOptions {
+                page_size: Some(page_size),
+                page_token,
+                reverse: false,
+            },
+            |key, value| {
+                headers.push(ShardChunkHeader::decode(chunk_record_payload(value)?)?);
+                if headers.len() >= page_size {
+                    last_key = key.to_vec();
+                    return Ok(true); // Stop iterating
+                }
+                Ok(false) // Continue iterating
+            },
+        )?;
+
+        let next_page_token = if last_key.is_empty() {
+            None
+        } else {
+            Some(last_key)
+        };
+        Ok(ShardHeaderPage {
+            headers,
+            next_page_token,
+        })
+    }
+
+    // Up to the latest n chunks, newest first
+    pub fn latest_n_chunks(&self, n: usize) -> Result<Vec<ShardChunk>, ShardStorageError> {
+        let mut shard_chunks = Vec::with_capacity(n.min(PAGE_SIZE));
+        let mut page_token = None;
+        while shard_chunks.len() < n {
+            let page = get_shard_page_by_prefix(
+                &self.db,
+                &PageOptions {
+                    reverse: true,
+                    page_size: Some((n - shard_chunks.len()).min(PAGE_SIZE)),
+                    page_token,
+                },
+                Some(make_shard_key(0)),
+                Some(make_shard_stop_key()),
+                self.skip_corrupt_chunks,
+            )?;
+            shard_chunks.extend(page.shard_chunks);
+            match page.next_page_token {
+                None => break,
+                Some(token) => page_token = Some(token),
+            }
+        }
+        Ok(shard_chunks)
+    }
+
+    pub fn iter_chunks(
+        &self,
+        start_block_number: u64,
+        stop_block_number: Option<u64>,
+        page_size: usize,
+    ) -> ShardChunkIterator<'_> {
+        ShardChunkIterator::new(self, start_block_number, stop_block_number, page_size)
+    }
+}
+
+/** Iterates over the shard chunks in [start, stop), fetching one page at a time */
+pub struct ShardChunkIterator<'a> {
+    store: &'a ShardStore,
+    start_block_number: u64,
+    stop_block_number: Option<u64>,
+    page_size: usize,
+    page: std::vec::IntoIter<ShardChunk>,
+    next_page_token: Option<Vec<u8>>,
+    done: bool,
+}
+
+impl<'a> ShardChunkIterator<'a> {
+    pub fn new(
+        store: &'a ShardStore,
+        start_block_number: u64,
+        stop_block_number: Option<u64>,
+        page_size: usize,
+    ) -> ShardChunkIterator<'a> {
+        ShardChunkIterator {
+            store,
+            start_block_number,
+            stop_block_number,
+            page_size,
+            page: vec![].into_iter(),
+            next_page_token: None,
+            done: false,
+        }
+    }
+}
+
+impl<'a> Iterator for ShardChunkIterator<'a> {
+    type Item = Result<ShardChunk, ShardStorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(shard_chunk) = self.page.next() {
+                return Some(Ok(shard_chunk));
+            }
+            if self.done {
+                return None;
+            }
+
+            let shard_page = match get_shard_chunks_in_range(
+                &self.store.db,
+                &PageOptions {
+                    page_size: Some(self.page_size),
+                    page_token: self.next_page_token.take(),
+                    reverse: false,
+                },
+                self.start_block_number,
+                self.stop_block_number,
+                self.store.skip_corrupt_chunks,
+            ) {
+                Ok(shard_page) => shard_page,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            };
+            self.done = shard_page.next_page_token.is_none();
+            self.next_page_token = shard_page.next_page_token;
+            self.page = shard_page.shard_chunks.into_iter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::proposer::DEFAULT_HEADER_HASH_LEN;
+    use crate::core::types::Height;
+    use crate::proto::snapchain::ShardHeader;
+
+    fn make_tmp_path() -> String {
+        tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn new_shard_store() -> ShardStore {
+        let db = RocksDB::new(&make_tmp_path());
+        db.open().unwrap();
+        ShardStore::new(db)
+    }
+
+    fn shard_chunk(block_number: u64) -> ShardChunk {
+        ShardChunk {
+            header: Some(ShardHeader {
+                height: Some(Height::new(1, block_number)),
+                timestamp: 0,
+                parent_hash: vec![],
+                shard_root: vec![],
+                version: 0,
+                transactions_root: vec![],
+            }),
+            hash: block_number.to_be_bytes().to_vec(),
+            transactions: vec![],
+            votes: None,
+        }
+    }
+
+    // Chunks with valid header hashes, each linked to the one before it
+    fn linked_chunks(count: u64) -> Vec<ShardChunk> {
+        let mut parent_hash = vec![];
+        (1..=count)
+            .map(|block_number| {
+                let mut chunk = shard_chunk(block_number);
+                let header = chunk.header.as_mut().unwrap();
+                header.parent_hash = parent_hash.clone();
+                header.version = 32;
+                chunk.hash = header_hash(&header.encode_to_vec(), 32);
+                parent_hash = chunk.hash.clone();
+                chunk
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_unversioned_chunks() {
+        let store = new_shard_store();
+        // Written before header versions recorded the hash length
+        let mut chunks = linked_chunks(3);
+        let mut parent_hash = vec![];
+        for chunk in chunks.iter_mut() {
+            let header = chunk.header.as_mut().unwrap();
+            header.parent_hash = parent_hash.clone();
+            header.version = 0;
+            chunk.hash = header_hash(&header.encode_to_vec(), DEFAULT_HEADER_HASH_LEN);
+            parent_hash = chunk.hash.clone();
+        }
+        store.put_shard_chunks_batch(&chunks).unwrap();
+
+        let report = store.verify_and_repair(true).unwrap();
+        assert_eq!(report.chunks_checked, 3);
+        assert_eq!(report.first_corruption, None);
+        assert!(report.truncated.is_empty());
+        assert_eq!(store.max_block_number().unwrap(), 3);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_verify_and_repair_truncates_at_corruption() {
+        let store = new_shard_store();
+        store.put_shard_chunks_batch(&linked_chunks(5)).unwrap();
+        assert_eq!(
+            store.verify_and_repair(false).unwrap(),
+            RepairReport {
+                chunks_checked: 5,
+                first_corruption: None,
+                truncated: vec![],
+            }
+        );
+
+        // Tamper with chunk 3's header without rehashing it
+        let mut corrupted = store.get_shard_chunk(3).unwrap().unwrap();
+        corrupted.header.as_mut().unwrap().timestamp = 42;
+        store.put_shard_chunk(corrupted).unwrap();
+
+        // Report only by default
+        let report = store.verify_and_repair(false).unwrap();
+        assert_eq!(
+            report.first_corruption,
+            Some((3, ChunkCorruption::HashMismatch))
+        );
+        assert!(report.truncated.is_empty());
+        assert_eq!(store.max_block_number().unwrap(), 5);
+
+        let report = store.verify_and_repair(true).unwrap();
+        assert_eq!(
+            report.first_corruption,
+            Some((3, ChunkCorruption::HashMismatch))
+        );
+        assert_eq!(report.truncated, vec![3, 4, 5]);
+        assert_eq!(store.max_block_number().unwrap(), 2);
+        assert_eq!(store.get_shard_chunk(3).unwrap(), None);
+
+        let report = store.verify_and_repair(true).unwrap();
+        assert_eq!(report.chunks_checked, 2);
+        assert_eq!(report.first_corruption, None);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_put_shard_chunks_batch() {
+        let store = new_shard_store();
+        let chunks: Vec<ShardChunk> = (1..=1000).map(shard_chunk).collect();
+
+        store.put_shard_chunks_batch(&chunks).unwrap();
+
+        assert_eq!(store.get_shard_chunks(0, None).unwrap(), chunks);
+        assert_eq!(store.max_block_number().unwrap(), 1000);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_put_shard_chunks_batch_not_contiguous() {
+        let store = new_shard_store();
+        let chunks = vec![shard_chunk(1), shard_chunk(2), shard_chunk(4)];
+
+        let result = store.put_shard_chunks_batch(&chunks);
+
+        assert!(matches!(
+            result,
+            Err(ShardStorageError::NonContiguousChunks {
+                expected: 3,
+                actual: 4
+            })
+        ));
+        assert_eq!(store.max_block_number().unwrap(), 0);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_read_only_store_rejects_writes() {
+        let tmp_path = make_tmp_path();
+        let db = RocksDB::new(&tmp_path);
+        db.open().unwrap();
+        put_shard_chunk(&db, shard_chunk(1)).unwrap();
+
+        let store = ShardStore::new_read_only(db);
+        assert!(store.is_read_only());
+        assert!(matches!(
+            store.put_shard_chunk(shard_chunk(2)),
+            Err(ShardStorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            store.put_shard_chunks_batch(&[shard_chunk(2)]),
+            Err(ShardStorageError::ReadOnly)
+        ));
+
+        // Reads keep working
+        assert_eq!(store.max_block_number().unwrap(), 1);
+        assert_eq!(
+            store.get_shard_chunks(0, None).unwrap(),
+            vec![shard_chunk(1)]
+        );
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_put_shard_chunk_if_absent_is_idempotent() {
+        let store = new_shard_store();
+
+        store.put_shard_chunk_if_absent(shard_chunk(1)).unwrap();
+        store.put_shard_chunk_if_absent(shard_chunk(1)).unwrap();
+
+        assert_eq!(
+            store.get_shard_chunks(0, None).unwrap(),
+            vec![shard_chunk(1)]
+        );
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_put_shard_chunk_if_absent_rejects_conflicting_chunk() {
+        let store = new_shard_store();
+        store.put_shard_chunk_if_absent(shard_chunk(1)).unwrap();
+
+        let mut conflicting = shard_chunk(1);
+        conflicting.hash = vec![9, 9];
+        let result = store.put_shard_chunk_if_absent(conflicting);
+
+        match result {
+            Err(ShardStorageError::AlreadyExists {
+                block_number,
+                stored_hash,
+            }) => {
+                assert_eq!(block_number, 1);
+                assert_eq!(stored_hash, shard_chunk(1).hash);
+            }
+            other => panic!("Expected AlreadyExists, got {:?}", other),
+        }
+        assert_eq!(
+            store.get_shard_chunks(0, None).unwrap(),
+            vec![shard_chunk(1)]
+        );
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_max_block_number_cache_matches_scan() {
+        let tmp_path = make_tmp_path();
+        let db = RocksDB::new(&tmp_path);
+        db.open().unwrap();
+        put_shard_chunk(&db, shard_chunk(1)).unwrap();
+        let store = ShardStore::new(db);
+
+        // First access falls back to the scan
+        assert_eq!(store.max_block_number().unwrap(), 1);
+
+        store.put_shard_chunk(shard_chunk(2)).unwrap();
+        assert_eq!(store.max_block_number().unwrap(), 2);
+
+        store
+            .put_shard_chunks_batch(&[shard_chunk(3), shard_chunk(4)])
+            .unwrap();
+        assert_eq!(store.max_block_number().unwrap(), 4);
+
+        // Re-putting an older chunk doesn't lower the max
+        store.put_shard_chunk_if_absent(shard_chunk(2)).unwrap();
+        assert_eq!(store.max_block_number().unwrap(), 4);
+
+        // Clones share the cache
+        let clone = store.clone();
+        clone.put_shard_chunk(shard_chunk(5)).unwrap();
+        assert_eq!(store.max_block_number().unwrap(), 5);
+
+        assert_eq!(get_current_height(&store.db).unwrap(), Some(5));
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_iter_chunks_across_pages() {
+        let store = new_shard_store();
+        let chunks: Vec<ShardChunk> = (1..=250).map(shard_chunk).collect();
+        store.put_shard_chunks_batch(&chunks).unwrap();
+
+        let mut iterated = vec![];
+        for chunk in store.iter_chunks(0, None, 100) {
+            iterated.push(chunk.unwrap());
+        }
+        assert_eq!(iterated, chunks);
+
+        // Bounded ranges stop early
+        let iterated: Vec<ShardChunk> = store
+            .iter_chunks(10, Some(20), 3)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(iterated, chunks[9..19].to_vec());
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_prove_transaction() {
+        let store = new_shard_store();
+        let mut chunk = shard_chunk(1);
+        chunk.transactions = (1..=5)
+            .map(|fid| Transaction {
+                fid,
+                ..Default::default()
+            })
+            .collect();
+        let root = merkle::transactions_root(&chunk.transactions);
+        chunk.header.as_mut().unwrap().transactions_root = root.clone();
+        store.put_shard_chunk(chunk.clone()).unwrap();
+
+        let proof = store.prove_transaction(1, 3).unwrap();
+        assert!(merkle::verify_transaction_proof(
+            &root,
+            &proof,
+            &chunk.transactions[3]
+        ));
+
+        // A tampered transaction or proof doesn't verify
+        let mut tampered_tx = chunk.transactions[3].clone();
+        tampered_tx.fid = 42;
+        assert!(!merkle::verify_transaction_proof(
+            &root,
+            &proof,
+            &tampered_tx
+        ));
+        let mut tampered_proof = proof.clone();
+        tampered_proof.siblings.pop();
+        assert!(!merkle::verify_transaction_proof(
+            &root,
+            &tampered_proof,
+            &chunk.transactions[3]
+        ));
+
+        assert!(matches!(
+            store.prove_transaction(1, 5),
+            Err(ShardStorageError::TransactionNotFound { .. })
+        ));
+        assert!(matches!(
+            store.prove_transaction(2, 0),
+            Err(ShardStorageError::ChunkNotFound { block_number: 2 })
+        ));
+
+        store.db.destroy().unwrap();
+    }
+
+    fn chunk_with_messages(block_number: u64, hashes: &[&[Vec<u8>]]) -> ShardChunk {
+        let mut chunk = shard_chunk(block_number);
+        chunk.transactions = hashes
+            .iter()
+            .map(|hashes| Transaction {
+                user_messages: hashes
+                    .iter()
+                    .map(|hash| crate::proto::message::Message {
+                        hash: hash.clone(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+        chunk
+    }
+
+    #[test]
+    fn test_find_message() {
+        let store = new_shard_store();
+        store
+            .put_shard_chunk(chunk_with_messages(1, &[&[vec![1; 20]]]))
+            .unwrap();
+        store
+            .put_shard_chunks_batch(&[chunk_with_messages(
+                2,
+                &[&[vec![2; 20]], &[vec![3; 20], vec![4; 20]]],
+            )])
+            .unwrap();
+
+        assert_eq!(store.find_message(&[1; 20]).unwrap(), Some((1, 0)));
+        assert_eq!(store.find_message(&[2; 20]).unwrap(), Some((2, 0)));
+        assert_eq!(store.find_message(&[4; 20]).unwrap(), Some((2, 1)));
+        assert_eq!(store.find_message(&[5; 20]).unwrap(), None);
+        // Locations live in their own column family, not the default one
+        assert!(store
+            .db
+            .get(&make_message_location_key(&[1; 20]))
+            .unwrap()
+            .is_none());
+
+        // Pruning drops the index entries with the chunks
+        store.prune_chunks_before(2).unwrap();
+        assert_eq!(store.find_message(&[1; 20]).unwrap(), None);
+        assert_eq!(store.find_message(&[3; 20]).unwrap(), Some((2, 1)));
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_chunk_puts_reuse_encode_buffers() {
+        let store = new_shard_store();
+        let before = buffer_pool::stats();
+        for block_number in 1..=50 {
+            store.put_shard_chunk(shard_chunk(block_number)).unwrap();
+        }
+        let after = buffer_pool::stats();
+        // Only the first put allocates, every later one encodes into the buffer the previous commit freed
+        assert_eq!(after.allocated - before.allocated, 1);
+        assert_eq!(after.reused - before.reused, 49);
+        assert_eq!(store.get_shard_chunk(50).unwrap(), Some(shard_chunk(50)));
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_transaction_count_in_range() {
+        let store = new_shard_store();
+        let chunks: Vec<ShardChunk> = (1..=5)
+            .map(|block_number| {
+                let mut chunk = shard_chunk(block_number);
+                chunk.transactions = vec![Transaction::default(); block_number as usize];
+                chunk
+            })
+            .collect();
+        store.put_shard_chunks_batch(&chunks).unwrap();
+
+        assert_eq!(store.transaction_count_in_range(1, 6).unwrap(), 15);
+        // The stop height is excluded
+        assert_eq!(store.transaction_count_in_range(2, 4).unwrap(), 5);
+        assert_eq!(store.transaction_count_in_range(4, 100).unwrap(), 9);
+        assert_eq!(store.transaction_count_in_range(6, 10).unwrap(), 0);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_chunk_metadata_independent_of_chunk() {
+        let store = new_shard_store();
+        store.put_shard_chunk(shard_chunk(1)).unwrap();
+
+        store.put_chunk_metadata(1, b"events=3".to_vec()).unwrap();
+        assert_eq!(
+            store.get_chunk_metadata(1).unwrap(),
+            Some(b"events=3".to_vec())
+        );
+        // The canonical chunk is untouched
+        assert_eq!(store.get_shard_chunk(1).unwrap(), Some(shard_chunk(1)));
+        assert_eq!(store.get_chunk_metadata(2).unwrap(), None);
+
+        store.put_chunk_metadata(1, b"events=4".to_vec()).unwrap();
+        assert_eq!(
+            store.get_chunk_metadata(1).unwrap(),
+            Some(b"events=4".to_vec())
+        );
+
+        // Pruning the chunk drops its metadata with it
+        store.put_shard_chunk(shard_chunk(2)).unwrap();
+        store.prune_chunks_before(2).unwrap();
+        assert_eq!(store.get_chunk_metadata(1).unwrap(), None);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_state_root_at() {
+        let store = new_shard_store();
+        let chunks: Vec<ShardChunk> = (1..=5)
+            .map(|block_number| {
+                let mut chunk = shard_chunk(block_number);
+                chunk.header.as_mut().unwrap().shard_root = vec![block_number as u8; 32];
+                chunk
+            })
+            .collect();
+        store.put_shard_chunks_batch(&chunks).unwrap();
+
+        for chunk in [&chunks[0], &chunks[2], &chunks[4]] {
+            let header = chunk.header.as_ref().unwrap();
+            assert_eq!(
+                store
+                    .state_root_at(header.height.unwrap().block_number)
+                    .unwrap(),
+                Some(header.shard_root.clone())
+            );
+        }
+        assert_eq!(store.state_root_at(6).unwrap(), None);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_latest_n_chunks() {
+        let store = new_shard_store();
+        let chunks: Vec<ShardChunk> = (1..=5).map(shard_chunk).collect();
+        store.put_shard_chunks_batch(&chunks).unwrap();
+
+        let latest: Vec<ShardChunk> = chunks.iter().rev().cloned().collect();
+        assert_eq!(store.latest_n_chunks(10).unwrap(), latest);
+        assert_eq!(store.latest_n_chunks(3).unwrap(), latest[..3].to_vec());
+        assert_eq!(store.latest_n_chunks(0).unwrap(), vec![]);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_shard_chunks_stored_in_column_family() {
+        let store = new_shard_store();
+        store.put_shard_chunk(shard_chunk(1)).unwrap();
+        store.put_shard_chunks_batch(&[shard_chunk(2)]).unwrap();
+
+        for block_number in [1, 2] {
+            let key = make_shard_key(block_number);
+            assert!(store.db.get(&key).unwrap().is_none());
+            assert!(store.db.get_cf(SHARD_CHUNKS_CF, &key).unwrap().is_some());
+        }
+        assert_eq!(store.get_shard_chunk(2).unwrap(), Some(shard_chunk(2)));
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_reads_legacy_and_versioned_records() {
+        let store = new_shard_store();
+        // Written before records were versioned
+        let mut txn = store.db.txn();
+        txn.put_cf(
+            SHARD_CHUNKS_CF,
+            make_shard_key(1),
+            shard_chunk(1).encode_to_vec(),
+        );
+        store.db.commit(txn).unwrap();
+        store.put_shard_chunk(shard_chunk(2)).unwrap();
+
+        let stored = store
+            .db
+            .get_cf(SHARD_CHUNKS_CF, &make_shard_key(2))
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored[0], CHUNK_RECORD_VERSION);
+        assert_eq!(
+            store.get_shard_chunks(1, None).unwrap(),
+            vec![shard_chunk(1), shard_chunk(2)]
+        );
+        // Re-putting the legacy chunk is still a no-op
+        store.put_shard_chunk_if_absent(shard_chunk(1)).unwrap();
+
+        let mut txn = store.db.txn();
+        txn.put_cf(SHARD_CHUNKS_CF, make_shard_key(3), vec![2, 0, 0]);
+        store.db.commit(txn).unwrap();
+        assert!(matches!(
+            store.get_shard_chunk(3),
+            Err(ShardStorageError::RocksdbError(
+                RocksdbError::UnknownRecordVersion(2)
+            ))
+        ));
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_skip_corrupt_chunks_in_range() {
+        let store = new_shard_store();
+        for block_number in 1..=4 {
+            store.put_shard_chunk(shard_chunk(block_number)).unwrap();
+        }
+        let mut txn = store.db.txn();
+        txn.put_cf(
+            SHARD_CHUNKS_CF,
+            make_shard_key(2),
+            vec![CHUNK_RECORD_VERSION, 0xff, 0xff],
+        );
+        store.db.commit(txn).unwrap();
+
+        assert!(store.get_shard_chunks_page(1, None, 10, None).is_err());
+
+        let store = store.with_skip_corrupt_chunks(true);
+        let page = store.get_shard_chunks_page(1, None, 10, None).unwrap();
+        assert_eq!(
+            page.shard_chunks,
+            vec![shard_chunk(1), shard_chunk(3), shard_chunk(4)]
+        );
+        assert_eq!(page.corrupt_keys, vec![make_shard_key(2)]);
+        assert_eq!(store.get_shard_chunks(1, None).unwrap().len(), 3);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_shard_headers_match_full_chunks() {
+        let store = new_shard_store();
+        let mut chunks = linked_chunks(5);
+        chunks[2].transactions = vec![Transaction {
+            fid: 2,
+            ..Default::default()
+        }];
+        store.put_shard_chunks_batch(&chunks).unwrap();
+
+        let full = store.get_shard_chunks_page(2, Some(5), 10, None).unwrap();
+        let headers = store.get_shard_headers_page(2, Some(5), 10, None).unwrap();
+        assert_eq!(
+            headers.headers,
+            full.shard_chunks
+                .iter()
+                .map(|chunk| ShardChunkHeader {
+                    header: chunk.header.clone(),
+                    hash: chunk.hash.clone(),
+                })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(headers.headers.len(), 3);
+        assert_eq!(headers.next_page_token, None);
+
+        let first = store.get_shard_headers_page(1, None, 2, None).unwrap();
+        assert_eq!(first.headers.len(), 2);
+        let rest = store
+            .get_shard_headers_page(1, None, 10, first.next_page_token)
+            .unwrap();
+        assert_eq!(rest.headers.len(), 3);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[test]
+    fn test_find_gaps() {
+        let store = new_shard_store();
+        for block_number in [1, 2, 5, 6, 7, 10] {
+            store.put_shard_chunk(shard_chunk(block_number)).unwrap();
+        }
+
+        assert_eq!(store.find_gaps(1, 11).unwrap(), vec![(3, 4), (8, 9)]);
+        assert_eq!(store.find_gaps(5, 8).unwrap(), vec![]);
+        assert_eq!(store.find_gaps(6, 13).unwrap(), vec![(8, 9), (11, 12)]);
+
+        store.db.destroy().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_async_read_lets_other_tasks_run() {
+        let store = new_shard_store();
+        let count = 5 * PAGE_SIZE as u64 + 7;
+        for block_number in 1..=count {
+            store.put_shard_chunk(shard_chunk(block_number)).unwrap();
+        }
+
+        // On the single threaded test runtime this only advances while the read is waiting
+        let ticks = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    ticks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    tokio::task::yield_now().await;
+                }
+            }
+        });
+
+        let shard_chunks = store.get_shard_chunks_async(1, None).await.unwrap();
+        assert_eq!(shard_chunks.len() as u64, count);
+        assert_eq!(shard_chunks, store.get_shard_chunks(1, None).unwrap());
+        assert!(ticks.load(std::sync::atomic::Ordering::SeqCst) >= 5);
+
+        ticker.abort();
+        store.db.destroy().unwrap();
+    }
 }
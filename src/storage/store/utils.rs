@@ -25,3 +25,36 @@ pub fn increment_vec_u8(vec: &Vec<u8>) -> Vec<u8> {
 
     result
 }
+
+/** Tracks the heights missing from an ascending scan, as inclusive (first, last) ranges */
+pub struct GapFinder {
+    expected: u64,
+    gaps: Vec<(u64, u64)>,
+}
+
+impl GapFinder {
+    pub fn new(from: u64) -> GapFinder {
+        GapFinder {
+            expected: from,
+            gaps: vec![],
+        }
+    }
+
+    pub fn observe(&mut self, height: u64) {
+        if height < self.expected {
+            return;
+        }
+        if height > self.expected {
+            self.gaps.push((self.expected, height - 1));
+        }
+        self.expected = height + 1;
+    }
+
+    // Heights from the last observed one up to `to` (exclusive) are missing too
+    pub fn finish(mut self, to: u64) -> Vec<(u64, u64)> {
+        if self.expected < to {
+            self.gaps.push((self.expected, to - 1));
+        }
+        self.gaps
+    }
+}
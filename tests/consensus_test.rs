@@ -68,12 +68,17 @@ impl NodeForTest {
             block_store.clone(),
             make_tmp_path(),
         )
-        .await;
+        .await
+        .unwrap();
 
         let node_id = node.id();
         let assert_valid_block = move |block: &Block| {
             let header = block.header.as_ref().unwrap();
-            let message_count = block.shard_chunks[0].transactions[0].user_messages.len();
+            let message_count: usize = block
+                .shard_chunks
+                .iter()
+                .map(|chunk| chunk.iter_messages().count())
+                .sum();
             info!(
                 hash = hex::encode(&block.hash),
                 height = header.height.as_ref().map(|h| h.block_number),
@@ -92,15 +97,24 @@ impl NodeForTest {
             }
         });
 
-        //TODO: don't assume shard
-        //TODO: remove/redo unwrap
-        let messages_tx = node.messages_tx_by_shard.get(&1u32).unwrap().clone();
+        let messages_tx_by_shard = node.messages_tx_by_shard.clone();
+        let shard_assignment = node.shard_assignment.clone();
 
         let grpc_addr = format!("0.0.0.0:{}", grpc_port);
         let addr = grpc_addr.clone();
         let grpc_block_store = block_store.clone();
+        let grpc_shard_stores = node.shard_stores.clone();
+        let grpc_address = node.address.clone();
+        let grpc_validator_sets = node.validator_sets.clone();
         tokio::spawn(async move {
-            let service = MySnapchainService::new(grpc_block_store, messages_tx);
+            let service = MySnapchainService::new(
+                grpc_address,
+                grpc_block_store,
+                grpc_shard_stores,
+                grpc_validator_sets,
+                messages_tx_by_shard,
+                shard_assignment,
+            );
 
             let grpc_socket_addr: SocketAddr = addr.parse().unwrap();
             let resp = Server::builder()
@@ -130,22 +144,23 @@ impl NodeForTest {
         self.gossip_rx.recv().await
     }
 
-    pub fn cast(&self, msg: ConsensusMsg<SnapchainValidatorContext>) {
-        self.node.dispatch(msg)
+    pub async fn cast(&self, msg: ConsensusMsg<SnapchainValidatorContext>) {
+        self.node.dispatch(msg).await;
     }
 
-    pub fn start_height(&self, block_number: u64) {
-        self.node.start_height(block_number);
+    pub async fn start_height(&self, block_number: u64) {
+        self.node.start_height(block_number).await;
     }
 
-    pub fn register_keypair(&self, keypair: Keypair, rpc_address: String) {
+    pub async fn register_keypair(&self, keypair: Keypair, rpc_address: String) {
         for i in 0..=self.num_shards {
             self.cast(ConsensusMsg::RegisterValidator(SnapchainValidator::new(
                 SnapchainShard::new(i),
                 keypair.public().clone(),
                 Some(rpc_address.clone()),
                 0,
-            )));
+            )))
+            .await;
         }
     }
 
@@ -170,7 +185,7 @@ impl NodeForTest {
                 .get_blocks(0, None, i)
                 .unwrap()
                 .into_iter()
-                .map(|b| b.shard_chunks[0].transactions[0].user_messages.len());
+                .map(|b| b.shard_chunks[0].iter_messages().count());
             count += messages.len()
         }
 
@@ -198,7 +213,8 @@ impl TestNetwork {
         for i in 0..num_nodes {
             for keypair in keypairs.iter() {
                 nodes[i as usize]
-                    .register_keypair(keypair.clone(), format!("0.0.0.0:{}", base_grpc_port + i));
+                    .register_keypair(keypair.clone(), format!("0.0.0.0:{}", base_grpc_port + i))
+                    .await;
             }
         }
         // Wait for the RegisterValidator message to be processed
@@ -209,7 +225,7 @@ impl TestNetwork {
 
     pub async fn produce_blocks(&mut self, num_blocks: u64) {
         for node in self.nodes.iter_mut() {
-            node.start_height(1);
+            node.start_height(1).await;
         }
 
         let timeout = tokio::time::Duration::from_secs(5);
@@ -230,32 +246,7 @@ impl TestNetwork {
                 }
             }
 
-            // Loop through each node, and select all other nodes to send gossip messages
-            for i in 0..self.nodes.len() {
-                if let Ok(gossip_event) = self.nodes[i].gossip_rx.try_recv() {
-                    match gossip_event {
-                        GossipEvent::BroadcastSignedProposal(proposal) => {
-                            self.dispatch_to_other_nodes(
-                                i,
-                                ConsensusMsg::ReceivedSignedProposal(proposal.clone()),
-                            );
-                        }
-                        GossipEvent::BroadcastSignedVote(vote) => {
-                            self.dispatch_to_other_nodes(
-                                i,
-                                ConsensusMsg::ReceivedSignedVote(vote.clone()),
-                            );
-                        }
-                        GossipEvent::BroadcastFullProposal(full_proposal) => {
-                            self.dispatch_to_other_nodes(
-                                i,
-                                ConsensusMsg::ReceivedFullProposal(full_proposal.clone()),
-                            );
-                        }
-                        _ => {}
-                    }
-                }
-            }
+            self.route_gossip_events().await;
 
             if start.elapsed() > timeout {
                 break;
@@ -263,10 +254,60 @@ impl TestNetwork {
         }
     }
 
-    fn dispatch_to_other_nodes(&self, i: usize, msg: ConsensusMsg<SnapchainValidatorContext>) {
+    // Start all nodes and route gossip between them for the given duration
+    pub async fn run_for(&mut self, duration: time::Duration) {
+        for node in self.nodes.iter_mut() {
+            node.start_height(1).await;
+        }
+
+        let start = tokio::time::Instant::now();
+        let mut timer = time::interval(tokio::time::Duration::from_millis(10));
+        while start.elapsed() < duration {
+            let _ = timer.tick().await;
+            self.route_gossip_events().await;
+        }
+    }
+
+    // Loop through each node, and select all other nodes to send gossip messages
+    async fn route_gossip_events(&mut self) {
+        for i in 0..self.nodes.len() {
+            if let Ok(gossip_event) = self.nodes[i].gossip_rx.try_recv() {
+                match gossip_event {
+                    GossipEvent::BroadcastSignedProposal(proposal) => {
+                        self.dispatch_to_other_nodes(
+                            i,
+                            ConsensusMsg::ReceivedSignedProposal(proposal.clone()),
+                        )
+                        .await;
+                    }
+                    GossipEvent::BroadcastSignedVote(vote) => {
+                        self.dispatch_to_other_nodes(
+                            i,
+                            ConsensusMsg::ReceivedSignedVote(vote.clone()),
+                        )
+                        .await;
+                    }
+                    GossipEvent::BroadcastFullProposal(full_proposal) => {
+                        self.dispatch_to_other_nodes(
+                            i,
+                            ConsensusMsg::ReceivedFullProposal(full_proposal.clone()),
+                        )
+                        .await;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    async fn dispatch_to_other_nodes(
+        &self,
+        i: usize,
+        msg: ConsensusMsg<SnapchainValidatorContext>,
+    ) {
         for j in 0..self.nodes.len() {
             if i != j {
-                self.nodes[j].cast(msg.clone());
+                self.nodes[j].cast(msg.clone()).await;
             }
         }
     }
@@ -285,9 +326,8 @@ async fn test_basic_consensus() {
     let messages_tx1 = network.nodes[0]
         .node
         .messages_tx_by_shard
-        .get(&1u32)
-        .expect("message channel should exist")
-        .clone();
+        .get(1)
+        .expect("message channel should exist");
 
     tokio::spawn(async move {
         let mut i: i32 = 0;
@@ -361,13 +401,17 @@ async fn test_basic_block_sync() {
     network.produce_blocks(3).await;
 
     let node4 = NodeForTest::create(keypair4.clone(), num_shards, 3207).await;
-    node4.register_keypair(keypair4.clone(), format!("0.0.0.0:{}", 3207));
-    node4.cast(ConsensusMsg::RegisterValidator(SnapchainValidator::new(
-        SnapchainShard::new(0),
-        network.nodes[0].keypair.public().clone(),
-        Some(network.nodes[0].grpc_addr.clone()),
-        network.nodes[0].num_blocks().await as u64,
-    )));
+    node4
+        .register_keypair(keypair4.clone(), format!("0.0.0.0:{}", 3207))
+        .await;
+    node4
+        .cast(ConsensusMsg::RegisterValidator(SnapchainValidator::new(
+            SnapchainShard::new(0),
+            network.nodes[0].keypair.public().clone(),
+            Some(network.nodes[0].grpc_addr.clone()),
+            network.nodes[0].num_blocks().await as u64,
+        )))
+        .await;
 
     let timeout = tokio::time::Duration::from_secs(5);
     let start = tokio::time::Instant::now();
@@ -399,3 +443,58 @@ async fn test_basic_block_sync() {
         "Node 4 should have confirmed blocks"
     );
 }
+
+#[tokio::test]
+async fn test_pause_shard() {
+    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .try_init();
+
+    let num_shards = 2;
+    let mut network = TestNetwork::create(3, num_shards, 3220).await;
+
+    for node in network.nodes.iter() {
+        node.node.pause_shard(1);
+    }
+
+    network.run_for(time::Duration::from_secs(2)).await;
+
+    for node in network.nodes.iter() {
+        let metrics = &node.node.consensus_metrics;
+        assert!(
+            metrics.decided_rounds(1).is_empty(),
+            "Paused shard 1 should not decide any heights"
+        );
+        assert!(
+            !metrics.decided_rounds(2).is_empty(),
+            "Shard 2 should keep producing while shard 1 is paused"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_block_only_node() {
+    let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("warn"));
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(env_filter)
+        .try_init();
+
+    let mut network = TestNetwork::create(3, 0, 3240).await;
+    assert!(network.nodes[0].node.messages_tx_by_shard.is_empty());
+
+    network.run_for(time::Duration::from_secs(2)).await;
+
+    for node in network.nodes.iter() {
+        let blocks = node.block_store.get_blocks(0, None, 0).unwrap();
+        assert!(
+            blocks.len() >= 3,
+            "Node should produce blocks without shards"
+        );
+        for (i, block) in blocks.iter().enumerate() {
+            let height = block.header.as_ref().unwrap().height.unwrap();
+            assert_eq!(height.block_number, i as u64 + 1);
+            assert!(block.shard_chunks.is_empty());
+        }
+    }
+}